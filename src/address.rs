@@ -0,0 +1,151 @@
+#[cfg(feature = "backtrace")]
+use std::backtrace::Backtrace;
+use std::fmt;
+use std::net::Ipv6Addr;
+use thiserror::Error;
+
+pub const DEFAULT_SERVER_PORT: u16 = 25565;
+
+#[derive(Debug, Error)]
+pub enum AddressError {
+    #[error("server address has no host")]
+    EmptyHost {
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("bad port '{port}' in server address")]
+    BadPort {
+        port: String,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("bad IPv6 address '{address}'")]
+    BadIpv6 {
+        address: String,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+}
+
+impl AddressError {
+    fn empty_host() -> Self {
+        AddressError::EmptyHost {
+            #[cfg(feature = "backtrace")]
+            backtrace: Backtrace::capture(),
+        }
+    }
+
+    fn bad_port(port: &str) -> Self {
+        AddressError::BadPort {
+            port: port.to_owned(),
+            #[cfg(feature = "backtrace")]
+            backtrace: Backtrace::capture(),
+        }
+    }
+
+    fn bad_ipv6(address: &str) -> Self {
+        AddressError::BadIpv6 {
+            address: address.to_owned(),
+            #[cfg(feature = "backtrace")]
+            backtrace: Backtrace::capture(),
+        }
+    }
+}
+
+///
+/// A server address, as typed by a player or sent in a handshake, split into its host and port.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ServerAddress {
+    ///
+    /// The host name (lowercased, without trailing dots) or IP address. IPv6 addresses are stored
+    /// without brackets.
+    ///
+    pub host: String,
+    ///
+    /// The port, if one was given. When there is none, the vanilla client looks up the host's SRV
+    /// record before falling back to `DEFAULT_SERVER_PORT`.
+    ///
+    pub port: Option<u16>,
+}
+
+impl ServerAddress {
+    pub fn port_or_default(&self) -> u16 {
+        self.port.unwrap_or(DEFAULT_SERVER_PORT)
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.host.contains(':')
+    }
+}
+
+impl fmt::Display for ServerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_ipv6() {
+            write!(f, "[{}]", self.host)?;
+        } else {
+            f.write_str(&self.host)?;
+        }
+
+        if let Some(port) = self.port {
+            write!(f, ":{}", port)?;
+        }
+
+        Ok(())
+    }
+}
+
+///
+/// Parses a server address, handling all the forms it is found in:
+///
+/// * `host` and `host:port`
+/// * IPv6 literals, either bare (`::1`, which cannot have a port) or in brackets (`[::1]:25565`)
+/// * a trailing dot after the host name (`example.com.`), which clients send when the address was
+///   resolved using an SRV record
+/// * markers appended by mod loaders and proxies after a `\0` (such as Forge's `\0FML\0`), which
+///   are removed
+///
+/// Host names are lowercased, since they are not case sensitive.
+///
+pub fn parse_server_address(input: &str) -> Result<ServerAddress, AddressError> {
+    let input = input.split('\0').next().unwrap_or("").trim();
+
+    let (host, port) = if let Some(rest) = input.strip_prefix('[') {
+        let end = rest.find(']').ok_or_else(|| AddressError::bad_ipv6(input))?;
+        let host = &rest[..end];
+        let port = match &rest[end + 1..] {
+            "" => None,
+            after => Some(after.strip_prefix(':').ok_or_else(|| AddressError::bad_port(after))?),
+        };
+        (parse_ipv6(host)?, port)
+    } else if input.matches(':').count() > 1 {
+        (parse_ipv6(input)?, None)
+    } else {
+        match input.rsplit_once(':') {
+            Some((host, port)) => (normalize_host_name(host), Some(port)),
+            None => (normalize_host_name(input), None),
+        }
+    };
+
+    if host.is_empty() {
+        return Err(AddressError::empty_host());
+    }
+
+    let port = match port {
+        Some(port) => Some(port.parse::<u16>().map_err(|_| AddressError::bad_port(port))?),
+        None => None,
+    };
+
+    Ok(ServerAddress { host, port })
+}
+
+fn parse_ipv6(address: &str) -> Result<String, AddressError> {
+    address
+        .parse::<Ipv6Addr>()
+        .map(|ip| ip.to_string())
+        .map_err(|_| AddressError::bad_ipv6(address))
+}
+
+fn normalize_host_name(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
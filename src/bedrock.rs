@@ -0,0 +1,1542 @@
+use crate::util::is_timeout;
+#[cfg(feature = "backtrace")]
+use std::backtrace::Backtrace;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+#[cfg(feature = "tokio-io")]
+use tokio::net::UdpSocket as TokioUdpSocket;
+
+///
+/// The largest datagram sent by default, which fits into the MTU of nearly every path.
+///
+pub const DEFAULT_RAKNET_MTU: usize = 1400;
+
+///
+/// The smallest and largest datagram sizes a `RakSession` can be configured with.
+///
+pub const MIN_RAKNET_MTU: usize = 576;
+pub const MAX_RAKNET_MTU: usize = 1500;
+
+///
+/// The number of ordering channels RakNet has. Ordered and sequenced messages are only ordered
+/// relative to other messages on the same channel.
+///
+pub const RAKNET_ORDER_CHANNELS: usize = 32;
+
+const FLAG_VALID: u8 = 0x80;
+const FLAG_ACK: u8 = 0x40;
+const FLAG_NACK: u8 = 0x20;
+const FLAG_SPLIT: u8 = 0x10;
+// the id of a datagram carrying frames, which has only the valid flag set
+const FRAME_SET_ID: u8 = 0x84;
+
+// the id and the sequence number
+const DATAGRAM_HEADER_SIZE: usize = 4;
+
+// indexes are 24 bit numbers which wrap around
+const U24_MASK: u32 = 0xFF_FFFF;
+const HALF_U24: u32 = 0x80_0000;
+
+// limits which stop a peer from making a session hold unbounded amounts of data
+const MAX_SPLIT_COUNT: u32 = 8192;
+const MAX_OPEN_SPLITS: usize = 32;
+// the most bytes the parts of incomplete split messages may hold, across all of them
+const MAX_SPLIT_BYTES: usize = 16 * 1024 * 1024;
+const MAX_PENDING_ORDERED: usize = 4096;
+const MAX_RELIABLE_GAP: usize = 65536;
+const MAX_NACK_GAP: u32 = 1024;
+// the most sequence numbers one ack or nack datagram may cover, counting every number in its ranges
+const MAX_RECORD_ENTRIES: usize = 8192;
+
+// a datagram which was not acknowledged this long after it was sent is sent again
+const RESEND_AFTER: Duration = Duration::from_millis(500);
+// after this many resends of the same frames, the peer is considered gone
+const MAX_RESENDS: u32 = 20;
+// how often a `BedrockConnection` which is waiting for data sends acknowledgements and resends
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Error)]
+pub enum BedrockError {
+    #[error("io error on raknet socket")]
+    IoFail {
+        #[from]
+        err: io::Error,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("malformed raknet datagram: {reason}")]
+    Malformed {
+        reason: &'static str,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("message of {size} bytes would be split into more than {max_parts} parts")]
+    MessageTooLarge {
+        size: usize,
+        max_parts: u32,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("order channel {channel} does not exist")]
+    InvalidChannel {
+        channel: u8,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("peer did not acknowledge a datagram which was sent {resends} times")]
+    Unresponsive {
+        resends: u32,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+}
+
+pub type BedrockResult<T> = Result<T, BedrockError>;
+
+fn malformed(reason: &'static str) -> BedrockError {
+    BedrockError::Malformed {
+        reason,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace::capture(),
+    }
+}
+
+///
+/// How a message is delivered. Reliable messages are resent until the peer acknowledges them,
+/// ordered messages are delivered in the order they were sent (on their channel), and of the
+/// sequenced messages only the newest is delivered, so older ones which arrive late are dropped.
+///
+/// The variants requesting an ack receipt are delivered like the ones without, since this
+/// implementation does not report receipts.
+///
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Reliability {
+    Unreliable,
+    UnreliableSequenced,
+    Reliable,
+    ReliableOrdered,
+    ReliableSequenced,
+    UnreliableWithAckReceipt,
+    ReliableWithAckReceipt,
+    ReliableOrderedWithAckReceipt,
+}
+
+impl Reliability {
+    fn from_bits(bits: u8) -> Self {
+        use Reliability::*;
+        match bits & 0x07 {
+            0 => Unreliable,
+            1 => UnreliableSequenced,
+            2 => Reliable,
+            3 => ReliableOrdered,
+            4 => ReliableSequenced,
+            5 => UnreliableWithAckReceipt,
+            6 => ReliableWithAckReceipt,
+            _ => ReliableOrderedWithAckReceipt,
+        }
+    }
+
+    fn bits(self) -> u8 {
+        use Reliability::*;
+        match self {
+            Unreliable => 0,
+            UnreliableSequenced => 1,
+            Reliable => 2,
+            ReliableOrdered => 3,
+            ReliableSequenced => 4,
+            UnreliableWithAckReceipt => 5,
+            ReliableWithAckReceipt => 6,
+            ReliableOrderedWithAckReceipt => 7,
+        }
+    }
+
+    pub fn is_reliable(self) -> bool {
+        use Reliability::*;
+        matches!(
+            self,
+            Reliable
+                | ReliableOrdered
+                | ReliableSequenced
+                | ReliableWithAckReceipt
+                | ReliableOrderedWithAckReceipt
+        )
+    }
+
+    pub fn is_ordered(self) -> bool {
+        matches!(
+            self,
+            Reliability::ReliableOrdered | Reliability::ReliableOrderedWithAckReceipt
+        )
+    }
+
+    pub fn is_sequenced(self) -> bool {
+        matches!(
+            self,
+            Reliability::UnreliableSequenced | Reliability::ReliableSequenced
+        )
+    }
+
+    fn has_channel(self) -> bool {
+        self.is_ordered() || self.is_sequenced()
+    }
+
+    // a message which is split is only usable if every part arrives, so its parts are always sent
+    // reliably
+    fn for_split(self) -> Self {
+        use Reliability::*;
+        match self {
+            Unreliable => Reliable,
+            UnreliableSequenced => ReliableSequenced,
+            UnreliableWithAckReceipt => ReliableWithAckReceipt,
+            other => other,
+        }
+    }
+}
+
+///
+/// Something a `RakSession` received.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RakMessage {
+    ///
+    /// A message sent through the session, after reassembly and ordering.
+    ///
+    Connected(Vec<u8>),
+    ///
+    /// A datagram sent outside of the session, such as the offline handshake packets (open
+    /// connection requests and replies) and unconnected pings, as it was received.
+    ///
+    Unconnected(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SplitInfo {
+    count: u32,
+    id: u16,
+    index: u32,
+}
+
+#[derive(Debug)]
+struct Frame {
+    reliability: Reliability,
+    reliable_index: u32,
+    sequence_index: u32,
+    order_index: u32,
+    channel: u8,
+    split: Option<SplitInfo>,
+    body: Vec<u8>,
+}
+
+fn frame_header_len(reliability: Reliability, split: bool) -> usize {
+    let mut len = 3;
+    if reliability.is_reliable() {
+        len += 3;
+    }
+    if reliability.is_sequenced() {
+        len += 3;
+    }
+    if reliability.has_channel() {
+        len += 4;
+    }
+    if split {
+        len += 10;
+    }
+    len
+}
+
+fn put_u24(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes()[..3]);
+}
+
+fn encode_frame(frame: &Frame, out: &mut Vec<u8>) {
+    let split_flag = if frame.split.is_some() { FLAG_SPLIT } else { 0 };
+    out.push((frame.reliability.bits() << 5) | split_flag);
+    out.extend_from_slice(&((frame.body.len() * 8) as u16).to_be_bytes());
+    if frame.reliability.is_reliable() {
+        put_u24(out, frame.reliable_index);
+    }
+    if frame.reliability.is_sequenced() {
+        put_u24(out, frame.sequence_index);
+    }
+    if frame.reliability.has_channel() {
+        put_u24(out, frame.order_index);
+        out.push(frame.channel);
+    }
+    if let Some(split) = frame.split {
+        out.extend_from_slice(&split.count.to_be_bytes());
+        out.extend_from_slice(&split.id.to_be_bytes());
+        out.extend_from_slice(&split.index.to_be_bytes());
+    }
+    out.extend_from_slice(&frame.body);
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn bytes(&mut self, n: usize) -> BedrockResult<&'a [u8]> {
+        if self.data.len() < n {
+            return Err(malformed("datagram ended early"));
+        }
+
+        let (taken, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> BedrockResult<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16_be(&mut self) -> BedrockResult<u16> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24_le(&mut self) -> BedrockResult<u32> {
+        let bytes = self.bytes(3)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]))
+    }
+
+    fn u32_be(&mut self) -> BedrockResult<u32> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+fn decode_frame(cursor: &mut Cursor) -> BedrockResult<Frame> {
+    let flags = cursor.u8()?;
+    let reliability = Reliability::from_bits(flags >> 5);
+    let body_len = (cursor.u16_be()? as usize + 7) / 8;
+    let reliable_index = if reliability.is_reliable() {
+        cursor.u24_le()?
+    } else {
+        0
+    };
+    let sequence_index = if reliability.is_sequenced() {
+        cursor.u24_le()?
+    } else {
+        0
+    };
+    let (order_index, channel) = if reliability.has_channel() {
+        (cursor.u24_le()?, cursor.u8()?)
+    } else {
+        (0, 0)
+    };
+    if channel as usize >= RAKNET_ORDER_CHANNELS {
+        return Err(malformed("order channel out of range"));
+    }
+
+    let split = if flags & FLAG_SPLIT != 0 {
+        let split = SplitInfo {
+            count: cursor.u32_be()?,
+            id: cursor.u16_be()?,
+            index: cursor.u32_be()?,
+        };
+        if split.count == 0 || split.count > MAX_SPLIT_COUNT || split.index >= split.count {
+            return Err(malformed("invalid split"));
+        }
+        Some(split)
+    } else {
+        None
+    };
+
+    Ok(Frame {
+        reliability,
+        reliable_index,
+        sequence_index,
+        order_index,
+        channel,
+        split,
+        body: cursor.bytes(body_len)?.to_vec(),
+    })
+}
+
+// writes acknowledgement datagrams (with the ack or nack flag) for the sequence numbers, as ranges
+fn encode_records(flag: u8, sequences: &mut Vec<u32>, mtu: usize, out: &mut Vec<Vec<u8>>) {
+    if sequences.is_empty() {
+        return;
+    }
+
+    sequences.sort_unstable();
+    sequences.dedup();
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for &sequence in sequences.iter() {
+        match ranges.last_mut() {
+            Some((start, end))
+                if *end + 1 == sequence && ((sequence - *start) as usize) < MAX_RECORD_ENTRIES =>
+            {
+                *end = sequence
+            }
+            _ => ranges.push((sequence, sequence)),
+        }
+    }
+    sequences.clear();
+
+    // the id and record count, and at most 7 bytes per record
+    let per_datagram = (mtu - 3) / 7;
+    let mut remaining = &ranges[..];
+    while !remaining.is_empty() {
+        let mut records = 0;
+        let mut entries = 0;
+        for &(start, end) in remaining.iter().take(per_datagram) {
+            entries += (end - start) as usize + 1;
+            if entries > MAX_RECORD_ENTRIES {
+                break;
+            }
+            records += 1;
+        }
+
+        let (chunk, rest) = remaining.split_at(records);
+        remaining = rest;
+        let mut datagram = Vec::with_capacity(3 + chunk.len() * 7);
+        datagram.push(flag);
+        datagram.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+        for &(start, end) in chunk {
+            if start == end {
+                datagram.push(1);
+                put_u24(&mut datagram, start);
+            } else {
+                datagram.push(0);
+                put_u24(&mut datagram, start);
+                put_u24(&mut datagram, end);
+            }
+        }
+        out.push(datagram);
+    }
+}
+
+fn decode_records(cursor: &mut Cursor) -> BedrockResult<Vec<u32>> {
+    let count = cursor.u16_be()?;
+    let mut sequences = Vec::new();
+    for _ in 0..count {
+        let single = cursor.u8()? != 0;
+        let start = cursor.u24_le()?;
+        let end = if single { start } else { cursor.u24_le()? };
+        if end < start {
+            return Err(malformed("invalid acknowledgement range"));
+        }
+        if sequences.len() + (end - start) as usize + 1 > MAX_RECORD_ENTRIES {
+            return Err(malformed("too many acknowledged datagrams"));
+        }
+        sequences.extend(start..=end);
+    }
+
+    Ok(sequences)
+}
+
+// whether `a` comes after `b`, allowing for the 24 bit indexes wrapping around
+fn is_after(a: u32, b: u32) -> bool {
+    a != b && (a.wrapping_sub(b) & U24_MASK) < HALF_U24
+}
+
+fn next_u24(index: &mut u32) -> u32 {
+    let current = *index;
+    *index = (current + 1) & U24_MASK;
+    current
+}
+
+struct SplitAssembly {
+    parts: Vec<Option<Vec<u8>>>,
+    received: u32,
+    bytes: usize,
+}
+
+struct InFlight {
+    payload: Vec<u8>,
+    sent_at: Instant,
+    resends: u32,
+}
+
+///
+/// The state of one RakNet session (the transport Bedrock edition uses) which does not do any I/O
+/// itself, so it can be driven by any UDP socket. Datagrams received from the peer are passed to
+/// `handle_datagram`, and the messages they carried are taken using `next_message`. Messages are
+/// queued using `queue_message`, and `poll_transmit` returns the datagrams to send to the peer,
+/// which include acknowledgements and resends, so it should also be called regularly (every 10ms
+/// or so) while the session is idle.
+///
+/// A server receiving from many peers on one socket keeps a session for each peer address. For
+/// a single peer, `BedrockConnection` drives a session over a connected `UdpSocket`.
+///
+/// This only implements the framing (acknowledgements, resends, ordering channels, and split
+/// messages). The handshake messages, and the game packets carried inside of messages, are
+/// passed through as they are.
+///
+pub struct RakSession {
+    mtu: usize,
+    // receiving
+    datagrams_to_ack: Vec<u32>,
+    datagrams_to_nack: Vec<u32>,
+    highest_datagram: Option<u32>,
+    reliable_floor: u32,
+    reliable_seen: HashSet<u32>,
+    splits: HashMap<u16, SplitAssembly>,
+    split_bytes: usize,
+    order_expected: [u32; RAKNET_ORDER_CHANNELS],
+    order_pending: Vec<HashMap<u32, Vec<u8>>>,
+    sequence_expected: [u32; RAKNET_ORDER_CHANNELS],
+    received: VecDeque<RakMessage>,
+    // sending
+    next_datagram: u32,
+    next_reliable: u32,
+    next_order: [u32; RAKNET_ORDER_CHANNELS],
+    next_sequence: [u32; RAKNET_ORDER_CHANNELS],
+    next_split_id: u16,
+    outgoing_frames: VecDeque<(Vec<u8>, bool)>,
+    outgoing_unconnected: VecDeque<Vec<u8>>,
+    in_flight: HashMap<u32, InFlight>,
+    to_resend: Vec<InFlight>,
+}
+
+impl Default for RakSession {
+    fn default() -> Self {
+        Self::new(DEFAULT_RAKNET_MTU)
+    }
+}
+
+impl RakSession {
+    ///
+    /// Creates a session which sends datagrams of at most `mtu` bytes (the size agreed on in the
+    /// offline handshake), which is kept between `MIN_RAKNET_MTU` and `MAX_RAKNET_MTU`.
+    ///
+    pub fn new(mtu: usize) -> Self {
+        Self {
+            mtu: mtu.clamp(MIN_RAKNET_MTU, MAX_RAKNET_MTU),
+            datagrams_to_ack: Vec::new(),
+            datagrams_to_nack: Vec::new(),
+            highest_datagram: None,
+            reliable_floor: 0,
+            reliable_seen: HashSet::new(),
+            splits: HashMap::new(),
+            split_bytes: 0,
+            order_expected: [0; RAKNET_ORDER_CHANNELS],
+            order_pending: (0..RAKNET_ORDER_CHANNELS).map(|_| HashMap::new()).collect(),
+            sequence_expected: [0; RAKNET_ORDER_CHANNELS],
+            received: VecDeque::new(),
+            next_datagram: 0,
+            next_reliable: 0,
+            next_order: [0; RAKNET_ORDER_CHANNELS],
+            next_sequence: [0; RAKNET_ORDER_CHANNELS],
+            next_split_id: 0,
+            outgoing_frames: VecDeque::new(),
+            outgoing_unconnected: VecDeque::new(),
+            in_flight: HashMap::new(),
+            to_resend: Vec::new(),
+        }
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    ///
+    /// The number of datagrams which were sent, but not acknowledged by the peer yet.
+    ///
+    pub fn unacknowledged(&self) -> usize {
+        self.in_flight.len() + self.to_resend.len()
+    }
+
+    ///
+    /// Processes a datagram received from the peer. Any messages it completes can be taken using
+    /// `next_message`.
+    ///
+    pub fn handle_datagram(&mut self, datagram: &[u8]) -> BedrockResult<()> {
+        let mut cursor = Cursor { data: datagram };
+        let flags = cursor.u8()?;
+        if flags & FLAG_VALID == 0 {
+            self.received
+                .push_back(RakMessage::Unconnected(datagram.to_vec()));
+            return Ok(());
+        }
+
+        if flags & FLAG_ACK != 0 {
+            for sequence in decode_records(&mut cursor)? {
+                self.in_flight.remove(&sequence);
+            }
+            return Ok(());
+        }
+
+        if flags & FLAG_NACK != 0 {
+            for sequence in decode_records(&mut cursor)? {
+                if let Some(datagram) = self.in_flight.remove(&sequence) {
+                    self.to_resend.push(datagram);
+                }
+            }
+            return Ok(());
+        }
+
+        let sequence = cursor.u24_le()?;
+        self.track_datagram(sequence);
+        while !cursor.is_empty() {
+            let frame = decode_frame(&mut cursor)?;
+            self.receive_frame(frame)?;
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Takes the next message which was received completely, in the order they are delivered.
+    ///
+    pub fn next_message(&mut self) -> Option<RakMessage> {
+        self.received.pop_front()
+    }
+
+    fn track_datagram(&mut self, sequence: u32) {
+        self.datagrams_to_ack.push(sequence);
+        self.datagrams_to_nack.retain(|missing| *missing != sequence);
+        match self.highest_datagram {
+            Some(highest) if !is_after(sequence, highest) => {}
+            Some(highest) => {
+                // nack the datagrams which were skipped, so they are resent without waiting
+                let mut missing = (highest + 1) & U24_MASK;
+                let mut gap = 0;
+                while missing != sequence && gap < MAX_NACK_GAP {
+                    self.datagrams_to_nack.push(missing);
+                    missing = (missing + 1) & U24_MASK;
+                    gap += 1;
+                }
+                self.highest_datagram = Some(sequence);
+            }
+            None => self.highest_datagram = Some(sequence),
+        }
+    }
+
+    fn receive_frame(&mut self, frame: Frame) -> BedrockResult<()> {
+        if frame.reliability.is_reliable() && !self.accept_reliable(frame.reliable_index)? {
+            return Ok(());
+        }
+
+        let frame = match frame.split {
+            Some(split) => match self.reassemble(frame, split)? {
+                Some(frame) => frame,
+                None => return Ok(()),
+            },
+            None => frame,
+        };
+
+        let channel = frame.channel as usize;
+        if frame.reliability.is_sequenced() {
+            // only a message newer than every message delivered on the channel is delivered
+            if !is_after(self.sequence_expected[channel], frame.sequence_index) {
+                self.sequence_expected[channel] = (frame.sequence_index + 1) & U24_MASK;
+                self.deliver(frame.body);
+            }
+        } else if frame.reliability.is_ordered() {
+            let expected = self.order_expected[channel];
+            if frame.order_index == expected {
+                self.deliver(frame.body);
+                let mut next = (expected + 1) & U24_MASK;
+                while let Some(body) = self.order_pending[channel].remove(&next) {
+                    self.deliver(body);
+                    next = (next + 1) & U24_MASK;
+                }
+                self.order_expected[channel] = next;
+            } else if is_after(frame.order_index, expected) {
+                let pending = &mut self.order_pending[channel];
+                if pending.len() >= MAX_PENDING_ORDERED {
+                    return Err(malformed("too many ordered messages are missing"));
+                }
+                pending.insert(frame.order_index, frame.body);
+            }
+        } else {
+            self.deliver(frame.body);
+        }
+
+        Ok(())
+    }
+
+    fn deliver(&mut self, body: Vec<u8>) {
+        self.received.push_back(RakMessage::Connected(body));
+    }
+
+    // whether a reliable frame was received for the first time
+    fn accept_reliable(&mut self, index: u32) -> BedrockResult<bool> {
+        if is_after(self.reliable_floor, index) || !self.reliable_seen.insert(index) {
+            return Ok(false);
+        }
+
+        while self.reliable_seen.remove(&self.reliable_floor) {
+            self.reliable_floor = (self.reliable_floor + 1) & U24_MASK;
+        }
+
+        if self.reliable_seen.len() > MAX_RELIABLE_GAP {
+            return Err(malformed("too many reliable frames are missing"));
+        }
+
+        Ok(true)
+    }
+
+    fn reassemble(&mut self, frame: Frame, split: SplitInfo) -> BedrockResult<Option<Frame>> {
+        if !self.splits.contains_key(&split.id) && self.splits.len() >= MAX_OPEN_SPLITS {
+            return Err(malformed("too many split messages are incomplete"));
+        }
+
+        let assembly = self.splits.entry(split.id).or_insert_with(|| SplitAssembly {
+            parts: vec![None; split.count as usize],
+            received: 0,
+            bytes: 0,
+        });
+        if assembly.parts.len() != split.count as usize {
+            return Err(malformed("split part count changed"));
+        }
+
+        let part = &mut assembly.parts[split.index as usize];
+        if part.is_none() {
+            if self.split_bytes + frame.body.len() > MAX_SPLIT_BYTES {
+                return Err(malformed("incomplete split messages are too large"));
+            }
+
+            self.split_bytes += frame.body.len();
+            assembly.bytes += frame.body.len();
+            *part = Some(frame.body);
+            assembly.received += 1;
+        }
+
+        if assembly.received < split.count {
+            return Ok(None);
+        }
+
+        let assembly = self.splits.remove(&split.id).expect("assembly was just completed");
+        self.split_bytes -= assembly.bytes;
+        let mut body = Vec::new();
+        for part in assembly.parts {
+            body.extend_from_slice(&part.expect("every part was received"));
+        }
+
+        Ok(Some(Frame {
+            split: None,
+            body,
+            ..frame
+        }))
+    }
+
+    ///
+    /// Queues a message to be sent to the peer with the given reliability, on the given ordering
+    /// channel (which is ignored unless the message is ordered or sequenced). A message which does
+    /// not fit into one datagram is split, and its parts are sent reliably.
+    ///
+    pub fn queue_message(
+        &mut self,
+        data: &[u8],
+        reliability: Reliability,
+        channel: u8,
+    ) -> BedrockResult<()> {
+        let channel_index = channel as usize;
+        if channel_index >= RAKNET_ORDER_CHANNELS {
+            return Err(BedrockError::InvalidChannel {
+                channel,
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture(),
+            });
+        }
+
+        let (sequence_index, order_index) = if reliability.is_sequenced() {
+            (
+                next_u24(&mut self.next_sequence[channel_index]),
+                self.next_order[channel_index],
+            )
+        } else if reliability.is_ordered() {
+            (0, next_u24(&mut self.next_order[channel_index]))
+        } else {
+            (0, 0)
+        };
+
+        let max_body = self.mtu - DATAGRAM_HEADER_SIZE - frame_header_len(reliability, false);
+        if data.len() <= max_body {
+            self.queue_frame(Frame {
+                reliability,
+                reliable_index: 0,
+                sequence_index,
+                order_index,
+                channel,
+                split: None,
+                body: data.to_vec(),
+            });
+            return Ok(());
+        }
+
+        let reliability = reliability.for_split();
+        let part_size = self.mtu - DATAGRAM_HEADER_SIZE - frame_header_len(reliability, true);
+        let count = (data.len() + part_size - 1) / part_size;
+        if count > MAX_SPLIT_COUNT as usize {
+            return Err(BedrockError::MessageTooLarge {
+                size: data.len(),
+                max_parts: MAX_SPLIT_COUNT,
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture(),
+            });
+        }
+
+        let id = self.next_split_id;
+        self.next_split_id = self.next_split_id.wrapping_add(1);
+        for (index, part) in data.chunks(part_size).enumerate() {
+            self.queue_frame(Frame {
+                reliability,
+                reliable_index: 0,
+                sequence_index,
+                order_index,
+                channel,
+                split: Some(SplitInfo {
+                    count: count as u32,
+                    id,
+                    index: index as u32,
+                }),
+                body: part.to_vec(),
+            });
+        }
+
+        Ok(())
+    }
+
+    fn queue_frame(&mut self, mut frame: Frame) {
+        let reliable = frame.reliability.is_reliable();
+        if reliable {
+            frame.reliable_index = next_u24(&mut self.next_reliable);
+        }
+
+        let mut encoded =
+            Vec::with_capacity(frame_header_len(frame.reliability, true) + frame.body.len());
+        encode_frame(&frame, &mut encoded);
+        self.outgoing_frames.push_back((encoded, reliable));
+    }
+
+    ///
+    /// Queues a datagram to be sent as it is, outside of the session (such as a reply to an
+    /// unconnected ping).
+    ///
+    pub fn queue_unconnected(&mut self, datagram: &[u8]) {
+        self.outgoing_unconnected.push_back(datagram.to_vec());
+    }
+
+    ///
+    /// Appends the datagrams which should be sent to the peer now to `out`: acknowledgements of
+    /// received datagrams, datagrams the peer did not acknowledge in time, and the queued
+    /// messages. Fails if the peer stopped acknowledging datagrams.
+    ///
+    pub fn poll_transmit(&mut self, now: Instant, out: &mut Vec<Vec<u8>>) -> BedrockResult<()> {
+        encode_records(FLAG_VALID | FLAG_ACK, &mut self.datagrams_to_ack, self.mtu, out);
+        encode_records(FLAG_VALID | FLAG_NACK, &mut self.datagrams_to_nack, self.mtu, out);
+
+        let expired: Vec<u32> = self
+            .in_flight
+            .iter()
+            .filter(|(_, datagram)| now.saturating_duration_since(datagram.sent_at) >= RESEND_AFTER)
+            .map(|(sequence, _)| *sequence)
+            .collect();
+        for sequence in expired {
+            let datagram = self.in_flight.remove(&sequence).expect("was just found");
+            self.to_resend.push(datagram);
+        }
+
+        // frames which are resent get a new sequence number, like in RakNet itself
+        for datagram in std::mem::take(&mut self.to_resend) {
+            if datagram.resends >= MAX_RESENDS {
+                return Err(BedrockError::Unresponsive {
+                    resends: datagram.resends,
+                    #[cfg(feature = "backtrace")]
+                    backtrace: Backtrace::capture(),
+                });
+            }
+            self.send_frames(datagram.payload, true, datagram.resends + 1, now, out);
+        }
+
+        let mut payload = Vec::new();
+        let mut reliable = false;
+        while let Some((frame, frame_reliable)) = self.outgoing_frames.pop_front() {
+            if !payload.is_empty() && DATAGRAM_HEADER_SIZE + payload.len() + frame.len() > self.mtu
+            {
+                self.send_frames(std::mem::take(&mut payload), reliable, 0, now, out);
+                reliable = false;
+            }
+            payload.extend_from_slice(&frame);
+            reliable |= frame_reliable;
+        }
+        if !payload.is_empty() {
+            self.send_frames(payload, reliable, 0, now, out);
+        }
+
+        out.extend(self.outgoing_unconnected.drain(..));
+        Ok(())
+    }
+
+    fn send_frames(
+        &mut self,
+        payload: Vec<u8>,
+        reliable: bool,
+        resends: u32,
+        now: Instant,
+        out: &mut Vec<Vec<u8>>,
+    ) {
+        let sequence = next_u24(&mut self.next_datagram);
+        let mut datagram = Vec::with_capacity(DATAGRAM_HEADER_SIZE + payload.len());
+        datagram.push(FRAME_SET_ID);
+        put_u24(&mut datagram, sequence);
+        datagram.extend_from_slice(&payload);
+        out.push(datagram);
+
+        if reliable {
+            self.in_flight.insert(
+                sequence,
+                InFlight {
+                    payload,
+                    sent_at: now,
+                    resends,
+                },
+            );
+        }
+    }
+}
+
+///
+/// A RakNet session with a single peer, over a `UdpSocket` which is connected to that peer. Like
+/// a `CraftConnection`, messages are read and written as raw bytes, and framing (including
+/// acknowledgements and resends) is handled internally. It can be split into a `BedrockReader`
+/// and a `BedrockWriter` using `into_split`, to read and write from different threads.
+///
+/// The socket's read timeout is used to send acknowledgements and resends while waiting for
+/// messages, so it must not be changed while it is wrapped.
+///
+pub struct BedrockConnection {
+    socket: UdpSocket,
+    session: RakSession,
+    recv_buf: Vec<u8>,
+    outgoing: Vec<Vec<u8>>,
+}
+
+///
+/// The reading half of a `BedrockConnection`. Acknowledgements and resends are sent while it
+/// waits for messages, so it should be read from continuously, or the `BedrockWriter` stops
+/// making progress once the peer stops acknowledging its datagrams.
+///
+pub struct BedrockReader {
+    socket: UdpSocket,
+    session: Arc<Mutex<RakSession>>,
+    recv_buf: Vec<u8>,
+    outgoing: Vec<Vec<u8>>,
+}
+
+///
+/// The writing half of a `BedrockConnection`.
+///
+pub struct BedrockWriter {
+    socket: UdpSocket,
+    session: Arc<Mutex<RakSession>>,
+    outgoing: Vec<Vec<u8>>,
+}
+
+fn lock_session(session: &Mutex<RakSession>) -> MutexGuard<'_, RakSession> {
+    session.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn send_all(socket: &UdpSocket, outgoing: &mut Vec<Vec<u8>>) -> BedrockResult<()> {
+    for datagram in outgoing.drain(..) {
+        socket.send(&datagram)?;
+    }
+
+    Ok(())
+}
+
+impl BedrockConnection {
+    pub fn new(socket: UdpSocket) -> BedrockResult<Self> {
+        Self::with_mtu(socket, DEFAULT_RAKNET_MTU)
+    }
+
+    ///
+    /// Wraps a socket which is already connected to the peer (see `UdpSocket::connect`), after the
+    /// offline handshake agreed on an `mtu`.
+    ///
+    pub fn with_mtu(socket: UdpSocket, mtu: usize) -> BedrockResult<Self> {
+        socket.set_read_timeout(Some(TICK_INTERVAL))?;
+        Ok(Self {
+            socket,
+            session: RakSession::new(mtu),
+            recv_buf: vec![0; MAX_RAKNET_MTU],
+            outgoing: Vec::new(),
+        })
+    }
+
+    pub fn session(&self) -> &RakSession {
+        &self.session
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+
+    pub fn get_ref(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    pub fn into_inner(self) -> UdpSocket {
+        self.socket
+    }
+
+    ///
+    /// Splits the connection into a reader and a writer which share the session, each with its own
+    /// handle to the socket (see `UdpSocket::try_clone`).
+    ///
+    pub fn into_split(self) -> BedrockResult<(BedrockReader, BedrockWriter)> {
+        let write_socket = self.socket.try_clone()?;
+        let session = Arc::new(Mutex::new(self.session));
+        let reader = BedrockReader {
+            socket: self.socket,
+            session: session.clone(),
+            recv_buf: self.recv_buf,
+            outgoing: self.outgoing,
+        };
+        let writer = BedrockWriter {
+            socket: write_socket,
+            session,
+            outgoing: Vec::new(),
+        };
+
+        Ok((reader, writer))
+    }
+
+    ///
+    /// Blocks until the next message is received, sending acknowledgements and resends while it
+    /// waits.
+    ///
+    pub fn read_message(&mut self) -> BedrockResult<RakMessage> {
+        loop {
+            if let Some(message) = self.session.next_message() {
+                self.flush()?;
+                return Ok(message);
+            }
+
+            match self.socket.recv(&mut self.recv_buf) {
+                Ok(n) => self.session.handle_datagram(&self.recv_buf[..n])?,
+                Err(err) if is_timeout(&err) => {}
+                Err(err) => return Err(err.into()),
+            }
+            self.flush()?;
+        }
+    }
+
+    ///
+    /// Sends a message, see `RakSession::queue_message`.
+    ///
+    pub fn write_message(
+        &mut self,
+        data: &[u8],
+        reliability: Reliability,
+        channel: u8,
+    ) -> BedrockResult<()> {
+        self.session.queue_message(data, reliability, channel)?;
+        self.flush()
+    }
+
+    pub fn write_unconnected(&mut self, datagram: &[u8]) -> BedrockResult<()> {
+        self.session.queue_unconnected(datagram);
+        self.flush()
+    }
+
+    ///
+    /// Sends everything the session has to send now (see `RakSession::poll_transmit`).
+    ///
+    pub fn flush(&mut self) -> BedrockResult<()> {
+        self.session.poll_transmit(Instant::now(), &mut self.outgoing)?;
+        send_all(&self.socket, &mut self.outgoing)
+    }
+}
+
+impl BedrockReader {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+
+    pub fn get_ref(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    ///
+    /// Same as `BedrockConnection::read_message`.
+    ///
+    pub fn read_message(&mut self) -> BedrockResult<RakMessage> {
+        loop {
+            let next = lock_session(&self.session).next_message();
+            if let Some(message) = next {
+                self.flush()?;
+                return Ok(message);
+            }
+
+            match self.socket.recv(&mut self.recv_buf) {
+                Ok(n) => lock_session(&self.session).handle_datagram(&self.recv_buf[..n])?,
+                Err(err) if is_timeout(&err) => {}
+                Err(err) => return Err(err.into()),
+            }
+            self.flush()?;
+        }
+    }
+
+    fn flush(&mut self) -> BedrockResult<()> {
+        lock_session(&self.session).poll_transmit(Instant::now(), &mut self.outgoing)?;
+        send_all(&self.socket, &mut self.outgoing)
+    }
+}
+
+impl BedrockWriter {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+
+    pub fn get_ref(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    ///
+    /// The number of datagrams which were sent, but not acknowledged by the peer yet.
+    ///
+    pub fn unacknowledged(&self) -> usize {
+        lock_session(&self.session).unacknowledged()
+    }
+
+    ///
+    /// Same as `BedrockConnection::write_message`.
+    ///
+    pub fn write_message(
+        &mut self,
+        data: &[u8],
+        reliability: Reliability,
+        channel: u8,
+    ) -> BedrockResult<()> {
+        lock_session(&self.session).queue_message(data, reliability, channel)?;
+        self.flush()
+    }
+
+    pub fn write_unconnected(&mut self, datagram: &[u8]) -> BedrockResult<()> {
+        lock_session(&self.session).queue_unconnected(datagram);
+        self.flush()
+    }
+
+    pub fn flush(&mut self) -> BedrockResult<()> {
+        lock_session(&self.session).poll_transmit(Instant::now(), &mut self.outgoing)?;
+        send_all(&self.socket, &mut self.outgoing)
+    }
+}
+
+///
+/// Same as `BedrockConnection`, but over a tokio `UdpSocket`. Acknowledgements and resends are
+/// sent while `read_message_async` waits for messages, using a timer instead of a read timeout.
+///
+#[cfg(feature = "tokio-io")]
+pub struct BedrockTokioConnection {
+    socket: TokioUdpSocket,
+    session: RakSession,
+    recv_buf: Vec<u8>,
+    outgoing: Vec<Vec<u8>>,
+}
+
+///
+/// The reading half of a `BedrockTokioConnection`, see `BedrockReader`.
+///
+#[cfg(feature = "tokio-io")]
+pub struct BedrockTokioReader {
+    socket: Arc<TokioUdpSocket>,
+    session: Arc<Mutex<RakSession>>,
+    recv_buf: Vec<u8>,
+    outgoing: Vec<Vec<u8>>,
+}
+
+///
+/// The writing half of a `BedrockTokioConnection`.
+///
+#[cfg(feature = "tokio-io")]
+pub struct BedrockTokioWriter {
+    socket: Arc<TokioUdpSocket>,
+    session: Arc<Mutex<RakSession>>,
+    outgoing: Vec<Vec<u8>>,
+}
+
+// datagrams which are not sent because the future is dropped are lost, like any other datagram,
+// and reliable ones are sent again later
+#[cfg(feature = "tokio-io")]
+async fn send_all_async(socket: &TokioUdpSocket, outgoing: &mut Vec<Vec<u8>>) -> BedrockResult<()> {
+    for datagram in outgoing.drain(..) {
+        socket.send(&datagram).await?;
+    }
+
+    Ok(())
+}
+
+// waits for the next datagram for up to one tick, returning `None` if none arrived in time
+#[cfg(feature = "tokio-io")]
+async fn recv_tick(socket: &TokioUdpSocket, buf: &mut [u8]) -> BedrockResult<Option<usize>> {
+    match tokio::time::timeout(TICK_INTERVAL, socket.recv(buf)).await {
+        Ok(Ok(n)) => Ok(Some(n)),
+        Ok(Err(err)) => Err(err.into()),
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(feature = "tokio-io")]
+impl BedrockTokioConnection {
+    pub fn new(socket: TokioUdpSocket) -> Self {
+        Self::with_mtu(socket, DEFAULT_RAKNET_MTU)
+    }
+
+    ///
+    /// Wraps a socket which is already connected to the peer (see `UdpSocket::connect`), after the
+    /// offline handshake agreed on an `mtu`.
+    ///
+    pub fn with_mtu(socket: TokioUdpSocket, mtu: usize) -> Self {
+        Self {
+            socket,
+            session: RakSession::new(mtu),
+            recv_buf: vec![0; MAX_RAKNET_MTU],
+            outgoing: Vec::new(),
+        }
+    }
+
+    pub fn session(&self) -> &RakSession {
+        &self.session
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+
+    pub fn get_ref(&self) -> &TokioUdpSocket {
+        &self.socket
+    }
+
+    pub fn into_inner(self) -> TokioUdpSocket {
+        self.socket
+    }
+
+    ///
+    /// Splits the connection into a reader and a writer which share the session and the socket.
+    ///
+    pub fn into_split(self) -> (BedrockTokioReader, BedrockTokioWriter) {
+        let socket = Arc::new(self.socket);
+        let session = Arc::new(Mutex::new(self.session));
+        let reader = BedrockTokioReader {
+            socket: socket.clone(),
+            session: session.clone(),
+            recv_buf: self.recv_buf,
+            outgoing: self.outgoing,
+        };
+        let writer = BedrockTokioWriter {
+            socket,
+            session,
+            outgoing: Vec::new(),
+        };
+
+        (reader, writer)
+    }
+
+    ///
+    /// Waits until the next message is received, sending acknowledgements and resends while it
+    /// waits.
+    ///
+    pub async fn read_message_async(&mut self) -> BedrockResult<RakMessage> {
+        loop {
+            if let Some(message) = self.session.next_message() {
+                self.flush_async().await?;
+                return Ok(message);
+            }
+
+            if let Some(n) = recv_tick(&self.socket, &mut self.recv_buf).await? {
+                self.session.handle_datagram(&self.recv_buf[..n])?;
+            }
+            self.flush_async().await?;
+        }
+    }
+
+    ///
+    /// Sends a message, see `RakSession::queue_message`.
+    ///
+    pub async fn write_message_async(
+        &mut self,
+        data: &[u8],
+        reliability: Reliability,
+        channel: u8,
+    ) -> BedrockResult<()> {
+        self.session.queue_message(data, reliability, channel)?;
+        self.flush_async().await
+    }
+
+    pub async fn write_unconnected_async(&mut self, datagram: &[u8]) -> BedrockResult<()> {
+        self.session.queue_unconnected(datagram);
+        self.flush_async().await
+    }
+
+    ///
+    /// Sends everything the session has to send now (see `RakSession::poll_transmit`).
+    ///
+    pub async fn flush_async(&mut self) -> BedrockResult<()> {
+        self.session.poll_transmit(Instant::now(), &mut self.outgoing)?;
+        send_all_async(&self.socket, &mut self.outgoing).await
+    }
+}
+
+#[cfg(feature = "tokio-io")]
+impl BedrockTokioReader {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+
+    pub fn get_ref(&self) -> &TokioUdpSocket {
+        &self.socket
+    }
+
+    ///
+    /// Same as `BedrockTokioConnection::read_message_async`.
+    ///
+    pub async fn read_message_async(&mut self) -> BedrockResult<RakMessage> {
+        loop {
+            let next = lock_session(&self.session).next_message();
+            if let Some(message) = next {
+                self.flush_async().await?;
+                return Ok(message);
+            }
+
+            if let Some(n) = recv_tick(&self.socket, &mut self.recv_buf).await? {
+                lock_session(&self.session).handle_datagram(&self.recv_buf[..n])?;
+            }
+            self.flush_async().await?;
+        }
+    }
+
+    async fn flush_async(&mut self) -> BedrockResult<()> {
+        lock_session(&self.session).poll_transmit(Instant::now(), &mut self.outgoing)?;
+        send_all_async(&self.socket, &mut self.outgoing).await
+    }
+}
+
+#[cfg(feature = "tokio-io")]
+impl BedrockTokioWriter {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+
+    pub fn get_ref(&self) -> &TokioUdpSocket {
+        &self.socket
+    }
+
+    ///
+    /// The number of datagrams which were sent, but not acknowledged by the peer yet.
+    ///
+    pub fn unacknowledged(&self) -> usize {
+        lock_session(&self.session).unacknowledged()
+    }
+
+    ///
+    /// Same as `BedrockTokioConnection::write_message_async`.
+    ///
+    pub async fn write_message_async(
+        &mut self,
+        data: &[u8],
+        reliability: Reliability,
+        channel: u8,
+    ) -> BedrockResult<()> {
+        lock_session(&self.session).queue_message(data, reliability, channel)?;
+        self.flush_async().await
+    }
+
+    pub async fn write_unconnected_async(&mut self, datagram: &[u8]) -> BedrockResult<()> {
+        lock_session(&self.session).queue_unconnected(datagram);
+        self.flush_async().await
+    }
+
+    pub async fn flush_async(&mut self) -> BedrockResult<()> {
+        lock_session(&self.session).poll_transmit(Instant::now(), &mut self.outgoing)?;
+        send_all_async(&self.socket, &mut self.outgoing).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn udp_pair() -> (UdpSocket, UdpSocket) {
+        let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        a.connect(b.local_addr().unwrap()).unwrap();
+        b.connect(a.local_addr().unwrap()).unwrap();
+        (a, b)
+    }
+
+    fn ack_datagram(ranges: &[(u32, u32)]) -> Vec<u8> {
+        let mut datagram = vec![FLAG_VALID | FLAG_ACK];
+        datagram.extend_from_slice(&(ranges.len() as u16).to_be_bytes());
+        for &(start, end) in ranges {
+            datagram.push(0);
+            put_u24(&mut datagram, start);
+            put_u24(&mut datagram, end);
+        }
+        datagram
+    }
+
+    fn decoded(datagram: &[u8]) -> BedrockResult<Vec<u32>> {
+        let mut cursor = Cursor {
+            data: &datagram[1..],
+        };
+        decode_records(&mut cursor)
+    }
+
+    #[test]
+    fn acknowledgements_are_capped_per_datagram() {
+        let full = decoded(&ack_datagram(&[(0, MAX_RECORD_ENTRIES as u32 - 1)])).unwrap();
+        assert_eq!(full.len(), MAX_RECORD_ENTRIES);
+
+        let ranges: Vec<(u32, u32)> = (0..64)
+            .map(|n| (n * 0x4_0000, n * 0x4_0000 + 4095))
+            .collect();
+        assert!(matches!(
+            decoded(&ack_datagram(&ranges)),
+            Err(BedrockError::Malformed { .. })
+        ));
+    }
+
+    #[test]
+    fn large_acknowledgements_are_split_into_datagrams_within_the_cap() {
+        let mut sequences: Vec<u32> = (0..20000).collect();
+        let mut out = Vec::new();
+        encode_records(
+            FLAG_VALID | FLAG_ACK,
+            &mut sequences,
+            DEFAULT_RAKNET_MTU,
+            &mut out,
+        );
+        assert!(sequences.is_empty());
+
+        let mut acknowledged = Vec::new();
+        for datagram in &out {
+            acknowledged.extend(decoded(datagram).unwrap());
+        }
+        assert_eq!(acknowledged, (0..20000).collect::<Vec<u32>>());
+    }
+
+    fn split_part(id: u16, index: u32, body: Vec<u8>) -> (Frame, SplitInfo) {
+        let split = SplitInfo {
+            count: MAX_SPLIT_COUNT,
+            id,
+            index,
+        };
+        let frame = Frame {
+            reliability: Reliability::Reliable,
+            reliable_index: index,
+            sequence_index: 0,
+            order_index: 0,
+            channel: 0,
+            split: Some(split),
+            body,
+        };
+        (frame, split)
+    }
+
+    #[test]
+    fn incomplete_splits_are_limited_in_size() {
+        let mut session = RakSession::default();
+        let part_len = MAX_SPLIT_BYTES / 16;
+        for index in 0..16 {
+            let (frame, split) = split_part(index as u16, index, vec![0; part_len]);
+            assert!(session.reassemble(frame, split).unwrap().is_none());
+        }
+
+        let (frame, split) = split_part(0, 16, vec![0; 1]);
+        assert!(matches!(
+            session.reassemble(frame, split),
+            Err(BedrockError::Malformed { .. })
+        ));
+    }
+
+    #[test]
+    fn completed_splits_release_their_bytes() {
+        let mut session = RakSession::default();
+        let (frame, mut split) = split_part(0, 0, vec![1; 4]);
+        split.count = 2;
+        assert!(session.reassemble(frame, split).unwrap().is_none());
+        assert_eq!(session.split_bytes, 4);
+
+        let (frame, mut split) = split_part(0, 1, vec![2; 4]);
+        split.count = 2;
+        let message = session.reassemble(frame, split).unwrap().unwrap();
+        assert_eq!(message.body, vec![1, 1, 1, 1, 2, 2, 2, 2]);
+        assert_eq!(session.split_bytes, 0);
+    }
+
+    #[test]
+    fn split_connection_round_trips() {
+        let (a, b) = udp_pair();
+        let (mut reader, mut writer) = BedrockConnection::new(a).unwrap().into_split().unwrap();
+        let mut peer = BedrockConnection::new(b).unwrap();
+
+        let echo = thread::spawn(move || {
+            for _ in 0..50 {
+                match peer.read_message().unwrap() {
+                    RakMessage::Connected(data) => peer
+                        .write_message(&data, Reliability::ReliableOrdered, 0)
+                        .unwrap(),
+                    other => panic!("unexpected message {:?}", other),
+                }
+            }
+        });
+
+        let sender = thread::spawn(move || {
+            for n in 0..50u8 {
+                writer
+                    .write_message(&[n; 600], Reliability::ReliableOrdered, 0)
+                    .unwrap();
+            }
+            writer
+        });
+
+        for n in 0..50u8 {
+            match reader.read_message().unwrap() {
+                RakMessage::Connected(data) => assert_eq!(data, vec![n; 600]),
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+        sender.join().unwrap();
+        echo.join().unwrap();
+    }
+
+    #[cfg(feature = "tokio-io")]
+    #[test]
+    fn tokio_split_connection_round_trips() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let (a, b) = udp_pair();
+            a.set_nonblocking(true).unwrap();
+            b.set_nonblocking(true).unwrap();
+            let (mut reader, mut writer) =
+                BedrockTokioConnection::new(TokioUdpSocket::from_std(a).unwrap()).into_split();
+            let mut peer = BedrockTokioConnection::new(TokioUdpSocket::from_std(b).unwrap());
+
+            for n in 0..20u8 {
+                writer
+                    .write_message_async(&[n; 3000], Reliability::ReliableOrdered, 1)
+                    .await
+                    .unwrap();
+                match peer.read_message_async().await.unwrap() {
+                    RakMessage::Connected(data) => peer
+                        .write_message_async(&data, Reliability::ReliableOrdered, 1)
+                        .await
+                        .unwrap(),
+                    other => panic!("unexpected message {:?}", other),
+                }
+                match reader.read_message_async().await.unwrap() {
+                    RakMessage::Connected(data) => assert_eq!(data, vec![n; 3000]),
+                    other => panic!("unexpected message {:?}", other),
+                }
+            }
+        });
+    }
+}
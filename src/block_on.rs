@@ -0,0 +1,124 @@
+use crate::reader::AsyncReadExact;
+use crate::writer::AsyncWriteAll;
+use std::future::Future;
+use std::io;
+
+///
+/// Runs a future to completion on the current thread, blocking it until the future completes. This
+/// is the executor handle `BlockOn` uses.
+///
+pub trait BlockOnExecutor {
+    fn block_on<F>(&self, future: F) -> F::Output
+    where
+        F: Future;
+}
+
+///
+/// The I/O of a stream blocked on this way is only driven while the runtime is running elsewhere,
+/// so the runtime must be a multi-threaded one (see `tokio::runtime::Handle::block_on`). This must
+/// not be called from within an async context.
+///
+#[cfg(feature = "tokio-io")]
+impl BlockOnExecutor for tokio::runtime::Handle {
+    fn block_on<F>(&self, future: F) -> F::Output
+    where
+        F: Future,
+    {
+        tokio::runtime::Handle::block_on(self, future)
+    }
+}
+
+///
+/// Runs futures using `futures::executor::block_on`, which suits streams which do not depend on a
+/// runtime to make progress.
+///
+#[cfg(feature = "futures-io")]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct FuturesExecutor;
+
+#[cfg(feature = "futures-io")]
+impl BlockOnExecutor for FuturesExecutor {
+    fn block_on<F>(&self, future: F) -> F::Output
+    where
+        F: Future,
+    {
+        futures::executor::block_on(future)
+    }
+}
+
+///
+/// Exposes an async stream as a blocking `std::io::Read` and `std::io::Write` stream, by blocking
+/// on each operation using an executor handle. This lets sync tooling (such as command line tools
+/// and tests) wrap an async-only transport with a `CraftReader` or `CraftWriter` and use the
+/// blocking API.
+///
+/// To use both halves of a split stream, wrap each of them with its own `BlockOn`, using clones of
+/// the same executor handle.
+///
+#[derive(Debug)]
+pub struct BlockOn<T, E> {
+    inner: T,
+    executor: E,
+}
+
+impl<T, E> BlockOn<T, E>
+where
+    E: BlockOnExecutor,
+{
+    pub fn new(inner: T, executor: E) -> Self {
+        Self { inner, executor }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T, E> io::Read for BlockOn<T, E>
+where
+    T: AsyncReadExact,
+    E: BlockOnExecutor,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let inner = &mut self.inner;
+        self.executor.block_on(inner.read_some(buf))
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let inner = &mut self.inner;
+        self.executor.block_on(inner.read_exact(buf))
+    }
+}
+
+impl<T, E> io::Write for BlockOn<T, E>
+where
+    T: AsyncWriteAll,
+    E: BlockOnExecutor,
+{
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        io::Write::write_all(self, data)?;
+        Ok(data.len())
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        let inner = &mut self.inner;
+        self.executor.block_on(inner.write_all(data))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let inner = &mut self.inner;
+        self.executor.block_on(inner.flush())
+    }
+}
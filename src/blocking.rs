@@ -0,0 +1,254 @@
+use crate::connection::CraftConnection;
+use crate::reader::CraftReader;
+use crate::writer::CraftWriter;
+use mcproto_rs::protocol::{PacketDirection, State};
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::task::{spawn_blocking, JoinHandle};
+
+// the size of the reads made on the blocking stream, unless the caller asks for more
+const BLOCKING_BUF_SIZE: usize = 8192;
+
+// the stream (and its buffer) are moved into the blocking task while an operation runs, and moved
+// back when it completes, so an operation which is not waited for is never lost
+enum BlockingState<S> {
+    Idle(S, Vec<u8>),
+    Busy(JoinHandle<(S, Vec<u8>, io::Result<usize>)>),
+    // the blocking task panicked, and took the stream with it
+    Failed,
+}
+
+fn failed_error() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "blocking stream was lost by a panicked task")
+}
+
+// polls the running operation, putting the stream back once it completes
+fn poll_operation<S>(
+    state: &mut BlockingState<S>,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<usize>> {
+    match std::mem::replace(state, BlockingState::Failed) {
+        BlockingState::Idle(inner, buf) => {
+            *state = BlockingState::Idle(inner, buf);
+            Poll::Ready(Ok(0))
+        }
+        BlockingState::Busy(mut handle) => match Pin::new(&mut handle).poll(cx) {
+            Poll::Pending => {
+                *state = BlockingState::Busy(handle);
+                Poll::Pending
+            }
+            Poll::Ready(Ok((inner, buf, result))) => {
+                *state = BlockingState::Idle(inner, buf);
+                Poll::Ready(result)
+            }
+            Poll::Ready(Err(err)) => Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, err))),
+        },
+        BlockingState::Failed => Poll::Ready(Err(failed_error())),
+    }
+}
+
+///
+/// Exposes a blocking `std::io::Read` stream (such as a transport which only has a blocking API)
+/// as a `tokio::io::AsyncRead`, by running each read on tokio's blocking thread pool. This lets it
+/// be wrapped by a `CraftReader` and read from asynchronously.
+///
+/// A read keeps running when the future waiting for it is dropped, and the data it returns is
+/// kept for the next read, so no data is lost. This must be used from within a tokio runtime.
+///
+pub struct BlockingReader<R> {
+    state: BlockingState<R>,
+    // the range of the buffer holding data which was read, but not returned yet
+    pos: usize,
+    filled: usize,
+}
+
+// the stream is never pinned, it is only moved between this type and the blocking task
+impl<R> Unpin for BlockingReader<R> {}
+
+impl<R> BlockingReader<R>
+where
+    R: Read + Send + 'static,
+{
+    pub fn new(inner: R) -> Self {
+        Self {
+            state: BlockingState::Idle(inner, Vec::new()),
+            pos: 0,
+            filled: 0,
+        }
+    }
+}
+
+impl<R> AsyncRead for BlockingReader<R>
+where
+    R: Read + Send + 'static,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if let BlockingState::Idle(_, buf) = &this.state {
+                if this.pos < this.filled {
+                    let n = (this.filled - this.pos).min(out.remaining());
+                    out.put_slice(&buf[this.pos..this.pos + n]);
+                    this.pos += n;
+                    return Poll::Ready(Ok(()));
+                }
+
+                let (mut inner, mut buf) =
+                    match std::mem::replace(&mut this.state, BlockingState::Failed) {
+                        BlockingState::Idle(inner, buf) => (inner, buf),
+                        _ => unreachable!("state was just matched as idle"),
+                    };
+                buf.resize(out.remaining().max(BLOCKING_BUF_SIZE), 0);
+                this.state = BlockingState::Busy(spawn_blocking(move || {
+                    let result = loop {
+                        match inner.read(&mut buf) {
+                            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                            other => break other,
+                        }
+                    };
+                    (inner, buf, result)
+                }));
+            }
+
+            this.pos = 0;
+            this.filled = 0;
+            match poll_operation(&mut this.state, cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                // the stream has ended
+                Poll::Ready(Ok(0)) => return Poll::Ready(Ok(())),
+                Poll::Ready(Ok(n)) => this.filled = n,
+            }
+        }
+    }
+}
+
+///
+/// Exposes a blocking `std::io::Write` stream as a `tokio::io::AsyncWrite`, by running each write
+/// on tokio's blocking thread pool. This lets it be wrapped by a `CraftWriter` and written to
+/// asynchronously.
+///
+/// Like `tokio::fs::File`, a write is accepted as soon as its data was copied, and completes in the
+/// background. A failed write is reported by the next write or flush. This must be used from within
+/// a tokio runtime.
+///
+pub struct BlockingWriter<W> {
+    state: BlockingState<W>,
+    needs_flush: bool,
+}
+
+impl<W> Unpin for BlockingWriter<W> {}
+
+impl<W> BlockingWriter<W>
+where
+    W: Write + Send + 'static,
+{
+    pub fn new(inner: W) -> Self {
+        Self {
+            state: BlockingState::Idle(inner, Vec::new()),
+            needs_flush: false,
+        }
+    }
+}
+
+impl<W> AsyncWrite for BlockingWriter<W>
+where
+    W: Write + Send + 'static,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match poll_operation(&mut this.state, cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Ready(Ok(_)) => {}
+        }
+
+        if data.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let (mut inner, mut buf) = match std::mem::replace(&mut this.state, BlockingState::Failed) {
+            BlockingState::Idle(inner, buf) => (inner, buf),
+            _ => unreachable!("the previous operation just completed"),
+        };
+        buf.clear();
+        buf.extend_from_slice(data);
+        this.needs_flush = true;
+        this.state = BlockingState::Busy(spawn_blocking(move || {
+            let result = inner.write_all(&buf).map(|()| buf.len());
+            (inner, buf, result)
+        }));
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match poll_operation(&mut this.state, cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Ready(Ok(_)) => {}
+            }
+
+            if !this.needs_flush {
+                return Poll::Ready(Ok(()));
+            }
+
+            let (mut inner, buf) = match std::mem::replace(&mut this.state, BlockingState::Failed) {
+                BlockingState::Idle(inner, buf) => (inner, buf),
+                _ => unreachable!("the previous operation just completed"),
+            };
+            this.needs_flush = false;
+            this.state = BlockingState::Busy(spawn_blocking(move || {
+                let result = inner.flush().map(|()| 0);
+                (inner, buf, result)
+            }));
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl<R, W> CraftConnection<BlockingReader<R>, BlockingWriter<W>>
+where
+    R: Read + Send + Sync + 'static,
+    W: Write + Send + Sync + 'static,
+{
+    ///
+    /// Wraps the two halves of a blocking stream (such as a stream and a clone of it) into a
+    /// connection which is read and written asynchronously. See `BlockingReader` and
+    /// `BlockingWriter`.
+    ///
+    pub fn from_blocking(read: R, write: W, read_direction: PacketDirection) -> Self {
+        Self::from_blocking_with_state(read, write, read_direction, State::Handshaking)
+    }
+
+    pub fn from_blocking_with_state(
+        read: R,
+        write: W,
+        read_direction: PacketDirection,
+        state: State,
+    ) -> Self {
+        Self::from_split(
+            CraftReader::wrap_with_state(BlockingReader::new(read), read_direction, state),
+            CraftWriter::wrap_with_state(
+                BlockingWriter::new(write),
+                read_direction.opposite(),
+                state,
+            ),
+        )
+    }
+}
@@ -0,0 +1,311 @@
+use crate::connection::CraftConnection;
+use std::io::{Read, Write};
+use std::pin::Pin;
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+use {
+    std::io,
+    std::task::{Context, Poll},
+};
+
+///
+/// A boxed blocking read stream, so that a connection's type does not depend on its transport.
+///
+pub type BoxedSyncRead = Box<dyn Read + Send>;
+
+///
+/// A boxed blocking write stream, so that a connection's type does not depend on its transport.
+///
+pub type BoxedSyncWrite = Box<dyn Write + Send>;
+
+///
+/// A connection over any blocking transport. It supports everything a connection over the
+/// original streams supports (the blocking reader and writer traits and `CraftIo`), at the cost of
+/// a dynamic call for each read or write on the stream. See `CraftConnection::into_boxed_sync`.
+///
+pub type BoxedSyncCraftConnection = CraftConnection<BoxedSyncRead, BoxedSyncWrite>;
+
+impl<R, W> CraftConnection<R, W>
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    ///
+    /// Boxes the streams of this connection, keeping all of its state. This lets connections over
+    /// different transports be stored in the same place, without making it generic.
+    ///
+    pub fn into_boxed_sync(self) -> BoxedSyncCraftConnection {
+        self.map_streams(|read, write| {
+            (Box::new(read) as BoxedSyncRead, Box::new(write) as BoxedSyncWrite)
+        })
+    }
+}
+
+///
+/// Owns a stream which is `Send` but not necessarily `Sync` or `Unpin` (such as a
+/// `Pin<Box<dyn AsyncRead + Send>>` assembled from several layers at runtime), and makes it usable
+/// where this crate requires async streams to be `Unpin + Send + Sync`. See
+/// `CraftConnection::from_boxed`.
+///
+/// The stream is only ever used through `&mut self`, so shared references to this type give no
+/// access to it, and it is safe to share them between threads.
+///
+pub struct SyncStream<T: ?Sized> {
+    inner: Pin<Box<T>>,
+}
+
+// no method gives access to the stream through `&self`, so `&SyncStream` can not be used to reach
+// it from several threads at once (like `std::sync::Exclusive`)
+unsafe impl<T: ?Sized + Send> Sync for SyncStream<T> {}
+
+impl<T> SyncStream<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner: Box::pin(inner),
+        }
+    }
+}
+
+impl<T: ?Sized> SyncStream<T> {
+    pub fn from_pin(inner: Pin<Box<T>>) -> Self {
+        Self { inner }
+    }
+
+    pub fn get_pin_mut(&mut self) -> Pin<&mut T> {
+        self.inner.as_mut()
+    }
+
+    pub fn into_inner(self) -> Pin<Box<T>> {
+        self.inner
+    }
+}
+
+#[cfg(feature = "tokio-io")]
+impl<T> tokio::io::AsyncRead for SyncStream<T>
+where
+    T: tokio::io::AsyncRead + ?Sized,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        tokio::io::AsyncRead::poll_read(self.get_mut().inner.as_mut(), cx, buf)
+    }
+}
+
+#[cfg(feature = "tokio-io")]
+impl<T> tokio::io::AsyncWrite for SyncStream<T>
+where
+    T: tokio::io::AsyncWrite + ?Sized,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        tokio::io::AsyncWrite::poll_write(self.get_mut().inner.as_mut(), cx, data)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        tokio::io::AsyncWrite::poll_flush(self.get_mut().inner.as_mut(), cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        tokio::io::AsyncWrite::poll_shutdown(self.get_mut().inner.as_mut(), cx)
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl<T> futures::AsyncRead for SyncStream<T>
+where
+    T: futures::AsyncRead + ?Sized,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        futures::AsyncRead::poll_read(self.get_mut().inner.as_mut(), cx, buf)
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl<T> futures::AsyncWrite for SyncStream<T>
+where
+    T: futures::AsyncWrite + ?Sized,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        futures::AsyncWrite::poll_write(self.get_mut().inner.as_mut(), cx, data)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        futures::AsyncWrite::poll_flush(self.get_mut().inner.as_mut(), cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        futures::AsyncWrite::poll_close(self.get_mut().inner.as_mut(), cx)
+    }
+}
+
+#[cfg(feature = "tokio-io")]
+mod boxed_async {
+    use super::{CraftConnection, SyncStream};
+    use crate::reader::CraftReader;
+    use crate::writer::CraftWriter;
+    use mcproto_rs::protocol::{PacketDirection, State};
+    use tokio::io::{AsyncRead, AsyncWrite};
+
+    ///
+    /// A boxed async read stream, so that a connection's type does not depend on its transport.
+    ///
+    pub type BoxedAsyncRead = Box<dyn AsyncRead + Unpin + Send + Sync>;
+
+    ///
+    /// A boxed async write stream, so that a connection's type does not depend on its transport.
+    ///
+    pub type BoxedAsyncWrite = Box<dyn AsyncWrite + Unpin + Send + Sync>;
+
+    ///
+    /// A connection over any async transport. It supports everything a connection over the
+    /// original streams supports (the async and poll based reader and writer traits and
+    /// `CraftIo`), at the cost of a dynamic call for each read or write on the stream. See
+    /// `CraftConnection::into_boxed`.
+    ///
+    pub type BoxedCraftConnection = CraftConnection<BoxedAsyncRead, BoxedAsyncWrite>;
+
+    impl<R, W> CraftConnection<R, W>
+    where
+        R: AsyncRead + Unpin + Send + Sync + 'static,
+        W: AsyncWrite + Unpin + Send + Sync + 'static,
+    {
+        ///
+        /// Boxes the streams of this connection, keeping all of its state. This lets connections
+        /// over different transports (such as plain tcp, and tcp through a proxy) be stored in the
+        /// same place, without making it generic over every combination of streams.
+        ///
+        pub fn into_boxed(self) -> BoxedCraftConnection {
+            self.map_streams(|read, write| {
+                (Box::new(read) as BoxedAsyncRead, Box::new(write) as BoxedAsyncWrite)
+            })
+        }
+    }
+
+    impl BoxedCraftConnection {
+        ///
+        /// Wraps streams which are only `Send` (such as `Pin<Box<dyn AsyncRead + Send>>` streams
+        /// layered at runtime, like TLS over SOCKS over TCP) into a boxed connection, without
+        /// naming their types. A `Box<dyn AsyncWrite + Send>` can be passed using `Box::into_pin`.
+        /// See `SyncStream`.
+        ///
+        pub fn from_boxed<R, W>(read: R, write: W, read_direction: PacketDirection) -> Self
+        where
+            R: AsyncRead + Send + 'static,
+            W: AsyncWrite + Send + 'static,
+        {
+            Self::from_boxed_with_state(read, write, read_direction, State::Handshaking)
+        }
+
+        pub fn from_boxed_with_state<R, W>(
+            read: R,
+            write: W,
+            read_direction: PacketDirection,
+            state: State,
+        ) -> Self
+        where
+            R: AsyncRead + Send + 'static,
+            W: AsyncWrite + Send + 'static,
+        {
+            let read = Box::new(SyncStream::new(read)) as BoxedAsyncRead;
+            let write = Box::new(SyncStream::new(write)) as BoxedAsyncWrite;
+            Self::from_split(
+                CraftReader::wrap_with_state(read, read_direction, state),
+                CraftWriter::wrap_with_state(write, read_direction.opposite(), state),
+            )
+        }
+    }
+}
+
+#[cfg(all(feature = "futures-io", not(feature = "tokio-io")))]
+mod boxed_async {
+    use super::{CraftConnection, SyncStream};
+    use crate::reader::CraftReader;
+    use crate::writer::CraftWriter;
+    use futures::{AsyncRead, AsyncWrite};
+    use mcproto_rs::protocol::{PacketDirection, State};
+
+    ///
+    /// A boxed async read stream, so that a connection's type does not depend on its transport.
+    ///
+    pub type BoxedAsyncRead = Box<dyn AsyncRead + Unpin + Send + Sync>;
+
+    ///
+    /// A boxed async write stream, so that a connection's type does not depend on its transport.
+    ///
+    pub type BoxedAsyncWrite = Box<dyn AsyncWrite + Unpin + Send + Sync>;
+
+    ///
+    /// A connection over any async transport. It supports everything a connection over the
+    /// original streams supports (the async and poll based reader and writer traits and
+    /// `CraftIo`), at the cost of a dynamic call for each read or write on the stream. See
+    /// `CraftConnection::into_boxed`.
+    ///
+    pub type BoxedCraftConnection = CraftConnection<BoxedAsyncRead, BoxedAsyncWrite>;
+
+    impl<R, W> CraftConnection<R, W>
+    where
+        R: AsyncRead + Unpin + Send + Sync + 'static,
+        W: AsyncWrite + Unpin + Send + Sync + 'static,
+    {
+        ///
+        /// Boxes the streams of this connection, keeping all of its state. This lets connections
+        /// over different transports (such as plain tcp, and tcp through a proxy) be stored in the
+        /// same place, without making it generic over every combination of streams.
+        ///
+        pub fn into_boxed(self) -> BoxedCraftConnection {
+            self.map_streams(|read, write| {
+                (Box::new(read) as BoxedAsyncRead, Box::new(write) as BoxedAsyncWrite)
+            })
+        }
+    }
+
+    impl BoxedCraftConnection {
+        ///
+        /// Wraps streams which are only `Send` (such as `Pin<Box<dyn AsyncRead + Send>>` streams
+        /// layered at runtime, like TLS over SOCKS over TCP) into a boxed connection, without
+        /// naming their types. A `Box<dyn AsyncWrite + Send>` can be passed using `Box::into_pin`.
+        /// See `SyncStream`.
+        ///
+        pub fn from_boxed<R, W>(read: R, write: W, read_direction: PacketDirection) -> Self
+        where
+            R: AsyncRead + Send + 'static,
+            W: AsyncWrite + Send + 'static,
+        {
+            Self::from_boxed_with_state(read, write, read_direction, State::Handshaking)
+        }
+
+        pub fn from_boxed_with_state<R, W>(
+            read: R,
+            write: W,
+            read_direction: PacketDirection,
+            state: State,
+        ) -> Self
+        where
+            R: AsyncRead + Send + 'static,
+            W: AsyncWrite + Send + 'static,
+        {
+            let read = Box::new(SyncStream::new(read)) as BoxedAsyncRead;
+            let write = Box::new(SyncStream::new(write)) as BoxedAsyncWrite;
+            Self::from_split(
+                CraftReader::wrap_with_state(read, read_direction, state),
+                CraftWriter::wrap_with_state(write, read_direction.opposite(), state),
+            )
+        }
+    }
+}
+
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+pub use boxed_async::*;
@@ -0,0 +1,352 @@
+#[cfg(feature = "encryption")]
+use crate::cfb8::{CipherError, CraftCipher};
+#[cfg(feature = "serde")]
+use crate::config::ConnectionConfig;
+use crate::connection::CraftConnection;
+use crate::reader::{CraftReader, DEAFULT_MAX_PACKET_SIZE};
+#[cfg(feature = "socket-options")]
+use crate::socket::{CloseBehavior, TcpSocket};
+use crate::strict::PacketIdRules;
+use crate::timing::MinThroughput;
+use crate::wrapper::{BufferCapacities, BufferGrowth, CraftIo, StateMaxPacketSizes};
+use crate::writer::CraftWriter;
+use mcproto_rs::protocol::{PacketDirection, State};
+#[cfg(feature = "socket-options")]
+use std::io;
+
+///
+/// Builds a `CraftReader` with all of its options configured up front, instead of wrapping a stream
+/// and then calling the setters from `CraftIo` one by one.
+///
+#[derive(Debug)]
+pub struct CraftReaderBuilder {
+    options: BuilderOptions,
+}
+
+///
+/// Builds a `CraftWriter` with all of its options configured up front, instead of wrapping a stream
+/// and then calling the setters from `CraftIo` one by one.
+///
+#[derive(Debug)]
+pub struct CraftWriterBuilder {
+    options: BuilderOptions,
+    flush_threshold: Option<usize>,
+}
+
+///
+/// Builds a `CraftConnection` from a reader and writer stream, with the options of both halves
+/// configured up front. The options can also be loaded from a configuration file, see
+/// `from_config`.
+///
+#[derive(Debug)]
+pub struct CraftConnectionBuilder {
+    options: BuilderOptions,
+    read_ahead: usize,
+    strict_ids: bool,
+    min_throughput: Option<MinThroughput>,
+    flush_threshold: Option<usize>,
+    auto_state: bool,
+    #[cfg(feature = "socket-options")]
+    close_behavior: Option<CloseBehavior>,
+}
+
+#[derive(Debug)]
+struct BuilderOptions {
+    direction: PacketDirection,
+    state: State,
+    max_packet_size: usize,
+    state_max_packet_sizes: Option<StateMaxPacketSizes>,
+    buffer_growth: BufferGrowth,
+    capacities: BufferCapacities,
+    #[cfg(feature = "compression")]
+    compression_threshold: Option<i32>,
+    #[cfg(feature = "encryption")]
+    cipher: Option<CraftCipher>,
+}
+
+impl BuilderOptions {
+    fn new(direction: PacketDirection) -> Self {
+        Self {
+            direction,
+            state: State::Handshaking,
+            max_packet_size: DEAFULT_MAX_PACKET_SIZE,
+            state_max_packet_sizes: None,
+            buffer_growth: BufferGrowth::default(),
+            capacities: BufferCapacities::default(),
+            #[cfg(feature = "compression")]
+            compression_threshold: None,
+            #[cfg(feature = "encryption")]
+            cipher: None,
+        }
+    }
+
+    fn apply<T>(&self, target: &mut T)
+    where
+        T: CraftIo,
+    {
+        target.set_max_packet_size(self.max_packet_size);
+        target.set_state_max_packet_sizes(self.state_max_packet_sizes);
+        target.set_buffer_growth(self.buffer_growth);
+        #[cfg(feature = "compression")]
+        target.set_compression_threshold(self.compression_threshold);
+        self.capacities.apply(target);
+    }
+}
+
+macro_rules! builder_setters {
+    () => {
+        ///
+        /// Sets the protocol state the built type starts in. The default is `State::Handshaking`.
+        ///
+        pub fn state(mut self, state: State) -> Self {
+            self.options.state = state;
+            self
+        }
+
+        ///
+        /// Sets the max packet size. See `CraftIo::set_max_packet_size`.
+        ///
+        pub fn max_packet_size(mut self, max_size: usize) -> Self {
+            debug_assert!(max_size > 5);
+            self.options.max_packet_size = max_size;
+            self
+        }
+
+        ///
+        /// Sets a max packet size for each protocol state, which replaces the max packet size. See
+        /// `CraftIo::set_state_max_packet_sizes`.
+        ///
+        pub fn state_max_packet_sizes(mut self, sizes: StateMaxPacketSizes) -> Self {
+            self.options.state_max_packet_sizes = Some(sizes);
+            self
+        }
+
+        ///
+        /// Sets the buffer growth policy. See `CraftIo::set_buffer_growth`.
+        ///
+        pub fn buffer_growth(mut self, growth: BufferGrowth) -> Self {
+            self.options.buffer_growth = growth;
+            self
+        }
+
+        ///
+        /// Sets the initial sizes of the internal buffers, which are allocated when the type is built.
+        ///
+        pub fn buffer_capacities(mut self, capacities: BufferCapacities) -> Self {
+            self.options.capacities = capacities;
+            self
+        }
+
+        ///
+        /// Sets the compression threshold. See `CraftIo::set_compression_threshold`.
+        ///
+        #[cfg(feature = "compression")]
+        pub fn compression_threshold(mut self, threshold: Option<i32>) -> Self {
+            self.options.compression_threshold = threshold;
+            self
+        }
+    };
+}
+
+impl CraftReaderBuilder {
+    ///
+    /// Starts building a reader which reads packets travelling in the given `direction`.
+    ///
+    pub fn new(direction: PacketDirection) -> Self {
+        Self {
+            options: BuilderOptions::new(direction),
+        }
+    }
+
+    builder_setters!();
+
+    ///
+    /// Enables encryption on the built reader. The key and iv are validated immediately, so an error
+    /// is returned here rather than from `build`.
+    ///
+    #[cfg(feature = "encryption")]
+    pub fn encryption(mut self, key: &[u8], iv: &[u8]) -> Result<Self, CipherError> {
+        self.options.cipher = Some(CraftCipher::new(key, iv, false)?);
+        Ok(self)
+    }
+
+    pub fn build<R>(self, inner: R) -> CraftReader<R> {
+        let mut out =
+            CraftReader::wrap_with_state(inner, self.options.direction, self.options.state);
+        self.options.apply(&mut out);
+        #[cfg(feature = "encryption")]
+        out.set_cipher(self.options.cipher);
+        out
+    }
+}
+
+impl CraftWriterBuilder {
+    ///
+    /// Starts building a writer which writes packets travelling in the given `direction`.
+    ///
+    pub fn new(direction: PacketDirection) -> Self {
+        Self {
+            options: BuilderOptions::new(direction),
+            flush_threshold: None,
+        }
+    }
+
+    builder_setters!();
+
+    ///
+    /// Sets the flush threshold of the built writer. See `CraftWriter::set_flush_threshold`.
+    ///
+    pub fn flush_threshold(mut self, threshold: Option<usize>) -> Self {
+        self.flush_threshold = threshold;
+        self
+    }
+
+    ///
+    /// Enables encryption on the built writer. The key and iv are validated immediately, so an error
+    /// is returned here rather than from `build`.
+    ///
+    #[cfg(feature = "encryption")]
+    pub fn encryption(mut self, key: &[u8], iv: &[u8]) -> Result<Self, CipherError> {
+        self.options.cipher = Some(CraftCipher::new(key, iv, true)?);
+        Ok(self)
+    }
+
+    pub fn build<W>(self, inner: W) -> CraftWriter<W> {
+        let mut out =
+            CraftWriter::wrap_with_state(inner, self.options.direction, self.options.state);
+        self.options.apply(&mut out);
+        out.set_flush_threshold(self.flush_threshold);
+        #[cfg(feature = "encryption")]
+        out.set_cipher(self.options.cipher);
+        out
+    }
+}
+
+impl CraftConnectionBuilder {
+    ///
+    /// Starts building a connection which reads packets travelling in `read_direction`, and writes
+    /// packets travelling in the opposite direction.
+    ///
+    pub fn new(read_direction: PacketDirection) -> Self {
+        Self {
+            options: BuilderOptions::new(read_direction),
+            read_ahead: 0,
+            strict_ids: false,
+            min_throughput: None,
+            flush_threshold: None,
+            auto_state: false,
+            #[cfg(feature = "socket-options")]
+            close_behavior: None,
+        }
+    }
+
+    ///
+    /// Starts building a connection with the options from `config`.
+    ///
+    #[cfg(feature = "serde")]
+    pub fn from_config(read_direction: PacketDirection, config: &ConnectionConfig) -> Self {
+        let mut out = Self::new(read_direction)
+            .max_packet_size(config.max_packet_size)
+            .buffer_growth(config.buffer_growth)
+            .buffer_capacities(config.buffer_capacities())
+            .read_ahead(config.read_ahead)
+            .strict_ids(config.strict_ids)
+            .min_throughput(config.min_throughput())
+            .flush_threshold(config.flush_threshold);
+        out.options.state_max_packet_sizes = config.state_max_packet_sizes;
+        #[cfg(feature = "compression")]
+        let out = out.compression_threshold(config.compression_threshold);
+        out
+    }
+
+    builder_setters!();
+
+    ///
+    /// Sets the read-ahead of the reader. See `CraftReader::set_read_ahead`.
+    ///
+    pub fn read_ahead(mut self, bytes: usize) -> Self {
+        self.read_ahead = bytes;
+        self
+    }
+
+    ///
+    /// Enables strict mode on the reader, with the built in rules. See
+    /// `CraftReader::set_strict_ids`.
+    ///
+    pub fn strict_ids(mut self, enabled: bool) -> Self {
+        self.strict_ids = enabled;
+        self
+    }
+
+    ///
+    /// Sets the minimum throughput of both halves. See `CraftConnection::set_min_throughput`.
+    ///
+    pub fn min_throughput(mut self, limit: Option<MinThroughput>) -> Self {
+        self.min_throughput = limit;
+        self
+    }
+
+    ///
+    /// Sets the flush threshold of the writer. See `CraftWriter::set_flush_threshold`.
+    ///
+    pub fn flush_threshold(mut self, threshold: Option<usize>) -> Self {
+        self.flush_threshold = threshold;
+        self
+    }
+
+    ///
+    /// Makes the connection follow its state from the packets which change it. See
+    /// `CraftConnection::set_auto_state`.
+    ///
+    pub fn auto_state(mut self, enabled: bool) -> Self {
+        self.auto_state = enabled;
+        self
+    }
+
+    ///
+    /// Sets what closing the socket does with data which was not sent yet. This is a socket
+    /// option, so it is only applied by `build_socket`. See `CloseBehavior`.
+    ///
+    #[cfg(feature = "socket-options")]
+    pub fn close_behavior(mut self, behavior: CloseBehavior) -> Self {
+        self.close_behavior = Some(behavior);
+        self
+    }
+
+    ///
+    /// Same as `build`, but also applies the options which belong to the socket (the close
+    /// behavior), for streams which are backed by a TCP socket.
+    ///
+    #[cfg(feature = "socket-options")]
+    pub fn build_socket<R, W>(self, read: R, write: W) -> io::Result<CraftConnection<R, W>>
+    where
+        W: TcpSocket,
+    {
+        let close_behavior = self.close_behavior;
+        let out = self.build(read, write);
+        if let Some(behavior) = close_behavior {
+            out.set_close_behavior(behavior)?;
+        }
+        Ok(out)
+    }
+
+    pub fn build<R, W>(self, read: R, write: W) -> CraftConnection<R, W> {
+        let direction = self.options.direction;
+        let mut reader = CraftReader::wrap_with_state(read, direction, self.options.state);
+        self.options.apply(&mut reader);
+        reader.set_read_ahead(self.read_ahead);
+        if self.strict_ids {
+            reader.set_strict_ids(Some(PacketIdRules::for_protocol_version(None)));
+        }
+
+        let mut writer =
+            CraftWriter::wrap_with_state(write, direction.opposite(), self.options.state);
+        self.options.apply(&mut writer);
+        writer.set_flush_threshold(self.flush_threshold);
+
+        let mut out = CraftConnection::from_split(reader, writer);
+        out.set_min_throughput(self.min_throughput);
+        out.set_auto_state(self.auto_state);
+        out
+    }
+}
@@ -0,0 +1,113 @@
+use crate::connection::CraftConnection;
+#[cfg(feature = "compression")]
+use crate::wrapper::CraftIo;
+use crate::writer::{CraftWriter, WriteResult};
+use mcproto_rs::protocol::{Id, Packet};
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+use crate::writer::AsyncWriteAll;
+
+///
+/// A packet which was serialized and compressed once, so it can be written to many connections
+/// without repeating that work for each of them. This is how a server should broadcast identical
+/// packets (such as chunks, or boss bar updates) to many players.
+///
+/// A frame is prepared for one compression threshold, and can be written to any connection which
+/// uses that same threshold (see `CraftWriter::write_cached_frame`). Encryption is applied
+/// separately for each connection when the frame is written, since every connection has its own
+/// cipher state.
+///
+#[derive(Debug, Clone)]
+pub struct CachedFrame {
+    pub(crate) id: Id,
+    pub(crate) compression_threshold: Option<i32>,
+    // the packet body without the id, which middleware is called with
+    pub(crate) body: Vec<u8>,
+    // the complete unencrypted frame, including the length prefix
+    pub(crate) frame: Vec<u8>,
+}
+
+impl CachedFrame {
+    ///
+    /// Serializes and compresses a packet into a frame for connections using the given compression
+    /// threshold (which has the same meaning as in `set_compression_threshold`).
+    ///
+    pub fn new<P>(packet: &P, compression_threshold: Option<i32>) -> WriteResult<Self>
+    where
+        P: Packet,
+    {
+        let id = packet.id();
+        let (body, frame) =
+            scratch_writer(id, compression_threshold).detached_packet_frame(packet)?;
+        Ok(Self {
+            id,
+            compression_threshold,
+            body,
+            frame,
+        })
+    }
+
+    ///
+    /// Same as `new`, but for a packet which is already serialized.
+    ///
+    pub fn from_raw(id: Id, body: &[u8], compression_threshold: Option<i32>) -> WriteResult<Self> {
+        let (body, frame) =
+            scratch_writer(id, compression_threshold).detached_untyped_frame(id, body)?;
+        Ok(Self {
+            id,
+            compression_threshold,
+            body,
+            frame,
+        })
+    }
+
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    pub fn compression_threshold(&self) -> Option<i32> {
+        self.compression_threshold
+    }
+
+    ///
+    /// The size of the frame in bytes, as it is written to each connection.
+    ///
+    pub fn frame_len(&self) -> usize {
+        self.frame.len()
+    }
+}
+
+// a writer which is only used to prepare one frame, so it never touches its stream
+fn scratch_writer(id: Id, compression_threshold: Option<i32>) -> CraftWriter<()> {
+    #[allow(unused_mut)]
+    let mut writer = CraftWriter::wrap_with_state((), id.direction, id.state);
+    #[cfg(feature = "compression")]
+    writer.set_compression_threshold(compression_threshold);
+    #[cfg(not(feature = "compression"))]
+    let _ = compression_threshold;
+    writer
+}
+
+impl<R, W> CraftConnection<R, W>
+where
+    W: std::io::Write,
+{
+    ///
+    /// Writes a `CachedFrame` to this connection. See `CraftWriter::write_cached_frame`.
+    ///
+    pub fn write_cached_frame(&mut self, frame: &CachedFrame) -> WriteResult<()> {
+        self.writer.write_cached_frame(frame)
+    }
+}
+
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+impl<R, W> CraftConnection<R, W>
+where
+    W: AsyncWriteAll,
+{
+    ///
+    /// Same as `write_cached_frame`, but writes to an async stream.
+    ///
+    pub async fn write_cached_frame_async(&mut self, frame: &CachedFrame) -> WriteResult<()> {
+        self.writer.write_cached_frame_async(frame).await
+    }
+}
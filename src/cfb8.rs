@@ -39,7 +39,7 @@ impl CipherError {
         }
     }
 
-    fn already_enabled() -> Self {
+    pub(crate) fn already_enabled() -> Self {
         CipherError::AlreadyEnabled {
             #[cfg(feature = "backtrace")]
             backtrace: Backtrace::capture(),
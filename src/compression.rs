@@ -0,0 +1,195 @@
+use crate::reader::{DecompressErr, ReadError};
+use crate::util::{get_sized_buf, get_sized_buf_with, move_data_rightwards, VAR_INT_BUF_SIZE};
+use crate::wrapper::{BufferAllocator, BufferGrowth};
+use crate::writer::{PacketSerializeFail, SliceSerializer, WriteError};
+use flate2::{Compression, FlushCompress, FlushDecompress, Status};
+use mcproto_rs::types::VarInt;
+use mcproto_rs::{Deserialize, Deserialized, Serialize};
+#[cfg(feature = "backtrace")]
+use std::backtrace::Backtrace;
+
+///
+/// The zlib compression level used unless another one is configured, which favors speed over
+/// compression ratio.
+///
+pub const DEFAULT_COMPRESSION_LEVEL: u32 = 1;
+
+///
+/// Compresses `src` using zlib, writing the compressed bytes into `output` starting at `offset`.
+/// The buffer is allocated (or grown) if required, and the region before `offset` is left untouched,
+/// which lets callers reserve space for headers in front of the compressed data.
+///
+/// Returns the slice of `output` which holds the compressed data.
+///
+pub fn compress<'a, 'b>(
+    src: &'b [u8],
+    output: &'a mut Option<Vec<u8>>,
+    offset: usize,
+) -> Result<&'a mut [u8], WriteError> {
+    compress_with_level(src, output, offset, DEFAULT_COMPRESSION_LEVEL)
+}
+
+///
+/// Same as `compress`, but uses the given zlib compression level, from 1 (fastest) to 9 (best
+/// compression). Other levels are clamped into that range: level 0 would only store the data, and
+/// always produce output larger than the input.
+///
+pub fn compress_with_level<'a, 'b>(
+    src: &'b [u8],
+    output: &'a mut Option<Vec<u8>>,
+    offset: usize,
+    level: u32,
+) -> Result<&'a mut [u8], WriteError> {
+    let target = get_sized_buf(output, offset, src.len());
+    let level = Compression::new(level.clamp(1, 9));
+    let mut compressor = flate2::Compress::new_with_window_bits(level, true, 15);
+    loop {
+        let input = &src[(compressor.total_in() as usize)..];
+        let eof = input.is_empty();
+        let output = &mut target[(compressor.total_out() as usize)..];
+        let flush = if eof {
+            FlushCompress::Finish
+        } else {
+            FlushCompress::None
+        };
+
+        match compressor.compress(input, output, flush)? {
+            Status::Ok => {}
+            Status::BufError => {
+                return Err(WriteError::CompressBufError {
+                    #[cfg(feature = "backtrace")]
+                    backtrace: Backtrace::capture(),
+                })
+            }
+            Status::StreamEnd => break,
+        }
+    }
+
+    Ok(&mut target[..(compressor.total_out() as usize)])
+}
+
+///
+/// Decompresses the zlib stream in `src` into `target`, which is allocated (or grown) to hold
+/// `decompressed_len` bytes.
+///
+/// Returns the slice of `target` which holds the decompressed data.
+///
+pub fn decompress<'a>(
+    src: &'a [u8],
+    target: &'a mut Option<Vec<u8>>,
+    decompressed_len: usize,
+) -> Result<&'a mut [u8], ReadError> {
+    let mut decompress = flate2::Decompress::new(true);
+    let decompress_buf = get_sized_buf(target, 0, decompressed_len);
+    loop {
+        match decompress.decompress(src, decompress_buf, FlushDecompress::Finish) {
+            Ok(Status::StreamEnd) => break,
+            Ok(Status::Ok) => {}
+            Ok(Status::BufError) => return Err(DecompressErr::BufError.into()),
+            Err(err) => return Err(DecompressErr::Failure(err).into()),
+        }
+    }
+
+    let decompressed_size = decompress.total_out() as usize;
+    Ok(&mut decompress_buf[..decompressed_size])
+}
+
+///
+/// Unwraps the body of a frame which was sent while compression was enabled. The body is expected
+/// to start with the data length VarInt, exactly like vanilla sends it:
+///
+/// * a data length of 0 means the rest of the body (packet id + packet data) is not compressed,
+///   and a slice of `buf` is returned
+/// * any other data length is the size of the decompressed packet id + packet data, which is
+///   decompressed into `target` and returned
+///
+/// A data length at or above `max_packet_size` is rejected before anything is decompressed.
+///
+pub fn decompress_frame_body<'a>(
+    buf: &'a [u8],
+    target: &'a mut Option<Vec<u8>>,
+    max_packet_size: usize,
+) -> Result<&'a [u8], ReadError> {
+    Ok(match locate_frame_body(buf, target, max_packet_size, None)? {
+        FrameBody::Uncompressed { offset } => &buf[offset..],
+        FrameBody::Decompressed { len } => {
+            &target.as_ref().expect("body was just decompressed into target")[..len]
+        }
+    })
+}
+
+// where decompress_frame_body found the body of a frame
+pub(crate) enum FrameBody {
+    // the body was not compressed, and starts at this offset of the frame
+    Uncompressed { offset: usize },
+    // the body was decompressed into the front of the target buffer
+    Decompressed { len: usize },
+}
+
+pub(crate) fn locate_frame_body(
+    buf: &[u8],
+    target: &mut Option<Vec<u8>>,
+    max_packet_size: usize,
+    allocator: Option<&dyn BufferAllocator>,
+) -> Result<FrameBody, ReadError> {
+    // most frames are below the threshold, and a data length of 0 is always the single byte 0
+    if buf.first() == Some(&0) {
+        return Ok(FrameBody::Uncompressed { offset: 1 });
+    }
+
+    let Deserialized { value: data_len, data: rest } = VarInt::mc_deserialize(buf)?;
+    let data_len = data_len.0 as usize;
+    if data_len == 0 {
+        Ok(FrameBody::Uncompressed {
+            offset: buf.len() - rest.len(),
+        })
+    } else if data_len >= max_packet_size {
+        Err(ReadError::PacketTooLarge {
+            size: data_len,
+            max_size: max_packet_size,
+            #[cfg(feature = "backtrace")]
+            backtrace: Backtrace::capture(),
+        })
+    } else {
+        get_sized_buf_with(target, 0, data_len, BufferGrowth::Exponential, usize::MAX, allocator);
+        Ok(FrameBody::Decompressed {
+            len: decompress(rest, target, data_len)?.len(),
+        })
+    }
+}
+
+///
+/// Wraps a packet body (packet id + packet data) the way vanilla does when compression is enabled.
+/// The output written to `output` starts with the data length VarInt, followed by:
+///
+/// * the compressed body, if `threshold` is non-negative and the body is at least `threshold` bytes
+/// * the body as-is (with a data length of 0) otherwise
+///
+/// The returned slice does not include the frame length prefix.
+///
+pub fn compress_frame_body<'a>(
+    body: &[u8],
+    threshold: i32,
+    output: &'a mut Option<Vec<u8>>,
+) -> Result<&'a mut [u8], WriteError> {
+    if threshold < 0 || (threshold as usize) > body.len() {
+        let buf = get_sized_buf(output, 0, body.len() + 1);
+        buf[0] = 0; // data_len = 0
+        (&mut buf[1..]).copy_from_slice(body);
+        return Ok(buf);
+    }
+
+    let compressed_size = compress(body, output, VAR_INT_BUF_SIZE)?.len();
+    let buf = get_sized_buf(output, 0, VAR_INT_BUF_SIZE + compressed_size);
+
+    let mut data_len_serializer = SliceSerializer::create(&mut buf[..VAR_INT_BUF_SIZE]);
+    VarInt(body.len() as i32)
+        .mc_serialize(&mut data_len_serializer)
+        .map_err(move |err| PacketSerializeFail::Header(err))?;
+    let data_len_bytes = data_len_serializer.finish().len();
+
+    let n_shift_data_len = VAR_INT_BUF_SIZE - data_len_bytes;
+    move_data_rightwards(&mut buf[..VAR_INT_BUF_SIZE], data_len_bytes, n_shift_data_len);
+
+    Ok(&mut buf[n_shift_data_len..])
+}
@@ -0,0 +1,116 @@
+use crate::reader::DEAFULT_MAX_PACKET_SIZE;
+use crate::timing::MinThroughput;
+use crate::wrapper::{BufferCapacities, BufferGrowth, StateMaxPacketSizes};
+use serde::Deserialize;
+use std::time::Duration;
+
+///
+/// The options of a connection, in a form which can be deserialized from a configuration file
+/// (such as a section of a server's TOML config), so operators can tune them without code changes.
+/// Every field is optional, and missing fields keep the defaults of a new connection.
+///
+/// Connections are built from a config using `CraftConnectionBuilder::from_config`. The timeouts
+/// belong to the socket rather than the connection, so they are applied separately (such as with
+/// `CraftTcpConnection::set_read_timeout(config.read_timeout())`).
+///
+/// ```toml
+/// max_packet_size = 2097152
+/// compression_threshold = 256
+/// read_ahead = 4096
+/// read_timeout_ms = 30000
+/// min_bytes_per_sec = 1024
+///
+/// [state_max_packet_sizes]
+/// handshaking = 1024
+/// ```
+///
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionConfig {
+    pub max_packet_size: usize,
+    ///
+    /// A max packet size for each protocol state, which replaces `max_packet_size`. Missing states
+    /// use the defaults of `StateMaxPacketSizes`.
+    ///
+    pub state_max_packet_sizes: Option<StateMaxPacketSizes>,
+    pub buffer_growth: BufferGrowth,
+    ///
+    /// The initial size of the buffer which holds packet bytes as they are read or written, or 0
+    /// to allocate it lazily.
+    ///
+    pub raw_buf_capacity: usize,
+    ///
+    /// The initial size of the buffer which holds packets before compression or after
+    /// decompression, or 0 to allocate it lazily.
+    ///
+    #[cfg(feature = "compression")]
+    pub compression_buf_capacity: usize,
+    #[cfg(feature = "compression")]
+    pub compression_threshold: Option<i32>,
+    ///
+    /// See `CraftReader::set_read_ahead`.
+    ///
+    pub read_ahead: usize,
+    ///
+    /// Whether to reject packets with ids which are not valid in the current state. See
+    /// `CraftReader::set_strict_ids`.
+    ///
+    pub strict_ids: bool,
+    pub read_timeout_ms: Option<u64>,
+    pub write_timeout_ms: Option<u64>,
+    ///
+    /// The minimum throughput of a frame in flight. See `MinThroughput`.
+    ///
+    pub min_bytes_per_sec: Option<u64>,
+    pub min_throughput_grace_ms: u64,
+    ///
+    /// See `CraftWriter::set_flush_threshold`.
+    ///
+    pub flush_threshold: Option<usize>,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            max_packet_size: DEAFULT_MAX_PACKET_SIZE,
+            state_max_packet_sizes: None,
+            buffer_growth: BufferGrowth::default(),
+            raw_buf_capacity: 0,
+            #[cfg(feature = "compression")]
+            compression_buf_capacity: 0,
+            #[cfg(feature = "compression")]
+            compression_threshold: None,
+            read_ahead: 0,
+            strict_ids: false,
+            read_timeout_ms: None,
+            write_timeout_ms: None,
+            min_bytes_per_sec: None,
+            min_throughput_grace_ms: 5000,
+            flush_threshold: None,
+        }
+    }
+}
+
+impl ConnectionConfig {
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.write_timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn buffer_capacities(&self) -> BufferCapacities {
+        BufferCapacities {
+            raw: self.raw_buf_capacity,
+            #[cfg(feature = "compression")]
+            compression: self.compression_buf_capacity,
+        }
+    }
+
+    pub fn min_throughput(&self) -> Option<MinThroughput> {
+        self.min_bytes_per_sec.map(|bytes_per_sec| {
+            MinThroughput::new(bytes_per_sec, Duration::from_millis(self.min_throughput_grace_ms))
+        })
+    }
+}
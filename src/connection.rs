@@ -1,20 +1,187 @@
 #[cfg(feature = "encryption")]
-use crate::cfb8::CipherError;
+use crate::cfb8::{CipherError, CraftCipher};
+use crate::extensions::Extensions;
+use crate::middleware::{MiddlewareChain, PacketMiddleware, PacketVerdict};
+use crate::observer::{ConnectionObserver, Observers};
 use crate::reader::{CraftReader, CraftSyncReader, ReadResult};
-use crate::wrapper::{CraftIo, CraftWrapper};
+use crate::router::{Handshake, HANDSHAKE_ID};
+use crate::stats::FrameSizeStats;
+#[cfg(feature = "compression")]
+use crate::stats::CompressionStats;
+use crate::timing::{MinThroughput, StateTimings};
+use crate::wrapper::{
+    BufferAllocator, BufferGrowth, ConnectionSettings, CraftIo, CraftWrapper, MemoryUsage,
+    SessionOptions, StateMaxPacketSizes,
+};
 use crate::writer::{CraftSyncWriter, CraftWriter, WriteResult};
-use mcproto_rs::protocol::{Packet, RawPacket, State, Id};
+use mcproto_rs::protocol::{Packet, PacketDirection, RawPacket, State, Id};
+use mcproto_rs::DeserializeErr;
 #[cfg(feature = "gat")]
 use mcproto_rs::protocol::PacketKind;
+#[cfg(feature = "compression")]
+use {
+    crate::util::VAR_INT_BUF_SIZE,
+    crate::writer::{PacketSerializeFail, SliceSerializer},
+    mcproto_rs::{types::VarInt, Deserialize, Serialize},
+};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 #[cfg(any(feature = "futures-io", feature = "tokio-io"))]
 use {
-    crate::{reader::CraftAsyncReader, writer::CraftAsyncWriter},
+    crate::{
+        reader::CraftAsyncReader,
+        writer::{AsyncWriteAll, CraftAsyncWriter},
+    },
     async_trait::async_trait,
+    futures_core::Stream,
 };
+#[cfg(feature = "local-io")]
+use crate::{reader::CraftLocalReader, writer::CraftLocalWriter};
 
 pub struct CraftConnection<R, W> {
     pub(crate) reader: CraftReader<R>,
     pub(crate) writer: CraftWriter<W>,
+    pub(crate) peer_addr: Option<SocketAddr>,
+    pub(crate) local_addr: Option<SocketAddr>,
+    pub(crate) protocol_version: Option<i32>,
+    pub(crate) timings: StateTimings,
+    pub(crate) observers: Observers,
+    pub(crate) extensions: Extensions,
+    pub(crate) auto_state: Option<AutoState>,
+}
+
+///
+/// The state changes seen by the middleware `set_auto_state` installs, which the connection
+/// applies before its next read or write.
+///
+#[derive(Clone, Default)]
+pub(crate) struct AutoState {
+    shared: Arc<Mutex<AutoStateShared>>,
+}
+
+#[derive(Default)]
+struct AutoStateShared {
+    enabled: bool,
+    pending: Vec<Transition>,
+}
+
+impl AutoState {
+    fn with_shared<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut AutoStateShared) -> T,
+    {
+        let mut shared = self
+            .shared
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        f(&mut shared)
+    }
+
+    fn set_enabled(&self, enabled: bool) {
+        self.with_shared(move |shared| {
+            shared.enabled = enabled;
+            shared.pending.clear();
+        });
+    }
+
+    fn take_pending(&self) -> Vec<Transition> {
+        self.with_shared(|shared| std::mem::take(&mut shared.pending))
+    }
+
+    fn observe(&self, id: Id, body: &[u8]) {
+        // a malformed packet changes nothing, and is left for the code which handles it to reject
+        if let Ok(Some(transition)) = Transition::of(id, body) {
+            self.with_shared(move |shared| {
+                if shared.enabled {
+                    shared.pending.push(transition);
+                }
+            });
+        }
+    }
+}
+
+impl PacketMiddleware for AutoState {
+    fn on_read(&mut self, id: Id, body: &[u8]) -> PacketVerdict {
+        self.observe(id, body);
+        PacketVerdict::Pass
+    }
+
+    fn on_write(&mut self, id: Id, body: &[u8]) -> PacketVerdict {
+        self.observe(id, body);
+        PacketVerdict::Pass
+    }
+}
+
+const LOGIN_SUCCESS_ID: Id = Id {
+    id: 0x02,
+    state: State::Login,
+    direction: PacketDirection::ClientBound,
+};
+
+// a change to the state of a connection, caused by a packet read from it or written to it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Transition {
+    Handshake {
+        next_state: State,
+        protocol_version: i32,
+    },
+    #[cfg(feature = "compression")]
+    Compression(Option<i32>),
+    Play,
+}
+
+// why a packet which changes the state could not be understood
+#[derive(Debug)]
+pub(crate) enum TransitionError {
+    BadPacket(DeserializeErr),
+    BadNextState(i32),
+}
+
+impl Transition {
+    // Handshake, Set Compression and Login Success change the state, other packets do not
+    pub(crate) fn of(id: Id, body: &[u8]) -> Result<Option<Self>, TransitionError> {
+        if id == HANDSHAKE_ID {
+            let handshake = Handshake::parse(body).map_err(TransitionError::BadPacket)?;
+            let next_state = handshake
+                .next_state()
+                .ok_or(TransitionError::BadNextState(handshake.next_state))?;
+            return Ok(Some(Transition::Handshake {
+                next_state,
+                protocol_version: handshake.protocol_version,
+            }));
+        }
+
+        #[cfg(feature = "compression")]
+        if id == LOGIN_SET_COMPRESSION_ID {
+            let threshold = VarInt::mc_deserialize(body)
+                .map_err(TransitionError::BadPacket)?
+                .value
+                .0;
+            return Ok(Some(Transition::Compression(compression_threshold(threshold))));
+        }
+
+        if id == LOGIN_SUCCESS_ID {
+            return Ok(Some(Transition::Play));
+        }
+
+        Ok(None)
+    }
+
+    pub(crate) fn apply<R, W>(self, connection: &mut CraftConnection<R, W>) {
+        match self {
+            Transition::Handshake {
+                next_state,
+                protocol_version,
+            } => {
+                connection.set_state(next_state);
+                connection.set_protocol_version(protocol_version);
+            }
+            #[cfg(feature = "compression")]
+            Transition::Compression(threshold) => connection.set_compression_threshold(threshold),
+            Transition::Play => connection.set_state(State::Play),
+        }
+    }
 }
 
 impl<R, W> CraftWrapper<(R, W)> for CraftConnection<R, W> {
@@ -27,18 +194,32 @@ impl<R, W> CraftIo for CraftConnection<R, W> {
     fn set_state(&mut self, next: State) {
         self.reader.set_state(next);
         self.writer.set_state(next);
+        if let Some((left, duration)) = self.timings.enter(next, Instant::now()) {
+            self.observers.notify(move |observer| observer.on_state_duration(left, duration));
+        }
+        self.observers.notify(move |observer| observer.on_state_change(next));
+        #[cfg(feature = "tracing")]
+        tracing::debug!(parent: self.reader.span(), state = ?next, "state changed");
     }
 
     #[cfg(feature = "compression")]
     fn set_compression_threshold(&mut self, threshold: Option<i32>) {
         self.reader.set_compression_threshold(threshold);
         self.writer.set_compression_threshold(threshold);
+        if let Some(threshold) = threshold {
+            self.observers.notify(move |observer| observer.on_compression_enabled(threshold));
+            #[cfg(feature = "tracing")]
+            tracing::debug!(parent: self.reader.span(), threshold, "compression enabled");
+        }
     }
 
     #[cfg(feature = "encryption")]
     fn enable_encryption(&mut self, key: &[u8], iv: &[u8]) -> Result<(), CipherError> {
         self.reader.enable_encryption(key, iv)?;
         self.writer.enable_encryption(key, iv)?;
+        self.observers.notify(|observer| observer.on_encryption_enabled());
+        #[cfg(feature = "tracing")]
+        tracing::debug!(parent: self.reader.span(), "encryption enabled");
         Ok(())
     }
 
@@ -47,11 +228,30 @@ impl<R, W> CraftIo for CraftConnection<R, W> {
         self.writer.set_max_packet_size(max_size);
     }
 
+    fn set_state_max_packet_sizes(&mut self, sizes: Option<StateMaxPacketSizes>) {
+        self.reader.set_state_max_packet_sizes(sizes);
+        self.writer.set_state_max_packet_sizes(sizes);
+    }
+
     fn ensure_buf_capacity(&mut self, capacity: usize) {
         self.reader.ensure_buf_capacity(capacity);
         self.writer.ensure_buf_capacity(capacity);
     }
 
+    fn set_buffer_growth(&mut self, growth: BufferGrowth) {
+        self.reader.set_buffer_growth(growth);
+        self.writer.set_buffer_growth(growth);
+    }
+
+    fn set_buffer_allocator(&mut self, allocator: Option<Arc<dyn BufferAllocator>>) {
+        self.reader.set_buffer_allocator(allocator.clone());
+        self.writer.set_buffer_allocator(allocator);
+    }
+
+    fn memory_usage(&self) -> MemoryUsage {
+        self.reader.memory_usage() + self.writer.memory_usage()
+    }
+
     #[cfg(feature = "compression")]
     fn ensure_compression_buf_capacity(&mut self, capacity: usize) {
         self.reader.ensure_buf_capacity(capacity);
@@ -69,7 +269,10 @@ where
     where
         P: RawPacket<'a>,
     {
-        self.reader.read_packet::<P>()
+        self.follow_auto_state();
+        let result = self.reader.read_packet::<P>();
+        self.observers.observe_read(&result, self.peer_addr, &self.writer);
+        result
     }
 
     #[cfg(feature = "gat")]
@@ -77,7 +280,10 @@ where
     where
         P: PacketKind
     {
-        self.reader.read_packet::<P>()
+        self.follow_auto_state();
+        let result = self.reader.read_packet::<P>();
+        self.observers.observe_read(&result, self.peer_addr, &self.writer);
+        result
     }
 
     #[cfg(not(feature = "gat"))]
@@ -85,7 +291,10 @@ where
     where
         P: RawPacket<'a>,
     {
-        self.reader.read_raw_packet::<P>()
+        self.follow_auto_state();
+        let result = self.reader.read_raw_packet::<P>();
+        self.observers.observe_read(&result, self.peer_addr, &self.writer);
+        result
     }
 
     #[cfg(feature = "gat")]
@@ -93,11 +302,17 @@ where
     where
         P: PacketKind
     {
-        self.reader.read_raw_packet::<P>()
+        self.follow_auto_state();
+        let result = self.reader.read_raw_packet::<P>();
+        self.observers.observe_read(&result, self.peer_addr, &self.writer);
+        result
     }
 
     fn read_raw_untyped_packet(&mut self) -> ReadResult<(Id, &[u8])> {
-        self.reader.read_raw_untyped_packet()
+        self.follow_auto_state();
+        let result = self.reader.read_raw_untyped_packet();
+        self.observers.observe_read(&result, self.peer_addr, &self.writer);
+        result
     }
 }
 
@@ -110,14 +325,36 @@ where
     where
         P: Packet,
     {
-        self.writer.write_packet(packet)
+        self.follow_auto_state();
+        let result = self.writer.write_packet(packet);
+        self.observers.observe_write(&result, self.peer_addr, &self.writer);
+        result
     }
 
     fn write_raw_packet<'a, P>(&mut self, packet: P) -> WriteResult<()>
     where
         P: RawPacket<'a>,
     {
-        self.writer.write_raw_packet(packet)
+        self.follow_auto_state();
+        let result = self.writer.write_raw_packet(packet);
+        self.observers.observe_write(&result, self.peer_addr, &self.writer);
+        result
+    }
+}
+
+impl<R, W> CraftConnection<R, W>
+where
+    W: std::io::Write,
+{
+    ///
+    /// Writes a packet from its id and its serialized body, such as one read from another
+    /// connection with `read_raw_untyped_packet`. See `CraftWriter::write_raw_untyped_packet`.
+    ///
+    pub fn write_raw_untyped_packet(&mut self, id: Id, body: &[u8]) -> WriteResult<()> {
+        self.follow_auto_state();
+        let result = self.writer.write_raw_untyped_packet(id, body);
+        self.observers.observe_write(&result, self.peer_addr, &self.writer);
+        result
     }
 }
 
@@ -135,7 +372,10 @@ where
     where
         P: RawPacket<'a>,
     {
-        self.reader.read_packet_async::<P>().await
+        self.follow_auto_state();
+        let result = self.reader.read_packet_async::<P>().await;
+        self.observers.observe_read(&result, self.peer_addr, &self.writer);
+        result
     }
 
     #[cfg(feature = "gat")]
@@ -143,7 +383,10 @@ where
     where
         P: PacketKind
     {
-        self.reader.read_packet_async::<P>().await
+        self.follow_auto_state();
+        let result = self.reader.read_packet_async::<P>().await;
+        self.observers.observe_read(&result, self.peer_addr, &self.writer);
+        result
     }
 
     #[cfg(not(feature = "gat"))]
@@ -151,7 +394,10 @@ where
     where
         P: RawPacket<'a>,
     {
-        self.reader.read_raw_packet_async::<P>().await
+        self.follow_auto_state();
+        let result = self.reader.read_raw_packet_async::<P>().await;
+        self.observers.observe_read(&result, self.peer_addr, &self.writer);
+        result
     }
 
     #[cfg(feature = "gat")]
@@ -159,11 +405,17 @@ where
     where
         P: PacketKind
     {
-        self.reader.read_raw_packet_async::<P>().await
+        self.follow_auto_state();
+        let result = self.reader.read_raw_packet_async::<P>().await;
+        self.observers.observe_read(&result, self.peer_addr, &self.writer);
+        result
     }
 
     async fn read_raw_untyped_packet_async(&mut self) -> ReadResult<(Id, &[u8])> {
-        self.reader.read_raw_untyped_packet_async().await
+        self.follow_auto_state();
+        let result = self.reader.read_raw_untyped_packet_async().await;
+        self.observers.observe_read(&result, self.peer_addr, &self.writer);
+        result
     }
 }
 
@@ -180,18 +432,233 @@ where
     where
         P: Packet + Send + Sync,
     {
-        self.writer.write_packet_async(packet).await
+        self.follow_auto_state();
+        let result = self.writer.write_packet_async(packet).await;
+        self.observers.observe_write(&result, self.peer_addr, &self.writer);
+        result
     }
 
     async fn write_raw_packet_async<'a, P>(&mut self, packet: P) -> WriteResult<()>
     where
         P: RawPacket<'a> + Send + Sync,
     {
-        self.writer.write_raw_packet_async(packet).await
+        self.follow_auto_state();
+        let result = self.writer.write_raw_packet_async(packet).await;
+        self.observers.observe_write(&result, self.peer_addr, &self.writer);
+        result
+    }
+}
+
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+impl<R, W> CraftConnection<R, W>
+where
+    W: AsyncWriteAll,
+{
+    ///
+    /// Same as `write_raw_untyped_packet`, but writes to an async stream.
+    ///
+    pub async fn write_raw_untyped_packet_async(
+        &mut self,
+        id: Id,
+        body: &[u8],
+    ) -> WriteResult<()> {
+        self.follow_auto_state();
+        let result = self.writer.write_raw_untyped_packet_async(id, body).await;
+        self.observers.observe_write(&result, self.peer_addr, &self.writer);
+        result
+    }
+}
+
+#[cfg(feature = "local-io")]
+#[async_trait(?Send)]
+impl<R, W> CraftLocalReader for CraftConnection<R, W>
+where
+    CraftReader<R>: CraftLocalReader,
+    CraftWriter<W>: CraftLocalWriter,
+{
+    #[cfg(not(feature = "gat"))]
+    async fn read_packet_local<'a, P>(&'a mut self) -> ReadResult<<P as RawPacket<'a>>::Packet>
+    where
+        P: RawPacket<'a>,
+    {
+        self.follow_auto_state();
+        let result = self.reader.read_packet_local::<P>().await;
+        self.observers.observe_read(&result, self.peer_addr, &self.writer);
+        result
+    }
+
+    #[cfg(feature = "gat")]
+    async fn read_packet_local<P>(&mut self) -> ReadResult<<P::RawPacket<'_> as RawPacket<'_>>::Packet>
+    where
+        P: PacketKind
+    {
+        self.follow_auto_state();
+        let result = self.reader.read_packet_local::<P>().await;
+        self.observers.observe_read(&result, self.peer_addr, &self.writer);
+        result
+    }
+
+    #[cfg(not(feature = "gat"))]
+    async fn read_raw_packet_local<'a, P>(&'a mut self) -> ReadResult<P>
+    where
+        P: RawPacket<'a>,
+    {
+        self.follow_auto_state();
+        let result = self.reader.read_raw_packet_local::<P>().await;
+        self.observers.observe_read(&result, self.peer_addr, &self.writer);
+        result
+    }
+
+    #[cfg(feature = "gat")]
+    async fn read_raw_packet_local<P>(&mut self) -> ReadResult<P::RawPacket<'_>>
+    where
+        P: PacketKind
+    {
+        self.follow_auto_state();
+        let result = self.reader.read_raw_packet_local::<P>().await;
+        self.observers.observe_read(&result, self.peer_addr, &self.writer);
+        result
+    }
+
+    async fn read_raw_untyped_packet_local(&mut self) -> ReadResult<(Id, &[u8])> {
+        self.follow_auto_state();
+        let result = self.reader.read_raw_untyped_packet_local().await;
+        self.observers.observe_read(&result, self.peer_addr, &self.writer);
+        result
+    }
+}
+
+#[cfg(feature = "local-io")]
+#[async_trait(?Send)]
+impl<R, W> CraftLocalWriter for CraftConnection<R, W>
+where
+    CraftReader<R>: CraftLocalReader,
+    CraftWriter<W>: CraftLocalWriter,
+{
+    async fn write_packet_local<P>(&mut self, packet: P) -> WriteResult<()>
+    where
+        P: Packet,
+    {
+        self.follow_auto_state();
+        let result = self.writer.write_packet_local(packet).await;
+        self.observers.observe_write(&result, self.peer_addr, &self.writer);
+        result
+    }
+
+    async fn write_raw_packet_local<'a, P>(&mut self, packet: P) -> WriteResult<()>
+    where
+        P: RawPacket<'a>,
+    {
+        self.follow_auto_state();
+        let result = self.writer.write_raw_packet_local(packet).await;
+        self.observers.observe_write(&result, self.peer_addr, &self.writer);
+        result
+    }
+}
+
+impl<R, W> CraftConnection<R, W>
+where
+    W: std::io::Write,
+{
+    ///
+    /// Writes a batch of packets with one write call. See `CraftWriter::write_packets`.
+    ///
+    pub fn write_packets<I>(&mut self, packets: I) -> WriteResult<()>
+    where
+        I: IntoIterator,
+        I::Item: Packet,
+    {
+        self.follow_auto_state();
+        let result = self.writer.write_packets(packets);
+        self.observers.observe_write(&result, self.peer_addr, &self.writer);
+        result
+    }
+
+    ///
+    /// Writes the frames buffered by the writer and flushes the stream. See `CraftWriter::flush`.
+    ///
+    pub fn flush(&mut self) -> WriteResult<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+impl<R, W> CraftConnection<R, W>
+where
+    W: AsyncWriteAll,
+{
+    ///
+    /// Same as `write_packets`, but takes the packets from a `Stream`, and writes to an async
+    /// stream.
+    ///
+    pub async fn write_packets_async<S>(&mut self, packets: S) -> WriteResult<()>
+    where
+        S: Stream + Unpin,
+        S::Item: Packet,
+    {
+        self.follow_auto_state();
+        let result = self.writer.write_packets_async(packets).await;
+        self.observers.observe_write(&result, self.peer_addr, &self.writer);
+        result
+    }
+
+    ///
+    /// Same as `flush`, but writes to an async stream.
+    ///
+    pub async fn flush_async(&mut self) -> WriteResult<()> {
+        self.writer.flush_async().await
     }
 }
 
 impl<R, W> CraftConnection<R, W> {
+    ///
+    /// Combines a reader and a writer into a connection. This is the inverse of `into_split`, and
+    /// can be used to build a connection from halves which were constructed separately (such as
+    /// with `wrap_with_options`).
+    ///
+    /// The halves are made to share the reader's middleware chain. Any middleware which was added
+    /// to the writer's chain is moved to the end of the reader's chain.
+    ///
+    pub fn from_split(reader: CraftReader<R>, mut writer: CraftWriter<W>) -> Self {
+        reader.middleware().absorb(writer.middleware());
+        writer.set_middleware(reader.middleware().clone());
+        let timings = StateTimings::new(writer.state(), Instant::now());
+        #[allow(unused_mut)]
+        let mut observers = Observers::default();
+        #[cfg(feature = "tracing")]
+        {
+            observers.span = reader.span().clone();
+        }
+        Self {
+            reader,
+            writer,
+            peer_addr: None,
+            local_addr: None,
+            protocol_version: None,
+            timings,
+            observers,
+            extensions: Extensions::default(),
+            auto_state: None,
+        }
+    }
+
+    ///
+    /// Wraps the streams of a connection which is already part of an established session, applying
+    /// its state, compression and encryption from `options` to both halves before the connection
+    /// is returned. See `SessionOptions`.
+    ///
+    pub fn wrap_with_options(
+        read: R,
+        write: W,
+        read_direction: PacketDirection,
+        options: &SessionOptions,
+    ) -> Self {
+        Self::from_split(
+            CraftReader::wrap_with_options(read, read_direction, options),
+            CraftWriter::wrap_with_options(write, read_direction.opposite(), options),
+        )
+    }
+
     pub fn into_split(self) -> (CraftReader<R>, CraftWriter<W>) {
         (self.reader, self.writer)
     }
@@ -199,4 +666,695 @@ impl<R, W> CraftConnection<R, W> {
     pub fn split(&mut self) -> (&mut CraftReader<R>, &mut CraftWriter<W>) {
         (&mut self.reader, &mut self.writer)
     }
-}
\ No newline at end of file
+
+    ///
+    /// Registers an observer which is notified of lifecycle events on this connection. The
+    /// observer's `on_connect` is called immediately.
+    ///
+    pub fn add_observer<O>(&mut self, observer: O)
+    where
+        O: ConnectionObserver + 'static,
+    {
+        let mut observer = Box::new(observer);
+        observer.on_connect(self.peer_addr, self.local_addr);
+        self.observers.push(observer);
+    }
+
+    ///
+    /// Sets the span this connection's events are recorded in (such as one carrying the player's
+    /// name or the connection's address). The span is entered while middleware, frame hooks and
+    /// observers are called, so events they emit are recorded in it too.
+    ///
+    /// The connection does not spawn tasks of its own. Tasks which are spawned to serve it can be
+    /// instrumented with `span().clone()`, so that their events are recorded in the same span.
+    ///
+    #[cfg(feature = "tracing")]
+    pub fn set_span(&mut self, span: tracing::Span) {
+        self.reader.set_span(span.clone());
+        self.writer.set_span(span.clone());
+        self.observers.span = span;
+    }
+
+    #[cfg(feature = "tracing")]
+    pub fn span(&self) -> &tracing::Span {
+        self.reader.span()
+    }
+
+    ///
+    /// Data attached to this connection by other code, such as middleware, routers, and auth
+    /// helpers, looked up by its type. See `Extensions`.
+    ///
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    ///
+    /// The middleware chain shared by both halves of this connection, which every packet read or
+    /// written passes through.
+    ///
+    pub fn middleware(&self) -> &MiddlewareChain {
+        self.reader.middleware()
+    }
+
+    ///
+    /// Replaces the middleware chain of both halves of this connection.
+    ///
+    pub fn set_middleware(&mut self, chain: MiddlewareChain) {
+        self.reader.set_middleware(chain.clone());
+        self.writer.set_middleware(chain);
+    }
+
+    ///
+    /// The address of the remote end of this connection, if it is known.
+    ///
+    /// The tcp helpers in this crate record the socket's peer address when the connection is
+    /// created. If the connection arrived through a proxy which sends a PROXY protocol header,
+    /// call `set_peer_addr` with the address from that header, and it will be returned here.
+    ///
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    ///
+    /// The address of the local end of this connection, if it is known.
+    ///
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    ///
+    /// Overrides the recorded peer address, such as when the real address of the client is learned
+    /// from a PROXY protocol header.
+    ///
+    pub fn set_peer_addr(&mut self, addr: SocketAddr) {
+        self.peer_addr = Some(addr);
+    }
+
+    ///
+    /// Overrides the recorded local address.
+    ///
+    pub fn set_local_addr(&mut self, addr: SocketAddr) {
+        self.local_addr = Some(addr);
+    }
+
+    ///
+    /// The protocol version of the peer, if it is known. `VirtualHostRouter` records the version
+    /// from the client's handshake, and other code can record it using `set_protocol_version`.
+    ///
+    pub fn protocol_version(&self) -> Option<i32> {
+        self.protocol_version
+    }
+
+    pub fn set_protocol_version(&mut self, protocol_version: i32) {
+        self.protocol_version = Some(protocol_version);
+    }
+
+    ///
+    /// Follows the packets which change the state of a connection as they are read and written,
+    /// instead of leaving it to `set_state` and `set_compression_threshold` calls, which are easy
+    /// to get wrong. When enabled:
+    ///
+    /// * a Handshake switches both halves to the state it asks for (`Status` or `Login`), and
+    ///   records its protocol version
+    /// * Set Compression applies its threshold to both halves
+    /// * Login Success switches both halves to `Play`
+    ///
+    /// The packets are seen by a middleware, which is added to the end of the middleware chain
+    /// the first time this is enabled, and the change is applied before the next packet is read or
+    /// written through this connection. So a packet which changes the state must be the last
+    /// packet of a batch written with `write_packets`, and packets read or written through the
+    /// halves (see `split`) do not apply pending changes. `negotiate_compression`, `HostRouter`
+    /// and `CraftProxy` apply the changes they cause themselves, so these are not applied twice.
+    ///
+    pub fn set_auto_state(&mut self, enabled: bool) {
+        match self.auto_state.as_ref() {
+            Some(auto_state) => auto_state.set_enabled(enabled),
+            None if enabled => {
+                let auto_state = AutoState::default();
+                auto_state.set_enabled(true);
+                self.middleware().push(auto_state.clone());
+                self.auto_state = Some(auto_state);
+            }
+            None => {}
+        }
+    }
+
+    pub fn is_auto_state(&self) -> bool {
+        self.auto_state
+            .as_ref()
+            .map_or(false, |auto_state| auto_state.with_shared(|shared| shared.enabled))
+    }
+
+    fn follow_auto_state(&mut self) {
+        let pending = match self.auto_state.as_ref() {
+            Some(auto_state) => auto_state.take_pending(),
+            None => return,
+        };
+
+        for transition in pending {
+            transition.apply(self);
+        }
+    }
+
+    // for code which reads or writes a packet which changes the state through the halves, and
+    // applies the change itself
+    pub(crate) fn discard_auto_state(&mut self) {
+        if let Some(auto_state) = self.auto_state.as_ref() {
+            auto_state.take_pending();
+        }
+    }
+
+    ///
+    /// A snapshot of the protocol settings of both halves of this connection. See
+    /// `ConnectionSettings`.
+    ///
+    pub fn settings(&self) -> ConnectionSettings {
+        ConnectionSettings {
+            reader: self.reader.settings(),
+            writer: self.writer.settings(),
+            protocol_version: self.protocol_version,
+        }
+    }
+
+    ///
+    /// When this connection entered each state, and the durations of its handshake and login.
+    ///
+    pub fn state_timings(&self) -> &StateTimings {
+        &self.timings
+    }
+
+    ///
+    /// When a packet was last read from this connection. See `CraftReader::last_read_at`.
+    ///
+    pub fn last_read_at(&self) -> Option<Instant> {
+        self.reader.last_read_at()
+    }
+
+    ///
+    /// Enables (or disables) timestamping packets as they arrive. See
+    /// `CraftReader::set_packet_timestamps`.
+    ///
+    pub fn set_packet_timestamps(&mut self, enabled: bool) {
+        self.reader.set_packet_timestamps(enabled);
+    }
+
+    ///
+    /// When the packet which was read last arrived. See `CraftReader::packet_received_at`.
+    ///
+    pub fn packet_received_at(&self) -> Option<Instant> {
+        self.reader.packet_received_at()
+    }
+
+    ///
+    /// When data was last written to this connection. See `CraftWriter::last_write_at`.
+    ///
+    pub fn last_write_at(&self) -> Option<Instant> {
+        self.writer.last_write_at()
+    }
+
+    ///
+    /// Records the sizes of the frames read and written on this connection into `stats`, or stops
+    /// recording them if `None`. See `FrameSizeStats`.
+    ///
+    pub fn set_frame_size_stats(&mut self, stats: Option<FrameSizeStats>) {
+        self.reader.set_frame_size_stats(stats.clone());
+        self.writer.set_frame_size_stats(stats);
+    }
+
+    ///
+    /// The totals of the packets this connection compressed before writing them. See
+    /// `CraftWriter::compression_stats`.
+    ///
+    #[cfg(feature = "compression")]
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.writer.compression_stats()
+    }
+
+    ///
+    /// Fails reads and writes on this connection with a `TooSlow` error if the peer sends or
+    /// accepts the bytes of a frame more slowly than `limit` allows. See `MinThroughput`.
+    ///
+    pub fn set_min_throughput(&mut self, limit: Option<MinThroughput>) {
+        self.reader.set_min_throughput(limit);
+        self.writer.set_min_throughput(limit);
+    }
+
+    ///
+    /// Buffers written frames until at least `threshold` bytes are waiting. See
+    /// `CraftWriter::set_flush_threshold`.
+    ///
+    pub fn set_flush_threshold(&mut self, threshold: Option<usize>) {
+        self.writer.set_flush_threshold(threshold);
+    }
+
+    ///
+    /// Replaces both wrapped streams, keeping all other state of this connection (see
+    /// `CraftReader::map_inner` and `CraftWriter::map_inner`), including its observers, extensions
+    /// and recorded addresses. `f` receives both streams at once, so halves of a split socket can
+    /// be reunited.
+    ///
+    /// This is the supported way to move an established connection to a different I/O
+    /// implementation, such as from a blocking thread into a tokio runtime, or between runtimes. The
+    /// tcp connection types have helpers built on this (`CraftTcpConnection::into_tokio` and
+    /// `CraftTokioConnection::into_std`). If the old reader was buffered, pass any bytes left in its
+    /// buffer to `CraftReader::push_received` (using `split`) before reading from the connection.
+    ///
+    /// If `f` fails, the connection is dropped.
+    ///
+    pub fn try_map_streams<R2, W2, E, F>(self, f: F) -> Result<CraftConnection<R2, W2>, E>
+    where
+        F: FnOnce(R, W) -> Result<(R2, W2), E>,
+    {
+        let Self {
+            reader,
+            writer,
+            peer_addr,
+            local_addr,
+            protocol_version,
+            timings,
+            observers,
+            extensions,
+            auto_state,
+        } = self;
+
+        let mut read = None;
+        let reader = reader.map_inner(|inner| read = Some(inner));
+        let mut write = None;
+        let writer = writer.map_inner(|inner| write = Some(inner));
+        let (read, write) = f(
+            read.expect("reader was just unwrapped"),
+            write.expect("writer was just unwrapped"),
+        )?;
+
+        Ok(CraftConnection {
+            reader: reader.map_inner(move |_| read),
+            writer: writer.map_inner(move |_| write),
+            peer_addr,
+            local_addr,
+            protocol_version,
+            timings,
+            observers,
+            extensions,
+            auto_state,
+        })
+    }
+
+    ///
+    /// Same as `try_map_streams`, for a mapping which can not fail.
+    ///
+    pub fn map_streams<R2, W2, F>(self, f: F) -> CraftConnection<R2, W2>
+    where
+        F: FnOnce(R, W) -> (R2, W2),
+    {
+        match self.try_map_streams(move |read, write| {
+            Ok::<_, std::convert::Infallible>(f(read, write))
+        }) {
+            Ok(mapped) => mapped,
+            Err(never) => match never {},
+        }
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl<R, W> CraftConnection<R, W> {
+    fn prepare_ciphers(
+        &self,
+        shared_secret: &[u8],
+    ) -> Result<(CraftCipher, CraftCipher), CipherError> {
+        if self.reader.has_cipher() || self.writer.has_cipher() {
+            return Err(CipherError::already_enabled());
+        }
+
+        // the shared secret is used as both the key and the iv
+        let reader_cipher = CraftCipher::new(shared_secret, shared_secret, false)?;
+        let writer_cipher = CraftCipher::new(shared_secret, shared_secret, true)?;
+        Ok((reader_cipher, writer_cipher))
+    }
+
+    fn enable_prepared_ciphers(
+        &mut self,
+        reader_cipher: CraftCipher,
+        writer_cipher: CraftCipher,
+    ) {
+        self.reader.set_cipher(Some(reader_cipher));
+        self.writer.set_cipher(Some(writer_cipher));
+        self.observers.notify(|observer| observer.on_encryption_enabled());
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl<R, W> CraftConnection<R, W>
+where
+    W: std::io::Write,
+{
+    ///
+    /// Writes the `EncryptionResponse` packet to the server, and then enables encryption on both
+    /// halves of this connection using `shared_secret` as the key and iv. The response itself is
+    /// written unencrypted, and every packet read or written after it is encrypted, which is the
+    /// ordering the protocol requires.
+    ///
+    /// The `response` is the version-specific packet which already contains the shared secret and
+    /// verify token, encrypted with the server's public key. The cipher is set up before anything
+    /// is written, so an invalid `shared_secret` fails without writing the response.
+    ///
+    pub fn send_encryption_response<P>(
+        &mut self,
+        response: P,
+        shared_secret: &[u8],
+    ) -> WriteResult<()>
+    where
+        P: Packet,
+    {
+        let (reader_cipher, writer_cipher) = self.prepare_ciphers(shared_secret)?;
+        self.writer.write_packet(response)?;
+        self.enable_prepared_ciphers(reader_cipher, writer_cipher);
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "encryption", any(feature = "futures-io", feature = "tokio-io")))]
+impl<R, W> CraftConnection<R, W>
+where
+    W: AsyncWriteAll,
+{
+    ///
+    /// Same as `send_encryption_response`, but writes the packet to an async stream.
+    ///
+    pub async fn send_encryption_response_async<P>(
+        &mut self,
+        response: P,
+        shared_secret: &[u8],
+    ) -> WriteResult<()>
+    where
+        P: Packet + Send + Sync,
+    {
+        let (reader_cipher, writer_cipher) = self.prepare_ciphers(shared_secret)?;
+        self.writer.write_packet_async(response).await?;
+        self.enable_prepared_ciphers(reader_cipher, writer_cipher);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "compression")]
+const LOGIN_SET_COMPRESSION_ID: Id = Id {
+    id: 0x03,
+    state: State::Login,
+    direction: PacketDirection::ClientBound,
+};
+
+#[cfg(feature = "compression")]
+impl<R, W> CraftConnection<R, W>
+where
+    W: std::io::Write,
+{
+    ///
+    /// Sends the `SetCompression` packet to the client, and then applies `threshold` to both halves
+    /// of this connection. The packet itself is written uncompressed, and every packet read or
+    /// written after it uses compression, which is the ordering the protocol requires.
+    ///
+    /// This must be called on a client-bound connection in the `Login` state. A negative
+    /// `threshold` tells the client that compression is disabled.
+    ///
+    pub fn negotiate_compression(&mut self, threshold: i32) -> WriteResult<()> {
+        let mut body = [0u8; VAR_INT_BUF_SIZE];
+        let body = serialize_set_compression(threshold, &mut body)?;
+        self.follow_auto_state();
+        self.writer.write_raw_untyped_packet(LOGIN_SET_COMPRESSION_ID, body)?;
+        self.discard_auto_state();
+        self.set_compression_threshold(compression_threshold(threshold));
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "compression", any(feature = "futures-io", feature = "tokio-io")))]
+impl<R, W> CraftConnection<R, W>
+where
+    W: AsyncWriteAll,
+{
+    ///
+    /// Same as `negotiate_compression`, but writes the packet to an async stream.
+    ///
+    pub async fn negotiate_compression_async(&mut self, threshold: i32) -> WriteResult<()> {
+        let mut body = [0u8; VAR_INT_BUF_SIZE];
+        let body = serialize_set_compression(threshold, &mut body)?;
+        self.follow_auto_state();
+        self.writer
+            .write_raw_untyped_packet_async(LOGIN_SET_COMPRESSION_ID, body)
+            .await?;
+        self.discard_auto_state();
+        self.set_compression_threshold(compression_threshold(threshold));
+        Ok(())
+    }
+}
+
+#[cfg(feature = "compression")]
+fn serialize_set_compression(threshold: i32, target: &mut [u8]) -> WriteResult<&[u8]> {
+    let mut serializer = SliceSerializer::create(target);
+    VarInt(threshold)
+        .mc_serialize(&mut serializer)
+        .map_err(move |err| PacketSerializeFail::Body(err))?;
+    Ok(serializer.finish())
+}
+
+#[cfg(feature = "compression")]
+fn compression_threshold(threshold: i32) -> Option<i32> {
+    if threshold >= 0 {
+        Some(threshold)
+    } else {
+        None
+    }
+}
+
+#[cfg(all(test, feature = "compression", feature = "encryption"))]
+mod tests {
+    use super::*;
+    use crate::tcp::CraftTcpConnection;
+    use crate::test_util::tcp_pair;
+    use mcproto_rs::protocol::PacketDirection;
+    use std::io::Cursor;
+
+    const KEY: [u8; 16] = [3; 16];
+
+    fn secured(mut connection: CraftTcpConnection) -> CraftTcpConnection {
+        connection.set_state(State::Play);
+        connection.set_compression_threshold(Some(64));
+        connection.enable_encryption(&KEY, &KEY).unwrap();
+        connection
+    }
+
+    #[test]
+    fn map_streams_keeps_compression_and_encryption() {
+        let (client, server) = tcp_pair();
+        let mut client =
+            secured(CraftTcpConnection::from_std(client, PacketDirection::ClientBound).unwrap());
+        let mut server =
+            secured(CraftTcpConnection::from_std(server, PacketDirection::ServerBound).unwrap());
+        let serverbound = Id {
+            id: 0x10,
+            state: State::Play,
+            direction: PacketDirection::ServerBound,
+        };
+        let clientbound = Id {
+            direction: PacketDirection::ClientBound,
+            ..serverbound
+        };
+        let large = vec![7u8; 1000];
+
+        for body in [&b"first"[..], &large, &b"third"[..]].iter() {
+            client.write_raw_untyped_packet(serverbound, body).unwrap();
+        }
+        client.flush().unwrap();
+        let (_, body) = server.read_raw_untyped_packet().unwrap().unwrap();
+        assert_eq!(body, b"first");
+
+        // the buffered reader is replaced by the bare socket, in the middle of the stream
+        let mut received = Vec::new();
+        let mut server = server.map_streams(|read, write| {
+            received.extend_from_slice(read.buffer());
+            (read.into_inner(), write)
+        });
+        server.split().0.push_received(&received);
+
+        let (id, body) = server.read_raw_untyped_packet().unwrap().unwrap();
+        assert_eq!((id, body), (serverbound, &large[..]));
+        let (id, body) = server.read_raw_untyped_packet().unwrap().unwrap();
+        assert_eq!((id, body), (serverbound, &b"third"[..]));
+
+        server
+            .write_raw_untyped_packet(clientbound, &large)
+            .unwrap();
+        server.flush().unwrap();
+        let (id, body) = client.read_raw_untyped_packet().unwrap().unwrap();
+        assert_eq!((id, body), (clientbound, &large[..]));
+    }
+
+    #[test]
+    fn wrap_with_options_applies_the_session() {
+        let (client, server) = tcp_pair();
+        let options = SessionOptions {
+            compression_threshold: Some(64),
+            encryption: Some((KEY, KEY)),
+            ..SessionOptions::new(State::Play)
+        };
+        let mut client = CraftConnection::wrap_with_options(
+            client.try_clone().unwrap(),
+            client,
+            PacketDirection::ClientBound,
+            &options,
+        );
+        // the other end is configured the old way, so both must agree on the wire format
+        let mut server =
+            secured(CraftTcpConnection::from_std(server, PacketDirection::ServerBound).unwrap());
+        let serverbound = Id {
+            id: 0x10,
+            state: State::Play,
+            direction: PacketDirection::ServerBound,
+        };
+        let large = vec![7u8; 1000];
+
+        client
+            .write_raw_untyped_packet(serverbound, &large)
+            .unwrap();
+        client.flush().unwrap();
+        let (id, body) = server.read_raw_untyped_packet().unwrap().unwrap();
+        assert_eq!((id, body), (serverbound, &large[..]));
+        assert_eq!(client.settings().writer.compression_threshold, Some(64));
+        assert!(client.settings().reader.encrypted);
+    }
+
+    type TestConnection = CraftConnection<Cursor<Vec<u8>>, Vec<u8>>;
+
+    // a connection with auto state enabled, which reads `received` in the direction given
+    fn auto_connection(read: PacketDirection, state: State, received: Vec<u8>) -> TestConnection {
+        let mut connection = CraftConnection::from_split(
+            CraftReader::wrap_with_state(Cursor::new(received), read, state),
+            CraftWriter::wrap_with_state(Vec::new(), read.opposite(), state),
+        );
+        connection.set_auto_state(true);
+        connection
+    }
+
+    fn var_int(value: i32) -> Vec<u8> {
+        let mut value = value as u32;
+        let mut out = Vec::new();
+        loop {
+            if value < 0x80 {
+                out.push(value as u8);
+                return out;
+            }
+            out.push((value & 0x7F) as u8 | 0x80);
+            value >>= 7;
+        }
+    }
+
+    fn handshake(next_state: i32) -> Vec<u8> {
+        let mut body = var_int(764);
+        body.extend(var_int(9));
+        body.extend_from_slice(b"localhost");
+        body.extend_from_slice(&25565u16.to_be_bytes());
+        body.extend(var_int(next_state));
+        body
+    }
+
+    fn states(connection: &TestConnection) -> (State, State) {
+        let settings = connection.settings();
+        (settings.reader.state, settings.writer.state)
+    }
+
+    #[test]
+    fn handshake_switches_to_status_or_login() {
+        for (next_state, state) in [(1, State::Status), (2, State::Login)].iter() {
+            let mut connection =
+                auto_connection(PacketDirection::ClientBound, State::Handshaking, Vec::new());
+            connection
+                .write_raw_untyped_packet(HANDSHAKE_ID, &handshake(*next_state))
+                .unwrap();
+            // the change is applied before the next read or write
+            assert_eq!(
+                states(&connection),
+                (State::Handshaking, State::Handshaking)
+            );
+
+            connection.follow_auto_state();
+            assert_eq!(states(&connection), (*state, *state));
+            assert_eq!(connection.protocol_version(), Some(764));
+        }
+    }
+
+    #[test]
+    fn set_compression_applies_to_both_halves() {
+        let mut connection =
+            auto_connection(PacketDirection::ServerBound, State::Login, Vec::new());
+        connection
+            .write_raw_untyped_packet(LOGIN_SET_COMPRESSION_ID, &var_int(256))
+            .unwrap();
+        connection.follow_auto_state();
+
+        let settings = connection.settings();
+        assert_eq!(settings.reader.compression_threshold, Some(256));
+        assert_eq!(settings.writer.compression_threshold, Some(256));
+    }
+
+    #[test]
+    fn login_success_switches_to_play() {
+        let mut server =
+            CraftWriter::wrap_with_state(Vec::new(), PacketDirection::ClientBound, State::Login);
+        server
+            .write_raw_untyped_packet(LOGIN_SUCCESS_ID, b"profile")
+            .unwrap();
+        let mut connection = auto_connection(
+            PacketDirection::ClientBound,
+            State::Login,
+            server.into_inner(),
+        );
+
+        let (id, _) = connection.read_raw_untyped_packet().unwrap().unwrap();
+        assert_eq!(id, LOGIN_SUCCESS_ID);
+        connection.follow_auto_state();
+        assert_eq!(states(&connection), (State::Play, State::Play));
+    }
+
+    #[test]
+    fn transitions_are_ignored_while_disabled() {
+        let mut connection =
+            auto_connection(PacketDirection::ClientBound, State::Handshaking, Vec::new());
+        connection.set_auto_state(false);
+        assert!(!connection.is_auto_state());
+
+        connection
+            .write_raw_untyped_packet(HANDSHAKE_ID, &handshake(2))
+            .unwrap();
+        connection.follow_auto_state();
+        assert_eq!(
+            states(&connection),
+            (State::Handshaking, State::Handshaking)
+        );
+        assert_eq!(connection.protocol_version(), None);
+    }
+
+    #[test]
+    fn discarded_transitions_are_not_applied() {
+        let mut connection =
+            auto_connection(PacketDirection::ClientBound, State::Handshaking, Vec::new());
+        connection
+            .write_raw_untyped_packet(HANDSHAKE_ID, &handshake(2))
+            .unwrap();
+        connection.discard_auto_state();
+        connection.follow_auto_state();
+        assert_eq!(
+            states(&connection),
+            (State::Handshaking, State::Handshaking)
+        );
+
+        // later transitions are still followed
+        connection
+            .write_raw_untyped_packet(HANDSHAKE_ID, &handshake(1))
+            .unwrap();
+        connection.follow_auto_state();
+        assert_eq!(states(&connection), (State::Status, State::Status));
+    }
+}
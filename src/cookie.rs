@@ -0,0 +1,309 @@
+use crate::connection::CraftConnection;
+use crate::util::VAR_INT_BUF_SIZE;
+use crate::writer::{serialize_string, PacketSerializeFail, SliceSerializer, WriteError};
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+use crate::writer::AsyncWriteAll;
+use mcproto_rs::protocol::{Id, PacketDirection, State};
+use mcproto_rs::types::VarInt;
+use mcproto_rs::{Deserialize, DeserializeErr, Deserialized, Serialize};
+#[cfg(feature = "backtrace")]
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
+use thiserror::Error;
+
+///
+/// The first protocol version (1.20.5) with cookies.
+///
+pub const COOKIE_VERSION: i32 = 766;
+
+///
+/// The largest cookie payload the vanilla client and server accept.
+///
+pub const MAX_COOKIE_PAYLOAD_SIZE: usize = 5120;
+
+const LOGIN_COOKIE_REQUEST_ID: Id = Id {
+    id: 0x05,
+    state: State::Login,
+    direction: PacketDirection::ClientBound,
+};
+
+const LOGIN_COOKIE_RESPONSE_ID: Id = Id {
+    id: 0x04,
+    state: State::Login,
+    direction: PacketDirection::ServerBound,
+};
+
+#[derive(Debug, Error)]
+pub enum CookieError {
+    #[error("cookies require protocol version 766 or later, but the connection uses {protocol_version}")]
+    UnsupportedVersion {
+        protocol_version: i32,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("cookie payload of {size} bytes is larger than the max of 5120")]
+    PayloadTooLarge {
+        size: usize,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("malformed cookie packet")]
+    Malformed {
+        #[from]
+        err: DeserializeErr,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("failed to write cookie packet")]
+    WriteFailed {
+        #[from]
+        err: WriteError,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+}
+
+pub type CookieResult<T> = Result<T, CookieError>;
+
+///
+/// Where a client keeps the cookies servers store on it, keyed by their identifier (such as
+/// `example:session`). Servers ask for a cookie during login (and after a transfer), and the
+/// client answers from its store with `CraftConnection::answer_cookie_request`.
+///
+/// A store which should outlive a connection (so that cookies survive a transfer to another
+/// server) can be shared between connections, since it is passed to every call.
+///
+pub trait CookieStore: Send {
+    fn get(&self, key: &str) -> Option<Vec<u8>>;
+
+    fn store(&mut self, key: &str, payload: Vec<u8>);
+}
+
+///
+/// A `CookieStore` which keeps cookies in memory.
+///
+#[derive(Debug, Clone, Default)]
+pub struct MemoryCookieStore {
+    cookies: HashMap<String, Vec<u8>>,
+}
+
+impl MemoryCookieStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Vec<u8>> {
+        self.cookies.remove(key)
+    }
+
+    pub fn clear(&mut self) {
+        self.cookies.clear();
+    }
+}
+
+impl CookieStore for MemoryCookieStore {
+    fn get(&self, key: &str) -> Option<Vec<u8>> {
+        self.cookies.get(key).cloned()
+    }
+
+    fn store(&mut self, key: &str, payload: Vec<u8>) {
+        self.cookies.insert(key.to_owned(), payload);
+    }
+}
+
+impl<S> CookieStore for Box<S>
+where
+    S: CookieStore + ?Sized,
+{
+    fn get(&self, key: &str) -> Option<Vec<u8>> {
+        (**self).get(key)
+    }
+
+    fn store(&mut self, key: &str, payload: Vec<u8>) {
+        (**self).store(key, payload)
+    }
+}
+
+fn serialize_var_int(value: i32) -> Result<Vec<u8>, WriteError> {
+    let mut buf = [0u8; VAR_INT_BUF_SIZE];
+    let mut serializer = SliceSerializer::create(&mut buf);
+    VarInt(value)
+        .mc_serialize(&mut serializer)
+        .map_err(move |err| PacketSerializeFail::Body(err))?;
+    Ok(serializer.finish().to_vec())
+}
+
+fn cookie_response(key: &str, payload: Option<&[u8]>) -> CookieResult<Vec<u8>> {
+    let mut body = serialize_string(key)?;
+    match payload {
+        Some(payload) => {
+            if payload.len() > MAX_COOKIE_PAYLOAD_SIZE {
+                return Err(CookieError::PayloadTooLarge {
+                    size: payload.len(),
+                    #[cfg(feature = "backtrace")]
+                    backtrace: Backtrace::capture(),
+                });
+            }
+
+            body.push(1);
+            body.extend_from_slice(&serialize_var_int(payload.len() as i32)?);
+            body.extend_from_slice(payload);
+        }
+        None => body.push(0),
+    }
+    Ok(body)
+}
+
+fn parse_cookie_response(body: &[u8]) -> CookieResult<(String, Option<Vec<u8>>)> {
+    let Deserialized { value: key, data: rest } = String::mc_deserialize(body)?;
+    let Deserialized { value: has_payload, data: rest } = bool::mc_deserialize(rest)?;
+    if !has_payload {
+        return Ok((key, None));
+    }
+
+    let Deserialized { value: len, data: rest } = VarInt::mc_deserialize(rest)?;
+    let len = len.0.max(0) as usize;
+    if len > MAX_COOKIE_PAYLOAD_SIZE {
+        return Err(CookieError::PayloadTooLarge {
+            size: len,
+            #[cfg(feature = "backtrace")]
+            backtrace: Backtrace::capture(),
+        });
+    }
+    if rest.len() < len {
+        return Err(DeserializeErr::Eof.into());
+    }
+
+    Ok((key, Some(rest[..len].to_vec())))
+}
+
+impl<R, W> CraftConnection<R, W> {
+    fn check_cookie_version(&self) -> CookieResult<()> {
+        match self.protocol_version {
+            Some(protocol_version) if protocol_version < COOKIE_VERSION => {
+                Err(CookieError::UnsupportedVersion {
+                    protocol_version,
+                    #[cfg(feature = "backtrace")]
+                    backtrace: Backtrace::capture(),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // the key of a cookie request, if the packet is one
+    fn cookie_request_key(&self, id: Id, body: &[u8]) -> CookieResult<Option<String>> {
+        if id != LOGIN_COOKIE_REQUEST_ID {
+            return Ok(None);
+        }
+
+        self.check_cookie_version()?;
+        let Deserialized { value: key, .. } = String::mc_deserialize(body)?;
+        Ok(Some(key))
+    }
+
+    ///
+    /// If the packet is the client's answer to a cookie request (see `request_cookie`), returns
+    /// the key of the cookie and its payload, or `None` as the payload if the client has no cookie
+    /// with that key. Other packets are ignored, and `Ok(None)` is returned.
+    ///
+    /// The payload was stored on the client by a server, possibly a different one before a
+    /// transfer, so it must be verified (such as with a signature) before it is trusted.
+    ///
+    pub fn accept_cookie_response(
+        &mut self,
+        id: Id,
+        body: &[u8],
+    ) -> CookieResult<Option<(String, Option<Vec<u8>>)>> {
+        if id != LOGIN_COOKIE_RESPONSE_ID {
+            return Ok(None);
+        }
+
+        self.check_cookie_version()?;
+        parse_cookie_response(body).map(Some)
+    }
+}
+
+impl<R, W> CraftConnection<R, W>
+where
+    W: std::io::Write,
+{
+    ///
+    /// Asks the client for the cookie with `key`, which it answers with a cookie response (see
+    /// `accept_cookie_response`). This requires protocol version 766 (1.20.5) or later, and must be
+    /// sent in the `Login` state, before Login Success.
+    ///
+    pub fn request_cookie(&mut self, key: &str) -> CookieResult<()> {
+        self.check_cookie_version()?;
+        let body = serialize_string(key)?;
+        Ok(self.writer.write_raw_untyped_packet(LOGIN_COOKIE_REQUEST_ID, &body)?)
+    }
+
+    ///
+    /// If the packet is a server's cookie request, answers it with the cookie from `store` (or with
+    /// no payload if the store has no cookie with the requested key), and returns `true`. Other
+    /// packets are ignored, and `false` is returned.
+    ///
+    pub fn answer_cookie_request<S>(
+        &mut self,
+        store: &S,
+        id: Id,
+        body: &[u8],
+    ) -> CookieResult<bool>
+    where
+        S: CookieStore + ?Sized,
+    {
+        let key = match self.cookie_request_key(id, body)? {
+            Some(key) => key,
+            None => return Ok(false),
+        };
+
+        let payload = store.get(&key);
+        let body = cookie_response(&key, payload.as_deref())?;
+        self.writer.write_raw_untyped_packet(LOGIN_COOKIE_RESPONSE_ID, &body)?;
+        Ok(true)
+    }
+}
+
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+impl<R, W> CraftConnection<R, W>
+where
+    W: AsyncWriteAll,
+{
+    ///
+    /// Same as `request_cookie`, but writes to an async stream.
+    ///
+    pub async fn request_cookie_async(&mut self, key: &str) -> CookieResult<()> {
+        self.check_cookie_version()?;
+        let body = serialize_string(key)?;
+        Ok(self
+            .writer
+            .write_raw_untyped_packet_async(LOGIN_COOKIE_REQUEST_ID, &body)
+            .await?)
+    }
+
+    ///
+    /// Same as `answer_cookie_request`, but writes to an async stream.
+    ///
+    pub async fn answer_cookie_request_async<S>(
+        &mut self,
+        store: &S,
+        id: Id,
+        body: &[u8],
+    ) -> CookieResult<bool>
+    where
+        S: CookieStore + ?Sized + Sync,
+    {
+        let key = match self.cookie_request_key(id, body)? {
+            Some(key) => key,
+            None => return Ok(false),
+        };
+
+        let payload = store.get(&key);
+        let body = cookie_response(&key, payload.as_deref())?;
+        self.writer
+            .write_raw_untyped_packet_async(LOGIN_COOKIE_RESPONSE_ID, &body)
+            .await?;
+        Ok(true)
+    }
+}
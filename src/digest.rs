@@ -0,0 +1,136 @@
+use crate::middleware::{PacketMiddleware, PacketVerdict};
+use crate::mirror::{FrameDirection, MirroredFrame, OwnedMirroredFrame};
+use mcproto_rs::protocol::Id;
+use std::sync::{Arc, Mutex, MutexGuard};
+use xxhash_rust::xxh3::Xxh3;
+
+///
+/// The 64 bit XXH3 hash of a packet's id and body, which is the same for equal packets no matter
+/// which connection, compression threshold or cipher they were sent with. The id is hashed as a
+/// little endian 32 bit number, followed by the body.
+///
+pub fn packet_digest(id: Id, body: &[u8]) -> u64 {
+    let mut hasher = Xxh3::new();
+    hasher.update(&id.id.to_le_bytes());
+    hasher.update(body);
+    hasher.digest()
+}
+
+///
+/// The digest of a packet which passed a `DigestRecorder`.
+///
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PacketDigest {
+    pub direction: FrameDirection,
+    pub id: Id,
+    ///
+    /// The length of the packet's body.
+    ///
+    pub len: usize,
+    pub digest: u64,
+}
+
+impl<'a> MirroredFrame<'a> {
+    pub fn digest(&self) -> PacketDigest {
+        PacketDigest {
+            direction: self.direction,
+            id: self.id,
+            len: self.body.len(),
+            digest: packet_digest(self.id, self.body),
+        }
+    }
+}
+
+impl OwnedMirroredFrame {
+    pub fn digest(&self) -> PacketDigest {
+        PacketDigest {
+            direction: self.direction,
+            id: self.id,
+            len: self.body.len(),
+            digest: packet_digest(self.id, &self.body),
+        }
+    }
+}
+
+///
+/// Records the digest of every packet read and/or written by a connection, in order, so that two
+/// sessions (such as a replay and its original capture, or the same traffic sent through two
+/// proxies) can be compared by their digest streams instead of by their bytes. See
+/// `first_divergence`.
+///
+/// A `DigestRecorder` is a `PacketMiddleware`, so it is installed by adding it to the middleware
+/// chain of a `CraftReader`, a `CraftWriter`, or a `CraftConnection`. Packets are hashed as they are
+/// seen at the recorder's position in the chain. It is a handle which can be cloned, and every
+/// clone records into the same list, which grows until it is emptied with `take`.
+///
+#[derive(Clone, Default)]
+pub struct DigestRecorder {
+    digests: Arc<Mutex<Vec<PacketDigest>>>,
+}
+
+impl DigestRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Removes and returns every digest recorded so far.
+    ///
+    pub fn take(&self) -> Vec<PacketDigest> {
+        std::mem::take(&mut *self.lock())
+    }
+
+    ///
+    /// A copy of every digest recorded so far.
+    ///
+    pub fn digests(&self) -> Vec<PacketDigest> {
+        self.lock().clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    fn record(&self, direction: FrameDirection, id: Id, body: &[u8]) {
+        let digest = PacketDigest {
+            direction,
+            id,
+            len: body.len(),
+            digest: packet_digest(id, body),
+        };
+        self.lock().push(digest);
+    }
+
+    // a poisoned lock still holds a usable list
+    fn lock(&self) -> MutexGuard<'_, Vec<PacketDigest>> {
+        self.digests.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl PacketMiddleware for DigestRecorder {
+    fn on_read(&mut self, id: Id, body: &[u8]) -> PacketVerdict {
+        self.record(FrameDirection::Read, id, body);
+        PacketVerdict::Pass
+    }
+
+    fn on_write(&mut self, id: Id, body: &[u8]) -> PacketVerdict {
+        self.record(FrameDirection::Written, id, body);
+        PacketVerdict::Pass
+    }
+}
+
+///
+/// The index of the first packet at which two digest streams differ, or `None` if they are equal.
+/// If one stream is a prefix of the other, the index is the length of the shorter stream.
+///
+pub fn first_divergence(a: &[PacketDigest], b: &[PacketDigest]) -> Option<usize> {
+    match a.iter().zip(b.iter()).position(|(a, b)| a != b) {
+        Some(index) => Some(index),
+        None if a.len() != b.len() => Some(a.len().min(b.len())),
+        None => None,
+    }
+}
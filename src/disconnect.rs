@@ -0,0 +1,213 @@
+use crate::connection::CraftConnection;
+use crate::tcp::CraftTcpConnection;
+use crate::util::is_timeout;
+use crate::wrapper::CraftWrapper;
+use crate::writer::{serialize_string, WriteError, WriteResult};
+use mcproto_rs::protocol::{Id, PacketDirection, State};
+#[cfg(feature = "backtrace")]
+use std::backtrace::Backtrace;
+use std::io::Read;
+use std::net::Shutdown;
+use std::time::{Duration, Instant};
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+use crate::writer::AsyncWriteAll;
+#[cfg(feature = "tokio-io")]
+use {
+    crate::tcp::CraftTokioConnection,
+    tokio::io::{AsyncReadExt, AsyncWriteExt},
+};
+
+const LOGIN_DISCONNECT_ID: i32 = 0x00;
+
+///
+/// The id of the clientbound Disconnect packet in the `Play` state for a protocol version, if it
+/// is known. Unlike the `Login` state, where Disconnect has always been 0x00, this id has changed
+/// between many versions.
+///
+pub fn play_disconnect_id(protocol_version: i32) -> Option<i32> {
+    Some(match protocol_version {
+        47 => 0x40, // 1.8.x
+        340 => 0x1A, // 1.12.2
+        404 => 0x1B, // 1.13.2
+        498 => 0x1A, // 1.14.4
+        573..=578 => 0x1B, // 1.15.x
+        735 | 736 => 0x1A, // 1.16, 1.16.1
+        751..=754 => 0x19, // 1.16.2 - 1.16.5
+        _ => return None,
+    })
+}
+
+impl<R, W> CraftConnection<R, W> {
+    // None means there is no disconnect packet in the current state, so the connection is simply
+    // closed
+    fn disconnect_packet(&self, reason: &str) -> WriteResult<Option<(Id, Vec<u8>)>> {
+        let direction = self.writer.direction();
+        if direction != PacketDirection::ClientBound {
+            return Err(WriteError::BadDirection {
+                attempted: direction,
+                expected: PacketDirection::ClientBound,
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture(),
+            });
+        }
+
+        let state = self.writer.state();
+        let id = match state {
+            State::Handshaking | State::Status => return Ok(None),
+            State::Login => LOGIN_DISCONNECT_ID,
+            State::Play => match self.protocol_version.and_then(play_disconnect_id) {
+                Some(id) => id,
+                None => {
+                    return Err(WriteError::NoDisconnectPacket {
+                        state,
+                        protocol_version: self.protocol_version,
+                        #[cfg(feature = "backtrace")]
+                        backtrace: Backtrace::capture(),
+                    })
+                }
+            },
+        };
+
+        Ok(Some((
+            Id {
+                id,
+                state,
+                direction,
+            },
+            serialize_string(reason)?,
+        )))
+    }
+}
+
+impl<R, W> CraftConnection<R, W>
+where
+    W: std::io::Write,
+{
+    ///
+    /// Kicks the client: writes the Disconnect packet which is correct for the connection's current
+    /// state, flushes it, and closes the connection. `reason` is a JSON chat component.
+    ///
+    /// The connection is closed by dropping its streams, which does not wait for the client to read
+    /// the packet. To shut down a socket gracefully (so the client reliably shows the reason), use
+    /// `drain_and_close` instead.
+    ///
+    /// The connection must be client-bound (a server's connection to a client). In the `Play`
+    /// state, the packet id depends on the protocol version, so it must be known (see
+    /// `protocol_version` and `play_disconnect_id`), otherwise `WriteError::NoDisconnectPacket` is
+    /// returned. There is no Disconnect packet in the `Handshaking` and `Status` states, so the
+    /// connection is closed without writing anything.
+    ///
+    pub fn disconnect(mut self, reason: &str) -> WriteResult<()> {
+        if let Some((id, body)) = self.disconnect_packet(reason)? {
+            self.writer.write_raw_untyped_packet(id, &body)?;
+        }
+
+        self.writer.flush()
+    }
+}
+
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+impl<R, W> CraftConnection<R, W>
+where
+    W: AsyncWriteAll,
+{
+    ///
+    /// Same as `disconnect`, but writes to an async stream. See `drain_and_close_async` to shut
+    /// down a socket gracefully.
+    ///
+    pub async fn disconnect_async(mut self, reason: &str) -> WriteResult<()> {
+        if let Some((id, body)) = self.disconnect_packet(reason)? {
+            self.writer.write_raw_untyped_packet_async(id, &body).await?;
+        }
+
+        self.writer.flush_async().await
+    }
+}
+
+// the size of the buffer which the peer's last bytes are read into (and discarded) while draining
+const DRAIN_BUF_SIZE: usize = 1024;
+
+impl CraftTcpConnection {
+    ///
+    /// Closes the connection so that the peer receives everything which was written to it,
+    /// including (if `reason` is given) a final Disconnect packet, as written by `disconnect`.
+    ///
+    /// After writing, the sending side of the socket is shut down, and whatever the peer still
+    /// sends is read and discarded until it closes its side, or `timeout` elapses. Closing a
+    /// socket with unread data makes the operating system reset the connection, which can make the
+    /// peer discard what it had not read yet (clients then show a generic "connection reset"
+    /// instead of the reason).
+    ///
+    /// Returns whether the peer closed its side before the timeout.
+    ///
+    pub fn drain_and_close(mut self, reason: Option<&str>, timeout: Duration) -> WriteResult<bool> {
+        let deadline = Instant::now() + timeout;
+        if let Some(reason) = reason {
+            if let Some((id, body)) = self.disconnect_packet(reason)? {
+                self.writer.write_raw_untyped_packet(id, &body)?;
+            }
+        }
+
+        self.writer.flush()?;
+        let socket = self.writer.into_inner();
+        socket.shutdown(Shutdown::Write)?;
+
+        let mut reader = self.reader.into_inner();
+        let mut buf = [0u8; DRAIN_BUF_SIZE];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                return Ok(false);
+            }
+
+            socket.set_read_timeout(Some(remaining))?;
+            match reader.read(&mut buf) {
+                Ok(0) => return Ok(true),
+                Ok(_) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) if is_timeout(&err) => return Ok(false),
+                // the peer reset the connection, so there is nothing left to drain
+                Err(_) => return Ok(true),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tokio-io")]
+impl CraftTokioConnection {
+    ///
+    /// Same as `drain_and_close`, but for an async connection. Only draining is limited by
+    /// `timeout`, since an async write can not be abandoned part way through a packet.
+    ///
+    pub async fn drain_and_close_async(
+        mut self,
+        reason: Option<&str>,
+        timeout: Duration,
+    ) -> WriteResult<bool> {
+        if let Some(reason) = reason {
+            if let Some((id, body)) = self.disconnect_packet(reason)? {
+                self.writer.write_raw_untyped_packet_async(id, &body).await?;
+            }
+        }
+
+        self.writer.flush_async().await?;
+        let mut writer = self.writer.into_inner();
+        writer.shutdown().await?;
+
+        let mut reader = self.reader.into_inner();
+        let mut buf = [0u8; DRAIN_BUF_SIZE];
+        let drained = tokio::time::timeout(timeout, async {
+            loop {
+                match reader.read(&mut buf).await {
+                    Ok(0) => return true,
+                    Ok(_) => {}
+                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(_) => return true,
+                }
+            }
+        })
+        .await;
+
+        Ok(drained.unwrap_or(false))
+    }
+}
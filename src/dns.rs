@@ -0,0 +1,104 @@
+use crate::address::{ServerAddress, DEFAULT_SERVER_PORT};
+use hickory_resolver::config::{ResolverConfig, ResolverOpts};
+use hickory_resolver::error::ResolveError;
+use hickory_resolver::TokioAsyncResolver;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+const SRV_PREFIX: &str = "_minecraft._tcp.";
+
+///
+/// Resolves server addresses without blocking the async runtime, the same way the vanilla client
+/// does: when no port is given, the `_minecraft._tcp` SRV record of the host is looked up first,
+/// and otherwise (or if there is no SRV record) the host's addresses are used with the given port
+/// (or `DEFAULT_SERVER_PORT`).
+///
+/// A resolver caches the records it looks up, so it should be created once and shared. It can be
+/// cloned cheaply, and every clone shares the same cache.
+///
+#[derive(Clone)]
+pub struct CraftResolver {
+    resolver: TokioAsyncResolver,
+}
+
+impl CraftResolver {
+    ///
+    /// Creates a resolver with the given configuration (the name servers to use) and options
+    /// (timeouts, attempts, caching and so on). These types come from the `hickory-resolver` crate.
+    ///
+    pub fn new(config: ResolverConfig, options: ResolverOpts) -> Self {
+        Self {
+            resolver: TokioAsyncResolver::tokio(config, options),
+        }
+    }
+
+    ///
+    /// Creates a resolver using the operating system's configuration (such as `/etc/resolv.conf`).
+    ///
+    pub fn from_system_conf() -> io::Result<Self> {
+        Ok(Self {
+            resolver: TokioAsyncResolver::tokio_from_system_conf().map_err(resolve_err)?,
+        })
+    }
+
+    ///
+    /// Resolves a server address to the socket addresses which should be tried, in order. If the
+    /// host is an IP address, no lookup is performed.
+    ///
+    pub async fn resolve(&self, address: &ServerAddress) -> io::Result<Vec<SocketAddr>> {
+        let host = address.host.as_str();
+        let port = address.port;
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port.unwrap_or(DEFAULT_SERVER_PORT))]);
+        }
+
+        if port.is_none() {
+            if let Some(addrs) = self.resolve_srv(host).await? {
+                return Ok(addrs);
+            }
+        }
+
+        let addrs: Vec<SocketAddr> = self
+            .resolver
+            .lookup_ip(host)
+            .await
+            .map_err(resolve_err)?
+            .iter()
+            .map(|ip| SocketAddr::new(ip, port.unwrap_or(DEFAULT_SERVER_PORT)))
+            .collect();
+
+        if addrs.is_empty() {
+            Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no addresses found for {}", host),
+            ))
+        } else {
+            Ok(addrs)
+        }
+    }
+
+    // returns None if the host has no SRV record (which is the usual case)
+    async fn resolve_srv(&self, host: &str) -> io::Result<Option<Vec<SocketAddr>>> {
+        let lookup = match self.resolver.srv_lookup(format!("{}{}", SRV_PREFIX, host)).await {
+            Ok(lookup) => lookup,
+            Err(_) => return Ok(None),
+        };
+
+        let mut records: Vec<_> = lookup.iter().collect();
+        records.sort_by_key(|record| (record.priority(), std::cmp::Reverse(record.weight())));
+
+        let mut addrs = Vec::new();
+        for record in records {
+            let target = record.target().to_utf8();
+            if let Ok(ips) = self.resolver.lookup_ip(target.as_str()).await {
+                addrs.extend(ips.iter().map(|ip| SocketAddr::new(ip, record.port())));
+            }
+        }
+
+        Ok(if addrs.is_empty() { None } else { Some(addrs) })
+    }
+}
+
+fn resolve_err(err: ResolveError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
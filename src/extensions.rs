@@ -0,0 +1,105 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+
+///
+/// A map holding at most one value of each type, which middleware, routers and auth helpers use to
+/// attach their own data to a connection (such as a player's profile, tags, or rate limit
+/// buckets) without keeping a side table keyed by connection. See `CraftConnection::extensions`.
+///
+/// Values are looked up by their type, so a layer should store its data in a type of its own
+/// rather than in a common type (such as `String`) which another layer might also store.
+///
+#[derive(Default)]
+pub struct Extensions {
+    values: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.values.len())
+            .finish()
+    }
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Stores a value, returning the value of the same type which was stored before, if any.
+    ///
+    pub fn insert<T>(&mut self, value: T) -> Option<T>
+    where
+        T: Any + Send + Sync,
+    {
+        self.values
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| previous.downcast().ok())
+            .map(|previous| *previous)
+    }
+
+    pub fn get<T>(&self) -> Option<&T>
+    where
+        T: Any + Send + Sync,
+    {
+        self.values
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    pub fn get_mut<T>(&mut self) -> Option<&mut T>
+    where
+        T: Any + Send + Sync,
+    {
+        self.values
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_mut())
+    }
+
+    ///
+    /// Returns the value of type `T`, storing one made by `f` first if there is none.
+    ///
+    pub fn get_or_insert_with<T, F>(&mut self, f: F) -> &mut T
+    where
+        T: Any + Send + Sync,
+        F: FnOnce() -> T,
+    {
+        self.values
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(f()))
+            .downcast_mut()
+            .expect("values are stored under their own type id")
+    }
+
+    pub fn remove<T>(&mut self) -> Option<T>
+    where
+        T: Any + Send + Sync,
+    {
+        self.values
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok())
+            .map(|value| *value)
+    }
+
+    pub fn contains<T>(&self) -> bool
+    where
+        T: Any + Send + Sync,
+    {
+        self.values.contains_key(&TypeId::of::<T>())
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+}
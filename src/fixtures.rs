@@ -0,0 +1,332 @@
+use crate::frames::FrameSlices;
+use crate::reader::{CraftReader, CraftSyncReader, ReadError};
+use crate::writer::{CraftSyncWriter, CraftWriter, WriteError};
+use mcproto_rs::protocol::{Id, PacketDirection, PacketErr, RawPacket, State};
+#[cfg(feature = "backtrace")]
+use std::backtrace::Backtrace;
+use std::fs;
+use std::io;
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum FixtureError {
+    #[error("failed to load fixtures")]
+    Io {
+        #[from]
+        err: io::Error,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("fixture {name} has no metadata file")]
+    MissingMetadata {
+        name: String,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("fixture {name} has invalid metadata '{line}'")]
+    BadMetadata {
+        name: String,
+        line: String,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("fixture {name} is not a single frame")]
+    BadFrame {
+        name: String,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("fixture {name} holds packet {actual:#04x}, but its metadata says {expected:?}")]
+    IdMismatch {
+        name: String,
+        expected: Id,
+        actual: i32,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("fixture {name} could not be read")]
+    ReadFailed {
+        name: String,
+        #[source]
+        err: ReadError,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("fixture {name} could not be parsed")]
+    PacketFailed {
+        name: String,
+        #[source]
+        err: PacketErr,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("fixture {name} could not be written")]
+    WriteFailed {
+        name: String,
+        #[source]
+        err: WriteError,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error(
+        "fixture {name} was written differently from byte {offset} \
+         (expected {expected_len} bytes, got {actual_len})"
+    )]
+    RoundTripMismatch {
+        name: String,
+        offset: usize,
+        expected_len: usize,
+        actual_len: usize,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+}
+
+pub type FixtureResult<T> = Result<T, FixtureError>;
+
+///
+/// A captured frame and the packet it is expected to hold, loaded by `load_fixtures`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameFixture {
+    ///
+    /// The file name of the fixture, without its extension.
+    ///
+    pub name: String,
+    pub id: Id,
+    ///
+    /// The frame as it was sent over an uncompressed, unencrypted connection: the length prefix,
+    /// the packet id and the body.
+    ///
+    pub frame: Vec<u8>,
+}
+
+///
+/// Loads a directory of captured frames, so that protocol crates can check their packet
+/// definitions against real traffic with `FrameFixture::check`.
+///
+/// Each fixture is a `<name>.bin` file holding one frame, and a `<name>.meta` file describing the
+/// packet in it, with one `key = value` pair on each line (empty lines and lines starting with `#`
+/// are ignored):
+///
+/// ```text
+/// state = login
+/// direction = clientbound
+/// id = 0x02
+/// ```
+///
+/// Fixtures are returned in the order of their names. Other files in the directory are ignored.
+///
+pub fn load_fixtures<P>(dir: P) -> FixtureResult<Vec<FrameFixture>>
+where
+    P: AsRef<Path>,
+{
+    let mut fixtures = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().map_or(true, |ext| ext != "bin") {
+            continue;
+        }
+
+        let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(name) => name.to_owned(),
+            None => continue,
+        };
+        let meta = match fs::read_to_string(path.with_extension("meta")) {
+            Ok(meta) => meta,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(FixtureError::MissingMetadata {
+                    name,
+                    #[cfg(feature = "backtrace")]
+                    backtrace: Backtrace::capture(),
+                })
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let id = parse_metadata(&name, &meta)?;
+        fixtures.push(FrameFixture {
+            name,
+            id,
+            frame: fs::read(&path)?,
+        });
+    }
+
+    fixtures.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(fixtures)
+}
+
+fn parse_metadata(name: &str, meta: &str) -> FixtureResult<Id> {
+    let bad_line = |line: &str| FixtureError::BadMetadata {
+        name: name.to_owned(),
+        line: line.to_owned(),
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace::capture(),
+    };
+
+    let mut state = None;
+    let mut direction = None;
+    let mut id = None;
+    for line in meta.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = line.split_once('=').ok_or_else(|| bad_line(line))?;
+        let value = value.trim();
+        match key.trim() {
+            "state" => state = Some(parse_state(value).ok_or_else(|| bad_line(line))?),
+            "direction" => direction = Some(parse_direction(value).ok_or_else(|| bad_line(line))?),
+            "id" => id = Some(parse_id(value).ok_or_else(|| bad_line(line))?),
+            _ => return Err(bad_line(line)),
+        }
+    }
+
+    match (state, direction, id) {
+        (Some(state), Some(direction), Some(id)) => Ok(Id {
+            id,
+            state,
+            direction,
+        }),
+        _ => Err(bad_line("(state, direction and id are required)")),
+    }
+}
+
+fn parse_state(value: &str) -> Option<State> {
+    match value.to_ascii_lowercase().as_str() {
+        "handshaking" => Some(State::Handshaking),
+        "status" => Some(State::Status),
+        "login" => Some(State::Login),
+        "play" => Some(State::Play),
+        _ => None,
+    }
+}
+
+fn parse_direction(value: &str) -> Option<PacketDirection> {
+    match value.to_ascii_lowercase().as_str() {
+        "clientbound" => Some(PacketDirection::ClientBound),
+        "serverbound" => Some(PacketDirection::ServerBound),
+        _ => None,
+    }
+}
+
+fn parse_id(value: &str) -> Option<i32> {
+    match value.strip_prefix("0x") {
+        Some(hex) => i32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+impl FrameFixture {
+    ///
+    /// The body of the packet in the frame, after checking that the frame holds exactly one packet
+    /// with the expected id.
+    ///
+    pub fn body(&self) -> FixtureResult<&[u8]> {
+        let mut frames = FrameSlices::new(&self.frame);
+        let frame = match frames.next() {
+            Some(Ok(frame)) if frames.remaining().is_empty() => frame,
+            Some(Err(err)) => return Err(self.read_failed(err)),
+            _ => return Err(self.bad_frame()),
+        };
+
+        let (id, body) = frame
+            .packet(self.id.state, self.id.direction)
+            .map_err(|err| self.read_failed(err))?;
+        if id.id != self.id.id {
+            return Err(FixtureError::IdMismatch {
+                name: self.name.clone(),
+                expected: self.id,
+                actual: id.id,
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture(),
+            });
+        }
+
+        Ok(body)
+    }
+
+    ///
+    /// Reads the frame through a `CraftReader`, and checks that it reads the expected packet.
+    ///
+    pub fn check_reader(&self) -> FixtureResult<()> {
+        let body = self.body()?;
+        let mut reader =
+            CraftReader::wrap_with_state(&self.frame[..], self.id.direction, self.id.state);
+        match reader.read_raw_untyped_packet() {
+            Ok(Some((id, read))) if id == self.id && read == body => Ok(()),
+            Ok(_) => Err(self.bad_frame()),
+            Err(err) => Err(self.read_failed(err)),
+        }
+    }
+
+    ///
+    /// Parses the packet in the frame with the packet definitions of `P`, and writes it back
+    /// through a `CraftWriter`, which must produce the captured frame byte for byte.
+    ///
+    pub fn check<'a, P>(&'a self) -> FixtureResult<()>
+    where
+        P: RawPacket<'a>,
+    {
+        let body = self.body()?;
+        let packet = P::create(self.id, body)
+            .and_then(|raw| raw.deserialize())
+            .map_err(|err| FixtureError::PacketFailed {
+                name: self.name.clone(),
+                err,
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture(),
+            })?;
+
+        let mut writer = CraftWriter::wrap_with_state(
+            Vec::with_capacity(self.frame.len()),
+            self.id.direction,
+            self.id.state,
+        );
+        writer
+            .write_packet(packet)
+            .map_err(|err| FixtureError::WriteFailed {
+                name: self.name.clone(),
+                err,
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture(),
+            })?;
+
+        let written = writer.get_ref();
+        if written[..] != self.frame[..] {
+            let offset = written
+                .iter()
+                .zip(self.frame.iter())
+                .position(|(a, b)| a != b)
+                .unwrap_or_else(|| written.len().min(self.frame.len()));
+            return Err(FixtureError::RoundTripMismatch {
+                name: self.name.clone(),
+                offset,
+                expected_len: self.frame.len(),
+                actual_len: written.len(),
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture(),
+            });
+        }
+
+        Ok(())
+    }
+
+    fn read_failed(&self, err: ReadError) -> FixtureError {
+        FixtureError::ReadFailed {
+            name: self.name.clone(),
+            err,
+            #[cfg(feature = "backtrace")]
+            backtrace: Backtrace::capture(),
+        }
+    }
+
+    fn bad_frame(&self) -> FixtureError {
+        FixtureError::BadFrame {
+            name: self.name.clone(),
+            #[cfg(feature = "backtrace")]
+            backtrace: Backtrace::capture(),
+        }
+    }
+}
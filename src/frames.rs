@@ -0,0 +1,202 @@
+use crate::reader::{parse_frame_len, ReadError, DEAFULT_MAX_PACKET_SIZE};
+use mcproto_rs::protocol::{Id, PacketDirection, State};
+use mcproto_rs::types::VarInt;
+use mcproto_rs::{Deserialize, Deserialized};
+#[cfg(feature = "backtrace")]
+use std::backtrace::Backtrace;
+#[cfg(feature = "mmap")]
+use {memmap2::Mmap, std::fs::File, std::io, std::path::Path};
+
+///
+/// Splits bytes holding a stream of frames, as they were sent over an unencrypted connection (such
+/// as a capture file, see `MappedCapture`), into frames without copying them. The same checks a
+/// `CraftReader` applies to the length prefix of each frame are applied (see `parse_frame_len`).
+///
+/// This is meant for offline analysis of large session archives, where reading every frame
+/// through a `CraftReader` would copy the whole archive into its buffer. If the bytes end part way
+/// through a frame, the last item is `ReadError::ConnectionClosedMidPacket`, and iteration stops
+/// after the first error.
+///
+#[derive(Debug, Clone)]
+pub struct FrameSlices<'a> {
+    data: &'a [u8],
+    offset: usize,
+    max_packet_size: usize,
+}
+
+///
+/// A frame found by `FrameSlices`, which borrows the bytes it was found in.
+///
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FrameSlice<'a> {
+    ///
+    /// The offset of the frame's length prefix from the start of the bytes.
+    ///
+    pub offset: usize,
+    ///
+    /// Everything after the length prefix: the packet id and body of an uncompressed frame, or the
+    /// uncompressed length followed by the (possibly) compressed packet.
+    ///
+    pub payload: &'a [u8],
+}
+
+impl<'a> FrameSlices<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            offset: 0,
+            max_packet_size: DEAFULT_MAX_PACKET_SIZE,
+        }
+    }
+
+    pub fn with_max_packet_size(mut self, max_packet_size: usize) -> Self {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+
+    ///
+    /// The offset of the next frame from the start of the bytes.
+    ///
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    ///
+    /// The bytes which have not been split into frames yet.
+    ///
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.data[self.offset..]
+    }
+
+    fn fail(&mut self, err: ReadError) -> Option<Result<FrameSlice<'a>, ReadError>> {
+        self.offset = self.data.len();
+        Some(Err(err))
+    }
+}
+
+impl<'a> Iterator for FrameSlices<'a> {
+    type Item = Result<FrameSlice<'a>, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = self.remaining();
+        if rest.is_empty() {
+            return None;
+        }
+
+        let (body_len, header_len) = match parse_frame_len(rest, self.max_packet_size) {
+            Ok(Some(len)) => len,
+            Ok(None) => {
+                return self.fail(ReadError::ConnectionClosedMidPacket {
+                    expected: rest.len() + 1,
+                    received: rest.len(),
+                    #[cfg(feature = "backtrace")]
+                    backtrace: Backtrace::capture(),
+                })
+            }
+            Err(err) => return self.fail(err),
+        };
+
+        let frame_len = header_len + body_len;
+        if rest.len() < frame_len {
+            return self.fail(ReadError::ConnectionClosedMidPacket {
+                expected: frame_len,
+                received: rest.len(),
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture(),
+            });
+        }
+
+        let frame = FrameSlice {
+            offset: self.offset,
+            payload: &rest[header_len..frame_len],
+        };
+        self.offset += frame_len;
+        Some(Ok(frame))
+    }
+}
+
+impl<'a> FrameSlice<'a> {
+    ///
+    /// The id and body of the packet in a frame which was sent without compression. The body
+    /// borrows the frame's bytes.
+    ///
+    pub fn packet(
+        &self,
+        state: State,
+        direction: PacketDirection,
+    ) -> Result<(Id, &'a [u8]), ReadError> {
+        split_packet(self.payload, state, direction)
+    }
+
+    ///
+    /// The id and body of the packet in a frame which was sent with compression enabled. A frame
+    /// below the compression threshold borrows the frame's bytes, and a compressed frame is
+    /// decompressed into `buf`, which can be reused for every frame.
+    ///
+    #[cfg(feature = "compression")]
+    pub fn decompressed_packet<'b>(
+        &self,
+        state: State,
+        direction: PacketDirection,
+        buf: &'b mut Option<Vec<u8>>,
+        max_packet_size: usize,
+    ) -> Result<(Id, &'b [u8]), ReadError>
+    where
+        'a: 'b,
+    {
+        let packet =
+            crate::compression::decompress_frame_body(self.payload, buf, max_packet_size)?;
+        split_packet(packet, state, direction)
+    }
+}
+
+fn split_packet(
+    packet: &[u8],
+    state: State,
+    direction: PacketDirection,
+) -> Result<(Id, &[u8]), ReadError> {
+    let Deserialized { value: id, data: body } = VarInt::mc_deserialize(packet)?;
+    Ok((
+        Id {
+            id: id.0,
+            state,
+            direction,
+        },
+        body,
+    ))
+}
+
+///
+/// A capture file (a stream of unencrypted frames, such as the bytes a connection read or wrote)
+/// mapped into memory, so that analysis tools can scan multi-gigabyte archives with `frames`
+/// without reading them into memory first.
+///
+/// The file must not be modified or truncated while it is mapped, since the mapped bytes would
+/// change underneath the frames borrowed from it (and truncating it would crash the process).
+///
+#[cfg(feature = "mmap")]
+#[derive(Debug)]
+pub struct MappedCapture {
+    map: Mmap,
+}
+
+#[cfg(feature = "mmap")]
+impl MappedCapture {
+    pub fn open<P>(path: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let file = File::open(path)?;
+        // the caller is told not to modify the file while it is mapped, see above
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(Self { map })
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.map
+    }
+
+    pub fn frames(&self) -> FrameSlices<'_> {
+        FrameSlices::new(&self.map)
+    }
+}
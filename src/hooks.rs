@@ -0,0 +1,32 @@
+///
+/// Callbacks fired by a `CraftReader` or `CraftWriter` at exact frame boundaries, so that layers
+/// outside of this crate (such as custom crypto, byte accounting, or QoS marking of the socket) can
+/// stay synchronized with the wire format without forking the reader or writer.
+///
+/// A frame's payload is everything after its length prefix: the packet id and body, preceded by the
+/// uncompressed length and compressed when compression is enabled. Payloads are passed as
+/// plaintext, after decryption when reading and before encryption when writing.
+///
+/// Unlike `PacketMiddleware`, which sees packets after decompression, hooks see frames as they are
+/// framed on the wire, and cannot change or reject them. Hooks are set separately on each half of a
+/// connection (see `CraftConnection::split`).
+///
+/// All functions have a default implementation which does nothing.
+///
+pub trait FrameHooks: Send {
+    ///
+    /// Called once before the first byte of the next frame's length prefix is read.
+    ///
+    fn before_frame_read(&mut self) {}
+
+    ///
+    /// Called when a frame was completely received, before it is decompressed or passed to the
+    /// middleware.
+    ///
+    fn on_frame_read(&mut self, _payload: &[u8]) {}
+
+    ///
+    /// Called when a frame was prepared, before it is encrypted and handed to the wrapped stream.
+    ///
+    fn before_frame_write(&mut self, _payload: &[u8]) {}
+}
@@ -0,0 +1,238 @@
+use crate::connection::CraftConnection;
+use crate::util::VAR_INT_BUF_SIZE;
+use crate::writer::{PacketSerializeFail, SliceSerializer, WriteError, WriteResult};
+use mcproto_rs::protocol::{Id, PacketDirection, State};
+use mcproto_rs::types::VarInt;
+use mcproto_rs::{Deserialize, Serialize};
+#[cfg(feature = "backtrace")]
+use std::backtrace::Backtrace;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+use crate::writer::AsyncWriteAll;
+
+// 1.8 sends keep-alive ids as a VarInt, every later version as a long
+const VAR_INT_KEEP_ALIVE_VERSION: i32 = 47;
+
+///
+/// The ids of the `Play` state KeepAlive packets for a protocol version.
+///
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct KeepAliveIds {
+    pub clientbound: i32,
+    pub serverbound: i32,
+}
+
+///
+/// The KeepAlive packet ids for a protocol version, if they are known.
+///
+pub fn keep_alive_ids(protocol_version: i32) -> Option<KeepAliveIds> {
+    let (clientbound, serverbound) = match protocol_version {
+        47 => (0x00, 0x00), // 1.8.x
+        340 => (0x1F, 0x0B), // 1.12.2
+        404 => (0x21, 0x0E), // 1.13.2
+        498 => (0x20, 0x0F), // 1.14.4
+        573..=578 => (0x21, 0x0F), // 1.15.x
+        735 | 736 => (0x20, 0x10), // 1.16, 1.16.1
+        751..=754 => (0x1F, 0x10), // 1.16.2 - 1.16.5
+        _ => return None,
+    };
+
+    Some(KeepAliveIds {
+        clientbound,
+        serverbound,
+    })
+}
+
+///
+/// What a `KeepAliveDriver` wants its caller to do next.
+///
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeepAliveAction {
+    ///
+    /// Send a KeepAlive packet with this id (see `CraftConnection::send_keep_alive`).
+    ///
+    Send(i64),
+    ///
+    /// Nothing needs to happen until this much time has passed, unless a response arrives.
+    ///
+    Wait(Duration),
+    ///
+    /// The client did not answer the KeepAlive with this id before the deadline, and should be
+    /// disconnected.
+    ///
+    Expired(i64),
+}
+
+///
+/// Drives the server side of the keep-alive exchange: it decides when a KeepAlive should be sent
+/// (with a newly generated id), matches the client's responses, and reports when a client misses
+/// the deadline. Like vanilla, only one KeepAlive is outstanding at a time.
+///
+/// The driver does no I/O and does not read the clock by itself, so it fits into any event loop:
+/// call `poll` when the previous `Wait` has elapsed (or whenever convenient), and `on_response` for
+/// every KeepAlive read from the client (see `CraftConnection::parse_keep_alive_response`).
+///
+#[derive(Debug, Clone)]
+pub struct KeepAliveDriver {
+    interval: Duration,
+    timeout: Duration,
+    last_sent_at: Option<Instant>,
+    outstanding: Option<(i64, Instant)>,
+    last_id: i64,
+    latency: Option<Duration>,
+}
+
+impl KeepAliveDriver {
+    ///
+    /// Creates a driver which sends a KeepAlive every `interval`, and expires a client which has not
+    /// answered within `timeout`. Vanilla servers use 15 and 30 seconds.
+    ///
+    pub fn new(interval: Duration, timeout: Duration) -> Self {
+        Self {
+            interval,
+            timeout,
+            last_sent_at: None,
+            outstanding: None,
+            last_id: 0,
+            latency: None,
+        }
+    }
+
+    pub fn poll(&mut self, now: Instant) -> KeepAliveAction {
+        if let Some((id, sent_at)) = self.outstanding {
+            let deadline = sent_at + self.timeout;
+            return if now >= deadline {
+                KeepAliveAction::Expired(id)
+            } else {
+                KeepAliveAction::Wait(deadline - now)
+            };
+        }
+
+        if let Some(last_sent_at) = self.last_sent_at {
+            let next_at = last_sent_at + self.interval;
+            if now < next_at {
+                return KeepAliveAction::Wait(next_at - now);
+            }
+        }
+
+        let id = self.next_id();
+        self.outstanding = Some((id, now));
+        self.last_sent_at = Some(now);
+        KeepAliveAction::Send(id)
+    }
+
+    ///
+    /// Records a KeepAlive response from the client. Returns whether it answered the outstanding
+    /// KeepAlive; responses with any other id are ignored.
+    ///
+    pub fn on_response(&mut self, id: i64, now: Instant) -> bool {
+        match self.outstanding {
+            Some((outstanding, sent_at)) if outstanding == id => {
+                self.latency = Some(now.saturating_duration_since(sent_at));
+                self.outstanding = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    ///
+    /// The round trip time of the most recently answered KeepAlive.
+    ///
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+
+    // vanilla uses the current time in milliseconds, which is kept here (truncated so it also fits
+    // into the VarInt which 1.8 uses), while making sure no id is ever reused
+    fn next_id(&mut self) -> i64 {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| (elapsed.as_millis() as i64) & (i32::MAX as i64))
+            .unwrap_or(0);
+        self.last_id = millis.max(self.last_id + 1);
+        self.last_id
+    }
+}
+
+impl<R, W> CraftConnection<R, W> {
+    ///
+    /// If the packet is the client's KeepAlive response, returns the id it carries. This requires
+    /// the protocol version to be known (see `protocol_version`).
+    ///
+    pub fn parse_keep_alive_response(&self, id: Id, body: &[u8]) -> Option<i64> {
+        let protocol_version = self.protocol_version?;
+        let ids = keep_alive_ids(protocol_version)?;
+        if id.state != State::Play
+            || id.direction != PacketDirection::ServerBound
+            || id.id != ids.serverbound
+        {
+            return None;
+        }
+
+        if protocol_version == VAR_INT_KEEP_ALIVE_VERSION {
+            VarInt::mc_deserialize(body).ok().map(|parsed| parsed.value.0 as i64)
+        } else {
+            i64::mc_deserialize(body).ok().map(|parsed| parsed.value)
+        }
+    }
+
+    pub(crate) fn keep_alive_packet(&self, keep_alive_id: i64) -> WriteResult<(Id, Vec<u8>)> {
+        let ids = self.protocol_version.and_then(keep_alive_ids).ok_or_else(|| {
+            WriteError::NoKeepAlivePacket {
+                protocol_version: self.protocol_version,
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture(),
+            }
+        })?;
+
+        let mut body = [0u8; 8];
+        let body = if self.protocol_version == Some(VAR_INT_KEEP_ALIVE_VERSION) {
+            let mut serializer = SliceSerializer::create(&mut body[..VAR_INT_BUF_SIZE]);
+            VarInt(keep_alive_id as i32)
+                .mc_serialize(&mut serializer)
+                .map_err(move |err| PacketSerializeFail::Body(err))?;
+            serializer.finish().to_vec()
+        } else {
+            body.copy_from_slice(&keep_alive_id.to_be_bytes());
+            body.to_vec()
+        };
+
+        Ok((
+            Id {
+                id: ids.clientbound,
+                state: State::Play,
+                direction: PacketDirection::ClientBound,
+            },
+            body,
+        ))
+    }
+}
+
+impl<R, W> CraftConnection<R, W>
+where
+    W: std::io::Write,
+{
+    ///
+    /// Writes a KeepAlive packet with the given id to the client, using the packet id for the
+    /// connection's protocol version (see `protocol_version`).
+    ///
+    pub fn send_keep_alive(&mut self, keep_alive_id: i64) -> WriteResult<()> {
+        let (id, body) = self.keep_alive_packet(keep_alive_id)?;
+        self.writer.write_raw_untyped_packet(id, &body)
+    }
+}
+
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+impl<R, W> CraftConnection<R, W>
+where
+    W: AsyncWriteAll,
+{
+    ///
+    /// Same as `send_keep_alive`, but writes to an async stream.
+    ///
+    pub async fn send_keep_alive_async(&mut self, keep_alive_id: i64) -> WriteResult<()> {
+        let (id, body) = self.keep_alive_packet(keep_alive_id)?;
+        self.writer.write_raw_untyped_packet_async(id, &body).await
+    }
+}
@@ -1,19 +1,138 @@
 #![cfg_attr(feature = "backtrace", feature(backtrace))]
 #![cfg_attr(feature = "gat", feature(generic_associated_types))]
 
+// glommio streams implement the futures io traits, which are only used when tokio-io is disabled
+#[cfg(all(feature = "glommio-io", feature = "tokio-io"))]
+compile_error!("the glommio-io feature requires the default tokio-io feature to be disabled");
+
+pub mod address;
+#[cfg(feature = "bedrock")]
+pub mod bedrock;
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+pub mod block_on;
+#[cfg(feature = "tokio-io")]
+pub mod blocking;
+pub mod boxed;
+pub mod builder;
+pub mod cached;
 #[cfg(feature = "encryption")]
 pub mod cfb8;
+#[cfg(feature = "compression")]
+pub mod compression;
+#[cfg(feature = "serde")]
+pub mod config;
 pub mod connection;
+pub mod cookie;
+#[cfg(feature = "digest")]
+pub mod digest;
+pub mod disconnect;
+#[cfg(feature = "async-dns")]
+pub mod dns;
+pub mod extensions;
+#[cfg(feature = "testing")]
+pub mod fixtures;
+pub mod frames;
+pub mod hooks;
+pub mod keepalive;
+pub mod liveness;
+#[cfg(feature = "loadgen")]
+pub mod loadgen;
+pub mod middleware;
+pub mod mirror;
+pub mod mux;
+#[cfg(feature = "noise")]
+pub mod noise;
+pub mod observer;
+pub mod phase;
+pub mod proxy;
 pub mod reader;
+pub mod router;
+#[cfg(feature = "secure-link")]
+pub mod secure;
+pub mod sni;
+pub mod sniff;
+pub mod socket;
+pub mod stats;
+pub mod status;
+pub mod strict;
 pub mod tcp;
+#[cfg(test)]
+mod test_util;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod timing;
+pub mod tunnel;
+#[cfg(feature = "compression")]
+pub mod tuning;
 pub mod util;
+#[cfg(feature = "velocity")]
+pub mod velocity;
 pub mod wrapper;
 pub mod writer;
 
 #[cfg(feature = "encryption")]
 pub use crate::cfb8::CipherError;
+pub use address::*;
+#[cfg(feature = "bedrock")]
+pub use bedrock::*;
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+pub use block_on::*;
+#[cfg(feature = "tokio-io")]
+pub use blocking::*;
+pub use boxed::*;
+pub use builder::*;
+pub use cached::*;
+#[cfg(feature = "serde")]
+pub use config::*;
 pub use connection::CraftConnection;
+pub use cookie::*;
+#[cfg(feature = "digest")]
+pub use digest::*;
+pub use disconnect::*;
+#[cfg(feature = "async-dns")]
+pub use dns::*;
+pub use extensions::*;
+#[cfg(feature = "testing")]
+pub use fixtures::*;
+pub use frames::*;
+pub use hooks::*;
+pub use keepalive::*;
+pub use liveness::*;
+#[cfg(feature = "loadgen")]
+pub use loadgen::*;
+pub use middleware::*;
+pub use mirror::*;
+pub use mux::*;
+#[cfg(feature = "noise")]
+pub use noise::*;
+pub use observer::{ConnectionObserver, OversizePacket};
+pub use phase::*;
+pub use proxy::*;
 pub use reader::*;
+pub use router::*;
+#[cfg(feature = "secure-link")]
+pub use secure::*;
+pub use sni::*;
+pub use sniff::*;
+pub use socket::*;
+pub use stats::*;
+pub use status::*;
+pub use strict::*;
 pub use tcp::*;
+pub use timing::*;
+pub use tunnel::*;
+#[cfg(feature = "velocity")]
+pub use velocity::*;
+#[cfg(feature = "compression")]
+pub use tuning::*;
 pub use wrapper::*;
 pub use writer::*;
+
+// the protocol types, and the packet definitions selected with the version features, so crates
+// which only depend on this crate use the same mcproto-rs as it does
+pub use mcproto_rs;
+pub use mcproto_rs::protocol::{Id, Packet, PacketDirection, RawPacket, State};
+#[cfg(feature = "v1_15_2")]
+pub use mcproto_rs::v1_15_2;
+#[cfg(feature = "v1_16_3")]
+pub use mcproto_rs::v1_16_3;
@@ -0,0 +1,196 @@
+use crate::connection::CraftConnection;
+use crate::keepalive::keep_alive_ids;
+use crate::tcp::CraftTcpConnection;
+use crate::util::is_timeout;
+use crate::writer::{WriteError, WriteResult};
+use mcproto_rs::protocol::{Id, PacketDirection, State};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+#[cfg(feature = "tokio-io")]
+use crate::tcp::CraftTokioConnection;
+
+///
+/// The result of a liveness probe (see `CraftTcpConnection::probe_alive`).
+///
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Liveness {
+    ///
+    /// The peer sent data (or data was already waiting to be read) before the timeout.
+    ///
+    Alive,
+    ///
+    /// The connection is open, but the peer sent nothing before the timeout.
+    ///
+    Silent,
+    ///
+    /// The connection was closed by the peer, or failed.
+    ///
+    Dead,
+}
+
+///
+/// What a liveness probe sends to the peer before waiting for it.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LivenessProbe {
+    ///
+    /// A KeepAlive if this is the server side of a connection in the `Play` state and the protocol
+    /// version is known (see `CraftConnection::protocol_version`), which every client answers.
+    /// Otherwise nothing is sent, as in `Listen`.
+    ///
+    Default,
+    ///
+    /// Sends this packet, which must be valid for the current state.
+    ///
+    Packet { id: Id, body: Vec<u8> },
+    ///
+    /// Sends nothing, and only waits for data the peer sends by itself.
+    ///
+    Listen,
+}
+
+impl Default for LivenessProbe {
+    fn default() -> Self {
+        LivenessProbe::Default
+    }
+}
+
+impl<R, W> CraftConnection<R, W> {
+    fn liveness_probe_packet(&self, probe: &LivenessProbe) -> WriteResult<Option<(Id, Vec<u8>)>> {
+        match probe {
+            LivenessProbe::Default => {
+                let can_keep_alive = self.writer.state() == State::Play
+                    && self.writer.direction() == PacketDirection::ClientBound
+                    && self.protocol_version.and_then(keep_alive_ids).is_some();
+                if can_keep_alive {
+                    self.keep_alive_packet(probe_keep_alive_id()).map(Some)
+                } else {
+                    Ok(None)
+                }
+            }
+            LivenessProbe::Packet { id, body } => Ok(Some((*id, body.clone()))),
+            LivenessProbe::Listen => Ok(None),
+        }
+    }
+}
+
+// the answer to a probe is read like any other packet, so its id must not be mistaken for one sent
+// by a KeepAliveDriver; those are millisecond timestamps, which never have the top bit of an i32 set
+fn probe_keep_alive_id() -> i64 {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as i64)
+        .unwrap_or(0);
+    -1 - (millis & (i32::MAX as i64))
+}
+
+// whether a failed write means that the connection is gone, rather than a problem with the packet
+fn is_connection_failure(err: &WriteError) -> bool {
+    matches!(err, WriteError::IoFail { .. })
+}
+
+fn peek_result(result: std::io::Result<usize>) -> Option<Liveness> {
+    match result {
+        Ok(0) => Some(Liveness::Dead),
+        Ok(_) => Some(Liveness::Alive),
+        Err(err) if err.kind() == std::io::ErrorKind::Interrupted => None,
+        Err(err) if is_timeout(&err) => Some(Liveness::Silent),
+        Err(_) => Some(Liveness::Dead),
+    }
+}
+
+impl CraftTcpConnection {
+    ///
+    /// Checks whether the peer is still there, using `LivenessProbe::Default`. See
+    /// `probe_alive_with`.
+    ///
+    pub fn probe_alive(&mut self, timeout: Duration) -> WriteResult<Liveness> {
+        self.probe_alive_with(&LivenessProbe::Default, timeout)
+    }
+
+    ///
+    /// Sends the probe to the peer, and waits up to `timeout` for the peer to send anything. Data
+    /// which is received is not consumed, so the answer to the probe (and anything else the peer
+    /// sent) is read as usual afterwards. `KeepAliveDriver::on_response` ignores the answer to a
+    /// probe.
+    ///
+    /// Failing to send the probe because of an I/O error means the connection is `Dead`, and a send
+    /// which times out means it is `Silent`. Other failures (such as a packet rejected by
+    /// middleware) are returned as errors. The socket's read timeout is restored afterwards.
+    ///
+    pub fn probe_alive_with(
+        &mut self,
+        probe: &LivenessProbe,
+        timeout: Duration,
+    ) -> WriteResult<Liveness> {
+        if let Some((id, body)) = self.liveness_probe_packet(probe)? {
+            match self.writer.write_raw_untyped_packet(id, &body) {
+                Ok(()) => {}
+                Err(WriteError::TimedOut { .. }) => return Ok(Liveness::Silent),
+                Err(err) if is_connection_failure(&err) => return Ok(Liveness::Dead),
+                Err(err) => return Err(err),
+            }
+        }
+
+        if self.reader.buffered_len() > 0 || !self.reader.get_ref().buffer().is_empty() {
+            return Ok(Liveness::Alive);
+        }
+
+        let socket = self.writer.get_ref();
+        let previous_timeout = socket.read_timeout()?;
+        // a zero timeout is rejected by the socket
+        socket.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        let mut byte = [0u8; 1];
+        let liveness = loop {
+            if let Some(liveness) = peek_result(socket.peek(&mut byte)) {
+                break liveness;
+            }
+        };
+        socket.set_read_timeout(previous_timeout)?;
+        Ok(liveness)
+    }
+}
+
+#[cfg(feature = "tokio-io")]
+impl CraftTokioConnection {
+    ///
+    /// Same as `probe_alive`, but for an async connection.
+    ///
+    pub async fn probe_alive_async(&mut self, timeout: Duration) -> WriteResult<Liveness> {
+        self.probe_alive_with_async(&LivenessProbe::Default, timeout).await
+    }
+
+    ///
+    /// Same as `probe_alive_with`, but for an async connection. Only waiting for the peer is
+    /// limited by `timeout`, since an async write can not be abandoned part way through a packet.
+    ///
+    pub async fn probe_alive_with_async(
+        &mut self,
+        probe: &LivenessProbe,
+        timeout: Duration,
+    ) -> WriteResult<Liveness> {
+        if let Some((id, body)) = self.liveness_probe_packet(probe)? {
+            match self.writer.write_raw_untyped_packet_async(id, &body).await {
+                Ok(()) => {}
+                Err(err) if is_connection_failure(&err) => return Ok(Liveness::Dead),
+                Err(err) => return Err(err),
+            }
+        }
+
+        if self.reader.buffered_len() > 0 || !self.reader.get_ref().buffer().is_empty() {
+            return Ok(Liveness::Alive);
+        }
+
+        let socket = self.reader.get_mut().get_mut();
+        let mut byte = [0u8; 1];
+        let peeked = tokio::time::timeout(timeout, async {
+            loop {
+                if let Some(liveness) = peek_result(socket.peek(&mut byte).await) {
+                    return liveness;
+                }
+            }
+        })
+        .await;
+
+        Ok(peeked.unwrap_or(Liveness::Silent))
+    }
+}
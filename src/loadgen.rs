@@ -0,0 +1,275 @@
+use crate::writer::{CraftWriter, WriteResult};
+use mcproto_rs::protocol::{Id, PacketDirection, State};
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+use crate::writer::AsyncWriteAll;
+
+///
+/// A small, seedable pseudo random number generator (SplitMix64). It is not suitable for anything
+/// but generating test data, but produces the same sequence for the same seed on every platform,
+/// which is what makes generated traffic reproducible.
+///
+#[derive(Debug, Clone)]
+pub struct LoadRng {
+    state: u64,
+}
+
+impl LoadRng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    ///
+    /// A number in `0..bound`. `bound` must not be 0.
+    ///
+    pub fn below(&mut self, bound: u64) -> u64 {
+        debug_assert!(bound > 0);
+        self.next_u64() % bound
+    }
+
+    pub fn fill(&mut self, target: &mut [u8]) {
+        for chunk in target.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+///
+/// How the body sizes of one kind of packet in a `PacketMix` are distributed.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SizeDistribution {
+    Fixed(usize),
+    ///
+    /// Any size from `min` to `max` (inclusive), with equal probability.
+    ///
+    Uniform { min: usize, max: usize },
+    ///
+    /// One of the given sizes, with equal probability, such as sizes taken from a capture.
+    ///
+    Sampled(Vec<usize>),
+}
+
+impl SizeDistribution {
+    fn sample(&self, rng: &mut LoadRng) -> usize {
+        match self {
+            SizeDistribution::Fixed(size) => *size,
+            SizeDistribution::Uniform { min, max } => {
+                let (min, max) = (*min.min(max), *min.max(max));
+                min + rng.below((max - min + 1) as u64) as usize
+            }
+            SizeDistribution::Sampled(sizes) => {
+                if sizes.is_empty() {
+                    0
+                } else {
+                    sizes[rng.below(sizes.len() as u64) as usize]
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MixEntry {
+    id: Id,
+    weight: u32,
+    sizes: SizeDistribution,
+    // the fraction (0 to 1) of each body which is random, with the rest repeating bytes
+    entropy: f32,
+}
+
+///
+/// The kinds of packets a `LoadGenerator` produces, how often each kind is produced (relative to
+/// the others), and how large their bodies are.
+///
+/// Bodies are filler rather than valid packet data: part of each body is random and the rest
+/// repeats, so that compression behaves roughly like it does on real traffic.
+///
+#[derive(Debug, Clone, Default)]
+pub struct PacketMix {
+    entries: Vec<MixEntry>,
+    total_weight: u64,
+}
+
+impl PacketMix {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Adds a kind of packet. `weight` is how often it is produced relative to the other kinds, and
+    /// `entropy` is the fraction (from 0 to 1) of its body which does not compress.
+    ///
+    pub fn with(mut self, id: Id, weight: u32, sizes: SizeDistribution, entropy: f32) -> Self {
+        self.total_weight += weight as u64;
+        self.entries.push(MixEntry {
+            id,
+            weight,
+            sizes,
+            entropy: entropy.clamp(0.0, 1.0),
+        });
+        self
+    }
+
+    ///
+    /// A mix resembling the clientbound `Play` traffic of a busy 1.16.5 server: mostly small entity
+    /// movement packets, with occasional chunks, block changes, chat and keep-alives. The packet
+    /// ids are those of protocol version 754.
+    ///
+    pub fn play_server_preset() -> Self {
+        let play = move |id| Id {
+            id,
+            state: State::Play,
+            direction: PacketDirection::ClientBound,
+        };
+
+        Self::new()
+            // entity position
+            .with(play(0x27), 300, SizeDistribution::Uniform { min: 9, max: 12 }, 0.8)
+            // entity position and rotation
+            .with(play(0x28), 250, SizeDistribution::Uniform { min: 11, max: 14 }, 0.8)
+            // entity head look
+            .with(play(0x3A), 200, SizeDistribution::Uniform { min: 2, max: 6 }, 0.8)
+            // entity velocity
+            .with(play(0x46), 100, SizeDistribution::Uniform { min: 7, max: 11 }, 0.8)
+            // entity metadata
+            .with(play(0x44), 60, SizeDistribution::Uniform { min: 4, max: 120 }, 0.4)
+            // block change
+            .with(play(0x0B), 40, SizeDistribution::Uniform { min: 9, max: 11 }, 0.6)
+            // time update
+            .with(play(0x4E), 20, SizeDistribution::Fixed(16), 0.5)
+            // chat message
+            .with(play(0x0E), 10, SizeDistribution::Uniform { min: 40, max: 400 }, 0.3)
+            // chunk data
+            .with(play(0x20), 5, SizeDistribution::Uniform { min: 2_000, max: 24_000 }, 0.25)
+            // keep alive
+            .with(play(0x1F), 1, SizeDistribution::Fixed(8), 1.0)
+    }
+
+    fn pick(&self, rng: &mut LoadRng) -> Option<&MixEntry> {
+        if self.total_weight == 0 {
+            return None;
+        }
+
+        let mut at = rng.below(self.total_weight);
+        for entry in &self.entries {
+            if at < entry.weight as u64 {
+                return Some(entry);
+            }
+            at -= entry.weight as u64;
+        }
+
+        None
+    }
+}
+
+///
+/// What a `LoadGenerator` wrote.
+///
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct LoadReport {
+    pub packets: u64,
+    ///
+    /// The total size of the packet bodies written, before framing, compression and encryption.
+    ///
+    pub body_bytes: u64,
+}
+
+///
+/// Produces a reproducible stream of packets following a `PacketMix`, and writes them through a
+/// `CraftWriter`. Two generators with the same mix and seed produce exactly the same packets, so
+/// the transport-level cost of writing them (framing, compression, encryption and I/O) can be
+/// benchmarked end to end, and compared between changes.
+///
+#[derive(Debug, Clone)]
+pub struct LoadGenerator {
+    mix: PacketMix,
+    rng: LoadRng,
+    body: Vec<u8>,
+}
+
+impl LoadGenerator {
+    pub fn new(mix: PacketMix, seed: u64) -> Self {
+        Self {
+            mix,
+            rng: LoadRng::new(seed),
+            body: Vec::new(),
+        }
+    }
+
+    ///
+    /// Generates the next packet. Returns `None` if the mix is empty.
+    ///
+    pub fn next_packet(&mut self) -> Option<(Id, &[u8])> {
+        let entry = self.mix.pick(&mut self.rng)?;
+        let size = entry.sizes.sample(&mut self.rng);
+        let random_len = ((size as f32) * entry.entropy) as usize;
+
+        self.body.clear();
+        self.body.resize(size, 0);
+        self.rng.fill(&mut self.body[..random_len]);
+        let filler = (self.rng.next_u64() & 0xFF) as u8;
+        for byte in &mut self.body[random_len..] {
+            *byte = filler;
+        }
+
+        Some((entry.id, &self.body))
+    }
+
+    ///
+    /// Generates `count` packets, and writes each of them to `writer`.
+    ///
+    pub fn write_to<W>(&mut self, writer: &mut CraftWriter<W>, count: u64) -> WriteResult<LoadReport>
+    where
+        W: std::io::Write,
+    {
+        let mut report = LoadReport::default();
+        for _ in 0..count {
+            let (id, body) = match self.next_packet() {
+                Some(packet) => packet,
+                None => break,
+            };
+
+            writer.write_raw_untyped_packet(id, body)?;
+            report.packets += 1;
+            report.body_bytes += body.len() as u64;
+        }
+
+        Ok(report)
+    }
+
+    ///
+    /// Same as `write_to`, but writes to an async stream.
+    ///
+    #[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+    pub async fn write_to_async<W>(
+        &mut self,
+        writer: &mut CraftWriter<W>,
+        count: u64,
+    ) -> WriteResult<LoadReport>
+    where
+        W: AsyncWriteAll,
+    {
+        let mut report = LoadReport::default();
+        for _ in 0..count {
+            let (id, body) = match self.next_packet() {
+                Some(packet) => packet,
+                None => break,
+            };
+
+            writer.write_raw_untyped_packet_async(id, body).await?;
+            report.packets += 1;
+            report.body_bytes += body.len() as u64;
+        }
+
+        Ok(report)
+    }
+}
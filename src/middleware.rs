@@ -0,0 +1,156 @@
+use mcproto_rs::protocol::{Id, PacketDirection, State};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+///
+/// The decision made by a `PacketMiddleware` about a packet which was read or is about to be
+/// written.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacketVerdict {
+    ///
+    /// Keep the packet unchanged.
+    ///
+    Pass,
+    ///
+    /// Keep the packet with the same id, but with this body instead.
+    ///
+    Replace(Vec<u8>),
+    ///
+    /// Discard the packet. A rejected packet which was read is skipped, and the reader continues
+    /// with the next packet. A rejected packet which was about to be written is not written, and the
+    /// write fails with `WriteError::Rejected`.
+    ///
+    Reject,
+}
+
+///
+/// A stage in the packet pipeline of a `CraftReader`/`CraftWriter`. Middleware can observe
+/// packets (for capture or metrics), filter them, or rewrite their bodies (for sanitization or
+/// remapping).
+///
+/// Packet bodies are passed after decryption and decompression (when reading), or before
+/// compression and encryption (when writing), and do not include the packet id.
+///
+/// All functions have a default implementation which does nothing.
+///
+pub trait PacketMiddleware: Send {
+    ///
+    /// Called for every packet read, before it is returned to the caller.
+    ///
+    fn on_read(&mut self, _id: Id, _body: &[u8]) -> PacketVerdict {
+        PacketVerdict::Pass
+    }
+
+    ///
+    /// Called for every packet written, after it has been serialized.
+    ///
+    fn on_write(&mut self, _id: Id, _body: &[u8]) -> PacketVerdict {
+        PacketVerdict::Pass
+    }
+
+    ///
+    /// Called when `set_state` is called on a reader or writer. `direction` is the direction of the
+    /// packets that reader or writer handles, so middleware shared by both halves of a connection is
+    /// called once for each half.
+    ///
+    fn on_state_change(&mut self, _direction: PacketDirection, _state: State) {}
+}
+
+///
+/// An ordered list of `PacketMiddleware`. Each middleware sees the packet as it was left by the
+/// middleware before it, and a rejected packet is not passed to the rest of the chain.
+///
+/// A chain is a handle which can be cloned, and every clone refers to the same list. Both halves
+/// of a `CraftConnection` share one chain, so middleware added through either half (or through the
+/// connection) applies to packets in both directions.
+///
+#[derive(Clone, Default)]
+pub struct MiddlewareChain {
+    inner: Arc<Mutex<Vec<Box<dyn PacketMiddleware>>>>,
+}
+
+impl MiddlewareChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Adds a middleware to the end of the chain.
+    ///
+    pub fn push<M>(&self, middleware: M)
+    where
+        M: PacketMiddleware + 'static,
+    {
+        self.lock().push(Box::new(middleware));
+    }
+
+    ///
+    /// Removes every middleware from the chain.
+    ///
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    ///
+    /// Whether `self` and `other` are handles to the same chain.
+    ///
+    pub fn is_same_chain(&self, other: &MiddlewareChain) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+
+    // moves the middleware in other to the end of this chain, unless they're already the same chain
+    pub(crate) fn absorb(&self, other: &MiddlewareChain) {
+        if !self.is_same_chain(other) {
+            let mut moved = std::mem::take(&mut *other.lock());
+            self.lock().append(&mut moved);
+        }
+    }
+
+    pub(crate) fn on_read(&self, id: Id, body: &[u8]) -> PacketVerdict {
+        self.run(id, body, move |middleware, id, body| middleware.on_read(id, body))
+    }
+
+    pub(crate) fn on_write(&self, id: Id, body: &[u8]) -> PacketVerdict {
+        self.run(id, body, move |middleware, id, body| middleware.on_write(id, body))
+    }
+
+    pub(crate) fn on_state_change(&self, direction: PacketDirection, state: State) {
+        for middleware in self.lock().iter_mut() {
+            middleware.on_state_change(direction, state);
+        }
+    }
+
+    fn run<F>(&self, id: Id, body: &[u8], mut f: F) -> PacketVerdict
+    where
+        F: FnMut(&mut dyn PacketMiddleware, Id, &[u8]) -> PacketVerdict,
+    {
+        let mut chain = self.lock();
+        let mut replaced: Option<Vec<u8>> = None;
+        for middleware in chain.iter_mut() {
+            let current = replaced.as_deref().unwrap_or(body);
+            match f(middleware.as_mut(), id, current) {
+                PacketVerdict::Pass => {}
+                PacketVerdict::Replace(next) => replaced = Some(next),
+                PacketVerdict::Reject => return PacketVerdict::Reject,
+            }
+        }
+
+        match replaced {
+            Some(body) => PacketVerdict::Replace(body),
+            None => PacketVerdict::Pass,
+        }
+    }
+
+    // a middleware which panicked leaves the lock poisoned, but the list itself is still intact
+    fn lock(&self) -> MutexGuard<'_, Vec<Box<dyn PacketMiddleware>>> {
+        self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
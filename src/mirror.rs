@@ -0,0 +1,180 @@
+use crate::middleware::{PacketMiddleware, PacketVerdict};
+use mcproto_rs::protocol::Id;
+use std::io;
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+
+///
+/// Whether a mirrored frame was read from or written to the wrapped stream.
+///
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum FrameDirection {
+    Read,
+    Written,
+}
+
+///
+/// A packet which was read or written by a reader or writer with a `Mirror` installed. The body is
+/// decompressed and decrypted, and does not include the packet id.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct MirroredFrame<'a> {
+    pub direction: FrameDirection,
+    pub id: Id,
+    pub body: &'a [u8],
+}
+
+///
+/// An owned copy of a `MirroredFrame`, which can be sent to another thread.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct OwnedMirroredFrame {
+    pub direction: FrameDirection,
+    pub id: Id,
+    pub body: Vec<u8>,
+}
+
+impl<'a> MirroredFrame<'a> {
+    pub fn to_owned_frame(&self) -> OwnedMirroredFrame {
+        OwnedMirroredFrame {
+            direction: self.direction,
+            id: self.id,
+            body: self.body.to_vec(),
+        }
+    }
+}
+
+///
+/// The destination of mirrored frames.
+///
+/// If the sink cannot accept a frame right now, it should return an error with the kind
+/// `ErrorKind::WouldBlock`, and the frame is dropped (and counted, see `Mirror::dropped_frames`).
+/// Any other error disables the mirror (see `Mirror::take_error`). In no case does the mirror
+/// affect the connection it is installed on.
+///
+/// This is implemented for closures, and for `SyncSender<OwnedMirroredFrame>`, which drops frames
+/// when the channel is full.
+///
+pub trait MirrorSink: Send {
+    fn mirror_frame(&mut self, frame: &MirroredFrame<'_>) -> io::Result<()>;
+}
+
+impl<F> MirrorSink for F
+where
+    F: FnMut(&MirroredFrame<'_>) -> io::Result<()> + Send,
+{
+    fn mirror_frame(&mut self, frame: &MirroredFrame<'_>) -> io::Result<()> {
+        self(frame)
+    }
+}
+
+impl MirrorSink for SyncSender<OwnedMirroredFrame> {
+    fn mirror_frame(&mut self, frame: &MirroredFrame<'_>) -> io::Result<()> {
+        match self.try_send(frame.to_owned_frame()) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(TrySendError::Disconnected(_)) => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+}
+
+///
+/// Copies every packet read and/or written by a connection to a secondary `MirrorSink`, such as a
+/// file or a channel, without affecting the connection itself. This is useful for live debugging
+/// and for sending shadow traffic to a test server.
+///
+/// A `Mirror` is a `PacketMiddleware`, so it is installed by adding it to the middleware chain of a
+/// `CraftReader`, a `CraftWriter`, or a `CraftConnection`. Packets are mirrored as they are seen at
+/// the mirror's position in the chain. A `Mirror` is a handle which can be cloned, and every clone
+/// feeds the same sink.
+///
+#[derive(Clone)]
+pub struct Mirror {
+    shared: Arc<Mutex<MirrorShared>>,
+}
+
+struct MirrorShared {
+    sink: Box<dyn MirrorSink>,
+    dropped_frames: u64,
+    error: Option<io::Error>,
+    failed: bool,
+}
+
+impl Mirror {
+    pub fn new<S>(sink: S) -> Self
+    where
+        S: MirrorSink + 'static,
+    {
+        Self {
+            shared: Arc::new(Mutex::new(MirrorShared {
+                sink: Box::new(sink),
+                dropped_frames: 0,
+                error: None,
+                failed: false,
+            })),
+        }
+    }
+
+    ///
+    /// The number of frames which were dropped because the sink was full.
+    ///
+    pub fn dropped_frames(&self) -> u64 {
+        self.with_shared(|shared| shared.dropped_frames).unwrap_or(0)
+    }
+
+    ///
+    /// Whether the sink returned an error, after which no more frames are mirrored.
+    ///
+    pub fn is_failed(&self) -> bool {
+        self.with_shared(|shared| shared.failed).unwrap_or(true)
+    }
+
+    ///
+    /// Takes the error which disabled this mirror, if there was one.
+    ///
+    pub fn take_error(&self) -> Option<io::Error> {
+        self.with_shared(|shared| shared.error.take()).flatten()
+    }
+
+    fn mirror(&self, direction: FrameDirection, id: Id, body: &[u8]) {
+        self.with_shared(move |shared| {
+            if shared.failed {
+                return;
+            }
+
+            let frame = MirroredFrame {
+                direction,
+                id,
+                body,
+            };
+            match shared.sink.mirror_frame(&frame) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => shared.dropped_frames += 1,
+                Err(err) => {
+                    shared.failed = true;
+                    shared.error = Some(err);
+                }
+            }
+        });
+    }
+
+    // a poisoned lock means a sink panicked, in which case the mirror is treated as failed
+    fn with_shared<F, T>(&self, f: F) -> Option<T>
+    where
+        F: FnOnce(&mut MirrorShared) -> T,
+    {
+        self.shared.lock().ok().map(move |mut shared| f(&mut shared))
+    }
+}
+
+impl PacketMiddleware for Mirror {
+    fn on_read(&mut self, id: Id, body: &[u8]) -> PacketVerdict {
+        self.mirror(FrameDirection::Read, id, body);
+        PacketVerdict::Pass
+    }
+
+    fn on_write(&mut self, id: Id, body: &[u8]) -> PacketVerdict {
+        self.mirror(FrameDirection::Written, id, body);
+        PacketVerdict::Pass
+    }
+}
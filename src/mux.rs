@@ -0,0 +1,696 @@
+use crate::connection::CraftConnection;
+use crate::reader::CraftReader;
+use crate::writer::CraftWriter;
+use mcproto_rs::protocol::{PacketDirection, State};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+// Every frame on the physical stream is a 4 byte big-endian channel id, a 1 byte frame kind, and a
+// 4 byte big-endian payload length, followed by the payload. Only data frames have a payload.
+
+const FRAME_HEADER_SIZE: usize = 9;
+const FRAME_OPEN: u8 = 0;
+const FRAME_DATA: u8 = 1;
+const FRAME_CLOSE: u8 = 2;
+// larger writes are split into several frames, so no single frame has to be buffered whole
+const MAX_FRAME_PAYLOAD: usize = 64 * 1024;
+
+pub const DEFAULT_MAX_CHANNEL_BUFFER: usize = 4 * MAX_FRAME_PAYLOAD;
+pub const DEFAULT_MAX_MUX_BUFFER: usize = 16 * 1024 * 1024;
+pub const DEFAULT_MAX_PENDING_ACCEPT: usize = 64;
+
+///
+/// Which end of the physical stream a `Mux` is. The two ends must use different roles, which makes
+/// sure the channel ids allocated by each end never collide.
+///
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum MuxRole {
+    Initiator,
+    Responder,
+}
+
+impl MuxRole {
+    // the initiator allocates odd channel ids, and the responder even ones
+    fn first_channel(&self) -> u32 {
+        match self {
+            MuxRole::Initiator => 1,
+            MuxRole::Responder => 2,
+        }
+    }
+
+    fn allocates(&self, channel: u32) -> bool {
+        channel % 2 == self.first_channel() % 2
+    }
+}
+
+///
+/// A logical connection carried over a `Mux`.
+///
+pub type MuxConnection = CraftConnection<MuxReadHalf, MuxWriteHalf>;
+
+///
+/// Carries many logical connections (channels) over a single physical stream, such as the link
+/// between a proxy and one of its backend servers, so players do not each need their own TCP
+/// connection to the backend.
+///
+/// Each channel is a normal `CraftConnection` (a `MuxConnection`), with its own state, compression
+/// and middleware, and channel-id framing is added beneath the normal packet framing. Either end
+/// can open a channel using `open_channel`, which the other end receives from `accept_channel`.
+///
+/// A `Mux` is a handle which can be cloned, and channels can be used from different threads. The
+/// physical stream is read by whichever thread needs data first, which hands data for other
+/// channels to their buffers. Only blocking I/O is supported.
+///
+/// The buffers are limited, since one slow channel must not stop the others: a channel which has
+/// more than `set_max_channel_buffer` bytes waiting to be read is reset, which fails its reads and
+/// closes it on the other end. Once all channels together have `set_max_mux_buffer` bytes waiting,
+/// a channel which receives more data is reset the same way. Likewise, channels which the other
+/// end opens while `set_max_pending_accept` channels are waiting for `accept_channel` are closed
+/// right away.
+///
+#[derive(Clone)]
+pub struct Mux {
+    shared: Arc<MuxShared>,
+}
+
+struct MuxShared {
+    state: Mutex<MuxState>,
+    readable: Condvar,
+    writer: Mutex<Box<dyn io::Write + Send>>,
+}
+
+struct MuxState {
+    // None while a thread is reading a frame from the physical stream
+    reader: Option<Box<dyn io::Read + Send>>,
+    role: MuxRole,
+    channels: HashMap<u32, ChannelBuffer>,
+    pending_accept: VecDeque<u32>,
+    // channels which are closed on the other end once the state is unlocked
+    to_close: Vec<u32>,
+    next_channel: u32,
+    max_channel_buffer: usize,
+    max_mux_buffer: usize,
+    max_pending_accept: usize,
+    // the number of bytes waiting to be read, over all channels
+    buffered: usize,
+    eof: bool,
+    failure: Option<(io::ErrorKind, String)>,
+}
+
+#[derive(Default)]
+struct ChannelBuffer {
+    data: VecDeque<u8>,
+    closed: bool,
+    overflowed: bool,
+}
+
+struct Frame {
+    channel: u32,
+    kind: u8,
+    payload: Vec<u8>,
+}
+
+impl Mux {
+    pub fn new<R, W>(read: R, write: W, role: MuxRole) -> Self
+    where
+        R: io::Read + Send + 'static,
+        W: io::Write + Send + 'static,
+    {
+        Self {
+            shared: Arc::new(MuxShared {
+                state: Mutex::new(MuxState {
+                    reader: Some(Box::new(read)),
+                    role,
+                    channels: HashMap::new(),
+                    pending_accept: VecDeque::new(),
+                    to_close: Vec::new(),
+                    next_channel: role.first_channel(),
+                    max_channel_buffer: DEFAULT_MAX_CHANNEL_BUFFER,
+                    max_mux_buffer: DEFAULT_MAX_MUX_BUFFER,
+                    max_pending_accept: DEFAULT_MAX_PENDING_ACCEPT,
+                    buffered: 0,
+                    eof: false,
+                    failure: None,
+                }),
+                readable: Condvar::new(),
+                writer: Mutex::new(Box::new(write)),
+            }),
+        }
+    }
+
+    ///
+    /// Sets how many bytes which were received for a channel can wait to be read before the
+    /// channel is reset. Defaults to `DEFAULT_MAX_CHANNEL_BUFFER`.
+    ///
+    pub fn set_max_channel_buffer(&self, max_channel_buffer: usize) {
+        self.shared.lock_state().max_channel_buffer = max_channel_buffer;
+    }
+
+    ///
+    /// Sets how many bytes which were received for all channels together can wait to be read
+    /// before channels which receive more data are reset. Defaults to `DEFAULT_MAX_MUX_BUFFER`.
+    ///
+    pub fn set_max_mux_buffer(&self, max_mux_buffer: usize) {
+        self.shared.lock_state().max_mux_buffer = max_mux_buffer;
+    }
+
+    ///
+    /// Sets how many channels opened by the other end can wait for `accept_channel` before more are
+    /// refused. Defaults to `DEFAULT_MAX_PENDING_ACCEPT`.
+    ///
+    pub fn set_max_pending_accept(&self, max_pending_accept: usize) {
+        self.shared.lock_state().max_pending_accept = max_pending_accept;
+    }
+
+    ///
+    /// Opens a new channel. The returned connection reads clientbound packets, since the end which
+    /// opens a channel acts as the client.
+    ///
+    pub fn open_channel(&self) -> io::Result<MuxConnection> {
+        let channel = {
+            let mut state = self.shared.lock_state();
+            let channel = state.next_channel;
+            state.next_channel = channel.checked_add(2).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::Other, "mux channel ids are exhausted")
+            })?;
+            state.channels.insert(channel, ChannelBuffer::default());
+            channel
+        };
+
+        self.shared.write_frame(channel, FRAME_OPEN, &[])?;
+        Ok(self.connection(channel, PacketDirection::ClientBound))
+    }
+
+    ///
+    /// Waits for the other end to open a channel. The returned connection reads serverbound
+    /// packets.
+    ///
+    pub fn accept_channel(&self) -> io::Result<MuxConnection> {
+        let channel = self.shared.pump(|state| {
+            if let Some(channel) = state.pending_accept.pop_front() {
+                Some(Ok(channel))
+            } else if state.eof {
+                Some(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "mux stream was closed",
+                )))
+            } else {
+                None
+            }
+        })?;
+
+        Ok(self.connection(channel, PacketDirection::ServerBound))
+    }
+
+    fn connection(&self, channel: u32, read_direction: PacketDirection) -> MuxConnection {
+        CraftConnection::from_split(
+            CraftReader::wrap_with_state(
+                MuxReadHalf {
+                    shared: self.shared.clone(),
+                    channel,
+                },
+                read_direction,
+                State::Handshaking,
+            ),
+            CraftWriter::wrap_with_state(
+                MuxWriteHalf {
+                    shared: self.shared.clone(),
+                    channel,
+                },
+                read_direction.opposite(),
+                State::Handshaking,
+            ),
+        )
+    }
+}
+
+impl MuxShared {
+    // Runs poll whenever the state changes, until it returns a result. If no other thread is reading
+    // the physical stream, this thread reads the next frame itself.
+    fn pump<T, F>(&self, mut poll: F) -> io::Result<T>
+    where
+        F: FnMut(&mut MuxState) -> Option<io::Result<T>>,
+    {
+        let mut state = self.lock_state();
+        loop {
+            if let Some(result) = poll(&mut state) {
+                return result;
+            }
+
+            if let Some((kind, message)) = &state.failure {
+                return Err(io::Error::new(*kind, message.clone()));
+            }
+
+            match state.reader.take() {
+                Some(mut reader) => {
+                    drop(state);
+                    let frame = read_frame(&mut reader);
+                    state = self.lock_state();
+                    state.reader = Some(reader);
+                    match frame {
+                        Ok(Some(frame)) => state.dispatch(frame),
+                        Ok(None) => state.eof = true,
+                        Err(err) => state.failure = Some((err.kind(), err.to_string())),
+                    }
+                    self.readable.notify_all();
+
+                    if !state.to_close.is_empty() {
+                        let to_close = std::mem::take(&mut state.to_close);
+                        drop(state);
+                        for channel in to_close {
+                            // a failed write fails the next write of every channel anyway
+                            let _ = self.write_frame(channel, FRAME_CLOSE, &[]);
+                        }
+                        state = self.lock_state();
+                    }
+                }
+                None => {
+                    state = self
+                        .readable
+                        .wait(state)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner())
+                }
+            }
+        }
+    }
+
+    fn write_frame(&self, channel: u32, kind: u8, payload: &[u8]) -> io::Result<()> {
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        header[..4].copy_from_slice(&channel.to_be_bytes());
+        header[4] = kind;
+        header[5..].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+
+        // the lock is held for the whole frame, so frames from different channels never interleave
+        let mut writer = self.writer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        writer.write_all(&header)?;
+        writer.write_all(payload)?;
+        writer.flush()
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, MuxState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl MuxState {
+    fn dispatch(&mut self, frame: Frame) {
+        match frame.kind {
+            FRAME_OPEN => self.open(frame.channel),
+            // data for a channel whose read half was dropped (or which was reset) is discarded
+            FRAME_DATA => {
+                let max_channel_buffer = self.max_channel_buffer;
+                let mux_full = self.buffered + frame.payload.len() > self.max_mux_buffer;
+                let buffer = match self.channels.get_mut(&frame.channel) {
+                    Some(buffer) if !buffer.overflowed => buffer,
+                    _ => return,
+                };
+
+                if mux_full || buffer.data.len() + frame.payload.len() > max_channel_buffer {
+                    self.buffered -= buffer.data.len();
+                    buffer.data = VecDeque::new();
+                    buffer.overflowed = true;
+                    self.to_close.push(frame.channel);
+                } else {
+                    self.buffered += frame.payload.len();
+                    buffer.data.extend(frame.payload);
+                }
+            }
+            FRAME_CLOSE => {
+                if let Some(buffer) = self.channels.get_mut(&frame.channel) {
+                    buffer.closed = true;
+                }
+            }
+            other => {
+                self.failure = Some((
+                    io::ErrorKind::InvalidData,
+                    format!("unknown mux frame kind {}", other),
+                ))
+            }
+        }
+    }
+
+    fn open(&mut self, channel: u32) {
+        if channel == 0 || self.role.allocates(channel) || self.channels.contains_key(&channel) {
+            self.failure = Some((
+                io::ErrorKind::InvalidData,
+                format!("other end of mux opened invalid channel {}", channel),
+            ));
+            return;
+        }
+
+        if self.pending_accept.len() >= self.max_pending_accept {
+            self.to_close.push(channel);
+            return;
+        }
+
+        self.channels.insert(channel, ChannelBuffer::default());
+        self.pending_accept.push_back(channel);
+    }
+}
+
+// returns None if the stream ended cleanly between frames
+fn read_frame<R>(reader: &mut R) -> io::Result<Option<Frame>>
+where
+    R: io::Read,
+{
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    if let Err(err) = reader.read_exact(&mut header[..1]) {
+        return if err.kind() == io::ErrorKind::UnexpectedEof {
+            Ok(None)
+        } else {
+            Err(err)
+        };
+    }
+    reader.read_exact(&mut header[1..])?;
+
+    let mut channel = [0u8; 4];
+    channel.copy_from_slice(&header[..4]);
+    let mut len = [0u8; 4];
+    len.copy_from_slice(&header[5..]);
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_PAYLOAD {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "mux frame is too large"));
+    }
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(Frame {
+        channel: u32::from_be_bytes(channel),
+        kind: header[4],
+        payload,
+    }))
+}
+
+///
+/// The reading half of a mux channel. Dropping it discards any data which arrives for the channel
+/// afterwards.
+///
+pub struct MuxReadHalf {
+    shared: Arc<MuxShared>,
+    channel: u32,
+}
+
+impl MuxReadHalf {
+    pub fn channel_id(&self) -> u32 {
+        self.channel
+    }
+}
+
+impl io::Read for MuxReadHalf {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let channel = self.channel;
+        self.shared.pump(move |state| {
+            let eof = state.eof;
+            let buffer = match state.channels.get_mut(&channel) {
+                Some(buffer) => buffer,
+                None => return Some(Ok(0)),
+            };
+
+            if buffer.overflowed {
+                Some(Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "mux channel was reset because its buffer is full",
+                )))
+            } else if !buffer.data.is_empty() {
+                let n = buffer.data.len().min(buf.len());
+                for (to, from) in buf.iter_mut().zip(buffer.data.drain(..n)) {
+                    *to = from;
+                }
+                state.buffered -= n;
+                Some(Ok(n))
+            } else if buffer.closed || eof {
+                Some(Ok(0))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+impl Drop for MuxReadHalf {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock_state();
+        if let Some(buffer) = state.channels.remove(&self.channel) {
+            state.buffered -= buffer.data.len();
+        }
+    }
+}
+
+///
+/// The writing half of a mux channel. Every call to `write` sends one frame, and dropping it tells
+/// the other end that the channel is closed.
+///
+pub struct MuxWriteHalf {
+    shared: Arc<MuxShared>,
+    channel: u32,
+}
+
+impl MuxWriteHalf {
+    pub fn channel_id(&self) -> u32 {
+        self.channel
+    }
+}
+
+impl io::Write for MuxWriteHalf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let len = buf.len().min(MAX_FRAME_PAYLOAD);
+        self.shared.write_frame(self.channel, FRAME_DATA, &buf[..len])?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for MuxWriteHalf {
+    fn drop(&mut self) {
+        let _ = self.shared.write_frame(self.channel, FRAME_CLOSE, &[]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::CraftSyncReader;
+    use crate::test_util::tcp_pair;
+    use crate::wrapper::CraftWrapper;
+    use mcproto_rs::protocol::Id;
+    use std::io::{Cursor, Read};
+    use std::net::TcpStream;
+
+    fn mux(stream: TcpStream, role: MuxRole) -> Mux {
+        Mux::new(stream.try_clone().unwrap(), stream, role)
+    }
+
+    fn frame(channel: u32, kind: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&channel.to_be_bytes());
+        frame.push(kind);
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[derive(Clone, Default)]
+    struct Written(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Written {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Written {
+        fn contains(&self, frame: &[u8]) -> bool {
+            let written = self.0.lock().unwrap();
+            written.windows(frame.len()).any(|window| window == frame)
+        }
+    }
+
+    // a responder which reads the frames, as if the initiator sent them
+    fn scripted(frames: &[Vec<u8>]) -> (Mux, Written) {
+        let written = Written::default();
+        let mux = Mux::new(
+            Cursor::new(frames.concat()),
+            written.clone(),
+            MuxRole::Responder,
+        );
+        (mux, written)
+    }
+
+    fn read_half(connection: MuxConnection) -> MuxReadHalf {
+        connection.into_split().0.into_inner()
+    }
+
+    #[test]
+    fn channels_round_trip() {
+        let (initiator_stream, responder_stream) = tcp_pair();
+        let initiator = mux(initiator_stream, MuxRole::Initiator);
+        let responder = mux(responder_stream, MuxRole::Responder);
+        let serverbound = Id {
+            id: 0x00,
+            state: State::Handshaking,
+            direction: PacketDirection::ServerBound,
+        };
+        let clientbound = Id {
+            direction: PacketDirection::ClientBound,
+            ..serverbound
+        };
+
+        let mut first = initiator.open_channel().unwrap();
+        let mut second = initiator.open_channel().unwrap();
+        second
+            .write_raw_untyped_packet(serverbound, b"second")
+            .unwrap();
+        first
+            .write_raw_untyped_packet(serverbound, b"first")
+            .unwrap();
+
+        let mut accepted_first = responder.accept_channel().unwrap();
+        let mut accepted_second = responder.accept_channel().unwrap();
+        let (id, body) = accepted_first.read_raw_untyped_packet().unwrap().unwrap();
+        assert_eq!((id, body), (serverbound, &b"first"[..]));
+        let (id, body) = accepted_second.read_raw_untyped_packet().unwrap().unwrap();
+        assert_eq!((id, body), (serverbound, &b"second"[..]));
+
+        accepted_first
+            .write_raw_untyped_packet(clientbound, b"reply")
+            .unwrap();
+        let (id, body) = first.read_raw_untyped_packet().unwrap().unwrap();
+        assert_eq!((id, body), (clientbound, &b"reply"[..]));
+
+        drop(first);
+        assert!(accepted_first.read_raw_untyped_packet().unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_channels_with_the_local_parity() {
+        let (mux, _) = scripted(&[frame(2, FRAME_OPEN, &[])]);
+        let err = mux.accept_channel().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_channels_which_are_already_open() {
+        let (mux, _) = scripted(&[frame(1, FRAME_OPEN, &[]), frame(1, FRAME_OPEN, &[])]);
+        let _channel = mux.accept_channel().unwrap();
+        let err = mux.accept_channel().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn refuses_channels_beyond_the_accept_backlog() {
+        let (mux, written) = scripted(&[
+            frame(1, FRAME_OPEN, &[]),
+            frame(3, FRAME_OPEN, &[]),
+            frame(5, FRAME_OPEN, &[]),
+            frame(1, FRAME_DATA, &[7]),
+        ]);
+        mux.set_max_pending_accept(1);
+
+        // reading channel 1 reads the other frames on the way
+        let mut first = read_half(mux.accept_channel().unwrap());
+        let mut buf = [0u8; 1];
+        first.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [7]);
+
+        assert!(written.contains(&frame(5, FRAME_CLOSE, &[])));
+        assert_eq!(read_half(mux.accept_channel().unwrap()).channel_id(), 3);
+        let err = mux.accept_channel().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn resets_channels_which_overflow_their_buffer() {
+        let (mux, written) = scripted(&[
+            frame(1, FRAME_OPEN, &[]),
+            frame(3, FRAME_OPEN, &[]),
+            frame(1, FRAME_DATA, &[1, 2, 3]),
+            frame(1, FRAME_DATA, &[4, 5, 6]),
+            frame(3, FRAME_DATA, &[7]),
+        ]);
+        mux.set_max_channel_buffer(4);
+
+        // the write half is kept, since dropping it would close the channel too
+        let (first, _first_writer) = mux.accept_channel().unwrap().into_split();
+        let mut first = first.into_inner();
+        let mut second = read_half(mux.accept_channel().unwrap());
+        // reading channel 3 buffers the data of channel 1 on the way
+        let mut buf = [0u8; 1];
+        second.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [7]);
+
+        let err = first.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert!(written.contains(&frame(1, FRAME_CLOSE, &[])));
+    }
+
+    #[test]
+    fn resets_channels_once_the_mux_buffer_is_full() {
+        let (mux, written) = scripted(&[
+            frame(1, FRAME_OPEN, &[]),
+            frame(3, FRAME_OPEN, &[]),
+            frame(5, FRAME_OPEN, &[]),
+            frame(1, FRAME_DATA, &[1, 2, 3]),
+            frame(3, FRAME_DATA, &[4, 5]),
+            frame(5, FRAME_DATA, &[6]),
+        ]);
+        mux.set_max_mux_buffer(4);
+
+        let (first, _first_writer) = mux.accept_channel().unwrap().into_split();
+        let mut first = first.into_inner();
+        let (second, _second_writer) = mux.accept_channel().unwrap().into_split();
+        let mut second = second.into_inner();
+        let mut third = read_half(mux.accept_channel().unwrap());
+        // reading channel 5 buffers the data of the other channels on the way, and channel 3 does
+        // not fit next to the data of channel 1
+        let mut buf = [0u8; 3];
+        third.read_exact(&mut buf[..1]).unwrap();
+        assert_eq!(buf[0], 6);
+
+        first.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+        let err = second.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert!(written.contains(&frame(3, FRAME_CLOSE, &[])));
+    }
+
+    #[test]
+    fn large_writes_are_split_into_frames() {
+        let (initiator_stream, responder_stream) = tcp_pair();
+        let initiator = mux(initiator_stream, MuxRole::Initiator);
+        let responder = mux(responder_stream, MuxRole::Responder);
+        let id = Id {
+            id: 0x00,
+            state: State::Handshaking,
+            direction: PacketDirection::ServerBound,
+        };
+        let body = vec![9u8; 3 * MAX_FRAME_PAYLOAD];
+
+        let mut opened = initiator.open_channel().unwrap();
+        let mut accepted = responder.accept_channel().unwrap();
+        let writing = std::thread::spawn(move || {
+            opened.write_raw_untyped_packet(id, &body).unwrap();
+            opened
+        });
+        let (read_id, read_body) = accepted.read_raw_untyped_packet().unwrap().unwrap();
+        assert_eq!(read_id, id);
+        assert_eq!(read_body, &vec![9u8; 3 * MAX_FRAME_PAYLOAD][..]);
+        writing.join().unwrap();
+    }
+}
@@ -0,0 +1,426 @@
+use crate::secure::{SecureLink, SecureRole};
+use snow::{Builder, HandshakeState};
+#[cfg(feature = "backtrace")]
+use std::backtrace::Backtrace;
+use std::io;
+use thiserror::Error;
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+use crate::{reader::AsyncReadExact, writer::AsyncWriteAll};
+
+#[derive(Debug, Error)]
+pub enum NoiseError {
+    #[error("io failure during noise handshake")]
+    IoFailure {
+        #[from]
+        err: io::Error,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("noise protocol failure")]
+    Protocol {
+        #[from]
+        err: snow::Error,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("the IK pattern requires the responder's static key when initiating")]
+    MissingRemoteKey {
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("peer authenticated with an untrusted static key")]
+    UntrustedPeer {
+        remote_static_key: Vec<u8>,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+}
+
+pub type NoiseResult<T> = Result<T, NoiseError>;
+
+// handshake messages are sent with a 2 byte big-endian length prefix, which is enough for the
+// largest message the noise protocol allows
+const MAX_MESSAGE_SIZE: usize = 65535;
+
+///
+/// The noise handshake pattern used to authenticate the two ends of a link.
+///
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum NoisePattern {
+    ///
+    /// Both ends send their static keys during the handshake. Neither end has to know the other's
+    /// key in advance (though it must still be trusted, see `NoiseHandshake::with_trusted_peer`).
+    ///
+    XX,
+    ///
+    /// The initiator already knows the responder's static key (see
+    /// `NoiseHandshake::with_remote_key`), which saves a round trip.
+    ///
+    IK,
+}
+
+impl NoisePattern {
+    fn params(&self) -> &'static str {
+        match self {
+            NoisePattern::XX => "Noise_XX_25519_AESGCM_SHA256",
+            NoisePattern::IK => "Noise_IK_25519_AESGCM_SHA256",
+        }
+    }
+}
+
+///
+/// A static (long-term) key pair, which identifies one end of a link.
+///
+#[derive(Clone)]
+pub struct NoiseKeypair {
+    pub private_key: Vec<u8>,
+    pub public_key: Vec<u8>,
+}
+
+impl NoiseKeypair {
+    pub fn generate() -> NoiseResult<Self> {
+        let keypair = Builder::new(parse_params(NoisePattern::XX)).generate_keypair()?;
+        Ok(Self {
+            private_key: keypair.private,
+            public_key: keypair.public,
+        })
+    }
+}
+
+///
+/// Establishes the keys for a `SecureLink` using a noise handshake, so two endpoints which both
+/// use this crate (such as a proxy and its backends) can authenticate each other using static keys
+/// from their configuration, without a TLS PKI.
+///
+/// The handshake is performed on the raw streams, before they are wrapped by `SecureLink`. Each end
+/// only accepts peers whose static public key was added using `with_trusted_peer`.
+///
+#[derive(Clone)]
+pub struct NoiseHandshake {
+    pattern: NoisePattern,
+    private_key: Vec<u8>,
+    remote_key: Option<Vec<u8>>,
+    trusted_peers: Vec<Vec<u8>>,
+}
+
+///
+/// The result of a successful `NoiseHandshake`.
+///
+pub struct NoiseSession {
+    pub link: SecureLink,
+    pub remote_static_key: Vec<u8>,
+}
+
+impl NoiseHandshake {
+    pub fn new(pattern: NoisePattern, private_key: &[u8]) -> Self {
+        Self {
+            pattern,
+            private_key: private_key.to_vec(),
+            remote_key: None,
+            trusted_peers: Vec::new(),
+        }
+    }
+
+    ///
+    /// Accepts a peer which authenticates with this static public key.
+    ///
+    pub fn with_trusted_peer(mut self, public_key: &[u8]) -> Self {
+        self.trusted_peers.push(public_key.to_vec());
+        self
+    }
+
+    ///
+    /// Sets the static public key of the responder, which is required to initiate an `IK`
+    /// handshake. The key is also trusted.
+    ///
+    pub fn with_remote_key(mut self, public_key: &[u8]) -> Self {
+        self.remote_key = Some(public_key.to_vec());
+        self.with_trusted_peer(public_key)
+    }
+
+    ///
+    /// Performs the handshake as the end which opened the connection.
+    ///
+    pub fn initiate<S>(&self, stream: &mut S) -> NoiseResult<NoiseSession>
+    where
+        S: io::Read + io::Write,
+    {
+        let state = self.build(SecureRole::Initiator)?;
+        self.handshake_sync(state, SecureRole::Initiator, stream)
+    }
+
+    ///
+    /// Performs the handshake as the end which accepted the connection.
+    ///
+    pub fn respond<S>(&self, stream: &mut S) -> NoiseResult<NoiseSession>
+    where
+        S: io::Read + io::Write,
+    {
+        let state = self.build(SecureRole::Responder)?;
+        self.handshake_sync(state, SecureRole::Responder, stream)
+    }
+
+    #[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+    pub async fn initiate_async<S>(&self, stream: &mut S) -> NoiseResult<NoiseSession>
+    where
+        S: AsyncReadExact + AsyncWriteAll,
+    {
+        let state = self.build(SecureRole::Initiator)?;
+        self.handshake_async(state, SecureRole::Initiator, stream).await
+    }
+
+    #[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+    pub async fn respond_async<S>(&self, stream: &mut S) -> NoiseResult<NoiseSession>
+    where
+        S: AsyncReadExact + AsyncWriteAll,
+    {
+        let state = self.build(SecureRole::Responder)?;
+        self.handshake_async(state, SecureRole::Responder, stream).await
+    }
+
+    fn build(&self, role: SecureRole) -> NoiseResult<HandshakeState> {
+        let builder = Builder::new(parse_params(self.pattern)).local_private_key(&self.private_key);
+        Ok(match role {
+            SecureRole::Initiator => match (self.pattern, &self.remote_key) {
+                (NoisePattern::IK, None) => {
+                    return Err(NoiseError::MissingRemoteKey {
+                        #[cfg(feature = "backtrace")]
+                        backtrace: Backtrace::capture(),
+                    })
+                }
+                (NoisePattern::IK, Some(remote_key)) => {
+                    builder.remote_public_key(remote_key).build_initiator()?
+                }
+                (NoisePattern::XX, _) => builder.build_initiator()?,
+            },
+            SecureRole::Responder => builder.build_responder()?,
+        })
+    }
+
+    fn handshake_sync<S>(
+        &self,
+        mut state: HandshakeState,
+        role: SecureRole,
+        stream: &mut S,
+    ) -> NoiseResult<NoiseSession>
+    where
+        S: io::Read + io::Write,
+    {
+        let mut message = vec![0u8; MAX_MESSAGE_SIZE];
+        let mut payload = vec![0u8; MAX_MESSAGE_SIZE];
+        while !state.is_handshake_finished() {
+            if state.is_my_turn() {
+                let len = state.write_message(&[], &mut message)?;
+                io::Write::write_all(stream, &(len as u16).to_be_bytes())?;
+                io::Write::write_all(stream, &message[..len])?;
+                stream.flush()?;
+            } else {
+                let mut len_buf = [0u8; 2];
+                io::Read::read_exact(stream, &mut len_buf)?;
+                let len = u16::from_be_bytes(len_buf) as usize;
+                io::Read::read_exact(stream, &mut message[..len])?;
+                state.read_message(&message[..len], &mut payload)?;
+                self.check_remote(&state)?;
+            }
+        }
+
+        self.finish(state, role)
+    }
+
+    #[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+    async fn handshake_async<S>(
+        &self,
+        mut state: HandshakeState,
+        role: SecureRole,
+        stream: &mut S,
+    ) -> NoiseResult<NoiseSession>
+    where
+        S: AsyncReadExact + AsyncWriteAll,
+    {
+        let mut message = vec![0u8; MAX_MESSAGE_SIZE];
+        let mut payload = vec![0u8; MAX_MESSAGE_SIZE];
+        while !state.is_handshake_finished() {
+            if state.is_my_turn() {
+                let len = state.write_message(&[], &mut message)?;
+                AsyncWriteAll::write_all(stream, &(len as u16).to_be_bytes()).await?;
+                AsyncWriteAll::write_all(stream, &message[..len]).await?;
+                AsyncWriteAll::flush(stream).await?;
+            } else {
+                let mut len_buf = [0u8; 2];
+                AsyncReadExact::read_exact(stream, &mut len_buf).await?;
+                let len = u16::from_be_bytes(len_buf) as usize;
+                AsyncReadExact::read_exact(stream, &mut message[..len]).await?;
+                state.read_message(&message[..len], &mut payload)?;
+                self.check_remote(&state)?;
+            }
+        }
+
+        self.finish(state, role)
+    }
+
+    // rejects the peer as soon as its static key is known, so nothing more is sent to a peer which
+    // is not trusted (the XX initiator would otherwise send its own static key to it)
+    fn check_remote(&self, state: &HandshakeState) -> NoiseResult<()> {
+        match state.get_remote_static() {
+            Some(key) if !self.is_trusted(key) => Err(untrusted_peer(key.to_vec())),
+            _ => Ok(()),
+        }
+    }
+
+    fn is_trusted(&self, key: &[u8]) -> bool {
+        self.trusted_peers.iter().any(|trusted| trusted.as_slice() == key)
+    }
+
+    fn finish(&self, mut state: HandshakeState, role: SecureRole) -> NoiseResult<NoiseSession> {
+        let remote_static_key = state.get_remote_static().map(|key| key.to_vec()).unwrap_or_default();
+        if !self.is_trusted(&remote_static_key) {
+            return Err(untrusted_peer(remote_static_key));
+        }
+
+        // the first key is used for messages from the initiator, the second for the responder
+        let (initiator_key, responder_key) = state.dangerously_get_raw_split();
+        let (send_key, receive_key) = match role {
+            SecureRole::Initiator => (initiator_key, responder_key),
+            SecureRole::Responder => (responder_key, initiator_key),
+        };
+
+        Ok(NoiseSession {
+            link: SecureLink::with_keys(&send_key, &receive_key, role)?,
+            remote_static_key,
+        })
+    }
+}
+
+fn untrusted_peer(remote_static_key: Vec<u8>) -> NoiseError {
+    NoiseError::UntrustedPeer {
+        remote_static_key,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace::capture(),
+    }
+}
+
+fn parse_params(pattern: NoisePattern) -> snow::params::NoiseParams {
+    pattern
+        .params()
+        .parse()
+        .expect("noise parameter strings are valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::tcp_pair;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    fn handshake(
+        initiator: NoiseHandshake,
+        responder: NoiseHandshake,
+    ) -> (
+        (NoiseResult<NoiseSession>, TcpStream),
+        (NoiseResult<NoiseSession>, TcpStream),
+    ) {
+        let (mut initiating, mut responding) = tcp_pair();
+        let responded = std::thread::spawn(move || {
+            let session = responder.respond(&mut responding);
+            (session, responding)
+        });
+        let initiated = initiator.initiate(&mut initiating);
+        ((initiated, initiating), responded.join().unwrap())
+    }
+
+    fn assert_linked(initiated: (NoiseSession, TcpStream), responded: (NoiseSession, TcpStream)) {
+        let (initiator, initiating) = initiated;
+        let (responder, responding) = responded;
+
+        let (mut initiator_reader, mut initiator_writer) = initiator
+            .link
+            .split(initiating.try_clone().unwrap(), initiating);
+        let (mut responder_reader, mut responder_writer) = responder
+            .link
+            .split(responding.try_clone().unwrap(), responding);
+
+        let mut buf = [0u8; 12];
+        initiator_writer.write_all(b"to responder").unwrap();
+        responder_reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"to responder");
+
+        responder_writer.write_all(b"to initiator").unwrap();
+        initiator_reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"to initiator");
+    }
+
+    #[test]
+    fn xx_handshake_links_trusted_peers() {
+        let initiator_keys = NoiseKeypair::generate().unwrap();
+        let responder_keys = NoiseKeypair::generate().unwrap();
+        let ((initiated, initiating), (responded, responding)) = handshake(
+            NoiseHandshake::new(NoisePattern::XX, &initiator_keys.private_key)
+                .with_trusted_peer(&responder_keys.public_key),
+            NoiseHandshake::new(NoisePattern::XX, &responder_keys.private_key)
+                .with_trusted_peer(&initiator_keys.public_key),
+        );
+
+        let initiated = initiated.unwrap();
+        let responded = responded.unwrap();
+        assert_eq!(initiated.remote_static_key, responder_keys.public_key);
+        assert_eq!(responded.remote_static_key, initiator_keys.public_key);
+        assert_linked((initiated, initiating), (responded, responding));
+    }
+
+    #[test]
+    fn ik_handshake_links_trusted_peers() {
+        let initiator_keys = NoiseKeypair::generate().unwrap();
+        let responder_keys = NoiseKeypair::generate().unwrap();
+        let ((initiated, initiating), (responded, responding)) = handshake(
+            NoiseHandshake::new(NoisePattern::IK, &initiator_keys.private_key)
+                .with_remote_key(&responder_keys.public_key),
+            NoiseHandshake::new(NoisePattern::IK, &responder_keys.private_key)
+                .with_trusted_peer(&initiator_keys.public_key),
+        );
+
+        assert_linked(
+            (initiated.unwrap(), initiating),
+            (responded.unwrap(), responding),
+        );
+    }
+
+    #[test]
+    fn untrusted_peer_is_rejected() {
+        let initiator_keys = NoiseKeypair::generate().unwrap();
+        let responder_keys = NoiseKeypair::generate().unwrap();
+        let stranger_keys = NoiseKeypair::generate().unwrap();
+        let ((initiated, _), (responded, _)) = handshake(
+            NoiseHandshake::new(NoisePattern::XX, &initiator_keys.private_key)
+                .with_trusted_peer(&responder_keys.public_key),
+            NoiseHandshake::new(NoisePattern::XX, &responder_keys.private_key)
+                .with_trusted_peer(&stranger_keys.public_key),
+        );
+
+        assert!(initiated.is_ok());
+        assert!(matches!(responded, Err(NoiseError::UntrustedPeer { .. })));
+    }
+
+    #[test]
+    fn initiator_rejects_an_untrusted_responder_before_sending_its_key() {
+        let initiator_keys = NoiseKeypair::generate().unwrap();
+        let responder_keys = NoiseKeypair::generate().unwrap();
+        let stranger_keys = NoiseKeypair::generate().unwrap();
+        let initiator = NoiseHandshake::new(NoisePattern::XX, &initiator_keys.private_key)
+            .with_trusted_peer(&stranger_keys.public_key);
+        let responder = NoiseHandshake::new(NoisePattern::XX, &responder_keys.private_key)
+            .with_trusted_peer(&initiator_keys.public_key);
+
+        let (mut initiating, mut responding) = tcp_pair();
+        let responded = std::thread::spawn(move || responder.respond(&mut responding));
+        let initiated = initiator.initiate(&mut initiating);
+        drop(initiating);
+
+        assert!(matches!(initiated, Err(NoiseError::UntrustedPeer { .. })));
+        // the third message, carrying the initiator's static key, is never sent
+        assert!(matches!(
+            responded.join().unwrap(),
+            Err(NoiseError::IoFailure { .. })
+        ));
+    }
+}
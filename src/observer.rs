@@ -0,0 +1,164 @@
+use crate::reader::{ReadError, ReadResult};
+use crate::writer::{CraftWriter, WriteError, WriteResult};
+use mcproto_rs::protocol::{PacketDirection, State};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+///
+/// Receives lifecycle events from a `CraftConnection`, so operational logging and auditing can be
+/// attached to a connection in one place. Register one using `CraftConnection::add_observer`.
+///
+/// All functions have a default implementation which does nothing.
+///
+pub trait ConnectionObserver: Send + Sync {
+    ///
+    /// Called when the observer is added to a connection, with the addresses the connection has
+    /// recorded (see `CraftConnection::peer_addr`).
+    ///
+    fn on_connect(&mut self, _peer_addr: Option<SocketAddr>, _local_addr: Option<SocketAddr>) {}
+
+    ///
+    /// Called when the connection moves to a new state.
+    ///
+    fn on_state_change(&mut self, _state: State) {}
+
+    ///
+    /// Called when the connection leaves a state, with the state it left and how long it spent in
+    /// that state. This is called before `on_state_change` for the new state. The time spent in
+    /// `Login`, for example, includes authentication, which makes it a good metric for a slow
+    /// authentication backend or a flood of connections which never finish logging in.
+    ///
+    fn on_state_duration(&mut self, _state: State, _duration: Duration) {}
+
+    ///
+    /// Called when compression is enabled on the connection, with the compression threshold.
+    ///
+    fn on_compression_enabled(&mut self, _threshold: i32) {}
+
+    ///
+    /// Called when encryption is enabled on the connection.
+    ///
+    fn on_encryption_enabled(&mut self) {}
+
+    ///
+    /// Called when reading or writing a packet on the connection fails.
+    ///
+    fn on_error(&mut self, _error: &(dyn Error + 'static)) {}
+
+    ///
+    /// Called when a packet is rejected for exceeding the max packet size, before the error is
+    /// returned (and before `on_error`). A peer which sends such packets is usually malicious, so
+    /// this is a good place to feed bans or alerts from.
+    ///
+    fn on_packet_too_large(&mut self, _packet: &OversizePacket) {}
+
+    ///
+    /// Called when the connection is dropped, or taken apart using `into_split` or `into_inner`.
+    ///
+    fn on_close(&mut self) {}
+}
+
+///
+/// A packet which was rejected for exceeding the max packet size, as reported to
+/// `ConnectionObserver::on_packet_too_large`.
+///
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct OversizePacket {
+    ///
+    /// The size the packet claimed (or needed, when writing).
+    ///
+    pub size: usize,
+    pub max_size: usize,
+    pub state: State,
+    ///
+    /// The direction the packet was travelling in. Packets rejected while reading travel towards
+    /// this end of the connection.
+    ///
+    pub direction: PacketDirection,
+    pub peer_addr: Option<SocketAddr>,
+}
+
+#[derive(Default)]
+pub(crate) struct Observers {
+    list: Vec<Box<dyn ConnectionObserver>>,
+    // entered while observers are notified, see CraftConnection::set_span
+    #[cfg(feature = "tracing")]
+    pub(crate) span: tracing::Span,
+}
+
+impl Observers {
+    pub(crate) fn push(&mut self, observer: Box<dyn ConnectionObserver>) {
+        self.list.push(observer);
+    }
+
+    pub(crate) fn notify<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&mut dyn ConnectionObserver),
+    {
+        #[cfg(feature = "tracing")]
+        let _span = self.span.enter();
+        for observer in self.list.iter_mut() {
+            f(observer.as_mut());
+        }
+    }
+
+    pub(crate) fn observe_result<T, E>(&mut self, result: &Result<T, E>)
+    where
+        E: Error + 'static,
+    {
+        if let Err(err) = result {
+            self.notify(move |observer| observer.on_error(err));
+        }
+    }
+
+    // the state and direction are taken from the writer, because the reader may be borrowed by the
+    // result
+    pub(crate) fn observe_read<T, W>(
+        &mut self,
+        result: &ReadResult<T>,
+        peer_addr: Option<SocketAddr>,
+        writer: &CraftWriter<W>,
+    ) {
+        if let Err(ReadError::PacketTooLarge { size, max_size, .. }) = result {
+            self.notify_too_large(OversizePacket {
+                size: *size,
+                max_size: *max_size,
+                state: writer.state(),
+                direction: writer.direction().opposite(),
+                peer_addr,
+            });
+        }
+
+        self.observe_result(result);
+    }
+
+    pub(crate) fn observe_write<T, W>(
+        &mut self,
+        result: &WriteResult<T>,
+        peer_addr: Option<SocketAddr>,
+        writer: &CraftWriter<W>,
+    ) {
+        if let Err(WriteError::PacketTooLarge { size, max_size, .. }) = result {
+            self.notify_too_large(OversizePacket {
+                size: *size,
+                max_size: *max_size,
+                state: writer.state(),
+                direction: writer.direction(),
+                peer_addr,
+            });
+        }
+
+        self.observe_result(result);
+    }
+
+    fn notify_too_large(&mut self, packet: OversizePacket) {
+        self.notify(move |observer| observer.on_packet_too_large(&packet));
+    }
+}
+
+impl Drop for Observers {
+    fn drop(&mut self) {
+        self.notify(|observer| observer.on_close());
+    }
+}
@@ -0,0 +1,262 @@
+use crate::connection::CraftConnection;
+use crate::reader::{CraftSyncReader, ReadResult};
+use crate::wrapper::CraftIo;
+use crate::writer::{CraftSyncWriter, WriteResult};
+#[cfg(feature = "encryption")]
+use crate::cfb8::CipherError;
+#[cfg(feature = "gat")]
+use mcproto_rs::protocol::PacketKind;
+use mcproto_rs::protocol::{Id, Packet, RawPacket, State};
+use std::marker::PhantomData;
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+use {
+    crate::{reader::CraftAsyncReader, writer::CraftAsyncWriter},
+    async_trait::async_trait,
+};
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+///
+/// A protocol state as a type, used as the phase of a `PhasedConnection`. It is implemented by
+/// `HandshakingPhase`, `StatusPhase`, `LoginPhase` and `PlayPhase`.
+///
+pub trait Phase: sealed::Sealed {
+    const STATE: State;
+}
+
+macro_rules! phase {
+    ($name: ident, $state: expr) => {
+        #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+        pub enum $name {}
+
+        impl sealed::Sealed for $name {}
+
+        impl Phase for $name {
+            const STATE: State = $state;
+        }
+    };
+}
+
+phase!(HandshakingPhase, State::Handshaking);
+phase!(StatusPhase, State::Status);
+phase!(LoginPhase, State::Login);
+phase!(PlayPhase, State::Play);
+
+///
+/// A `CraftConnection` whose protocol state is part of its type. The state can only change through
+/// the transitions the protocol allows (such as `into_login` during the handshake, and `into_play`
+/// after logging in), which consume the connection and return it in the next phase, so code can
+/// not forget to switch states, or switch to a state the protocol never reaches from the current
+/// one.
+///
+/// Packets are written through the usual traits, and a packet of another state is rejected with
+/// `WriteError::BadState` before anything is written, because the state of the connection always
+/// matches the phase.
+///
+/// Options which do not affect the state are configured on the connection before it is wrapped,
+/// or through `get_ref`. Compression and encryption are enabled during login, so `LoginPhase`
+/// connections have functions for them.
+///
+pub struct PhasedConnection<Ph, R, W> {
+    inner: CraftConnection<R, W>,
+    // a function pointer keeps the connection Send and Sync no matter the phase
+    phase: PhantomData<fn() -> Ph>,
+}
+
+impl<Ph, R, W> PhasedConnection<Ph, R, W>
+where
+    Ph: Phase,
+{
+    ///
+    /// Wraps a connection, switching it to the state of `Ph`. A fresh connection is wrapped in
+    /// `HandshakingPhase`, but a connection which is adopted part way through a session can be
+    /// wrapped in the phase it is in.
+    ///
+    pub fn wrap(mut inner: CraftConnection<R, W>) -> Self {
+        inner.set_state(Ph::STATE);
+        Self {
+            inner,
+            phase: PhantomData,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        Ph::STATE
+    }
+
+    pub fn get_ref(&self) -> &CraftConnection<R, W> {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> CraftConnection<R, W> {
+        self.inner
+    }
+
+    fn transition<Next>(self) -> PhasedConnection<Next, R, W>
+    where
+        Next: Phase,
+    {
+        PhasedConnection::wrap(self.inner)
+    }
+}
+
+impl<R, W> PhasedConnection<HandshakingPhase, R, W> {
+    ///
+    /// Continues with a status request, after the handshake asked for the `Status` state.
+    ///
+    pub fn into_status(self) -> PhasedConnection<StatusPhase, R, W> {
+        self.transition()
+    }
+
+    ///
+    /// Continues with logging in, after the handshake asked for the `Login` state.
+    ///
+    pub fn into_login(self) -> PhasedConnection<LoginPhase, R, W> {
+        self.transition()
+    }
+}
+
+impl<R, W> PhasedConnection<LoginPhase, R, W> {
+    ///
+    /// Continues with the game, after Login Success.
+    ///
+    pub fn into_play(self) -> PhasedConnection<PlayPhase, R, W> {
+        self.transition()
+    }
+
+    #[cfg(feature = "compression")]
+    pub fn set_compression_threshold(&mut self, threshold: Option<i32>) {
+        self.inner.set_compression_threshold(threshold);
+    }
+
+    #[cfg(feature = "encryption")]
+    pub fn enable_encryption(&mut self, key: &[u8], iv: &[u8]) -> Result<(), CipherError> {
+        self.inner.enable_encryption(key, iv)
+    }
+}
+
+impl<Ph, R, W> CraftSyncReader for PhasedConnection<Ph, R, W>
+where
+    CraftConnection<R, W>: CraftSyncReader,
+{
+    #[cfg(not(feature = "gat"))]
+    fn read_packet<'a, P>(&'a mut self) -> ReadResult<<P as RawPacket<'a>>::Packet>
+    where
+        P: RawPacket<'a>,
+    {
+        self.inner.read_packet::<P>()
+    }
+
+    #[cfg(feature = "gat")]
+    fn read_packet<P>(&mut self) -> ReadResult<<P::RawPacket<'_> as RawPacket>::Packet>
+    where
+        P: PacketKind
+    {
+        self.inner.read_packet::<P>()
+    }
+
+    #[cfg(not(feature = "gat"))]
+    fn read_raw_packet<'a, P>(&'a mut self) -> ReadResult<P>
+    where
+        P: RawPacket<'a>,
+    {
+        self.inner.read_raw_packet::<P>()
+    }
+
+    #[cfg(feature = "gat")]
+    fn read_raw_packet<P>(&mut self) -> ReadResult<P::RawPacket<'_>>
+    where
+        P: PacketKind
+    {
+        self.inner.read_raw_packet::<P>()
+    }
+
+    fn read_raw_untyped_packet(&mut self) -> ReadResult<(Id, &[u8])> {
+        self.inner.read_raw_untyped_packet()
+    }
+}
+
+impl<Ph, R, W> CraftSyncWriter for PhasedConnection<Ph, R, W>
+where
+    CraftConnection<R, W>: CraftSyncWriter,
+{
+    fn write_packet<P>(&mut self, packet: P) -> WriteResult<()>
+    where
+        P: Packet,
+    {
+        self.inner.write_packet(packet)
+    }
+
+    fn write_raw_packet<'a, P>(&mut self, packet: P) -> WriteResult<()>
+    where
+        P: RawPacket<'a>,
+    {
+        self.inner.write_raw_packet(packet)
+    }
+}
+
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+#[async_trait]
+impl<Ph, R, W> CraftAsyncReader for PhasedConnection<Ph, R, W>
+where
+    CraftConnection<R, W>: CraftAsyncReader + Send,
+{
+    #[cfg(not(feature = "gat"))]
+    async fn read_packet_async<'a, P>(&'a mut self) -> ReadResult<<P as RawPacket<'a>>::Packet>
+    where
+        P: RawPacket<'a>,
+    {
+        self.inner.read_packet_async::<P>().await
+    }
+
+    #[cfg(feature = "gat")]
+    async fn read_packet_async<P>(&mut self) -> ReadResult<<P::RawPacket<'_> as RawPacket<'_>>::Packet>
+    where
+        P: PacketKind
+    {
+        self.inner.read_packet_async::<P>().await
+    }
+
+    #[cfg(not(feature = "gat"))]
+    async fn read_raw_packet_async<'a, P>(&'a mut self) -> ReadResult<P>
+    where
+        P: RawPacket<'a>,
+    {
+        self.inner.read_raw_packet_async::<P>().await
+    }
+
+    #[cfg(feature = "gat")]
+    async fn read_raw_packet_async<P>(&mut self) -> ReadResult<P::RawPacket<'_>>
+    where
+        P: PacketKind
+    {
+        self.inner.read_raw_packet_async::<P>().await
+    }
+
+    async fn read_raw_untyped_packet_async(&mut self) -> ReadResult<(Id, &[u8])> {
+        self.inner.read_raw_untyped_packet_async().await
+    }
+}
+
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+#[async_trait]
+impl<Ph, R, W> CraftAsyncWriter for PhasedConnection<Ph, R, W>
+where
+    CraftConnection<R, W>: CraftAsyncWriter + Send,
+{
+    async fn write_packet_async<P>(&mut self, packet: P) -> WriteResult<()>
+    where
+        P: Packet + Send + Sync,
+    {
+        self.inner.write_packet_async(packet).await
+    }
+
+    async fn write_raw_packet_async<'a, P>(&mut self, packet: P) -> WriteResult<()>
+    where
+        P: RawPacket<'a> + Send + Sync,
+    {
+        self.inner.write_raw_packet_async(packet).await
+    }
+}
@@ -0,0 +1,711 @@
+use crate::connection::{CraftConnection, Transition, TransitionError};
+use crate::middleware::PacketVerdict;
+use crate::reader::{CraftReader, CraftSyncReader, ReadError};
+use crate::wrapper::CraftIo;
+use crate::writer::{CraftWriter, WriteError, WriteResult};
+use mcproto_rs::protocol::{Id, PacketDirection, State};
+use mcproto_rs::DeserializeErr;
+#[cfg(feature = "backtrace")]
+use std::backtrace::Backtrace;
+use std::io;
+use std::sync::Mutex;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ProxyError {
+    #[error("failed to read packet")]
+    Read {
+        #[from]
+        err: ReadError,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("failed to write packet")]
+    Write {
+        #[from]
+        err: WriteError,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("malformed state change packet")]
+    BadPacket {
+        #[from]
+        err: DeserializeErr,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("handshake requested unknown next state {next_state}")]
+    BadNextState {
+        next_state: i32,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+}
+
+pub type ProxyResult<T> = Result<T, ProxyError>;
+
+// login packets the client answers, so the proxy reads from the client after forwarding one
+const LOGIN_REQUEST_IDS: [i32; 3] = [
+    0x01, // encryption request
+    0x04, // login plugin request
+    0x05, // cookie request
+];
+
+///
+/// Packets the hooks of a `CraftProxy` send of their own accord, which are written after the
+/// packet which is being forwarded (or instead of it, if the hook rejected it).
+///
+#[derive(Debug, Default)]
+pub struct ProxyInjector {
+    to_client: Vec<(Id, Vec<u8>)>,
+    to_server: Vec<(Id, Vec<u8>)>,
+}
+
+impl ProxyInjector {
+    ///
+    /// Sends a packet to the client. The id must be a client-bound id of the client's state.
+    ///
+    pub fn send_to_client(&mut self, id: Id, body: Vec<u8>) {
+        self.to_client.push((id, body));
+    }
+
+    ///
+    /// Sends a packet to the server. The id must be a server-bound id of the server's state.
+    ///
+    pub fn send_to_server(&mut self, id: Id, body: Vec<u8>) {
+        self.to_server.push((id, body));
+    }
+}
+
+///
+/// The callbacks of a `CraftProxy`, which see every packet before it is forwarded. Each callback
+/// decides what happens to the packet with a `PacketVerdict`: `Pass` forwards it unchanged,
+/// `Replace` forwards it with another body, and `Reject` drops it. Packets can be sent to either
+/// side through the `ProxyInjector`.
+///
+/// Bodies are passed after decryption and decompression, and do not include the packet id.
+///
+/// All functions have a default implementation which forwards every packet.
+///
+pub trait ProxyHooks: Send {
+    ///
+    /// Called for every packet the server sent, before it is forwarded to the client.
+    ///
+    fn on_clientbound(
+        &mut self,
+        _id: Id,
+        _body: &[u8],
+        _inject: &mut ProxyInjector,
+    ) -> PacketVerdict {
+        PacketVerdict::Pass
+    }
+
+    ///
+    /// Called for every packet the client sent, before it is forwarded to the server.
+    ///
+    fn on_serverbound(
+        &mut self,
+        _id: Id,
+        _body: &[u8],
+        _inject: &mut ProxyInjector,
+    ) -> PacketVerdict {
+        PacketVerdict::Pass
+    }
+
+    ///
+    /// Called when the side which sends packets in `direction` closed its connection (or when
+    /// forwarding its packets failed). `CraftProxy::run` waits for both directions to end, so this
+    /// is the place to close the other side's stream (such as with `TcpStream::shutdown` on a
+    /// clone of the socket).
+    ///
+    fn on_closed(&mut self, _direction: PacketDirection) {}
+}
+
+// the transition a packet causes, which fails the proxy if the packet is malformed
+fn transition_of(id: Id, body: &[u8]) -> ProxyResult<Option<Transition>> {
+    Transition::of(id, body).map_err(|err| match err {
+        TransitionError::BadPacket(err) => err.into(),
+        TransitionError::BadNextState(next_state) => ProxyError::BadNextState {
+            next_state,
+            #[cfg(feature = "backtrace")]
+            backtrace: Backtrace::capture(),
+        },
+    })
+}
+
+// what happened to a packet read from one side
+struct Forwarded {
+    id: Id,
+    // caused on the side the packet was read from
+    read_transition: Option<Transition>,
+    // caused on the other side, which is None if the packet was not forwarded
+    write_transition: Option<Transition>,
+    forwarded: bool,
+    inject: ProxyInjector,
+}
+
+fn forward_one<R, F>(
+    reader: &mut CraftReader<R>,
+    hooks: &Mutex<&mut Option<Box<dyn ProxyHooks>>>,
+    direction: PacketDirection,
+    write: F,
+) -> ProxyResult<Option<Forwarded>>
+where
+    CraftReader<R>: CraftSyncReader,
+    F: FnOnce(Id, &[u8]) -> WriteResult<()>,
+{
+    let (id, body) = match reader.read_raw_untyped_packet()? {
+        Some(packet) => packet,
+        None => return Ok(None),
+    };
+
+    let read_transition = transition_of(id, body)?;
+    let mut inject = ProxyInjector::default();
+    let verdict = match lock(hooks).as_mut() {
+        Some(hooks) => match direction {
+            PacketDirection::ClientBound => hooks.on_clientbound(id, body, &mut inject),
+            PacketDirection::ServerBound => hooks.on_serverbound(id, body, &mut inject),
+        },
+        None => PacketVerdict::Pass,
+    };
+
+    let (write_transition, forwarded) = match verdict {
+        PacketVerdict::Pass => {
+            write(id, body)?;
+            (read_transition, true)
+        }
+        PacketVerdict::Replace(body) => {
+            write(id, &body)?;
+            (transition_of(id, &body)?, true)
+        }
+        PacketVerdict::Reject => (None, false),
+    };
+
+    Ok(Some(Forwarded {
+        id,
+        read_transition,
+        write_transition,
+        forwarded,
+        inject,
+    }))
+}
+
+// a panicking hook does not stop the other direction, which keeps using the hooks as they are
+fn lock<'a, T>(mutex: &'a Mutex<T>) -> std::sync::MutexGuard<'a, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// writes injected packets, and applies the transitions they cause
+fn write_injected<R, W>(
+    connection: &mut CraftConnection<R, W>,
+    packets: Vec<(Id, Vec<u8>)>,
+) -> ProxyResult<()>
+where
+    W: io::Write,
+{
+    for (id, body) in packets {
+        connection.writer.write_raw_untyped_packet(id, &body)?;
+        if let Some(transition) = transition_of(id, &body)? {
+            transition.apply(connection);
+        }
+    }
+
+    Ok(())
+}
+
+// flushes what a flush threshold buffered once nothing more is waiting to be forwarded
+fn flush_if_idle<R, W>(reader: &CraftReader<R>, writer: &mut CraftWriter<W>) -> WriteResult<()>
+where
+    W: io::Write,
+{
+    if writer.buffered_len() > 0 && reader.buffered_len() == 0 {
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+///
+/// Relays packets between a client and a server, such as a player and a backend server.
+///
+/// Every packet is read from one side and written to the other without being parsed, so the two
+/// connections can use different compression thresholds and encryption (the body is framed again
+/// for the destination, see `CraftWriter::write_raw_untyped_packet`). `ProxyHooks` can inspect,
+/// change, drop and inject packets on the way.
+///
+/// The proxy follows the state of both connections: the client's handshake switches them to the
+/// state it asks for, Set Compression enables compression, and Login Success switches to `Play`.
+/// Each of these applies to the side which sent the packet as soon as it is read, and to the other
+/// side once it is forwarded, so a hook which drops or replaces one of them changes the other side
+/// accordingly. The backend must not enable encryption (it must be in offline mode), since the
+/// proxy can not decrypt what the client encrypts for the backend, but hooks can answer the login
+/// of the client themselves, and enable encryption on the client connection through
+/// `client_mut`.
+///
+/// Only blocking I/O is supported.
+///
+pub struct CraftProxy<CR, CW, SR, SW> {
+    client: CraftConnection<CR, CW>,
+    server: CraftConnection<SR, SW>,
+    hooks: Option<Box<dyn ProxyHooks>>,
+    // which side the next packet is read from until both sides are in Play
+    next: PacketDirection,
+}
+
+impl<CR, CW, SR, SW> CraftProxy<CR, CW, SR, SW> {
+    ///
+    /// Relays between `client`, which is the connection accepted from the client (reading
+    /// server-bound packets), and `server`, which is the connection to the server (reading
+    /// client-bound packets). Both are usually in the `Handshaking` state, but connections which
+    /// already went through the handshake (such as one routed with `HostRouter`) can be passed in
+    /// the state they are in.
+    ///
+    pub fn new(client: CraftConnection<CR, CW>, server: CraftConnection<SR, SW>) -> Self {
+        Self {
+            client,
+            server,
+            hooks: None,
+            next: PacketDirection::ServerBound,
+        }
+    }
+
+    pub fn set_hooks(&mut self, hooks: Option<Box<dyn ProxyHooks>>) {
+        self.hooks = hooks;
+    }
+
+    pub fn client(&self) -> &CraftConnection<CR, CW> {
+        &self.client
+    }
+
+    pub fn client_mut(&mut self) -> &mut CraftConnection<CR, CW> {
+        &mut self.client
+    }
+
+    pub fn server(&self) -> &CraftConnection<SR, SW> {
+        &self.server
+    }
+
+    pub fn server_mut(&mut self) -> &mut CraftConnection<SR, SW> {
+        &mut self.server
+    }
+
+    pub fn into_inner(self) -> (CraftConnection<CR, CW>, CraftConnection<SR, SW>) {
+        (self.client, self.server)
+    }
+
+    fn in_play(&self) -> bool {
+        self.client.writer.state() == State::Play && self.server.writer.state() == State::Play
+    }
+
+    // before Play, one side answers the other, so the proxy knows which side sends next
+    fn next_after(&self, direction: PacketDirection, id: Id, forwarded: bool) -> PacketDirection {
+        match (direction, id.state) {
+            (PacketDirection::ServerBound, State::Handshaking) => PacketDirection::ServerBound,
+            (PacketDirection::ServerBound, _) => PacketDirection::ClientBound,
+            (PacketDirection::ClientBound, State::Login)
+                if !(forwarded && LOGIN_REQUEST_IDS.contains(&id.id)) =>
+            {
+                PacketDirection::ClientBound
+            }
+            (PacketDirection::ClientBound, _) => PacketDirection::ServerBound,
+        }
+    }
+}
+
+impl<CR, CW, SR, SW> CraftProxy<CR, CW, SR, SW>
+where
+    CraftReader<CR>: CraftSyncReader,
+    CraftReader<SR>: CraftSyncReader,
+    CW: io::Write,
+    SW: io::Write,
+{
+    ///
+    /// Reads one packet from the client, and forwards it to the server. Returns `false` if the
+    /// client closed the connection.
+    ///
+    pub fn forward_serverbound(&mut self) -> ProxyResult<bool> {
+        let hooks = Mutex::new(&mut self.hooks);
+        let writer = &mut self.server.writer;
+        let forwarded = forward_one(
+            &mut self.client.reader,
+            &hooks,
+            PacketDirection::ServerBound,
+            |id, body| writer.write_raw_untyped_packet(id, body),
+        )?;
+        drop(hooks);
+        let forwarded = match forwarded {
+            Some(forwarded) => forwarded,
+            None => return Ok(false),
+        };
+
+        self.finish_forward(PacketDirection::ServerBound, forwarded)?;
+        flush_if_idle(&self.client.reader, &mut self.server.writer)?;
+        Ok(true)
+    }
+
+    ///
+    /// Reads one packet from the server, and forwards it to the client. Returns `false` if the
+    /// server closed the connection.
+    ///
+    pub fn forward_clientbound(&mut self) -> ProxyResult<bool> {
+        let hooks = Mutex::new(&mut self.hooks);
+        let writer = &mut self.client.writer;
+        let forwarded = forward_one(
+            &mut self.server.reader,
+            &hooks,
+            PacketDirection::ClientBound,
+            |id, body| writer.write_raw_untyped_packet(id, body),
+        )?;
+        drop(hooks);
+        let forwarded = match forwarded {
+            Some(forwarded) => forwarded,
+            None => return Ok(false),
+        };
+
+        self.finish_forward(PacketDirection::ClientBound, forwarded)?;
+        flush_if_idle(&self.server.reader, &mut self.client.writer)?;
+        Ok(true)
+    }
+
+    fn finish_forward(
+        &mut self,
+        direction: PacketDirection,
+        forwarded: Forwarded,
+    ) -> ProxyResult<()> {
+        let Forwarded {
+            id,
+            read_transition,
+            write_transition,
+            forwarded,
+            inject,
+        } = forwarded;
+
+        let (client, server) = match direction {
+            PacketDirection::ServerBound => (read_transition, write_transition),
+            PacketDirection::ClientBound => (write_transition, read_transition),
+        };
+        if let Some(transition) = client {
+            transition.apply(&mut self.client);
+        }
+        if let Some(transition) = server {
+            transition.apply(&mut self.server);
+        }
+
+        let written = write_injected(&mut self.client, inject.to_client)
+            .and_then(|_| write_injected(&mut self.server, inject.to_server));
+        // the proxy applied the transitions itself, so auto state must not apply them again
+        self.client.discard_auto_state();
+        self.server.discard_auto_state();
+        written?;
+
+        self.next = self.next_after(direction, id, forwarded);
+        Ok(())
+    }
+
+    ///
+    /// Relays packets in both directions until both sides closed their connection, or forwarding
+    /// fails. The handshake and login are relayed one packet at a time, since each side waits for
+    /// the other. Once both sides are in `Play`, each direction is relayed on its own thread.
+    ///
+    /// When one direction ends, `ProxyHooks::on_closed` is called, and the other direction keeps
+    /// going until its side closes too. If forwarding failed in either direction, the error is
+    /// returned.
+    ///
+    pub fn run(&mut self) -> ProxyResult<()>
+    where
+        CR: Send,
+        CW: Send,
+        SR: Send,
+        SW: Send,
+    {
+        while !self.in_play() {
+            let direction = self.next;
+            let result = match direction {
+                PacketDirection::ServerBound => self.forward_serverbound(),
+                PacketDirection::ClientBound => self.forward_clientbound(),
+            };
+            match result {
+                Ok(true) => {}
+                Ok(false) => {
+                    self.closed(direction);
+                    return Ok(());
+                }
+                Err(err) => {
+                    self.closed(direction);
+                    return Err(err);
+                }
+            }
+        }
+
+        self.run_play()
+    }
+
+    fn closed(&mut self, direction: PacketDirection) {
+        if let Some(hooks) = self.hooks.as_mut() {
+            hooks.on_closed(direction);
+        }
+    }
+
+    // no packet of the Play state changes the state, so the halves can be used independently
+    fn run_play(&mut self) -> ProxyResult<()>
+    where
+        CR: Send,
+        CW: Send,
+        SR: Send,
+        SW: Send,
+    {
+        let client_reader = &mut self.client.reader;
+        let server_reader = &mut self.server.reader;
+        let client_writer = Mutex::new(&mut self.client.writer);
+        let server_writer = Mutex::new(&mut self.server.writer);
+        let hooks = Mutex::new(&mut self.hooks);
+
+        let (serverbound, clientbound) = std::thread::scope(|scope| {
+            let serverbound = scope.spawn(|| {
+                pump_play(
+                    client_reader,
+                    &server_writer,
+                    &client_writer,
+                    &hooks,
+                    PacketDirection::ServerBound,
+                )
+            });
+            let clientbound = pump_play(
+                server_reader,
+                &client_writer,
+                &server_writer,
+                &hooks,
+                PacketDirection::ClientBound,
+            );
+            let serverbound = serverbound
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+            (serverbound, clientbound)
+        });
+
+        serverbound.and(clientbound)
+    }
+}
+
+fn pump_play<R, DW, SW>(
+    reader: &mut CraftReader<R>,
+    destination: &Mutex<&mut CraftWriter<DW>>,
+    source: &Mutex<&mut CraftWriter<SW>>,
+    hooks: &Mutex<&mut Option<Box<dyn ProxyHooks>>>,
+    direction: PacketDirection,
+) -> ProxyResult<()>
+where
+    CraftReader<R>: CraftSyncReader,
+    DW: io::Write,
+    SW: io::Write,
+{
+    let result = loop {
+        let forwarded = forward_one(reader, hooks, direction, |id, body| {
+            lock(destination).write_raw_untyped_packet(id, body)
+        });
+        let inject = match forwarded {
+            Ok(Some(forwarded)) => forwarded.inject,
+            Ok(None) => break Ok(()),
+            Err(err) => break Err(err),
+        };
+
+        let (to_destination, to_source) = match direction {
+            PacketDirection::ServerBound => (inject.to_server, inject.to_client),
+            PacketDirection::ClientBound => (inject.to_client, inject.to_server),
+        };
+        // the other direction locks the writers the other way around, so only one is held at once
+        let written = {
+            let mut destination = lock(destination);
+            write_all_injected(&mut destination, to_destination)
+                .and_then(|_| flush_if_idle(reader, &mut destination))
+        };
+        if let Err(err) = written {
+            break Err(err.into());
+        }
+
+        // nothing else flushes the source's writer until its own direction forwards a packet
+        let written = if to_source.is_empty() {
+            Ok(())
+        } else {
+            let mut source = lock(source);
+            write_all_injected(&mut source, to_source).and_then(|_| source.flush())
+        };
+        if let Err(err) = written {
+            break Err(err.into());
+        }
+    };
+
+    if let Some(hooks) = lock(hooks).as_mut() {
+        hooks.on_closed(direction);
+    }
+    result
+}
+
+fn write_all_injected<W>(writer: &mut CraftWriter<W>, packets: Vec<(Id, Vec<u8>)>) -> WriteResult<()>
+where
+    W: io::Write,
+{
+    for (id, body) in packets {
+        writer.write_raw_untyped_packet(id, &body)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::HANDSHAKE_ID;
+    use crate::tcp::CraftTcpConnection;
+    use crate::test_util::tcp_pair;
+
+    fn id(id: i32, state: State, direction: PacketDirection) -> Id {
+        Id {
+            id,
+            state,
+            direction,
+        }
+    }
+
+    fn send(connection: &mut CraftTcpConnection, id: Id, body: &[u8]) {
+        connection.write_raw_untyped_packet(id, body).unwrap();
+        connection.flush().unwrap();
+    }
+
+    fn receive(connection: &mut CraftTcpConnection) -> (Id, Vec<u8>) {
+        let (id, body) = connection.read_raw_untyped_packet().unwrap().unwrap();
+        (id, body.to_vec())
+    }
+
+    // protocol version 754, "localhost", port 25565, next state Login
+    const HANDSHAKE: [u8; 15] = [
+        0xF2, 0x05, 0x09, b'l', b'o', b'c', b'a', b'l', b'h', b'o', b's', b't', 0x63, 0xDD, 0x02,
+    ];
+
+    #[test]
+    fn relays_login_and_play_in_both_directions() {
+        let (player, proxy_client) = tcp_pair();
+        let (proxy_server, backend) = tcp_pair();
+        let mut player =
+            CraftTcpConnection::from_std(player, PacketDirection::ClientBound).unwrap();
+        let mut backend =
+            CraftTcpConnection::from_std(backend, PacketDirection::ServerBound).unwrap();
+        let mut proxy = CraftProxy::new(
+            CraftTcpConnection::wrap_client_stream_std(proxy_client).unwrap(),
+            CraftTcpConnection::from_std(proxy_server, PacketDirection::ClientBound).unwrap(),
+        );
+        let relay = std::thread::spawn(move || proxy.run().map(|_| proxy));
+
+        send(&mut player, HANDSHAKE_ID, &HANDSHAKE);
+        player.set_state(State::Login);
+        let login_start = id(0x00, State::Login, PacketDirection::ServerBound);
+        send(&mut player, login_start, b"\x04joey");
+
+        assert_eq!(receive(&mut backend), (HANDSHAKE_ID, HANDSHAKE.to_vec()));
+        backend.set_state(State::Login);
+        assert_eq!(receive(&mut backend), (login_start, b"\x04joey".to_vec()));
+        let login_success = id(0x02, State::Login, PacketDirection::ClientBound);
+        send(&mut backend, login_success, b"success");
+        backend.set_state(State::Play);
+
+        assert_eq!(receive(&mut player), (login_success, b"success".to_vec()));
+        player.set_state(State::Play);
+
+        let serverbound = id(0x10, State::Play, PacketDirection::ServerBound);
+        send(&mut player, serverbound, &[1, 2, 3]);
+        assert_eq!(receive(&mut backend), (serverbound, vec![1, 2, 3]));
+
+        let clientbound = id(0x20, State::Play, PacketDirection::ClientBound);
+        send(&mut backend, clientbound, &[4, 5, 6]);
+        assert_eq!(receive(&mut player), (clientbound, vec![4, 5, 6]));
+
+        drop(player);
+        drop(backend);
+        let proxy = relay.join().unwrap().unwrap();
+        let (client, server) = proxy.into_inner();
+        assert_eq!(client.writer.state(), State::Play);
+        assert_eq!(server.writer.state(), State::Play);
+        assert_eq!(server.protocol_version(), Some(754));
+    }
+
+    struct Echo;
+
+    impl ProxyHooks for Echo {
+        fn on_serverbound(
+            &mut self,
+            id: Id,
+            body: &[u8],
+            inject: &mut ProxyInjector,
+        ) -> PacketVerdict {
+            let echo = Id {
+                direction: PacketDirection::ClientBound,
+                ..id
+            };
+            inject.send_to_client(echo, body.to_vec());
+            PacketVerdict::Reject
+        }
+    }
+
+    #[test]
+    fn injects_into_the_source_while_relaying_play() {
+        let (player, proxy_client) = tcp_pair();
+        let (proxy_server, backend) = tcp_pair();
+        let mut player = CraftTcpConnection::from_std_with_state(
+            player,
+            PacketDirection::ClientBound,
+            State::Play,
+        )
+        .unwrap();
+        let mut backend = CraftTcpConnection::from_std_with_state(
+            backend,
+            PacketDirection::ServerBound,
+            State::Play,
+        )
+        .unwrap();
+        let mut proxy = CraftProxy::new(
+            CraftTcpConnection::from_std_with_state(
+                proxy_client,
+                PacketDirection::ServerBound,
+                State::Play,
+            )
+            .unwrap(),
+            CraftTcpConnection::from_std_with_state(
+                proxy_server,
+                PacketDirection::ClientBound,
+                State::Play,
+            )
+            .unwrap(),
+        );
+        proxy.set_hooks(Some(Box::new(Echo)));
+        let relay = std::thread::spawn(move || proxy.run());
+
+        // both directions write to the client at once, which must not deadlock
+        let clientbound = id(0x20, State::Play, PacketDirection::ClientBound);
+        let serverbound = id(0x20, State::Play, PacketDirection::ServerBound);
+        for n in 0..100u8 {
+            send(&mut backend, clientbound, &[n]);
+            send(&mut player, serverbound, &[128 + n]);
+        }
+
+        let mut relayed = 0;
+        let mut echoed = 0;
+        for _ in 0..200 {
+            let (id, body) = receive(&mut player);
+            assert_eq!(id, clientbound);
+            assert_eq!(body.len(), 1);
+            if body[0] < 128 {
+                assert_eq!(body[0] as usize, relayed);
+                relayed += 1;
+            } else {
+                assert_eq!(body[0] as usize, 128 + echoed);
+                echoed += 1;
+            }
+        }
+        assert_eq!(relayed, 100);
+        assert_eq!(echoed, 100);
+
+        drop(player);
+        drop(backend);
+        relay.join().unwrap().unwrap();
+    }
+}
@@ -1,9 +1,21 @@
 #[cfg(feature = "encryption")]
 use crate::cfb8::{setup_craft_cipher, CipherError, CraftCipher};
-use crate::util::get_sized_buf;
-use crate::wrapper::{CraftIo, CraftWrapper};
+use crate::util::{
+    buf_capacity, get_exact_sized_buf, get_sized_buf_with, is_timeout, VAR_INT_BUF_SIZE,
+};
+use crate::hooks::FrameHooks;
+use crate::middleware::{MiddlewareChain, PacketVerdict};
+use crate::stats::FrameSizeStats;
+use crate::strict::PacketIdRules;
+use crate::timing::MinThroughput;
+use crate::wrapper::{
+    BufferAllocator, BufferCapacities, BufferGrowth, CraftIo, CraftWrapper, MemoryUsage,
+    ProtocolSettings, SessionOptions, StateMaxPacketSizes,
+};
 #[cfg(feature = "compression")]
-use flate2::{DecompressError, FlushDecompress, Status};
+use crate::compression::{locate_frame_body, FrameBody};
+#[cfg(feature = "compression")]
+use flate2::DecompressError;
 use mcproto_rs::protocol::{Id, PacketDirection, RawPacket, State};
 #[cfg(feature = "gat")]
 use mcproto_rs::protocol::PacketKind;
@@ -11,13 +23,29 @@ use mcproto_rs::types::VarInt;
 use mcproto_rs::{Deserialize, Deserialized};
 #[cfg(feature = "backtrace")]
 use std::backtrace::Backtrace;
+#[cfg(feature = "tracing")]
+use tracing::Span;
 use std::io;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 #[cfg(any(feature = "futures-io", feature = "tokio-io"))]
-use async_trait::async_trait;
+use {
+    async_trait::async_trait,
+    std::{
+        pin::Pin,
+        task::{Context, Poll},
+    },
+};
+#[cfg(feature = "local-io")]
+use std::future::poll_fn;
 
 pub const DEAFULT_MAX_PACKET_SIZE: usize = 32 * 1000 * 1000; // 32MB
 
+// the read-ahead used by the small frame fast path
+const SMALL_FRAME_READ: usize = 128;
+
 #[derive(Debug, Error)]
 pub enum ReadError {
     #[error("i/o failure during read")]
@@ -55,7 +83,71 @@ pub enum ReadError {
         max_size: usize,
         #[cfg(feature = "backtrace")]
         backtrace: Backtrace,
-    }
+    },
+    #[error("packet length VarInt is longer than 5 bytes")]
+    VarIntTooLong {
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    ///
+    /// The wrapped stream's read timeout elapsed (or a non-blocking stream had no data). Any bytes
+    /// which were received are kept, so the read can simply be retried.
+    ///
+    #[error("read timed out")]
+    TimedOut {
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    ///
+    /// The peer sent only `received` bytes of a frame in `elapsed`, which is slower than the
+    /// reader's minimum throughput (see `CraftReader::set_min_throughput`).
+    ///
+    #[error("peer sent {received} bytes of a frame in {elapsed:?}, which is too slow")]
+    TooSlow {
+        received: usize,
+        elapsed: Duration,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    ///
+    /// The stream ended part way through a frame, after `received` of the `expected` bytes of the
+    /// frame (including its length prefix) were read. If the length prefix itself was cut off,
+    /// `expected` is the number of bytes needed to finish reading it. A stream which ends between
+    /// frames is not an error, and the read returns `Ok(None)` instead.
+    ///
+    #[error("connection closed after {received} of {expected} bytes of a frame")]
+    ConnectionClosedMidPacket {
+        expected: usize,
+        received: usize,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    ///
+    /// In strict mode (see `CraftReader::set_strict_ids`), a packet was read with an id which is not
+    /// valid in the reader's current state and direction. This usually means the two ends of the
+    /// connection disagree about the state, or that the peer is misbehaving.
+    ///
+    #[error("packet {id:?} is not valid in this state")]
+    StateViolation {
+        id: Id,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    ///
+    /// An earlier async read was cancelled (its future was dropped) while it was reading the rest
+    /// of a frame, so bytes of that frame may have been taken from the stream and lost, and the
+    /// start of the next frame is unknown. This usually means a read was raced against something
+    /// else (such as in `tokio::select!`). Reads which read ahead (see
+    /// `CraftReader::set_read_ahead`) and `poll_read_packet` can be cancelled safely.
+    ///
+    /// Every later read fails with this error too. It is only detected in debug builds, or with
+    /// the `cancel-guard` feature, and the reader silently loses its place otherwise.
+    ///
+    #[error("an earlier read was cancelled part way through a frame, so the stream is out of sync")]
+    CancelledMidFrame {
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
 }
 
 #[cfg(feature = "compression")]
@@ -69,6 +161,26 @@ pub enum DecompressErr {
 
 pub type ReadResult<P> = Result<Option<P>, ReadError>;
 
+///
+/// The result of the `try_read_*` functions of a `CraftReader`, which read from non-blocking
+/// streams.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TryRead<P> {
+    Packet(P),
+    ///
+    /// The stream has no more data for now. Everything received so far is buffered, and the next
+    /// call continues where this one stopped.
+    ///
+    WouldBlock,
+    ///
+    /// The stream ended between frames.
+    ///
+    Closed,
+}
+
+pub type TryReadResult<P> = Result<TryRead<P>, ReadError>;
+
 #[cfg(any(feature = "futures-io", feature = "tokio-io"))]
 #[async_trait]
 pub trait CraftAsyncReader {
@@ -101,6 +213,43 @@ pub trait CraftAsyncReader {
     async fn read_raw_untyped_packet_async(&mut self) -> ReadResult<(Id, &[u8])>;
 }
 
+///
+/// Same as `CraftAsyncReader`, but the reader and the futures it returns need not be `Send`. This
+/// is implemented for readers of streams which can not leave the thread they were created on, such
+/// as the streams of thread-per-core runtimes (glommio or monoio).
+///
+#[cfg(feature = "local-io")]
+#[async_trait(?Send)]
+pub trait CraftLocalReader {
+    #[cfg(not(feature = "gat"))]
+    async fn read_packet_local<'a, P>(&'a mut self) -> ReadResult<<P as RawPacket<'a>>::Packet>
+    where
+        P: RawPacket<'a>,
+    {
+        deserialize_raw_packet(self.read_raw_packet_local::<P>().await)
+    }
+
+    #[cfg(feature = "gat")]
+    async fn read_packet_local<P>(&mut self) -> ReadResult<<P::RawPacket<'_> as RawPacket<'_>>::Packet>
+    where
+        P: PacketKind
+    {
+        deserialize_raw_packet(self.read_raw_packet_local::<P>().await)
+    }
+
+    #[cfg(not(feature = "gat"))]
+    async fn read_raw_packet_local<'a, P>(&'a mut self) -> ReadResult<P>
+    where
+        P: RawPacket<'a>;
+
+    #[cfg(feature = "gat")]
+    async fn read_raw_packet_local<P>(&mut self) -> ReadResult<P::RawPacket<'_>>
+    where
+        P: PacketKind;
+
+    async fn read_raw_untyped_packet_local(&mut self) -> ReadResult<(Id, &[u8])>;
+}
+
 pub trait CraftSyncReader {
     #[cfg(not(feature = "gat"))]
     fn read_packet<'a, P>(&'a mut self) -> ReadResult<<P as RawPacket<'a>>::Packet>
@@ -147,6 +296,7 @@ pub struct CraftReader<R> {
     raw_ready: usize,
     raw_offset: usize,
     max_packet_size: usize,
+    buffer_growth: BufferGrowth,
     #[cfg(feature = "compression")]
     decompress_buf: Option<Vec<u8>>,
     #[cfg(feature = "compression")]
@@ -155,6 +305,26 @@ pub struct CraftReader<R> {
     direction: PacketDirection,
     #[cfg(feature = "encryption")]
     encryption: Option<CraftCipher>,
+    middleware: MiddlewareChain,
+    replaced_body: Option<Vec<u8>>,
+    strict_ids: Option<PacketIdRules>,
+    read_ahead: usize,
+    last_read_at: Option<Instant>,
+    allocator: Option<Arc<dyn BufferAllocator>>,
+    frame_sizes: Option<FrameSizeStats>,
+    state_max_packet_sizes: Option<StateMaxPacketSizes>,
+    min_throughput: Option<MinThroughput>,
+    frame_started_at: Option<Instant>,
+    packet_timestamps: bool,
+    filled_at: Option<Instant>,
+    packet_received_at: Option<Instant>,
+    frame_hooks: Option<Box<dyn FrameHooks>>,
+    awaiting_frame: bool,
+    // set while an async read of the rest of a frame is awaited, see ReadError::CancelledMidFrame
+    #[cfg(any(debug_assertions, feature = "cancel-guard"))]
+    read_in_flight: bool,
+    #[cfg(feature = "tracing")]
+    span: Span,
 }
 
 impl<R> CraftWrapper<R> for CraftReader<R> {
@@ -166,6 +336,10 @@ impl<R> CraftWrapper<R> for CraftReader<R> {
 impl<R> CraftIo for CraftReader<R> {
     fn set_state(&mut self, next: State) {
         self.state = next;
+        if let Some(sizes) = self.state_max_packet_sizes {
+            self.max_packet_size = sizes.for_state(next);
+        }
+        self.middleware.on_state_change(self.direction, next);
     }
 
     #[cfg(feature = "compression")]
@@ -175,7 +349,17 @@ impl<R> CraftIo for CraftReader<R> {
 
     #[cfg(feature = "encryption")]
     fn enable_encryption(&mut self, key: &[u8], iv: &[u8]) -> Result<(), CipherError> {
-        setup_craft_cipher(&mut self.encryption, key, iv, false)
+        setup_craft_cipher(&mut self.encryption, key, iv, false)?;
+
+        // the peer encrypts everything after the packet which was just read, so bytes which were
+        // read ahead of it are ciphertext, and are decrypted now rather than being read as plaintext
+        if self.raw_ready > 0 {
+            let ready = self.raw_offset..(self.raw_offset + self.raw_ready);
+            let raw_buf = self.raw_buf.as_mut().expect("ready data is in raw_buf");
+            handle_decryption(self.encryption.as_mut(), &mut raw_buf[ready]);
+        }
+
+        Ok(())
     }
 
     fn set_max_packet_size(&mut self, max_size: usize) {
@@ -183,6 +367,13 @@ impl<R> CraftIo for CraftReader<R> {
         self.max_packet_size = max_size;
     }
 
+    fn set_state_max_packet_sizes(&mut self, sizes: Option<StateMaxPacketSizes>) {
+        self.state_max_packet_sizes = sizes;
+        if let Some(sizes) = sizes {
+            self.max_packet_size = sizes.for_state(self.state);
+        }
+    }
+
     fn ensure_buf_capacity(&mut self, capacity: usize) {
         let alloc_to = if capacity > self.max_packet_size {
             self.max_packet_size
@@ -190,7 +381,23 @@ impl<R> CraftIo for CraftReader<R> {
             capacity
         };
         self.move_ready_data_to_front();
-        get_sized_buf(&mut self.raw_buf, 0, alloc_to);
+        get_exact_sized_buf(&mut self.raw_buf, 0, alloc_to, self.allocator.as_deref());
+    }
+
+    fn set_buffer_growth(&mut self, growth: BufferGrowth) {
+        self.buffer_growth = growth;
+    }
+
+    fn set_buffer_allocator(&mut self, allocator: Option<Arc<dyn BufferAllocator>>) {
+        self.allocator = allocator;
+    }
+
+    fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            raw_buf: buf_capacity(&self.raw_buf),
+            #[cfg(feature = "compression")]
+            compression_buf: buf_capacity(&self.decompress_buf),
+        }
     }
 
     #[cfg(feature = "compression")]
@@ -200,7 +407,7 @@ impl<R> CraftIo for CraftReader<R> {
         } else {
             capacity
         };
-        get_sized_buf(&mut self.decompress_buf, 0, alloc_to);
+        get_exact_sized_buf(&mut self.decompress_buf, 0, alloc_to, self.allocator.as_deref());
     }
 }
 
@@ -214,15 +421,17 @@ macro_rules! rr_unwrap {
     };
 }
 
-macro_rules! check_unexpected_eof {
-    ($result: expr) => {
-        if let Err(err) = $result {
-            if err.kind() == std::io::ErrorKind::UnexpectedEof {
-                return Ok(None);
-            }
-
-            return Err(err.into());
-        }
+// prepares the region of raw_buf which directly follows the ready data to receive `n` more bytes
+macro_rules! fill_target {
+    ($self: ident, $n: expr) => {
+        get_sized_buf_with(
+            &mut $self.raw_buf,
+            $self.raw_offset + $self.raw_ready,
+            $n,
+            $self.buffer_growth,
+            $self.max_packet_size + VAR_INT_BUF_SIZE,
+            $self.allocator.as_deref(),
+        )
     };
 }
 
@@ -278,104 +487,185 @@ where
     }
 }
 
-impl<R> CraftReader<R>
+// readers of local streams are driven by the same state machine as poll_read_packet, which only
+// needs the stream to be Unpin
+#[cfg(feature = "local-io")]
+#[async_trait(?Send)]
+impl<R> CraftLocalReader for CraftReader<R>
 where
-    R: io::Read,
+    R: AsyncPollRead,
 {
-    fn read_untyped_packet_inner(&mut self) -> ReadResult<(Id, &[u8])> {
-        if let Some(primary_packet_len) = self.read_raw_inner()? {
-            self.read_untyped_packet_in_buf(primary_packet_len)
-        } else {
-            Ok(None)
-        }
+    #[cfg(not(feature = "gat"))]
+    async fn read_raw_packet_local<'a, P>(&'a mut self) -> ReadResult<P>
+    where
+        P: RawPacket<'a>,
+    {
+        let packet = rr_unwrap!(poll_fn(|cx| self.poll_read_decoded(cx)).await);
+        self.create_raw_packet(packet)
     }
 
-    fn read_raw_packet_inner<'a, P>(&'a mut self) -> ReadResult<P>
+    #[cfg(feature = "gat")]
+    async fn read_raw_packet_local<P>(&mut self) -> ReadResult<P::RawPacket<'_>>
     where
-        P: RawPacket<'a>
+        P: PacketKind,
     {
-        if let Some(primary_packet_len) = self.read_raw_inner()? {
-            self.read_packet_in_buf(primary_packet_len)
-        } else {
-            Ok(None)
-        }
+        let packet = rr_unwrap!(poll_fn(|cx| self.poll_read_decoded(cx)).await);
+        self.create_raw_packet(packet)
     }
 
-    fn read_raw_inner(&mut self) -> ReadResult<usize> {
-        self.move_ready_data_to_front();
-        
-        let primary_packet_len = rr_unwrap!(self.read_packet_len_sync()).0 as usize;
-        if primary_packet_len > self.max_packet_size {
-            return Err(ReadError::PacketTooLarge {
-                size: primary_packet_len,
-                max_size: self.max_packet_size,
-                #[cfg(feature="backtrace")]
-                backtrace: Backtrace::capture(),
-            });
-        }
+    async fn read_raw_untyped_packet_local(&mut self) -> ReadResult<(Id, &[u8])> {
+        let packet = rr_unwrap!(poll_fn(|cx| self.poll_read_decoded(cx)).await);
+        Ok(Some(self.packet_parts(&packet)))
+    }
+}
 
-        if self.ensure_n_ready_sync(primary_packet_len)?.is_none() {
-            return Ok(None);
+impl<R> CraftReader<R>
+where
+    R: io::Read,
+{
+    // reads bytes without framing (see CraftTunnel), starting with the bytes which were already
+    // received
+    pub(crate) fn read_tunneled(&mut self, to: &mut [u8]) -> io::Result<usize> {
+        let buffered = self.take_buffered(to);
+        if buffered > 0 || to.is_empty() {
+            return Ok(buffered);
         }
 
-        Ok(Some(primary_packet_len))
+        let n = self.inner.read(to)?;
+        self.decrypt_tunneled(&mut to[..n]);
+        Ok(n)
     }
 
-    fn read_packet_len_sync(&mut self) -> ReadResult<VarInt> {
-        let mut position: usize = 0;
-        let mut value: i32 = 0;
-
-        loop {
-            let byte = &mut [rr_unwrap!(self.read_byte_sync())[0]];
-
-            #[cfg(feature = "encryption")]
-            handle_decryption(self.encryption.as_mut(), byte);
-
-            let byte = byte[0];
-
-            value |= ((byte & 0x7F) as i32) << (position * 7);
-
-            position += 1;
+    ///
+    /// Reads the next packet as if it was sent in the given direction, instead of this reader's
+    /// direction. The returned id (and the id middleware and strict mode see) has that direction.
+    ///
+    /// This is meant for passive sniffers which read a merged capture of both directions of a
+    /// connection from one source, and know the direction of each frame from the capture. Both
+    /// directions share this reader's compression threshold and cipher, so this only works for
+    /// captures where they are the same (such as unencrypted traffic).
+    ///
+    pub fn read_raw_untyped_packet_as(
+        &mut self,
+        direction: PacketDirection,
+    ) -> ReadResult<(Id, &[u8])> {
+        let packet = rr_unwrap!(self.read_decoded_inner(direction));
+        Ok(Some(self.packet_parts(&packet)))
+    }
 
-            self.raw_ready -= 1;
-            self.raw_offset += 1;
+    ///
+    /// Reads the next packet from a non-blocking stream (such as a socket registered with a mio
+    /// event loop), returning `TryRead::WouldBlock` instead of blocking once the stream has no
+    /// more data. Partial frames are kept in the buffer, and the next call resumes reading them,
+    /// so this can be called whenever the stream becomes readable, until it returns
+    /// `TryRead::WouldBlock`.
+    ///
+    /// This is the same as `read_packet`, except that a stream which would block (or times out)
+    /// is not an error.
+    ///
+    pub fn try_read_packet<'a, P>(&'a mut self) -> TryReadResult<P::Packet>
+    where
+        P: RawPacket<'a>,
+    {
+        Ok(match self.try_read_raw_packet::<P>()? {
+            TryRead::Packet(raw) => TryRead::Packet(raw.deserialize()?),
+            TryRead::WouldBlock => TryRead::WouldBlock,
+            TryRead::Closed => TryRead::Closed,
+        })
+    }
 
-            if byte & 0x80 == 0 {
-                break Ok(Some(value.into()));
+    ///
+    /// Same as `try_read_packet`, but the packet is not deserialized.
+    ///
+    pub fn try_read_raw_packet<'a, P>(&'a mut self) -> TryReadResult<P>
+    where
+        P: RawPacket<'a>,
+    {
+        Ok(match self.try_read_decoded()? {
+            TryRead::Packet(packet) => {
+                let (id, body) = self.packet_parts(&packet);
+                TryRead::Packet(P::create(id, body)?)
             }
+            TryRead::WouldBlock => TryRead::WouldBlock,
+            TryRead::Closed => TryRead::Closed,
+        })
+    }
 
-            if position > 4 {
-                panic!("VarInt too long");
-            }
-        }
+    ///
+    /// Same as `try_read_packet`, but the packet is returned as an `Id` and the bytes of its body.
+    ///
+    pub fn try_read_raw_untyped_packet(&mut self) -> TryReadResult<(Id, &[u8])> {
+        Ok(match self.try_read_decoded()? {
+            TryRead::Packet(packet) => TryRead::Packet(self.packet_parts(&packet)),
+            TryRead::WouldBlock => TryRead::WouldBlock,
+            TryRead::Closed => TryRead::Closed,
+        })
     }
 
-    fn read_byte_sync(&mut self) -> ReadResult<&mut [u8]> {
-        if self.raw_ready < 1 {
-            let target =
-                get_sized_buf(&mut self.raw_buf, self.raw_offset, 1);
-            debug_assert_eq!(target.len(), 1);
-            check_unexpected_eof!(self.inner.read_exact(target));
-            self.raw_ready = 1;
+    fn try_read_decoded(&mut self) -> TryReadResult<DecodedPacket> {
+        match self.read_decoded_inner(self.direction) {
+            Ok(Some(packet)) => Ok(TryRead::Packet(packet)),
+            Ok(None) => Ok(TryRead::Closed),
+            Err(ReadError::TimedOut { .. }) => Ok(TryRead::WouldBlock),
+            Err(err) => Err(err),
         }
+    }
 
-        let ready = get_sized_buf(&mut self.raw_buf, self.raw_offset, 1);
-        debug_assert_eq!(ready.len(), 1);
+    fn read_untyped_packet_inner(&mut self) -> ReadResult<(Id, &[u8])> {
+        let packet = rr_unwrap!(self.read_decoded_inner(self.direction));
+        Ok(Some(self.packet_parts(&packet)))
+    }
 
-        Ok(Some(ready))
+    fn read_raw_packet_inner<'a, P>(&'a mut self) -> ReadResult<P>
+    where
+        P: RawPacket<'a>
+    {
+        let packet = rr_unwrap!(self.read_decoded_inner(self.direction));
+        self.create_raw_packet(packet)
     }
 
-    fn ensure_n_ready_sync(&mut self, n: usize) -> ReadResult<&[u8]> {
-        if self.raw_ready < n {
-            let to_read = n - self.raw_ready;
-            let target =
-                get_sized_buf(&mut self.raw_buf, self.raw_offset + self.raw_ready, to_read);
-            check_unexpected_eof!(self.inner.read_exact(target));
-            self.raw_ready = n;
+    fn read_decoded_inner(&mut self, direction: PacketDirection) -> ReadResult<DecodedPacket> {
+        loop {
+            let primary_packet_len = rr_unwrap!(self.read_raw_inner());
+            if let Some(packet) = self.decode_packet(primary_packet_len, direction)? {
+                return Ok(Some(packet));
+            }
         }
+    }
 
-        let ready = get_sized_buf(&mut self.raw_buf, self.raw_offset, n);
-        Ok(Some(ready))
+    fn read_raw_inner(&mut self) -> ReadResult<usize> {
+        self.compact_ready_data();
+
+        loop {
+            self.begin_frame();
+            match self.frame_progress()? {
+                FrameProgress::Complete { header_len, body_len } => {
+                    self.consume_header(header_len);
+                    break Ok(Some(body_len));
+                }
+                FrameProgress::Incomplete { needed } => {
+                    self.check_throughput()?;
+                    // bytes are committed as soon as they are read, so a read which times out can
+                    // be retried without losing any data
+                    let request = self.read_request(needed);
+                    let target = fill_target!(self, request);
+                    let n = match io::Read::read(&mut self.inner, target) {
+                        Ok(0) => return self.stream_ended(needed),
+                        Ok(n) => n,
+                        Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(err) if is_timeout(&err) => {
+                            self.check_throughput()?;
+                            return Err(ReadError::TimedOut {
+                                #[cfg(feature = "backtrace")]
+                                backtrace: Backtrace::capture(),
+                            })
+                        }
+                        Err(err) => return Err(err.into()),
+                    };
+                    self.commit_filled(n);
+                }
+            }
+        }
     }
 }
 
@@ -384,104 +674,257 @@ impl<R> CraftReader<R>
 where
     R: AsyncReadExact,
 {
+    pub(crate) async fn read_tunneled_async(&mut self, to: &mut [u8]) -> io::Result<usize> {
+        let buffered = self.take_buffered(to);
+        if buffered > 0 || to.is_empty() {
+            return Ok(buffered);
+        }
+
+        let n = self.inner.read_some(to).await?;
+        self.decrypt_tunneled(&mut to[..n]);
+        Ok(n)
+    }
+
     async fn read_raw_packet_inner_async<'a, P>(&'a mut self) -> ReadResult<P>
     where
         P: RawPacket<'a>
     {
-        if let Some(primary_packet_len) = self.read_raw_inner_async().await? {
-            self.read_packet_in_buf(primary_packet_len)
-        } else {
-            Ok(None)
-        }
+        let packet = rr_unwrap!(self.read_decoded_inner_async(self.direction).await);
+        self.create_raw_packet(packet)
+    }
+
+    ///
+    /// Same as `read_raw_untyped_packet_as`, but reads from an async stream.
+    ///
+    pub async fn read_raw_untyped_packet_as_async(
+        &mut self,
+        direction: PacketDirection,
+    ) -> ReadResult<(Id, &[u8])> {
+        let packet = rr_unwrap!(self.read_decoded_inner_async(direction).await);
+        Ok(Some(self.packet_parts(&packet)))
     }
 
     async fn read_raw_untyped_packet_inner_async(&mut self) -> ReadResult<(Id, &[u8])> {
-        if let Some(primary_packet_len) = self.read_raw_inner_async().await? {
-            self.read_untyped_packet_in_buf(primary_packet_len)
-        } else {
-            Ok(None)
+        let packet = rr_unwrap!(self.read_decoded_inner_async(self.direction).await);
+        Ok(Some(self.packet_parts(&packet)))
+    }
+
+    async fn read_decoded_inner_async(
+        &mut self,
+        direction: PacketDirection,
+    ) -> ReadResult<DecodedPacket> {
+        loop {
+            let primary_packet_len = rr_unwrap!(self.read_raw_inner_async().await);
+            if let Some(packet) = self.decode_packet(primary_packet_len, direction)? {
+                return Ok(Some(packet));
+            }
         }
     }
 
     async fn read_raw_inner_async(&mut self) -> ReadResult<usize> {
-        self.move_ready_data_to_front();
-        
-        let primary_packet_len = rr_unwrap!(self.read_packet_len_async().await).0 as usize;
-        if primary_packet_len > self.max_packet_size {
-            return Err(ReadError::PacketTooLarge {
-                size: primary_packet_len,
-                max_size: self.max_packet_size,
-                #[cfg(feature="backtrace")]
-                backtrace: Backtrace::capture(),
-            });
-        }
+        self.check_cancelled()?;
+        self.compact_ready_data();
 
-        if self.ensure_n_ready_async(primary_packet_len).await?.is_none() {
-            return Ok(None);
+        loop {
+            self.begin_frame();
+            match self.frame_progress()? {
+                FrameProgress::Complete { header_len, body_len } => {
+                    self.consume_header(header_len);
+                    break Ok(Some(body_len));
+                }
+                FrameProgress::Incomplete { needed } => {
+                    self.check_throughput()?;
+                    let request = self.read_request(needed);
+                    if request > needed {
+                        let target = fill_target!(self, request);
+                        match self.inner.read_some(target).await? {
+                            0 => return self.stream_ended(needed),
+                            n => self.commit_filled(n),
+                        }
+                        continue;
+                    }
+
+                    // read_exact may take bytes from the stream without returning them if it is
+                    // cancelled, so the next read must know that this one finished. A read of one
+                    // byte either finishes or takes nothing.
+                    self.set_read_in_flight(needed > 1);
+                    let target = fill_target!(self, request);
+                    let result = self.inner.read_exact(target).await;
+                    self.set_read_in_flight(false);
+                    match result {
+                        Ok(()) => self.commit_filled(needed),
+                        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                            return self.stream_ended(needed)
+                        }
+                        Err(err) => return Err(err.into()),
+                    }
+                }
+            }
         }
-
-        debug_assert!(self.raw_ready >= primary_packet_len, "{} packet len bytes are ready (actual: {})", primary_packet_len, self.raw_ready);
-        Ok(Some(primary_packet_len))
     }
 
-    async fn read_packet_len_async(&mut self) -> ReadResult<VarInt> {
-        let mut position: usize = 0;
-        let mut value: i32 = 0;
-
-        loop {
-            let byte = &mut [rr_unwrap!(self.read_byte().await)[0]];
+    #[cfg(any(debug_assertions, feature = "cancel-guard"))]
+    fn set_read_in_flight(&mut self, in_flight: bool) {
+        self.read_in_flight = in_flight;
+    }
 
-            #[cfg(feature = "encryption")]
-            handle_decryption(self.encryption.as_mut(), byte);
+    #[cfg(not(any(debug_assertions, feature = "cancel-guard")))]
+    fn set_read_in_flight(&mut self, _in_flight: bool) {}
 
-            let byte = byte[0];
+    // fails if an earlier async read was cancelled part way through a frame
+    #[cfg(any(debug_assertions, feature = "cancel-guard"))]
+    fn check_cancelled(&self) -> Result<(), ReadError> {
+        if self.read_in_flight {
+            Err(ReadError::CancelledMidFrame {
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture(),
+            })
+        } else {
+            Ok(())
+        }
+    }
 
-            value |= ((byte & 0x7F) as i32) << (position * 7);
+    #[cfg(not(any(debug_assertions, feature = "cancel-guard")))]
+    fn check_cancelled(&self) -> Result<(), ReadError> {
+        Ok(())
+    }
+}
 
-            position += 1;
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+impl<R> CraftReader<R>
+where
+    R: AsyncPollRead,
+{
+    ///
+    /// Attempts to read a packet from the wrapped stream without blocking, registering the current
+    /// task to be woken up when more data is available if a complete packet cannot be read yet.
+    ///
+    /// Partially received packets are kept in the internal buffer, so the next call continues where
+    /// this one left off. This is a lower level alternative to `CraftAsyncReader`, which can be used
+    /// to drive a reader from a hand-written `Future` or a custom event loop without boxing.
+    ///
+    pub fn poll_read_packet<'a, P>(
+        &'a mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<ReadResult<P::Packet>>
+    where
+        P: RawPacket<'a>,
+    {
+        match self.poll_read_raw_packet::<P>(cx) {
+            Poll::Ready(raw) => Poll::Ready(deserialize_raw_packet(raw)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
 
-            self.raw_ready -= 1;
-            self.raw_offset += 1;
+    ///
+    /// Same as `poll_read_packet`, but the packet is not deserialized.
+    ///
+    pub fn poll_read_raw_packet<'a, P>(&'a mut self, cx: &mut Context<'_>) -> Poll<ReadResult<P>>
+    where
+        P: RawPacket<'a>,
+    {
+        match self.poll_read_decoded(cx) {
+            Poll::Ready(Ok(Some(packet))) => Poll::Ready(self.create_raw_packet(packet)),
+            Poll::Ready(Ok(None)) => Poll::Ready(Ok(None)),
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
 
-            if byte & 0x80 == 0 {
-                break Ok(Some(value.into()));
-            }
+    ///
+    /// Same as `poll_read_packet`, but the packet is returned as an `Id` and the bytes of its body.
+    ///
+    pub fn poll_read_raw_untyped_packet(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<ReadResult<(Id, &[u8])>> {
+        match self.poll_read_decoded(cx) {
+            Poll::Ready(Ok(Some(packet))) => Poll::Ready(Ok(Some(self.packet_parts(&packet)))),
+            Poll::Ready(Ok(None)) => Poll::Ready(Ok(None)),
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
 
-            if position > 4 {
-                panic!("VarInt too long");
+    fn poll_read_decoded(&mut self, cx: &mut Context<'_>) -> Poll<ReadResult<DecodedPacket>> {
+        loop {
+            let primary_packet_len = match self.poll_read_raw_inner(cx) {
+                Poll::Ready(Ok(Some(primary_packet_len))) => primary_packet_len,
+                Poll::Ready(Ok(None)) => return Poll::Ready(Ok(None)),
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            };
+
+            match self.decode_packet(primary_packet_len, self.direction) {
+                Ok(Some(packet)) => return Poll::Ready(Ok(Some(packet))),
+                Ok(None) => {}
+                Err(err) => return Poll::Ready(Err(err)),
             }
         }
     }
 
-    async fn read_byte(&mut self) -> ReadResult<&mut [u8]> {
-        if self.raw_ready < 1 {
-            let target =
-                get_sized_buf(&mut self.raw_buf, self.raw_offset, 1);
-            debug_assert_eq!(target.len(), 1);
-            check_unexpected_eof!(self.inner.read_exact(target).await);
-            self.raw_ready = 1;
+    fn poll_read_raw_inner(&mut self, cx: &mut Context<'_>) -> Poll<ReadResult<usize>> {
+        self.compact_ready_data();
+
+        loop {
+            self.begin_frame();
+            match self.frame_progress()? {
+                FrameProgress::Complete { header_len, body_len } => {
+                    self.consume_header(header_len);
+                    return Poll::Ready(Ok(Some(body_len)));
+                }
+                FrameProgress::Incomplete { needed } => {
+                    self.check_throughput()?;
+                    let request = self.read_request(needed);
+                    let target = fill_target!(self, request);
+                    match self.inner.poll_read_into(cx, target) {
+                        Poll::Ready(Ok(0)) => return Poll::Ready(self.stream_ended(needed)),
+                        Poll::Ready(Ok(n)) => self.commit_filled(n),
+                        Poll::Ready(Err(err)) => return Poll::Ready(Err(err.into())),
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+            }
         }
+    }
+}
 
-        let ready = get_sized_buf(&mut self.raw_buf, self.raw_offset, 1);
-        debug_assert_eq!(ready.len(), 1);
+///
+/// A stream which can be read from by polling, used by the `poll_read_*` functions of
+/// `CraftReader`. This crate implements it for implementors of the `AsyncRead` trait of either
+/// tokio or futures, depending on the enabled features.
+///
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+pub trait AsyncPollRead: Unpin {
+    ///
+    /// Attempts to read some bytes into `to`, returning how many bytes were read. Reading 0 bytes
+    /// into a non-empty `to` means the stream has ended.
+    ///
+    fn poll_read_into(&mut self, cx: &mut Context<'_>, to: &mut [u8]) -> Poll<io::Result<usize>>;
+}
 
-        Ok(Some(ready))
+#[cfg(all(feature = "futures-io", not(feature = "tokio-io")))]
+impl<R> AsyncPollRead for R
+where
+    R: futures::AsyncRead + Unpin,
+{
+    fn poll_read_into(&mut self, cx: &mut Context<'_>, to: &mut [u8]) -> Poll<io::Result<usize>> {
+        futures::AsyncRead::poll_read(Pin::new(self), cx, to)
     }
+}
 
-    async fn ensure_n_ready_async(&mut self, n: usize) -> ReadResult<&mut [u8]> {
-        if self.raw_ready < n {
-            let to_read = n - self.raw_ready;
-            let target =
-                get_sized_buf(&mut self.raw_buf, self.raw_offset + self.raw_ready, to_read);
-            debug_assert_eq!(target.len(), to_read);
-            check_unexpected_eof!(self.inner.read_exact(target).await);
-            self.raw_ready = n;
+#[cfg(feature = "tokio-io")]
+impl<R> AsyncPollRead for R
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    fn poll_read_into(&mut self, cx: &mut Context<'_>, to: &mut [u8]) -> Poll<io::Result<usize>> {
+        let mut buf = tokio::io::ReadBuf::new(to);
+        match tokio::io::AsyncRead::poll_read(Pin::new(self), cx, &mut buf) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(buf.filled().len())),
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            Poll::Pending => Poll::Pending,
         }
-
-        let ready = get_sized_buf(&mut self.raw_buf, self.raw_offset, n);
-        debug_assert_eq!(ready.len(), n);
-
-        Ok(Some(ready))
     }
 }
 
@@ -489,6 +932,12 @@ where
 #[async_trait]
 pub trait AsyncReadExact: Unpin + Sync + Send {
     async fn read_exact(&mut self, to: &mut [u8]) -> Result<(), io::Error>;
+
+    ///
+    /// Reads at least one byte (unless the stream has ended) and at most `to.len()` bytes,
+    /// returning how many bytes were read.
+    ///
+    async fn read_some(&mut self, to: &mut [u8]) -> Result<usize, io::Error>;
 }
 
 #[cfg(all(feature = "futures-io", not(feature = "tokio-io")))]
@@ -500,6 +949,10 @@ where
     async fn read_exact(&mut self, to: &mut [u8]) -> Result<(), io::Error> {
         futures::AsyncReadExt::read_exact(self, to).await
     }
+
+    async fn read_some(&mut self, to: &mut [u8]) -> Result<usize, io::Error> {
+        futures::AsyncReadExt::read(self, to).await
+    }
 }
 
 #[cfg(feature = "tokio-io")]
@@ -512,6 +965,10 @@ where
         tokio::io::AsyncReadExt::read_exact(self, to).await?;
         Ok(())
     }
+
+    async fn read_some(&mut self, to: &mut [u8]) -> Result<usize, io::Error> {
+        tokio::io::AsyncReadExt::read(self, to).await
+    }
 }
 
 macro_rules! dsz_unwrap {
@@ -547,12 +1004,432 @@ impl<R> CraftReader<R> {
             direction,
             #[cfg(feature = "encryption")]
             encryption: None,
-            max_packet_size: DEAFULT_MAX_PACKET_SIZE
+            max_packet_size: DEAFULT_MAX_PACKET_SIZE,
+            buffer_growth: BufferGrowth::default(),
+            middleware: MiddlewareChain::default(),
+            replaced_body: None,
+            strict_ids: None,
+            read_ahead: 0,
+            last_read_at: None,
+            allocator: None,
+            frame_sizes: None,
+            state_max_packet_sizes: None,
+            min_throughput: None,
+            frame_started_at: None,
+            packet_timestamps: false,
+            filled_at: None,
+            packet_received_at: None,
+            frame_hooks: None,
+            awaiting_frame: true,
+            #[cfg(any(debug_assertions, feature = "cancel-guard"))]
+            read_in_flight: false,
+            #[cfg(feature = "tracing")]
+            span: Span::none(),
         }
     }
 
-    fn read_untyped_packet_in_buf(&mut self, size: usize) -> ReadResult<(Id, &[u8])>
+    ///
+    /// Wraps a stream which is already part of an established session, applying its state,
+    /// compression and encryption from `options` before the reader is returned. See
+    /// `SessionOptions`.
+    ///
+    pub fn wrap_with_options(
+        inner: R,
+        direction: PacketDirection,
+        options: &SessionOptions,
+    ) -> Self {
+        let mut out = Self::wrap_with_state(inner, direction, options.state);
+        options.apply(&mut out);
+        out
+    }
+
+    ///
+    /// Same as `wrap_with_state`, but eagerly allocates the internal buffers to the sizes
+    /// specified in `capacities`, so a reader expecting large packets early on does not grow its
+    /// buffers several times while reading them.
+    ///
+    pub fn wrap_with_capacities(
+        inner: R,
+        direction: PacketDirection,
+        state: State,
+        capacities: BufferCapacities,
+    ) -> Self {
+        let mut out = Self::wrap_with_state(inner, direction, state);
+        capacities.apply(&mut out);
+        out
+    }
+
+    ///
+    /// Replaces the wrapped stream, keeping all other state of this reader (the packet state,
+    /// compression, encryption, middleware and any received bytes which have not been read as a
+    /// packet yet). This is useful to move an established connection to a different I/O
+    /// implementation, such as from a blocking thread into a tokio runtime.
+    ///
+    /// Any bytes which were already read from the old stream but are held in a buffer of its own
+    /// (such as a `BufReader`) must be passed to `push_received` afterwards.
+    ///
+    pub fn map_inner<R2, F>(self, f: F) -> CraftReader<R2>
+    where
+        F: FnOnce(R) -> R2,
     {
+        CraftReader {
+            inner: f(self.inner),
+            raw_buf: self.raw_buf,
+            raw_ready: self.raw_ready,
+            raw_offset: self.raw_offset,
+            max_packet_size: self.max_packet_size,
+            buffer_growth: self.buffer_growth,
+            #[cfg(feature = "compression")]
+            decompress_buf: self.decompress_buf,
+            #[cfg(feature = "compression")]
+            compression_threshold: self.compression_threshold,
+            state: self.state,
+            direction: self.direction,
+            #[cfg(feature = "encryption")]
+            encryption: self.encryption,
+            middleware: self.middleware,
+            replaced_body: self.replaced_body,
+            strict_ids: self.strict_ids,
+            read_ahead: self.read_ahead,
+            last_read_at: self.last_read_at,
+            allocator: self.allocator,
+            frame_sizes: self.frame_sizes,
+            state_max_packet_sizes: self.state_max_packet_sizes,
+            min_throughput: self.min_throughput,
+            frame_started_at: self.frame_started_at,
+            packet_timestamps: self.packet_timestamps,
+            filled_at: self.filled_at,
+            packet_received_at: self.packet_received_at,
+            frame_hooks: self.frame_hooks,
+            awaiting_frame: self.awaiting_frame,
+            #[cfg(any(debug_assertions, feature = "cancel-guard"))]
+            read_in_flight: self.read_in_flight,
+            #[cfg(feature = "tracing")]
+            span: self.span,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    // reading from the stream directly would skip decryption and framing, so this is only used to
+    // inspect the stream without consuming anything (such as peeking at a socket)
+    #[cfg(feature = "tokio-io")]
+    pub(crate) fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    ///
+    /// Adds bytes which were already received from the wrapped stream (or one it replaced, see
+    /// `map_inner`), but were not passed to this reader. They are handled exactly like bytes read
+    /// from the stream (including decryption), and are read before anything else.
+    ///
+    pub fn push_received(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+
+        self.compact_ready_data();
+        let target = fill_target!(self, data.len());
+        target.copy_from_slice(data);
+        self.commit_filled(data.len());
+    }
+
+    #[cfg(feature = "encryption")]
+    pub(crate) fn set_cipher(&mut self, cipher: Option<CraftCipher>) {
+        self.encryption = cipher;
+    }
+
+    #[cfg(feature = "encryption")]
+    pub(crate) fn has_cipher(&self) -> bool {
+        self.encryption.is_some()
+    }
+
+    ///
+    /// The middleware chain every packet read by this reader passes through. Add middleware to it
+    /// using `MiddlewareChain::push`.
+    ///
+    pub fn middleware(&self) -> &MiddlewareChain {
+        &self.middleware
+    }
+
+    ///
+    /// Replaces the middleware chain of this reader, such as with a chain which is shared with a
+    /// writer.
+    ///
+    pub fn set_middleware(&mut self, chain: MiddlewareChain) {
+        self.middleware = chain;
+    }
+
+    ///
+    /// Enables (or disables, with `None`) strict mode, in which a packet with an id that is not
+    /// valid in the current state and direction fails the read with `ReadError::StateViolation`
+    /// as soon as its id is known, before middleware or deserialization see it.
+    ///
+    pub fn set_strict_ids(&mut self, rules: Option<PacketIdRules>) {
+        self.strict_ids = rules;
+    }
+
+    pub fn strict_ids(&self) -> Option<&PacketIdRules> {
+        self.strict_ids.as_ref()
+    }
+
+    ///
+    /// Sets how many bytes the reader requests from the stream whenever it needs more data (0, the
+    /// default, disables read-ahead). Normally, the reader reads the length prefix of each frame one
+    /// byte at a time before reading exactly its body, so that it never reads past the end of a
+    /// frame. With read-ahead, a single read can return many frames, which are then parsed from the
+    /// buffer without touching the stream again. On a busy connection, this saves several read
+    /// calls per packet. Frames which have already been buffered can be taken without ever reading
+    /// from the stream using `drain_buffered`.
+    ///
+    /// Bytes read past the end of a frame are kept in this reader's buffer and read as the next
+    /// frames. They would be lost by `into_inner`, so read-ahead should only be enabled on
+    /// connections which are not handed back to other code as a plain stream.
+    ///
+    pub fn set_read_ahead(&mut self, bytes: usize) {
+        self.read_ahead = bytes;
+    }
+
+    pub fn read_ahead(&self) -> usize {
+        self.read_ahead
+    }
+
+    ///
+    /// Enables (or disables) the small frame fast path, which is a read-ahead of 128 bytes (see
+    /// `set_read_ahead`). With it, the length prefix and body of a small frame (which most frames
+    /// on a live server are) usually arrive in a single read call.
+    ///
+    pub fn set_small_frame_fast_path(&mut self, enabled: bool) {
+        self.read_ahead = if enabled { SMALL_FRAME_READ } else { 0 };
+    }
+
+    ///
+    /// Sets the span the events of this reader are recorded in, and which is entered while
+    /// middleware and frame hooks are called, so they can be correlated with the other events of
+    /// the connection (such as a span with the player's name). The default is `Span::none()`.
+    ///
+    #[cfg(feature = "tracing")]
+    pub fn set_span(&mut self, span: Span) {
+        self.span = span;
+    }
+
+    #[cfg(feature = "tracing")]
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    ///
+    /// The number of received bytes which are buffered in this reader and have not been read as
+    /// packets yet.
+    ///
+    pub fn buffered_len(&self) -> usize {
+        self.raw_ready
+    }
+
+    // moves bytes which were received (and decrypted), but not read as packets yet, into `to`
+    fn take_buffered(&mut self, to: &mut [u8]) -> usize {
+        let n = self.raw_ready.min(to.len());
+        if n > 0 {
+            let raw_buf = self.raw_buf.as_ref().expect("ready data is in raw_buf");
+            to[..n].copy_from_slice(&raw_buf[self.raw_offset..self.raw_offset + n]);
+            self.consume_header(n);
+        }
+
+        n
+    }
+
+    fn decrypt_tunneled(&mut self, data: &mut [u8]) {
+        #[cfg(feature = "encryption")]
+        handle_decryption(self.encryption.as_mut(), data);
+        #[cfg(not(feature = "encryption"))]
+        let _ = data;
+    }
+
+    ///
+    /// Records the size of every frame read into `stats`, or stops recording them if `None`
+    /// (which is the default).
+    ///
+    pub fn set_frame_size_stats(&mut self, stats: Option<FrameSizeStats>) {
+        self.frame_sizes = stats;
+    }
+
+    ///
+    /// Fails reads with `ReadError::TooSlow` if the peer sends the bytes of a frame more slowly
+    /// than `limit` allows, or stops enforcing a minimum throughput if `None` (which is the
+    /// default). See `MinThroughput`.
+    ///
+    pub fn set_min_throughput(&mut self, limit: Option<MinThroughput>) {
+        self.min_throughput = limit;
+        self.frame_started_at = None;
+    }
+
+    ///
+    /// When a packet was last read, or `None` if no packet was read yet.
+    ///
+    pub fn last_read_at(&self) -> Option<Instant> {
+        self.last_read_at
+    }
+
+    ///
+    /// Sets (or removes, with `None`) the callbacks fired at the boundaries of every frame read. See
+    /// `FrameHooks`.
+    ///
+    pub fn set_frame_hooks(&mut self, hooks: Option<Box<dyn FrameHooks>>) {
+        self.frame_hooks = hooks;
+    }
+
+    ///
+    /// Enables (or disables) timestamping packets as they arrive, see `packet_received_at`. This is
+    /// disabled by default, since it reads the clock on every read call.
+    ///
+    pub fn set_packet_timestamps(&mut self, enabled: bool) {
+        self.packet_timestamps = enabled;
+        if !enabled {
+            self.filled_at = None;
+            self.packet_received_at = None;
+        }
+    }
+
+    ///
+    /// When the frame of the packet which was read last was completely received from the stream,
+    /// if packet timestamps are enabled (see `set_packet_timestamps`). Unlike `last_read_at`, this
+    /// is the arrival time of the packet, even if it was buffered (such as with read-ahead) long
+    /// before it was read, which makes it suitable for latency analysis and capture files.
+    ///
+    pub fn packet_received_at(&self) -> Option<Instant> {
+        self.packet_received_at
+    }
+
+    ///
+    /// Returns the next packet if it has already been received completely (such as with
+    /// read-ahead, see `set_read_ahead`), and `Ok(None)` otherwise. This never reads from the
+    /// stream, so it can be called in a loop after each read to handle every buffered packet
+    /// before waiting for more data.
+    ///
+    pub fn drain_buffered(&mut self) -> ReadResult<(Id, &[u8])> {
+        let packet = rr_unwrap!(self.next_buffered_packet());
+        Ok(Some(self.packet_parts(&packet)))
+    }
+
+    ///
+    /// Whether the next read returns without reading from the stream, because a complete frame is
+    /// already buffered (or because the buffered length prefix is invalid, which fails the read).
+    /// An event loop should drain these packets (see `drain_buffered`) before waiting for the
+    /// stream to become readable again, since it may never be woken up for data which was
+    /// already received.
+    ///
+    /// A buffered frame may still be rejected by the middleware, in which case the next read
+    /// continues with the frames after it.
+    ///
+    pub fn has_complete_frame_buffered(&self) -> bool {
+        !matches!(self.frame_progress(), Ok(FrameProgress::Incomplete { .. }))
+    }
+
+    ///
+    /// Skips buffered bytes up to the next position which looks like the start of a frame, after
+    /// a read failed on a corrupt frame (such as with `VarIntTooLong` or `PacketTooLarge`). This is
+    /// meant for sniffers and analysis tools, which would rather lose a few packets than the rest
+    /// of a long capture.
+    ///
+    /// A position looks like the start of a frame if its length prefix is valid, its packet id is
+    /// valid in the current state (according to the rules of strict mode if it is enabled, see
+    /// `set_strict_ids`, or the built in rules otherwise), and the bytes after it start with a
+    /// valid length prefix if the whole frame is buffered. Compressed frames are checked for a
+    /// zlib header instead of a packet id.
+    ///
+    /// **This is a guess.** Random bytes can look like a frame, and the packets read afterwards may
+    /// be garbage which happens to deserialize. It must never be used on a connection whose
+    /// packets are acted upon, only to keep observing a stream. Only buffered bytes are scanned, so
+    /// a reader which may be resynced should read ahead (see `set_read_ahead`).
+    ///
+    #[cfg(feature = "resync")]
+    pub fn resync(&mut self) -> ResyncOutcome {
+        let rules = self
+            .strict_ids
+            .clone()
+            .unwrap_or_else(|| PacketIdRules::for_protocol_version(None));
+        #[cfg(feature = "compression")]
+        let compressed = self.compression_threshold.is_some();
+        #[cfg(not(feature = "compression"))]
+        let compressed = false;
+
+        let ready: &[u8] = match self.raw_buf.as_ref() {
+            Some(buf) => &buf[self.raw_offset..(self.raw_offset + self.raw_ready)],
+            None => &[],
+        };
+        let mut skipped = 0;
+        let outcome = loop {
+            let guess = guess_frame_start(
+                &ready[skipped..],
+                self.max_packet_size,
+                compressed,
+                |id| {
+                    rules.is_valid(Id {
+                        id,
+                        state: self.state,
+                        direction: self.direction,
+                    })
+                },
+            );
+            match guess {
+                FrameGuess::Plausible => break ResyncOutcome::Found { skipped },
+                FrameGuess::Undecided => break ResyncOutcome::NeedMoreData { skipped },
+                FrameGuess::Implausible => skipped += 1,
+            }
+        };
+
+        self.consume_header(skipped);
+        self.frame_started_at = None;
+        outcome
+    }
+
+    ///
+    /// A snapshot of the protocol settings of this reader. See `ProtocolSettings`.
+    ///
+    pub fn settings(&self) -> ProtocolSettings {
+        ProtocolSettings {
+            state: self.state,
+            direction: self.direction,
+            #[cfg(feature = "compression")]
+            compression_threshold: self.compression_threshold,
+            #[cfg(feature = "encryption")]
+            encrypted: self.encryption.is_some(),
+            max_packet_size: self.max_packet_size,
+            state_max_packet_sizes: self.state_max_packet_sizes,
+            buffer_growth: self.buffer_growth,
+            buffer_capacities: self.memory_usage().into(),
+        }
+    }
+
+    fn next_buffered_packet(&mut self) -> ReadResult<DecodedPacket> {
+        loop {
+            self.compact_ready_data();
+            self.begin_frame();
+            match self.frame_progress()? {
+                FrameProgress::Complete { header_len, body_len } => {
+                    self.consume_header(header_len);
+                    if let Some(packet) = self.decode_packet(body_len, self.direction)? {
+                        return Ok(Some(packet));
+                    }
+                }
+                FrameProgress::Incomplete { .. } => return Ok(None),
+            }
+        }
+    }
+
+    // removes the frame of length `size` from the ready data, unwraps it, and passes it through
+    // the middleware chain as a packet sent in `direction`. Returns None if the middleware rejected
+    // the packet.
+    fn decode_packet(
+        &mut self,
+        size: usize,
+        direction: PacketDirection,
+    ) -> Result<Option<DecodedPacket>, ReadError> {
+        #[cfg(feature = "tracing")]
+        let _span = self.span.clone().entered();
+
         // find data in buf
         let offset = self.raw_offset;
         if self.raw_ready < size {
@@ -560,65 +1437,219 @@ impl<R> CraftReader<R> {
         }
         self.raw_ready -= size;
         self.raw_offset += size;
-        let buf =
-            &mut self.raw_buf.as_mut().expect("should exist right now")[offset..offset + size];
-
-        #[cfg(feature = "encryption")]
-        handle_decryption(self.encryption.as_mut(), buf);
+        self.last_read_at = Some(Instant::now());
+        self.frame_started_at = None;
+        // frames are only decoded once complete, and more data is only read while the next frame is
+        // incomplete, so the last bytes received are the ones which completed this frame
+        self.packet_received_at = self.filled_at;
+        let buf = &self.raw_buf.as_ref().expect("should exist right now")[offset..offset + size];
+        self.awaiting_frame = true;
+        if let Some(hooks) = self.frame_hooks.as_mut() {
+            hooks.on_frame_read(buf);
+        }
 
         // try to get the packet body bytes... this boils down to:
         // * check if compression enabled,
         //    * read data len (VarInt) which isn't compressed
         //    * if data len is 0, then rest of packet is not compressed, remaining data is body
         //    * otherwise, data len is decompressed length, so prepare a decompression buf and decompress from
-        //      the buffer into the decompression buffer, and return the slice of the decompression buffer
+        //      the buffer into the decompression buffer, and use the region of the decompression buffer
         //      which contains this packet's data
         // * if compression not enabled, then the buf contains only the packet body bytes
+        //
+        // the location of the body is remembered rather than a slice, so that a packet rejected by
+        // the middleware can be skipped without holding a borrow of the buffers
 
         #[cfg(feature = "compression")]
-        let packet_buf = if let Some(_) = self.compression_threshold {
-            let (data_len, rest) = dsz_unwrap!(buf, VarInt);
-            let data_len = data_len.0 as usize;
-            if data_len == 0 {
-                rest
-            } else if data_len >= self.max_packet_size {
-                return Err(ReadError::PacketTooLarge {
-                    size: data_len,
-                    max_size: self.max_packet_size,
-                    #[cfg(feature = "backtrace")]
-                    backtrace: Backtrace::capture()
-                })
-            } else {
-                decompress(rest, &mut self.decompress_buf, data_len)?
+        let (source, packet_start, packet_buf) = if let Some(_) = self.compression_threshold {
+            match locate_frame_body(
+                buf,
+                &mut self.decompress_buf,
+                self.max_packet_size,
+                self.allocator.as_deref(),
+            )? {
+                FrameBody::Uncompressed { offset: body_offset } => {
+                    (BodySource::Raw, offset + body_offset, &buf[body_offset..])
+                }
+                FrameBody::Decompressed { len } => {
+                    let decompressed = self.decompress_buf.as_ref().expect("was just decompressed");
+                    (BodySource::Decompressed, 0, &decompressed[..len])
+                }
             }
         } else {
-            buf
+            (BodySource::Raw, offset, buf)
         };
 
         #[cfg(not(feature = "compression"))]
-        let packet_buf = buf;
+        let (source, packet_start, packet_buf) = (BodySource::Raw, offset, buf);
+
+        if let Some(stats) = self.frame_sizes.as_ref() {
+            stats.record_read(size, packet_buf.len());
+        }
 
         let (raw_id, body_buf) = dsz_unwrap!(packet_buf, VarInt);
         let id = Id {
             id: raw_id.0,
             state: self.state.clone(),
-            direction: self.direction.clone()
+            direction,
         };
+        if let Some(rules) = self.strict_ids.as_ref() {
+            if !rules.is_valid(id) {
+                return Err(ReadError::StateViolation {
+                    id,
+                    #[cfg(feature = "backtrace")]
+                    backtrace: Backtrace::capture(),
+                });
+            }
+        }
 
-        Ok(Some((id, body_buf)))
+        let body_start = packet_start + (packet_buf.len() - body_buf.len());
+        let body = body_start..(packet_start + packet_buf.len());
+        #[cfg(feature = "tracing")]
+        tracing::trace!(id = id.id, state = ?id.state, size, "read packet");
+
+        Ok(match self.middleware.on_read(id, body_buf) {
+            PacketVerdict::Pass => Some(DecodedPacket { id, source, body }),
+            PacketVerdict::Replace(replacement) => {
+                let body = 0..replacement.len();
+                self.replaced_body = Some(replacement);
+                Some(DecodedPacket {
+                    id,
+                    source: BodySource::Replaced,
+                    body,
+                })
+            }
+            PacketVerdict::Reject => None,
+        })
     }
 
-    fn read_packet_in_buf<'a, P>(&'a mut self, size: usize) -> ReadResult<P>
+    fn packet_parts(&self, packet: &DecodedPacket) -> (Id, &[u8]) {
+        let buf = match packet.source {
+            BodySource::Raw => &self.raw_buf,
+            #[cfg(feature = "compression")]
+            BodySource::Decompressed => &self.decompress_buf,
+            BodySource::Replaced => &self.replaced_body,
+        };
+
+        let buf = buf.as_ref().expect("decoded packet's buffer should exist");
+        (packet.id, &buf[packet.body.clone()])
+    }
+
+    fn create_raw_packet<'a, P>(&'a self, packet: DecodedPacket) -> ReadResult<P>
     where
         P: RawPacket<'a>,
     {
-        if let Some((id, body_buf)) = self.read_untyped_packet_in_buf(size)? {
-            match P::create(id, body_buf) {
-                Ok(raw) => Ok(Some(raw)),
-                Err(err) => Err(err.into()),
+        let (id, body_buf) = self.packet_parts(&packet);
+        match P::create(id, body_buf) {
+            Ok(raw) => Ok(Some(raw)),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // inspects the ready data to find out whether a complete frame has been received, and if not,
+    // how many more bytes must be read before it could be. Incoming bytes are decrypted as soon as
+    // they are read (see commit_filled), so everything that is ready is plaintext.
+    fn frame_progress(&self) -> Result<FrameProgress, ReadError> {
+        let ready: &[u8] = match self.raw_buf.as_ref() {
+            Some(buf) => &buf[self.raw_offset..(self.raw_offset + self.raw_ready)],
+            None => &[],
+        };
+
+        Ok(match parse_frame_len(ready, self.max_packet_size)? {
+            Some((body_len, header_len)) => {
+                let total_len = header_len + body_len;
+                if self.raw_ready >= total_len {
+                    FrameProgress::Complete { header_len, body_len }
+                } else {
+                    FrameProgress::Incomplete { needed: total_len - self.raw_ready }
+                }
             }
+            // read the length one byte at a time, so we never read past the end of this frame
+            None => FrameProgress::Incomplete { needed: 1 },
+        })
+    }
+
+    // tells the hooks that a new frame is about to be read, once for each frame
+    fn begin_frame(&mut self) {
+        if self.awaiting_frame {
+            self.awaiting_frame = false;
+            if let Some(hooks) = self.frame_hooks.as_mut() {
+                hooks.before_frame_read();
+            }
+        }
+    }
+
+    // called while a frame is incomplete. The frame is in flight once any of its bytes have been
+    // received, and those bytes must have arrived quickly enough since then.
+    fn check_throughput(&mut self) -> Result<(), ReadError> {
+        let limit = match self.min_throughput {
+            Some(limit) if self.raw_ready > 0 => limit,
+            _ => return Ok(()),
+        };
+
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(*self.frame_started_at.get_or_insert(now));
+        if limit.is_violated(self.raw_ready, elapsed) {
+            Err(ReadError::TooSlow {
+                received: self.raw_ready,
+                elapsed,
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture(),
+            })
         } else {
+            Ok(())
+        }
+    }
+
+    // how many bytes to request from the stream when `needed` more bytes of the current frame are
+    // expected
+    fn read_request(&self, needed: usize) -> usize {
+        needed.max(self.read_ahead)
+    }
+
+    // called when the stream ends while `needed` more bytes of the current frame are expected.
+    // Whatever is ready is the start of that frame, so the stream only ended cleanly if nothing is.
+    fn stream_ended<T>(&self, needed: usize) -> ReadResult<T> {
+        if self.raw_ready == 0 {
             Ok(None)
+        } else {
+            Err(ReadError::ConnectionClosedMidPacket {
+                expected: self.raw_ready + needed,
+                received: self.raw_ready,
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture(),
+            })
+        }
+    }
+
+    fn commit_filled(&mut self, n: usize) {
+        #[cfg(feature = "encryption")]
+        {
+            let start = self.raw_offset + self.raw_ready;
+            let raw_buf = self.raw_buf.as_mut().expect("bytes were just read into raw_buf");
+            handle_decryption(self.encryption.as_mut(), &mut raw_buf[start..start + n]);
+        }
+
+        self.raw_ready += n;
+        if self.packet_timestamps {
+            self.filled_at = Some(Instant::now());
+        }
+    }
+
+    fn consume_header(&mut self, header_len: usize) {
+        self.raw_ready -= header_len;
+        self.raw_offset += header_len;
+    }
+
+    // Moves the ready data to the front of the buffer, but only once at least as many bytes have
+    // been consumed in front of it as are ready. This way each byte is moved at most once, no matter
+    // how much data is buffered ahead of the current packet, and the buffer never has to grow
+    // beyond twice the ready data to make room for more.
+    fn compact_ready_data(&mut self) {
+        if self.raw_ready == 0 {
+            self.raw_offset = 0;
+        } else if self.raw_offset >= self.raw_ready {
+            self.move_ready_data_to_front();
         }
     }
 
@@ -637,6 +1668,210 @@ impl<R> CraftReader<R> {
     }
 }
 
+// which buffer holds the body of a decoded packet
+enum BodySource {
+    Raw,
+    #[cfg(feature = "compression")]
+    Decompressed,
+    Replaced,
+}
+
+struct DecodedPacket {
+    id: Id,
+    source: BodySource,
+    body: Range<usize>,
+}
+
+enum FrameProgress {
+    Complete { header_len: usize, body_len: usize },
+    Incomplete { needed: usize },
+}
+
+///
+/// The result of `CraftReader::resync`.
+///
+#[cfg(feature = "resync")]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ResyncOutcome {
+    ///
+    /// A plausible frame starts after `skipped` bytes, and is read next.
+    ///
+    Found { skipped: usize },
+    ///
+    /// After skipping `skipped` bytes, too few bytes are buffered to tell whether a frame starts
+    /// there. The next read continues from there, and `resync` can be called again if it fails.
+    ///
+    NeedMoreData { skipped: usize },
+}
+
+#[cfg(feature = "resync")]
+enum FrameGuess {
+    Plausible,
+    Implausible,
+    Undecided,
+}
+
+// whether `data` looks like it starts with a frame, see CraftReader::resync
+#[cfg(feature = "resync")]
+fn guess_frame_start<F>(
+    data: &[u8],
+    max_packet_size: usize,
+    compressed: bool,
+    is_valid_id: F,
+) -> FrameGuess
+where
+    F: Fn(i32) -> bool,
+{
+    let (body_len, header_len) = match parse_frame_len(data, max_packet_size) {
+        Ok(Some(len)) => len,
+        Ok(None) => return FrameGuess::Undecided,
+        Err(_) => return FrameGuess::Implausible,
+    };
+    if body_len == 0 {
+        return FrameGuess::Implausible;
+    }
+
+    let frame_len = header_len + body_len;
+    let complete = data.len() >= frame_len;
+    let payload = &data[header_len..frame_len.min(data.len())];
+    // a VarInt which runs past the end of the buffered bytes may still be valid, but one which
+    // runs past the end of the frame is not
+    let truncated = || {
+        if complete {
+            FrameGuess::Implausible
+        } else {
+            FrameGuess::Undecided
+        }
+    };
+
+    let packet = if compressed {
+        match VarInt::mc_deserialize(payload) {
+            Ok(Deserialized { value: VarInt(0), data: rest }) => rest,
+            Ok(Deserialized { value: VarInt(data_len), data: rest }) => {
+                if data_len < 0 || data_len as usize > max_packet_size {
+                    return FrameGuess::Implausible;
+                }
+
+                // zlib streams (with the default window size) start with 0x78
+                match rest.first() {
+                    Some(0x78) => return guess_next_frame(data, frame_len, complete, max_packet_size),
+                    Some(_) => return FrameGuess::Implausible,
+                    None => return truncated(),
+                }
+            }
+            Err(_) if payload.len() < VAR_INT_BUF_SIZE => return truncated(),
+            Err(_) => return FrameGuess::Implausible,
+        }
+    } else {
+        payload
+    };
+
+    match VarInt::mc_deserialize(packet) {
+        Ok(Deserialized { value: VarInt(id), .. }) if is_valid_id(id) => {
+            guess_next_frame(data, frame_len, complete, max_packet_size)
+        }
+        Ok(_) => FrameGuess::Implausible,
+        Err(_) if packet.len() < VAR_INT_BUF_SIZE => truncated(),
+        Err(_) => FrameGuess::Implausible,
+    }
+}
+
+// the frame at the start of `data` looks valid, so it is plausible unless it is complete, and the
+// bytes after it can not be the start of another frame
+#[cfg(feature = "resync")]
+fn guess_next_frame(
+    data: &[u8],
+    frame_len: usize,
+    complete: bool,
+    max_packet_size: usize,
+) -> FrameGuess {
+    if complete && parse_frame_len(&data[frame_len..], max_packet_size).is_err() {
+        FrameGuess::Implausible
+    } else {
+        FrameGuess::Plausible
+    }
+}
+
+///
+/// Parses the length prefix of a frame from the front of `buf`, applying the same checks the
+/// `CraftReader` applies to every frame it reads:
+///
+/// * the length VarInt may be at most 5 bytes long, otherwise `ReadError::VarIntTooLong` is returned
+/// * the length may not exceed `max_packet_size`, otherwise `ReadError::PacketTooLarge` is returned
+///
+/// If `buf` does not yet contain a complete VarInt, `Ok(None)` is returned. Otherwise, the frame
+/// length and the number of bytes the length prefix occupied are returned.
+///
+pub fn parse_frame_len(buf: &[u8], max_packet_size: usize) -> Result<Option<(usize, usize)>, ReadError> {
+    let mut value: u32 = 0;
+
+    for (position, byte) in buf.iter().take(VAR_INT_BUF_SIZE).enumerate() {
+        value |= ((byte & 0x7F) as u32) << (position * 7);
+
+        if byte & 0x80 == 0 {
+            let size = value as usize;
+            if size > max_packet_size {
+                return Err(ReadError::PacketTooLarge {
+                    size,
+                    max_size: max_packet_size,
+                    #[cfg(feature = "backtrace")]
+                    backtrace: Backtrace::capture(),
+                });
+            }
+
+            return Ok(Some((size, position + 1)));
+        }
+    }
+
+    if buf.len() >= VAR_INT_BUF_SIZE {
+        Err(ReadError::VarIntTooLong {
+            #[cfg(feature = "backtrace")]
+            backtrace: Backtrace::capture(),
+        })
+    } else {
+        Ok(None)
+    }
+}
+
+///
+/// Reads the length prefix of a frame from `from`, one byte at a time, so that no bytes past the
+/// length prefix are consumed. The checks described on `parse_frame_len` are applied.
+///
+/// Returns `Ok(None)` if the stream ends before any byte of the length prefix is read, and
+/// `ReadError::ConnectionClosedMidPacket` if it ends part way through the length prefix.
+///
+pub fn read_frame_len<R>(from: &mut R, max_packet_size: usize) -> ReadResult<usize>
+where
+    R: io::Read,
+{
+    let mut header = [0u8; VAR_INT_BUF_SIZE];
+    let mut position: usize = 0;
+
+    loop {
+        match from.read_exact(&mut header[position..position + 1]) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                return if position == 0 {
+                    Ok(None)
+                } else {
+                    Err(ReadError::ConnectionClosedMidPacket {
+                        expected: position + 1,
+                        received: position,
+                        #[cfg(feature = "backtrace")]
+                        backtrace: Backtrace::capture(),
+                    })
+                };
+            }
+            Err(err) => return Err(err.into()),
+        }
+        position += 1;
+
+        if let Some((len, _)) = parse_frame_len(&header[..position], max_packet_size)? {
+            break Ok(Some(len));
+        }
+    }
+}
+
 #[cfg(feature = "encryption")]
 fn handle_decryption(cipher: Option<&mut CraftCipher>, buf: &mut [u8]) {
     if let Some(encryption) = cipher {
@@ -658,23 +1893,225 @@ where
     }
 }
 
-#[cfg(feature = "compression")]
-fn decompress<'a>(
-    src: &'a [u8],
-    target: &'a mut Option<Vec<u8>>,
-    decompressed_len: usize,
-) -> Result<&'a mut [u8], ReadError> {
-    let mut decompress = flate2::Decompress::new(true);
-    let decompress_buf = get_sized_buf(target, 0, decompressed_len);
-    loop {
-        match decompress.decompress(src, decompress_buf, FlushDecompress::Finish) {
-            Ok(Status::StreamEnd) => break,
-            Ok(Status::Ok) => {}
-            Ok(Status::BufError) => return Err(DecompressErr::BufError.into()),
-            Err(err) => return Err(DecompressErr::Failure(err).into()),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::CraftWriter;
+    use std::io::{Cursor, Write};
+
+    // every other call fails with Interrupted, as if a signal arrived, and the others move at most
+    // 3 bytes
+    struct Interrupting<T> {
+        inner: T,
+        interrupt: bool,
+    }
+
+    impl<T> Interrupting<T> {
+        fn new(inner: T) -> Self {
+            Self {
+                inner,
+                interrupt: false,
+            }
+        }
+
+        fn interrupt(&mut self) -> io::Result<()> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                Err(io::ErrorKind::Interrupted.into())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl<T: io::Read> io::Read for Interrupting<T> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.interrupt()?;
+            let n = buf.len().min(3);
+            self.inner.read(&mut buf[..n])
+        }
+    }
+
+    impl<T: Write> Write for Interrupting<T> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.interrupt()?;
+            let n = buf.len().min(3);
+            self.inner.write(&buf[..n])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    #[test]
+    fn interrupted_reads_and_writes_are_retried() {
+        let id = Id {
+            id: 0x00,
+            state: State::Handshaking,
+            direction: PacketDirection::ServerBound,
+        };
+        let large = vec![0xAB; 300];
+
+        let mut writer =
+            CraftWriter::wrap(Interrupting::new(Vec::new()), PacketDirection::ServerBound);
+        writer.write_raw_untyped_packet(id, b"small").unwrap();
+        writer.write_raw_untyped_packet(id, &large).unwrap();
+        writer.flush().unwrap();
+        let written = writer.into_inner().inner;
+
+        let mut reader = CraftReader::wrap(
+            Interrupting::new(Cursor::new(written.clone())),
+            PacketDirection::ServerBound,
+        );
+        let (read_id, body) = reader.read_raw_untyped_packet().unwrap().unwrap();
+        assert_eq!((read_id, body), (id, &b"small"[..]));
+        let (read_id, body) = reader.read_raw_untyped_packet().unwrap().unwrap();
+        assert_eq!((read_id, body), (id, &large[..]));
+        assert!(reader.read_raw_untyped_packet().unwrap().is_none());
+
+        // the id and the body of the first frame follow its length
+        let mut frames = Interrupting::new(Cursor::new(written));
+        let len = read_frame_len(&mut frames, DEAFULT_MAX_PACKET_SIZE).unwrap();
+        assert_eq!(len, Some(1 + b"small".len()));
+    }
+
+    const HANDSHAKE: Id = Id {
+        id: 0x00,
+        state: State::Handshaking,
+        direction: PacketDirection::ServerBound,
+    };
+
+    // the frames of the bodies, each with the handshake id
+    fn frames(compression_threshold: Option<i32>, bodies: &[&[u8]]) -> Vec<Vec<u8>> {
+        bodies
+            .iter()
+            .map(|body| {
+                let mut writer = CraftWriter::wrap(Vec::new(), PacketDirection::ServerBound);
+                writer.set_compression_threshold(compression_threshold);
+                writer.write_raw_untyped_packet(HANDSHAKE, body).unwrap();
+                writer.into_inner()
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "resync")]
+    fn assert_reads(reader: &mut CraftReader<Cursor<Vec<u8>>>, body: &[u8]) {
+        let (id, read) = reader.read_raw_untyped_packet().unwrap().unwrap();
+        assert_eq!((id, read), (HANDSHAKE, body));
+    }
+
+    #[cfg(feature = "resync")]
+    #[test]
+    fn resync_skips_a_corrupt_length_prefix() {
+        let frames = frames(None, &[b"first", b"second", b"third", b"fourth"]);
+        let mut stream = frames[0].clone();
+        // the length prefix of the second frame is replaced by a VarInt which never ends
+        stream.extend_from_slice(&[0xFF; 5]);
+        stream.extend_from_slice(&frames[1][1..]);
+        stream.extend_from_slice(&frames[2]);
+        stream.extend_from_slice(&frames[3]);
+
+        let mut reader = CraftReader::wrap(Cursor::new(stream), PacketDirection::ServerBound);
+        reader.set_read_ahead(1024);
+        assert_reads(&mut reader, b"first");
+        assert!(matches!(
+            reader.read_raw_untyped_packet(),
+            Err(ReadError::VarIntTooLong { .. })
+        ));
+
+        assert_eq!(
+            reader.resync(),
+            ResyncOutcome::Found {
+                skipped: 5 + frames[1].len() - 1
+            }
+        );
+        assert_reads(&mut reader, b"third");
+        assert_reads(&mut reader, b"fourth");
+    }
+
+    #[cfg(all(feature = "resync", feature = "compression"))]
+    #[test]
+    fn resync_finds_compressed_frames_by_their_zlib_header() {
+        let frames = frames(Some(0), &[b"first", b"third", b"fourth"]);
+        let mut stream = frames[0].clone();
+        stream.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00]);
+        stream.extend_from_slice(&frames[1]);
+        stream.extend_from_slice(&frames[2]);
+
+        let mut reader = CraftReader::wrap(Cursor::new(stream), PacketDirection::ServerBound);
+        reader.set_compression_threshold(Some(0));
+        reader.set_read_ahead(1024);
+        assert_reads(&mut reader, b"first");
+        assert!(reader.read_raw_untyped_packet().is_err());
+
+        assert_eq!(reader.resync(), ResyncOutcome::Found { skipped: 6 });
+        assert_reads(&mut reader, b"third");
+        assert_reads(&mut reader, b"fourth");
+    }
+
+    #[cfg(feature = "resync")]
+    #[test]
+    fn compressed_frames_need_a_zlib_header() {
+        let max = DEAFULT_MAX_PACKET_SIZE;
+        let no_id = |_| false;
+        assert!(matches!(
+            guess_frame_start(&[0x03, 0x06, 0x78, 0x9C], max, true, no_id),
+            FrameGuess::Plausible
+        ));
+        assert!(matches!(
+            guess_frame_start(&[0x03, 0x06, 0x79, 0x9C], max, true, no_id),
+            FrameGuess::Implausible
+        ));
+        assert!(matches!(
+            guess_frame_start(&[0x03, 0x06], max, true, no_id),
+            FrameGuess::Undecided
+        ));
+        // a frame below the threshold is checked for a packet id instead
+        assert!(matches!(
+            guess_frame_start(&[0x02, 0x00, 0x00], max, true, |id| id == 0),
+            FrameGuess::Plausible
+        ));
+        assert!(matches!(
+            guess_frame_start(&[0x02, 0x00, 0x00], max, true, no_id),
+            FrameGuess::Implausible
+        ));
+    }
+
+    // counts the read calls which reach the stream
+    struct CountingReads<T> {
+        inner: T,
+        reads: usize,
+    }
+
+    impl<T: io::Read> io::Read for CountingReads<T> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            self.inner.read(buf)
         }
     }
 
-    let decompressed_size = decompress.total_out() as usize;
-    Ok(&mut decompress_buf[..decompressed_size])
+    #[test]
+    fn drain_buffered_takes_read_ahead_frames_without_reading() {
+        let stream = frames(None, &[b"first", b"second", b"third"]).concat();
+        let mut reader = CraftReader::wrap(
+            CountingReads {
+                inner: Cursor::new(stream),
+                reads: 0,
+            },
+            PacketDirection::ServerBound,
+        );
+        reader.set_read_ahead(1024);
+
+        let (id, body) = reader.read_raw_untyped_packet().unwrap().unwrap();
+        assert_eq!((id, body), (HANDSHAKE, &b"first"[..]));
+        let reads = reader.get_ref().reads;
+
+        for expected in [&b"second"[..], &b"third"[..]].iter() {
+            let (id, body) = reader.drain_buffered().unwrap().unwrap();
+            assert_eq!((id, body), (HANDSHAKE, *expected));
+        }
+        assert!(reader.drain_buffered().unwrap().is_none());
+        assert_eq!(reader.get_ref().reads, reads);
+    }
 }
@@ -0,0 +1,387 @@
+use crate::address::parse_server_address;
+use crate::connection::CraftConnection;
+use crate::reader::{CraftReader, CraftSyncReader, ReadError};
+use crate::wrapper::CraftIo;
+use mcproto_rs::protocol::{Id, PacketDirection, State};
+use mcproto_rs::types::VarInt;
+use mcproto_rs::{Deserialize, DeserializeErr, Deserialized};
+#[cfg(feature = "backtrace")]
+use std::backtrace::Backtrace;
+use thiserror::Error;
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+use crate::reader::CraftAsyncReader;
+
+#[derive(Debug, Error)]
+pub enum RouteError {
+    #[error("failed to read handshake")]
+    Read {
+        #[from]
+        err: ReadError,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("expected a handshake, got packet {id:?}")]
+    UnexpectedPacket {
+        id: Id,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("malformed handshake")]
+    BadHandshake {
+        #[from]
+        err: DeserializeErr,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("handshake requested unknown next state {next_state}")]
+    BadNextState {
+        next_state: i32,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+}
+
+pub type RouteResult<T> = Result<T, RouteError>;
+
+pub(crate) const HANDSHAKE_ID: Id = Id {
+    id: 0x00,
+    state: State::Handshaking,
+    direction: PacketDirection::ServerBound,
+};
+
+///
+/// The contents of a handshake packet, parsed without depending on any particular protocol
+/// version's packet definitions (the handshake's layout has never changed).
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub protocol_version: i32,
+    ///
+    /// The server address exactly as the client sent it, including any markers added by mod
+    /// loaders. See `host` for the address with these removed.
+    ///
+    pub server_address: String,
+    pub server_port: u16,
+    pub next_state: i32,
+}
+
+impl Handshake {
+    ///
+    /// Parses the body (everything after the packet id) of a handshake packet.
+    ///
+    pub fn parse(body: &[u8]) -> Result<Self, DeserializeErr> {
+        let Deserialized {
+            value: protocol_version,
+            data: rest,
+        } = VarInt::mc_deserialize(body)?;
+        let Deserialized {
+            value: server_address,
+            data: rest,
+        } = String::mc_deserialize(rest)?;
+        let Deserialized {
+            value: server_port,
+            data: rest,
+        } = u16::mc_deserialize(rest)?;
+        let Deserialized {
+            value: next_state,
+            ..
+        } = VarInt::mc_deserialize(rest)?;
+
+        Ok(Self {
+            protocol_version: protocol_version.0,
+            server_address,
+            server_port,
+            next_state: next_state.0,
+        })
+    }
+
+    ///
+    /// The host name the client connected to, normalized for matching against host rules.
+    ///
+    /// Forge and other mod loaders append markers to the address after a NUL byte (such as
+    /// `\0FML\0`), and clients which resolved the address using an SRV record may send it with a
+    /// trailing dot. Both are removed, and the result is lowercased (see `parse_server_address`).
+    ///
+    pub fn host(&self) -> String {
+        normalize_host(&self.server_address)
+    }
+
+    ///
+    /// The state the client requested to switch to, if it is one this crate knows about. Transfers
+    /// (next state 3) continue into the `Login` state.
+    ///
+    pub fn next_state(&self) -> Option<State> {
+        match self.next_state {
+            1 => Some(State::Status),
+            2 | 3 => Some(State::Login),
+            _ => None,
+        }
+    }
+}
+
+fn normalize_host(address: &str) -> String {
+    parse_server_address(address)
+        .map(|address| address.host)
+        .unwrap_or_default()
+}
+
+enum HostPattern {
+    Exact(String),
+    // stored with the leading dot, so "*.example.com" is stored as ".example.com"
+    Suffix(String),
+}
+
+impl HostPattern {
+    fn parse(pattern: &str) -> Self {
+        let pattern = normalize_host(pattern);
+        if let Some(suffix) = pattern.strip_prefix('*') {
+            HostPattern::Suffix(suffix.to_owned())
+        } else {
+            HostPattern::Exact(pattern)
+        }
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Exact(exact) => exact == host,
+            HostPattern::Suffix(suffix) => host.ends_with(suffix.as_str()),
+        }
+    }
+}
+
+///
+/// A connection whose handshake has been read by a `VirtualHostRouter`.
+///
+/// The connection has already been switched to the state the handshake requested, and the
+/// protocol version from the handshake is recorded on it.
+///
+pub struct Routed<'a, T, R, W> {
+    pub connection: CraftConnection<R, W>,
+    pub handshake: Handshake,
+    ///
+    /// The body of the handshake packet exactly as it was read, which can be written to the
+    /// selected backend unchanged.
+    ///
+    pub raw_handshake: Vec<u8>,
+    ///
+    /// The target of the first rule which matched the handshake's host, or the fallback target. This
+    /// is `None` if no rule matched and there is no fallback.
+    ///
+    pub target: Option<&'a T>,
+}
+
+///
+/// Dispatches incoming connections to a target (such as a backend server address) based on the
+/// host name the client used to connect.
+///
+/// Rules are either an exact host name (`play.example.com`) or a wildcard which matches any
+/// subdomain (`*.example.com`). They are matched in the order they were added, case-insensitively.
+///
+pub struct VirtualHostRouter<T> {
+    rules: Vec<(HostPattern, T)>,
+    fallback: Option<T>,
+}
+
+impl<T> Default for VirtualHostRouter<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> VirtualHostRouter<T> {
+    pub fn new() -> Self {
+        Self {
+            rules: Vec::new(),
+            fallback: None,
+        }
+    }
+
+    pub fn with_host(mut self, pattern: &str, target: T) -> Self {
+        self.add_host(pattern, target);
+        self
+    }
+
+    pub fn add_host(&mut self, pattern: &str, target: T) {
+        self.rules.push((HostPattern::parse(pattern), target));
+    }
+
+    ///
+    /// Sets the target used when no rule matches.
+    ///
+    pub fn with_fallback(mut self, target: T) -> Self {
+        self.fallback = Some(target);
+        self
+    }
+
+    ///
+    /// Finds the target for an already normalized host name (see `Handshake::host`).
+    ///
+    pub fn resolve(&self, host: &str) -> Option<&T> {
+        self.rules
+            .iter()
+            .find(move |(pattern, _)| pattern.matches(host))
+            .map(|(_, target)| target)
+            .or(self.fallback.as_ref())
+    }
+
+    ///
+    /// Finds the target for the server name a TLS client asked for (see `sniff_server_name`),
+    /// using the same rules as for the host in a handshake. A client which did not send a server
+    /// name gets the fallback target.
+    ///
+    pub fn resolve_server_name(&self, server_name: Option<&str>) -> Option<&T> {
+        match server_name {
+            Some(server_name) => self.resolve(&normalize_host(server_name)),
+            None => self.fallback.as_ref(),
+        }
+    }
+
+    ///
+    /// Reads the handshake from a newly accepted, server-bound connection in the `Handshaking`
+    /// state, and selects a target for it.
+    ///
+    /// Returns `Ok(None)` if the client disconnected before sending a handshake.
+    ///
+    pub fn route<R, W>(
+        &self,
+        mut connection: CraftConnection<R, W>,
+    ) -> RouteResult<Option<Routed<'_, T, R, W>>>
+    where
+        CraftReader<R>: CraftSyncReader,
+    {
+        let (id, body) = match connection.reader.read_raw_untyped_packet()? {
+            Some(packet) => packet,
+            None => return Ok(None),
+        };
+        let raw_handshake = body.to_vec();
+
+        self.finish_route(connection, id, raw_handshake).map(Some)
+    }
+
+    ///
+    /// Same as `route`, but for a connection wrapping async streams.
+    ///
+    #[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+    pub async fn route_async<R, W>(
+        &self,
+        mut connection: CraftConnection<R, W>,
+    ) -> RouteResult<Option<Routed<'_, T, R, W>>>
+    where
+        CraftReader<R>: CraftAsyncReader,
+    {
+        let (id, body) = match connection.reader.read_raw_untyped_packet_async().await? {
+            Some(packet) => packet,
+            None => return Ok(None),
+        };
+        let raw_handshake = body.to_vec();
+
+        self.finish_route(connection, id, raw_handshake).map(Some)
+    }
+
+    fn finish_route<R, W>(
+        &self,
+        mut connection: CraftConnection<R, W>,
+        id: Id,
+        raw_handshake: Vec<u8>,
+    ) -> RouteResult<Routed<'_, T, R, W>> {
+        if id != HANDSHAKE_ID {
+            return Err(RouteError::UnexpectedPacket {
+                id,
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture(),
+            });
+        }
+
+        let handshake = Handshake::parse(&raw_handshake)?;
+        let next_state = handshake.next_state().ok_or_else(|| RouteError::BadNextState {
+            next_state: handshake.next_state,
+            #[cfg(feature = "backtrace")]
+            backtrace: Backtrace::capture(),
+        })?;
+        connection.set_state(next_state);
+        connection.set_protocol_version(handshake.protocol_version);
+        connection.discard_auto_state();
+
+        let target = self.resolve(&handshake.host());
+        Ok(Routed {
+            connection,
+            handshake,
+            raw_handshake,
+            target,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake(server_address: &str) -> Handshake {
+        Handshake {
+            protocol_version: 764,
+            server_address: server_address.to_owned(),
+            server_port: 25565,
+            next_state: 2,
+        }
+    }
+
+    #[test]
+    fn handshake_hosts_are_normalized() {
+        let cases = [
+            ("play.example.com", "play.example.com"),
+            ("play.example.com\0FML\0", "play.example.com"),
+            ("play.example.com\0FML2\0", "play.example.com"),
+            ("play.example.com.", "play.example.com"),
+            ("Play.Example.COM.\0FML2\0", "play.example.com"),
+        ];
+
+        for (address, host) in cases.iter() {
+            assert_eq!(handshake(address).host(), *host, "{:?}", address);
+        }
+    }
+
+    #[test]
+    fn hosts_resolve_to_the_first_matching_rule() {
+        let router = VirtualHostRouter::new()
+            .with_host("play.example.com", "play")
+            .with_host("*.example.com", "wildcard")
+            .with_host("lobby.example.com", "shadowed")
+            .with_fallback("default");
+        let cases = [
+            ("play.example.com", "play"),
+            ("play.example.com\0FML\0", "play"),
+            ("play.example.com\0FML2\0", "play"),
+            ("play.example.com.", "play"),
+            ("lobby.example.com", "wildcard"),
+            ("a.b.example.com.", "wildcard"),
+            // the wildcard only matches subdomains
+            ("example.com", "default"),
+            ("notexample.com", "default"),
+            ("other.net", "default"),
+        ];
+
+        for (address, target) in cases.iter() {
+            let host = handshake(address).host();
+            assert_eq!(router.resolve(&host), Some(target), "{:?}", address);
+        }
+    }
+
+    #[test]
+    fn hosts_without_a_rule_or_fallback_have_no_target() {
+        let router = VirtualHostRouter::new().with_host("*.example.com", "wildcard");
+        assert_eq!(router.resolve(&handshake("other.net").host()), None);
+        assert_eq!(router.resolve_server_name(None), None);
+    }
+
+    #[test]
+    fn server_names_use_the_same_rules() {
+        let router = VirtualHostRouter::new()
+            .with_host("*.Example.com", "wildcard")
+            .with_fallback("default");
+        assert_eq!(
+            router.resolve_server_name(Some("PLAY.example.com")),
+            Some(&"wildcard")
+        );
+        assert_eq!(router.resolve_server_name(None), Some(&"default"));
+    }
+}
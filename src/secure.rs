@@ -0,0 +1,735 @@
+use crate::util::is_timeout;
+use crate::DEAFULT_MAX_PACKET_SIZE;
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use std::io;
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+use {
+    crate::{reader::AsyncPollRead, writer::AsyncPollWrite},
+    std::{
+        pin::Pin,
+        task::{Context, Poll},
+    },
+};
+
+// Each record on the wire is a 4 byte big-endian length, followed by that many bytes of AES-256-GCM
+// ciphertext (which ends with the 16 byte tag). Every record is sealed with a unique nonce: the
+// first 4 bytes identify which end of the link sealed it, and the last 8 bytes are a counter which
+// increments with every record. Both ends know the counter, so it is never sent.
+
+const LENGTH_SIZE: usize = 4;
+const TAG_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+const MAX_RECORD_SIZE: usize = DEAFULT_MAX_PACKET_SIZE + 1024;
+
+pub const SECURE_KEY_SIZE: usize = 32;
+
+///
+/// Which end of a secure link this is. The two ends of a link must use different roles, which
+/// makes sure the two directions never seal a record with the same nonce.
+///
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum SecureRole {
+    Initiator,
+    Responder,
+}
+
+impl SecureRole {
+    pub fn opposite(&self) -> Self {
+        match self {
+            SecureRole::Initiator => SecureRole::Responder,
+            SecureRole::Responder => SecureRole::Initiator,
+        }
+    }
+
+    fn nonce_prefix(&self) -> [u8; 4] {
+        match self {
+            SecureRole::Initiator => *b"init",
+            SecureRole::Responder => *b"resp",
+        }
+    }
+}
+
+///
+/// Authenticated-encryption framing for links between two endpoints which both use this crate
+/// (such as a proxy and its backend servers), where vanilla compatibility is not required.
+///
+/// Vanilla's CFB-8 encryption provides no integrity: a modified byte is not detected, and simply
+/// decrypts to garbage. A secure link seals every packet into a separate AES-256-GCM record, so any
+/// tampering, reordering or replay of records, and a stream which ends in the middle of a record,
+/// is detected and fails the read. A stream which is cut off between two records is not detected,
+/// and looks the same as the other end closing the link, so a protocol which must know that
+/// everything arrived has to confirm it with a packet of its own.
+///
+/// This works beneath the normal packet framing: wrap the raw streams using `split`, and then wrap
+/// those in a `CraftReader`/`CraftWriter` as usual, without enabling CFB-8 encryption.
+///
+/// The nonces of a link start over for every link, so a key must never be used by more than one
+/// link. Links are therefore only created by a `NoiseHandshake` (with the `noise` feature), which
+/// derives fresh keys for every connection, and `split` consumes the link.
+///
+pub struct SecureLink {
+    send_cipher: Aes256Gcm,
+    receive_cipher: Aes256Gcm,
+    role: SecureRole,
+}
+
+impl SecureLink {
+    ///
+    /// Creates one end of a link, where records written by this end are sealed with `send_key`,
+    /// and records read are opened with `receive_key`. Both keys must be `SECURE_KEY_SIZE` bytes
+    /// long, and must not have been used by any other link.
+    ///
+    #[cfg_attr(not(feature = "noise"), allow(dead_code))]
+    pub(crate) fn with_keys(
+        send_key: &[u8],
+        receive_key: &[u8],
+        role: SecureRole,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            send_cipher: create_cipher(send_key)?,
+            receive_cipher: create_cipher(receive_key)?,
+            role,
+        })
+    }
+
+    pub fn role(&self) -> SecureRole {
+        self.role
+    }
+
+    ///
+    /// Wraps the stream which records sealed by the other end of the link are read from, and the
+    /// stream which records are written to. Every call to `write` on the writer seals exactly one
+    /// record.
+    ///
+    pub fn split<R, W>(self, read: R, write: W) -> (SecureReader<R>, SecureWriter<W>) {
+        (self.reader(read), self.writer(write))
+    }
+
+    fn reader<R>(&self, inner: R) -> SecureReader<R> {
+        SecureReader {
+            inner,
+            opener: RecordCipher::new(self.receive_cipher.clone(), self.role.opposite()),
+            header: [0; LENGTH_SIZE],
+            header_filled: 0,
+            record: Vec::new(),
+            record_filled: 0,
+            plaintext: Vec::new(),
+            plaintext_offset: 0,
+        }
+    }
+
+    fn writer<W>(&self, inner: W) -> SecureWriter<W> {
+        SecureWriter {
+            inner,
+            sealer: RecordCipher::new(self.send_cipher.clone(), self.role),
+            pending: Vec::new(),
+            pending_offset: 0,
+        }
+    }
+}
+
+fn create_cipher(key: &[u8]) -> io::Result<Aes256Gcm> {
+    Aes256Gcm::new_from_slice(key)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "bad secure link key size"))
+}
+
+struct RecordCipher {
+    cipher: Aes256Gcm,
+    nonce_prefix: [u8; 4],
+    counter: u64,
+}
+
+impl RecordCipher {
+    fn new(cipher: Aes256Gcm, role: SecureRole) -> Self {
+        Self {
+            cipher,
+            nonce_prefix: role.nonce_prefix(),
+            counter: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> io::Result<[u8; NONCE_SIZE]> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce[..4].copy_from_slice(&self.nonce_prefix);
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter = self.counter.checked_add(1).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Other, "secure link nonces are exhausted")
+        })?;
+        Ok(nonce)
+    }
+
+    // seals plaintext into out as a complete record, including the length header
+    fn seal(&mut self, plaintext: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        let record_len = plaintext.len() + TAG_SIZE;
+        if record_len > MAX_RECORD_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "secure record is too large"));
+        }
+
+        let nonce = self.next_nonce()?;
+        out.clear();
+        out.extend_from_slice(&(record_len as u32).to_be_bytes());
+        let mut sealed = plaintext.to_vec();
+        self.cipher
+            .encrypt_in_place(Nonce::from_slice(&nonce), &[], &mut sealed)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "failed to seal secure record"))?;
+        out.extend_from_slice(&sealed);
+        Ok(())
+    }
+
+    // opens a record (without the length header) in place, leaving only the plaintext
+    fn open(&mut self, record: &mut Vec<u8>) -> io::Result<()> {
+        let nonce = self.next_nonce()?;
+        self.cipher
+            .decrypt_in_place(Nonce::from_slice(&nonce), &[], record)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "secure record failed authentication"))
+    }
+}
+
+fn record_len(header: [u8; LENGTH_SIZE]) -> io::Result<usize> {
+    let len = u32::from_be_bytes(header) as usize;
+    if len < TAG_SIZE || len > MAX_RECORD_SIZE {
+        Err(io::Error::new(io::ErrorKind::InvalidData, "bad secure record length"))
+    } else {
+        Ok(len)
+    }
+}
+
+///
+/// The reading half of a `SecureLink`. See `SecureLink::reader`.
+///
+pub struct SecureReader<R> {
+    inner: R,
+    opener: RecordCipher,
+    header: [u8; LENGTH_SIZE],
+    header_filled: usize,
+    record: Vec<u8>,
+    record_filled: usize,
+    plaintext: Vec<u8>,
+    plaintext_offset: usize,
+}
+
+impl<R> SecureReader<R> {
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    fn copy_plaintext(&mut self, to: &mut [u8]) -> usize {
+        let available = &self.plaintext[self.plaintext_offset..];
+        let n = available.len().min(to.len());
+        to[..n].copy_from_slice(&available[..n]);
+        self.plaintext_offset += n;
+        n
+    }
+
+    fn has_plaintext(&self) -> bool {
+        self.plaintext_offset < self.plaintext.len()
+    }
+
+    // called once a full record has been read into self.record
+    fn finish_record(&mut self) -> io::Result<()> {
+        self.opener.open(&mut self.record)?;
+        std::mem::swap(&mut self.record, &mut self.plaintext);
+        self.plaintext_offset = 0;
+        self.header_filled = 0;
+        self.record_filled = 0;
+        Ok(())
+    }
+}
+
+impl<R> io::Read for SecureReader<R>
+where
+    R: io::Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // empty records are allowed, so keep going until there is some plaintext. A read which fails
+        // (such as with a timeout) keeps the part of the record read so far, and can be retried
+        while !self.has_plaintext() {
+            if self.header_filled < LENGTH_SIZE {
+                match self.inner.read(&mut self.header[self.header_filled..]) {
+                    Ok(0) if self.header_filled == 0 => return Ok(0),
+                    Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                    Ok(n) => self.header_filled += n,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    Err(err) => return Err(err),
+                }
+
+                if self.header_filled == LENGTH_SIZE {
+                    let len = record_len(self.header)?;
+                    self.record.resize(len, 0);
+                }
+                continue;
+            }
+
+            if self.record_filled < self.record.len() {
+                match self.inner.read(&mut self.record[self.record_filled..]) {
+                    Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                    Ok(n) => self.record_filled += n,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    Err(err) => return Err(err),
+                }
+                continue;
+            }
+
+            self.finish_record()?;
+        }
+
+        Ok(self.copy_plaintext(buf))
+    }
+}
+
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+impl<R> SecureReader<R>
+where
+    R: AsyncPollRead,
+{
+    fn poll_read_plaintext(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        while !self.has_plaintext() {
+            if self.header_filled < LENGTH_SIZE {
+                let target = &mut self.header[self.header_filled..];
+                match self.inner.poll_read_into(cx, target) {
+                    Poll::Ready(Ok(0)) if self.header_filled == 0 => return Poll::Ready(Ok(0)),
+                    Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into())),
+                    Poll::Ready(Ok(n)) => self.header_filled += n,
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                    Poll::Pending => return Poll::Pending,
+                }
+
+                if self.header_filled == LENGTH_SIZE {
+                    let len = match record_len(self.header) {
+                        Ok(len) => len,
+                        Err(err) => return Poll::Ready(Err(err)),
+                    };
+                    self.record.resize(len, 0);
+                }
+                continue;
+            }
+
+            if self.record_filled < self.record.len() {
+                let target = &mut self.record[self.record_filled..];
+                match self.inner.poll_read_into(cx, target) {
+                    Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into())),
+                    Poll::Ready(Ok(n)) => self.record_filled += n,
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                    Poll::Pending => return Poll::Pending,
+                }
+                continue;
+            }
+
+            if let Err(err) = self.finish_record() {
+                return Poll::Ready(Err(err));
+            }
+        }
+
+        Poll::Ready(Ok(self.copy_plaintext(buf)))
+    }
+}
+
+#[cfg(all(feature = "futures-io", not(feature = "tokio-io")))]
+impl<R> futures::AsyncRead for SecureReader<R>
+where
+    R: AsyncPollRead,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_read_plaintext(cx, buf)
+    }
+}
+
+#[cfg(feature = "tokio-io")]
+impl<R> tokio::io::AsyncRead for SecureReader<R>
+where
+    R: AsyncPollRead,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut().poll_read_plaintext(cx, buf.initialize_unfilled()) {
+            Poll::Ready(Ok(n)) => {
+                buf.advance(n);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+///
+/// The writing half of a `SecureLink`. See `SecureLink::writer`.
+///
+/// `CraftWriter` writes every packet using a single `write_all` call, so each packet is sealed into
+/// its own record.
+///
+/// A write is done as soon as its record is sealed. If the record can not be written to the
+/// wrapped stream completely (such as when a write times out), the rest of it is kept, and written
+/// before the next record, or by `flush`.
+///
+pub struct SecureWriter<W> {
+    inner: W,
+    sealer: RecordCipher,
+    pending: Vec<u8>,
+    pending_offset: usize,
+}
+
+impl<W> SecureWriter<W> {
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    fn has_pending(&self) -> bool {
+        self.pending_offset < self.pending.len()
+    }
+
+    fn seal_pending(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.sealer.seal(buf, &mut self.pending)?;
+        self.pending_offset = 0;
+        Ok(())
+    }
+}
+
+impl<W> SecureWriter<W>
+where
+    W: io::Write,
+{
+    fn write_pending(&mut self) -> io::Result<()> {
+        while self.has_pending() {
+            match self.inner.write(&self.pending[self.pending_offset..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.pending_offset += n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<W> io::Write for SecureWriter<W>
+where
+    W: io::Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_pending()?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        self.seal_pending(buf)?;
+        match self.write_pending() {
+            Err(err) if !is_timeout(&err) => Err(err),
+            // the record is sealed, so the rest of it is written later
+            _ => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_pending()?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+impl<W> SecureWriter<W>
+where
+    W: AsyncPollWrite,
+{
+    // the rest of the previous record is written before another one is sealed, and a record which
+    // is sealed is accepted even if it can not be written yet, since sealing it again would use
+    // another nonce
+    fn poll_write_plaintext(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.poll_write_pending(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Pending => return Poll::Pending,
+        }
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        if let Err(err) = self.seal_pending(buf) {
+            return Poll::Ready(Err(err));
+        }
+
+        match self.poll_write_pending(cx) {
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            _ => Poll::Ready(Ok(buf.len())),
+        }
+    }
+
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.has_pending() {
+            match self.inner.poll_write_from(cx, &self.pending[self.pending_offset..]) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(n)) => self.pending_offset += n,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(all(feature = "futures-io", not(feature = "tokio-io")))]
+impl<W> futures::AsyncWrite for SecureWriter<W>
+where
+    W: futures::AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_write_plaintext(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_write_pending(cx) {
+            Poll::Ready(Ok(())) => futures::AsyncWrite::poll_flush(Pin::new(&mut this.inner), cx),
+            other => other,
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_write_pending(cx) {
+            Poll::Ready(Ok(())) => futures::AsyncWrite::poll_close(Pin::new(&mut this.inner), cx),
+            other => other,
+        }
+    }
+}
+
+#[cfg(feature = "tokio-io")]
+impl<W> tokio::io::AsyncWrite for SecureWriter<W>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_write_plaintext(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_write_pending(cx) {
+            Poll::Ready(Ok(())) => tokio::io::AsyncWrite::poll_flush(Pin::new(&mut this.inner), cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_write_pending(cx) {
+            Poll::Ready(Ok(())) => tokio::io::AsyncWrite::poll_shutdown(Pin::new(&mut this.inner), cx),
+            other => other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::{CraftReader, CraftSyncReader};
+    use crate::wrapper::CraftWrapper;
+    use crate::writer::CraftWriter;
+    use mcproto_rs::protocol::{Id, PacketDirection, State};
+    use std::io::{Cursor, Read, Write};
+
+    const KEY: [u8; SECURE_KEY_SIZE] = [7; SECURE_KEY_SIZE];
+
+    // the tests reuse the same key for every link, which is only safe because nothing they seal
+    // leaves the test
+    fn ends() -> (SecureLink, SecureLink) {
+        (
+            SecureLink::with_keys(&KEY, &KEY, SecureRole::Initiator).unwrap(),
+            SecureLink::with_keys(&KEY, &KEY, SecureRole::Responder).unwrap(),
+        )
+    }
+
+    fn seal_all(link: &SecureLink, records: &[&[u8]]) -> Vec<u8> {
+        let mut writer = link.writer(Vec::new());
+        for record in records {
+            assert_eq!(writer.write(record).unwrap(), record.len());
+        }
+        writer.flush().unwrap();
+        writer.inner
+    }
+
+    // every other call fails with WouldBlock, and the others move a single byte
+    struct Stutter<T> {
+        inner: T,
+        blocked: bool,
+    }
+
+    impl<T> Stutter<T> {
+        fn new(inner: T) -> Self {
+            Self {
+                inner,
+                blocked: true,
+            }
+        }
+
+        fn block(&mut self) -> io::Result<()> {
+            self.blocked = !self.blocked;
+            if self.blocked {
+                Ok(())
+            } else {
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+        }
+    }
+
+    impl<T: Read> Read for Stutter<T> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.block()?;
+            let n = buf.len().min(1);
+            self.inner.read(&mut buf[..n])
+        }
+    }
+
+    impl<T: Write> Write for Stutter<T> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.block()?;
+            let n = buf.len().min(1);
+            self.inner.write(&buf[..n])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    fn is_would_block<T>(result: &io::Result<T>) -> bool {
+        matches!(result, Err(err) if err.kind() == io::ErrorKind::WouldBlock)
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let (initiator, responder) = ends();
+        let sealed = seal_all(&initiator, &[b"hello", b"", b"secure link"]);
+
+        let mut plaintext = Vec::new();
+        responder
+            .reader(Cursor::new(sealed))
+            .read_to_end(&mut plaintext)
+            .unwrap();
+        assert_eq!(plaintext, b"hellosecure link");
+    }
+
+    #[test]
+    fn tampering_fails_the_read() {
+        let (initiator, responder) = ends();
+        let mut sealed = seal_all(&initiator, &[b"hello"]);
+        sealed[LENGTH_SIZE] ^= 1;
+
+        let err = responder
+            .reader(Cursor::new(sealed))
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn records_from_the_same_role_fail_the_read() {
+        let (initiator, _) = ends();
+        let sealed = seal_all(&initiator, &[b"hello"]);
+
+        let err = initiator
+            .reader(Cursor::new(sealed))
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn truncated_record_fails_the_read() {
+        let (initiator, responder) = ends();
+        let mut sealed = seal_all(&initiator, &[b"hello"]);
+        sealed.pop();
+
+        let err = responder
+            .reader(Cursor::new(sealed))
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn resumes_after_would_block() {
+        let (initiator, responder) = ends();
+        let records: [&[u8]; 3] = [b"first", b"second", b"third"];
+
+        let mut writer = initiator.writer(Stutter::new(Vec::new()));
+        for record in records.iter() {
+            loop {
+                let written = writer.write(record);
+                if !is_would_block(&written) {
+                    assert_eq!(written.unwrap(), record.len());
+                    break;
+                }
+            }
+        }
+        while is_would_block(&writer.flush()) {}
+        let sealed = writer.inner.inner;
+        assert_eq!(sealed, seal_all(&initiator, &records));
+
+        let mut reader = responder.reader(Stutter::new(Cursor::new(sealed)));
+        let mut plaintext = Vec::new();
+        let mut buf = [0u8; 64];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => plaintext.extend_from_slice(&buf[..n]),
+                Err(err) => assert_eq!(err.kind(), io::ErrorKind::WouldBlock),
+            }
+        }
+        assert_eq!(plaintext, b"firstsecondthird");
+    }
+
+    #[test]
+    fn packets_round_trip() {
+        let (initiator, responder) = ends();
+        let id = Id {
+            id: 0x00,
+            state: State::Handshaking,
+            direction: PacketDirection::ServerBound,
+        };
+
+        let mut writer =
+            CraftWriter::wrap(initiator.writer(Vec::new()), PacketDirection::ServerBound);
+        writer.write_raw_untyped_packet(id, b"first").unwrap();
+        writer.write_raw_untyped_packet(id, b"second").unwrap();
+        writer.flush().unwrap();
+        let sealed = writer.into_inner().inner;
+
+        let mut reader = CraftReader::wrap(
+            responder.reader(Cursor::new(sealed)),
+            PacketDirection::ServerBound,
+        );
+        let (read_id, body) = reader.read_raw_untyped_packet().unwrap().unwrap();
+        assert_eq!((read_id, body), (id, &b"first"[..]));
+        let (read_id, body) = reader.read_raw_untyped_packet().unwrap().unwrap();
+        assert_eq!((read_id, body), (id, &b"second"[..]));
+        assert!(reader.read_raw_untyped_packet().unwrap().is_none());
+    }
+}
@@ -0,0 +1,191 @@
+use crate::sniff::Replay;
+use std::io;
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+use crate::reader::AsyncReadExact;
+
+// the content type of a record carrying handshake messages, and the ClientHello message type
+const HANDSHAKE_RECORD: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const SERVER_NAME_EXTENSION: u16 = 0x0000;
+const HOST_NAME: u8 = 0x00;
+
+const RECORD_HEADER_LEN: usize = 5;
+// the largest record a TLS peer may send
+const MAX_RECORD_LEN: usize = 16384 + 2048;
+
+struct Cursor<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.data.len() < n {
+            return None;
+        }
+
+        let (taken, rest) = self.data.split_at(n);
+        self.data = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|bytes| u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]) as usize)
+    }
+
+    // a field prefixed with its length, as an 8 or 16 bit number
+    fn prefixed_u8(&mut self) -> Option<Cursor<'a>> {
+        let len = self.u8()? as usize;
+        self.take(len).map(|data| Cursor { data })
+    }
+
+    fn prefixed_u16(&mut self) -> Option<Cursor<'a>> {
+        let len = self.u16()? as usize;
+        self.take(len).map(|data| Cursor { data })
+    }
+}
+
+///
+/// Finds the server name (SNI) a TLS client asked for in its ClientHello, given the first TLS
+/// record it sent (including the record header). Returns `None` if the record is not a
+/// ClientHello, or if the client did not send a host name.
+///
+/// If the ClientHello does not fit into the record, the part which is in the record is searched,
+/// which nearly always contains the server name.
+///
+pub fn parse_server_name(record: &[u8]) -> Option<String> {
+    let mut record = Cursor { data: record };
+    if record.u8()? != HANDSHAKE_RECORD {
+        return None;
+    }
+    record.take(2)?;
+    let mut handshake = record.prefixed_u16().unwrap_or(record);
+
+    if handshake.u8()? != CLIENT_HELLO {
+        return None;
+    }
+    let hello_len = handshake.u24()?;
+    let mut hello = match handshake.take(hello_len) {
+        Some(data) => Cursor { data },
+        None => handshake,
+    };
+
+    // the client version, the random, the session id, the cipher suites, and the compression
+    // methods come before the extensions
+    hello.take(2 + 32)?;
+    hello.prefixed_u8()?;
+    hello.prefixed_u16()?;
+    hello.prefixed_u8()?;
+
+    let mut extensions = hello.prefixed_u16().unwrap_or(hello);
+    while let Some(extension_type) = extensions.u16() {
+        let mut extension = extensions.prefixed_u16()?;
+        if extension_type != SERVER_NAME_EXTENSION {
+            continue;
+        }
+
+        let mut names = extension.prefixed_u16()?;
+        while let Some(name_type) = names.u8() {
+            let name = names.prefixed_u16()?;
+            if name_type == HOST_NAME {
+                return std::str::from_utf8(name.data).ok().map(str::to_owned);
+            }
+        }
+        return None;
+    }
+
+    None
+}
+
+// the length of the record the header starts, if it is a handshake record
+fn handshake_record_len(header: &[u8; RECORD_HEADER_LEN]) -> Option<usize> {
+    if header[0] != HANDSHAKE_RECORD {
+        return None;
+    }
+
+    let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+    if len > MAX_RECORD_LEN {
+        None
+    } else {
+        Some(len)
+    }
+}
+
+///
+/// Reads the first TLS record from a freshly accepted connection (usually one which `sniff`
+/// classified as `ProtocolKind::TlsClientHello`), and returns the server name the client asked
+/// for, along with a reader which replays the record before continuing with the rest of the
+/// stream. This lets a single TLS endpoint pass connections through to different backends by the
+/// host name the client connected to, without terminating TLS.
+///
+/// Returns no server name if the stream does not start with a handshake record, or if it ended
+/// before the record did.
+///
+pub fn sniff_server_name<R>(mut inner: R) -> io::Result<(Option<String>, Replay<R>)>
+where
+    R: io::Read,
+{
+    let mut header = [0u8; RECORD_HEADER_LEN];
+    let len = match io::Read::read_exact(&mut inner, &mut header) {
+        Ok(()) => handshake_record_len(&header),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+            // what was read before the stream ended is not known, so nothing is replayed
+            return Ok((None, Replay::new(Vec::new(), inner)));
+        }
+        Err(err) => return Err(err),
+    };
+
+    let mut record = header.to_vec();
+    if let Some(len) = len {
+        record.resize(RECORD_HEADER_LEN + len, 0);
+        match io::Read::read_exact(&mut inner, &mut record[RECORD_HEADER_LEN..]) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok((None, Replay::new(Vec::new(), inner)));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok((parse_server_name(&record), Replay::new(record, inner)))
+}
+
+///
+/// Same as `sniff_server_name`, but reads from an async stream.
+///
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+pub async fn sniff_server_name_async<R>(mut inner: R) -> io::Result<(Option<String>, Replay<R>)>
+where
+    R: AsyncReadExact,
+{
+    let mut header = [0u8; RECORD_HEADER_LEN];
+    let len = match inner.read_exact(&mut header).await {
+        Ok(()) => handshake_record_len(&header),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+            return Ok((None, Replay::new(Vec::new(), inner)));
+        }
+        Err(err) => return Err(err),
+    };
+
+    let mut record = header.to_vec();
+    if let Some(len) = len {
+        record.resize(RECORD_HEADER_LEN + len, 0);
+        match inner.read_exact(&mut record[RECORD_HEADER_LEN..]).await {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok((None, Replay::new(Vec::new(), inner)));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok((parse_server_name(&record), Replay::new(record, inner)))
+}
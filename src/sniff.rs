@@ -0,0 +1,271 @@
+use crate::reader::parse_frame_len;
+use crate::DEAFULT_MAX_PACKET_SIZE;
+use std::io;
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+use {
+    crate::reader::AsyncReadExact,
+    std::{
+        pin::Pin,
+        task::{Context, Poll},
+    },
+};
+
+///
+/// The kind of protocol a peer appears to be speaking, based on the first bytes it sent.
+///
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ProtocolKind {
+    ///
+    /// A modern Minecraft client, which starts with a length-prefixed handshake packet.
+    ///
+    Handshake,
+    ///
+    /// A pre-netty (1.4 to 1.6) client sending a legacy server list ping, which starts with
+    /// `0xFE 0x01`. Clients before 1.4 only send `0xFE`, and are not recognized.
+    ///
+    LegacyPing,
+    ///
+    /// A TLS ClientHello record.
+    ///
+    TlsClientHello,
+    ///
+    /// An HTTP/1.x request.
+    ///
+    Http,
+    ///
+    /// A version 1 or version 2 PROXY protocol header.
+    ///
+    ProxyHeader,
+    ///
+    /// None of the above.
+    ///
+    Unknown,
+}
+
+// the longest signature is the PROXY protocol v2 signature
+const MAX_SNIFF_LEN: usize = 12;
+
+const LEGACY_PING: [u8; 2] = [0xFE, 0x01];
+
+const SIGNATURES: &[(&[u8], ProtocolKind)] = &[
+    (&LEGACY_PING, ProtocolKind::LegacyPing),
+    (&[0x16, 0x03], ProtocolKind::TlsClientHello),
+    (b"PROXY ", ProtocolKind::ProxyHeader),
+    (
+        &[0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A],
+        ProtocolKind::ProxyHeader,
+    ),
+    (b"GET ", ProtocolKind::Http),
+    (b"HEAD ", ProtocolKind::Http),
+    (b"POST ", ProtocolKind::Http),
+    (b"PUT ", ProtocolKind::Http),
+    (b"DELETE ", ProtocolKind::Http),
+    (b"OPTIONS ", ProtocolKind::Http),
+    (b"CONNECT ", ProtocolKind::Http),
+    (b"PATCH ", ProtocolKind::Http),
+];
+
+///
+/// Classifies a connection by the bytes it has sent so far. Returns `None` if more bytes are
+/// required to decide.
+///
+/// A handshake's length prefix is always followed by the packet id 0, which never appears in the
+/// second byte of any other signature. The one exception is the legacy ping (`0xFE 0x01`), which is
+/// also the length prefix of a handshake of exactly 254 bytes. If the third byte is available, a
+/// 0 there classifies the connection as a handshake, but the legacy ping is recognized as soon as
+/// its two bytes are available (1.4 and 1.5 clients send nothing else and wait for an answer), so
+/// `sniff` classifies such a handshake as a legacy ping.
+///
+pub fn classify_protocol(prefix: &[u8]) -> Option<ProtocolKind> {
+    if prefix.starts_with(&LEGACY_PING) && prefix.get(LEGACY_PING.len()) == Some(&0x00) {
+        return Some(ProtocolKind::Handshake);
+    }
+
+    let mut undecided = false;
+    for (signature, kind) in SIGNATURES {
+        if prefix.starts_with(signature) {
+            return Some(*kind);
+        }
+
+        undecided |= signature.starts_with(prefix);
+    }
+
+    match parse_frame_len(prefix, DEAFULT_MAX_PACKET_SIZE) {
+        Ok(Some((len, header_len))) => match prefix.get(header_len) {
+            Some(&0x00) if len > 0 => return Some(ProtocolKind::Handshake),
+            None => undecided = true,
+            _ => {}
+        },
+        Ok(None) => undecided = true,
+        Err(_) => {}
+    }
+
+    if undecided && prefix.len() < MAX_SNIFF_LEN {
+        None
+    } else {
+        Some(ProtocolKind::Unknown)
+    }
+}
+
+///
+/// Reads from a freshly accepted connection until it can be classified, then returns the
+/// classification and a reader which replays every byte that was read before continuing with the
+/// rest of the stream. This lets a listener which accepts several protocols route a connection
+/// without consuming any of the data the eventual handler needs.
+///
+/// Bytes are read one at a time, so nothing past the bytes required to classify the connection is
+/// read, and at most 12 bytes are read. If the stream ends before the connection can be classified,
+/// it is classified as `ProtocolKind::Unknown`.
+///
+pub fn sniff<R>(mut inner: R) -> io::Result<(ProtocolKind, Replay<R>)>
+where
+    R: io::Read,
+{
+    let mut prefix = Vec::with_capacity(MAX_SNIFF_LEN);
+    let kind = loop {
+        if let Some(kind) = classify_protocol(&prefix) {
+            break kind;
+        }
+
+        let mut next = [0u8; 1];
+        match inner.read_exact(&mut next) {
+            Ok(()) => prefix.push(next[0]),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break ProtocolKind::Unknown,
+            Err(err) => return Err(err),
+        }
+    };
+
+    Ok((kind, Replay::new(prefix, inner)))
+}
+
+///
+/// Same as `sniff`, but reads from an async stream.
+///
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+pub async fn sniff_async<R>(mut inner: R) -> io::Result<(ProtocolKind, Replay<R>)>
+where
+    R: AsyncReadExact,
+{
+    let mut prefix = Vec::with_capacity(MAX_SNIFF_LEN);
+    let kind = loop {
+        if let Some(kind) = classify_protocol(&prefix) {
+            break kind;
+        }
+
+        let mut next = [0u8; 1];
+        match inner.read_exact(&mut next).await {
+            Ok(()) => prefix.push(next[0]),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break ProtocolKind::Unknown,
+            Err(err) => return Err(err),
+        }
+    };
+
+    Ok((kind, Replay::new(prefix, inner)))
+}
+
+///
+/// A reader which first yields some bytes which were already read from `inner` (such as by
+/// `sniff`), and then continues reading from `inner`.
+///
+/// It implements `std::io::Read`, and the `AsyncRead` traits of `tokio` or `futures` when the
+/// respective feature is enabled, so it can be wrapped by a `CraftReader` directly.
+///
+pub struct Replay<R> {
+    prefix: Vec<u8>,
+    offset: usize,
+    inner: R,
+}
+
+impl<R> Replay<R> {
+    pub fn new(prefix: Vec<u8>, inner: R) -> Self {
+        Self {
+            prefix,
+            offset: 0,
+            inner,
+        }
+    }
+
+    ///
+    /// The bytes which have not been replayed yet.
+    ///
+    pub fn pending(&self) -> &[u8] {
+        &self.prefix[self.offset..]
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    ///
+    /// Returns the bytes which have not been replayed yet, and the wrapped stream.
+    ///
+    pub fn into_parts(mut self) -> (Vec<u8>, R) {
+        self.prefix.drain(..self.offset);
+        (self.prefix, self.inner)
+    }
+
+    fn replay_into(&mut self, to: &mut [u8]) -> usize {
+        let pending = self.pending();
+        let n = pending.len().min(to.len());
+        to[..n].copy_from_slice(&pending[..n]);
+        self.offset += n;
+        n
+    }
+}
+
+impl<R> io::Read for Replay<R>
+where
+    R: io::Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending().is_empty() {
+            self.inner.read(buf)
+        } else {
+            Ok(self.replay_into(buf))
+        }
+    }
+}
+
+#[cfg(all(feature = "futures-io", not(feature = "tokio-io")))]
+impl<R> futures::AsyncRead for Replay<R>
+where
+    R: futures::AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.pending().is_empty() {
+            futures::AsyncRead::poll_read(Pin::new(&mut this.inner), cx, buf)
+        } else {
+            Poll::Ready(Ok(this.replay_into(buf)))
+        }
+    }
+}
+
+#[cfg(feature = "tokio-io")]
+impl<R> tokio::io::AsyncRead for Replay<R>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.pending().is_empty() {
+            tokio::io::AsyncRead::poll_read(Pin::new(&mut this.inner), cx, buf)
+        } else {
+            let n = this.replay_into(buf.initialize_unfilled());
+            buf.advance(n);
+            Poll::Ready(Ok(()))
+        }
+    }
+}
@@ -0,0 +1,373 @@
+use crate::connection::CraftConnection;
+use crate::reader::CraftReader;
+use crate::writer::CraftWriter;
+#[cfg(feature = "socket-options")]
+use socket2::SockRef;
+#[cfg(feature = "socket-options")]
+use std::io;
+use std::io::BufReader as StdBufReader;
+use std::net::TcpStream;
+#[cfg(feature = "socket-options")]
+use std::time::Duration;
+#[cfg(unix)]
+use std::{
+    os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd},
+    os::unix::net::UnixStream,
+};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, AsSocket, BorrowedSocket, RawSocket};
+#[cfg(feature = "tokio-io")]
+use tokio::{
+    io::BufReader as TokioBufReader,
+    net::{
+        tcp::{OwnedReadHalf as TokioReadHalf, OwnedWriteHalf as TokioWriteHalf},
+        TcpStream as TokioTcpStream,
+    },
+};
+
+///
+/// A stream which is backed by an OS socket, such as a `TcpStream` (or either half of a tokio
+/// `TcpStream`, or a buffered reader around one). Readers, writers and connections over such
+/// streams implement `AsFd` and `AsRawFd` (or `AsSocket` and `AsRawSocket` on Windows), so the
+/// socket can be registered with an event loop such as epoll, configured with socket options the
+/// standard library does not expose, or passed to another process, without unwrapping the
+/// connection.
+///
+/// Reading from or writing to the socket directly would skip the framing, compression and
+/// encryption of the connection, so the socket should only be used for things which do not
+/// consume or produce data.
+///
+pub trait OsSocket {
+    #[cfg(unix)]
+    fn socket_fd(&self) -> BorrowedFd<'_>;
+
+    #[cfg(windows)]
+    fn socket_handle(&self) -> BorrowedSocket<'_>;
+}
+
+///
+/// An `OsSocket` which is a TCP socket, so TCP socket options (such as the `CloseBehavior`) can be
+/// applied to it. Unix domain sockets are `OsSocket`s, but not `TcpSocket`s.
+///
+pub trait TcpSocket: OsSocket {}
+
+macro_rules! os_socket_impl {
+    ($stream: ty, |$s: ident| $socket: expr) => {
+        impl OsSocket for $stream {
+            #[cfg(unix)]
+            fn socket_fd(&self) -> BorrowedFd<'_> {
+                let $s = self;
+                $socket.as_fd()
+            }
+
+            #[cfg(windows)]
+            fn socket_handle(&self) -> BorrowedSocket<'_> {
+                let $s = self;
+                $socket.as_socket()
+            }
+        }
+    };
+}
+
+os_socket_impl!(TcpStream, |s| s);
+#[cfg(feature = "tokio-io")]
+os_socket_impl!(TokioTcpStream, |s| s);
+#[cfg(feature = "tokio-io")]
+os_socket_impl!(TokioReadHalf, |s| AsRef::<TokioTcpStream>::as_ref(s));
+#[cfg(feature = "tokio-io")]
+os_socket_impl!(TokioWriteHalf, |s| AsRef::<TokioTcpStream>::as_ref(s));
+
+impl TcpSocket for TcpStream {}
+#[cfg(feature = "tokio-io")]
+impl TcpSocket for TokioTcpStream {}
+#[cfg(feature = "tokio-io")]
+impl TcpSocket for TokioReadHalf {}
+#[cfg(feature = "tokio-io")]
+impl TcpSocket for TokioWriteHalf {}
+
+#[cfg(unix)]
+impl OsSocket for UnixStream {
+    fn socket_fd(&self) -> BorrowedFd<'_> {
+        self.as_fd()
+    }
+}
+
+impl<S> OsSocket for StdBufReader<S>
+where
+    S: OsSocket,
+{
+    #[cfg(unix)]
+    fn socket_fd(&self) -> BorrowedFd<'_> {
+        self.get_ref().socket_fd()
+    }
+
+    #[cfg(windows)]
+    fn socket_handle(&self) -> BorrowedSocket<'_> {
+        self.get_ref().socket_handle()
+    }
+}
+
+#[cfg(feature = "tokio-io")]
+impl<S> OsSocket for TokioBufReader<S>
+where
+    S: OsSocket,
+{
+    #[cfg(unix)]
+    fn socket_fd(&self) -> BorrowedFd<'_> {
+        self.get_ref().socket_fd()
+    }
+
+    #[cfg(windows)]
+    fn socket_handle(&self) -> BorrowedSocket<'_> {
+        self.get_ref().socket_handle()
+    }
+}
+
+impl<S> TcpSocket for StdBufReader<S> where S: TcpSocket {}
+
+#[cfg(feature = "tokio-io")]
+impl<S> TcpSocket for TokioBufReader<S> where S: TcpSocket {}
+
+#[cfg(unix)]
+impl<R> AsFd for CraftReader<R>
+where
+    R: OsSocket,
+{
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.get_ref().socket_fd()
+    }
+}
+
+#[cfg(unix)]
+impl<R> AsRawFd for CraftReader<R>
+where
+    R: OsSocket,
+{
+    fn as_raw_fd(&self) -> RawFd {
+        self.as_fd().as_raw_fd()
+    }
+}
+
+#[cfg(unix)]
+impl<W> AsFd for CraftWriter<W>
+where
+    W: OsSocket,
+{
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.get_ref().socket_fd()
+    }
+}
+
+#[cfg(unix)]
+impl<W> AsRawFd for CraftWriter<W>
+where
+    W: OsSocket,
+{
+    fn as_raw_fd(&self) -> RawFd {
+        self.as_fd().as_raw_fd()
+    }
+}
+
+// both halves of a connection share one socket, so a connection exposes its writer's socket, which
+// (unlike the reader's) is never wrapped in a buffer
+#[cfg(unix)]
+impl<R, W> AsFd for CraftConnection<R, W>
+where
+    W: OsSocket,
+{
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.writer.as_fd()
+    }
+}
+
+#[cfg(unix)]
+impl<R, W> AsRawFd for CraftConnection<R, W>
+where
+    W: OsSocket,
+{
+    fn as_raw_fd(&self) -> RawFd {
+        self.writer.as_raw_fd()
+    }
+}
+
+#[cfg(windows)]
+impl<R> AsSocket for CraftReader<R>
+where
+    R: OsSocket,
+{
+    fn as_socket(&self) -> BorrowedSocket<'_> {
+        self.get_ref().socket_handle()
+    }
+}
+
+#[cfg(windows)]
+impl<R> AsRawSocket for CraftReader<R>
+where
+    R: OsSocket,
+{
+    fn as_raw_socket(&self) -> RawSocket {
+        self.as_socket().as_raw_socket()
+    }
+}
+
+#[cfg(windows)]
+impl<W> AsSocket for CraftWriter<W>
+where
+    W: OsSocket,
+{
+    fn as_socket(&self) -> BorrowedSocket<'_> {
+        self.get_ref().socket_handle()
+    }
+}
+
+#[cfg(windows)]
+impl<W> AsRawSocket for CraftWriter<W>
+where
+    W: OsSocket,
+{
+    fn as_raw_socket(&self) -> RawSocket {
+        self.as_socket().as_raw_socket()
+    }
+}
+
+#[cfg(windows)]
+impl<R, W> AsSocket for CraftConnection<R, W>
+where
+    W: OsSocket,
+{
+    fn as_socket(&self) -> BorrowedSocket<'_> {
+        self.writer.as_socket()
+    }
+}
+
+#[cfg(windows)]
+impl<R, W> AsRawSocket for CraftConnection<R, W>
+where
+    W: OsSocket,
+{
+    fn as_raw_socket(&self) -> RawSocket {
+        self.writer.as_raw_socket()
+    }
+}
+
+///
+/// What closing a TCP socket does with data which was written but not sent yet, which is
+/// configured with the `SO_LINGER` socket option (see `CraftConnection::set_close_behavior`).
+///
+#[cfg(feature = "socket-options")]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum CloseBehavior {
+    ///
+    /// Closing returns immediately, and the operating system sends the remaining data followed by
+    /// a FIN in the background. This is the default of new sockets.
+    ///
+    Graceful,
+    ///
+    /// Closing blocks for up to the given time while the remaining data is sent, and then resets
+    /// the connection if it could not be sent.
+    ///
+    /// Dropping the connection blocks the thread which drops it, even if the socket is in
+    /// non-blocking mode (as the sockets of async runtimes are), so this is meant for blocking
+    /// sockets. Async code should use `drain_and_close_async` to close gracefully instead, which
+    /// waits without blocking the runtime.
+    ///
+    Linger(Duration),
+    ///
+    /// Closing discards the remaining data and resets the connection (RST) instead of closing it
+    /// gracefully. The socket then skips the `TIME_WAIT` state, which keeps a proxy which
+    /// disconnects many abusive clients at once from running out of ports, but the peer may not
+    /// receive the last data which was written (such as a Disconnect packet).
+    ///
+    Reset,
+}
+
+#[cfg(feature = "socket-options")]
+impl Default for CloseBehavior {
+    fn default() -> Self {
+        CloseBehavior::Graceful
+    }
+}
+
+#[cfg(feature = "socket-options")]
+impl CloseBehavior {
+    fn linger(&self) -> Option<Duration> {
+        match self {
+            CloseBehavior::Graceful => None,
+            CloseBehavior::Linger(timeout) => Some(*timeout),
+            CloseBehavior::Reset => Some(Duration::from_secs(0)),
+        }
+    }
+}
+
+#[cfg(feature = "socket-options")]
+impl<R, W> CraftConnection<R, W>
+where
+    W: TcpSocket,
+{
+    ///
+    /// Sets what closing the socket does with data which was not sent yet. See `CloseBehavior`.
+    ///
+    pub fn set_close_behavior(&self, behavior: CloseBehavior) -> io::Result<()> {
+        SockRef::from(self).set_linger(behavior.linger())
+    }
+
+    pub fn close_behavior(&self) -> io::Result<CloseBehavior> {
+        Ok(match SockRef::from(self).linger()? {
+            None => CloseBehavior::Graceful,
+            Some(timeout) if timeout == Duration::from_secs(0) => CloseBehavior::Reset,
+            Some(timeout) => CloseBehavior::Linger(timeout),
+        })
+    }
+
+    ///
+    /// Closes the connection by resetting it (see `CloseBehavior::Reset`), discarding anything
+    /// which was not sent yet. Use `drain_and_close` to close it gracefully instead.
+    ///
+    pub fn reset(self) -> io::Result<()> {
+        self.set_close_behavior(CloseBehavior::Reset)?;
+        drop(self);
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "socket-options"))]
+mod tests {
+    use super::*;
+    use crate::tcp::CraftTcpConnection;
+    use crate::test_util::tcp_pair;
+    use mcproto_rs::protocol::PacketDirection;
+    use std::io::Read;
+
+    #[test]
+    fn close_behavior_round_trips() {
+        let (client, _server) = tcp_pair();
+        let connection =
+            CraftTcpConnection::from_std(client, PacketDirection::ClientBound).unwrap();
+        assert_eq!(
+            connection.close_behavior().unwrap(),
+            CloseBehavior::Graceful
+        );
+        for behavior in [
+            CloseBehavior::Reset,
+            CloseBehavior::Linger(Duration::from_secs(5)),
+            CloseBehavior::Graceful,
+        ]
+        .iter()
+        {
+            connection.set_close_behavior(*behavior).unwrap();
+            assert_eq!(connection.close_behavior().unwrap(), *behavior);
+        }
+    }
+
+    #[test]
+    fn reset_closes_with_a_rst() {
+        let (client, mut server) = tcp_pair();
+        let connection =
+            CraftTcpConnection::from_std(client, PacketDirection::ClientBound).unwrap();
+        connection.reset().unwrap();
+
+        let mut buf = [0u8; 1];
+        let err = server.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    }
+}
@@ -0,0 +1,236 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+///
+/// The number of buckets of a `SizeHistogram`. Bucket `n` holds sizes of `2^(n-1)` up to
+/// `2^n - 1` bytes (bucket 0 holds only empty frames), and the last bucket holds every larger
+/// size, so a histogram takes the same amount of memory no matter how many sizes it records.
+///
+pub const SIZE_BUCKET_COUNT: usize = 24;
+
+///
+/// A bounded histogram of sizes, in power of two buckets. Recording a size is a single atomic
+/// increment, so one histogram can be shared by many connections (see `FrameSizeStats`).
+///
+#[derive(Debug)]
+pub struct SizeHistogram {
+    buckets: [AtomicU64; SIZE_BUCKET_COUNT],
+}
+
+impl Default for SizeHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: Default::default(),
+        }
+    }
+}
+
+impl SizeHistogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, size: usize) {
+        self.buckets[bucket_of(size)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    ///
+    /// The number of sizes recorded in each bucket so far.
+    ///
+    pub fn snapshot(&self) -> SizeSnapshot {
+        let mut counts = [0; SIZE_BUCKET_COUNT];
+        for (count, bucket) in counts.iter_mut().zip(self.buckets.iter()) {
+            *count = bucket.load(Ordering::Relaxed);
+        }
+
+        SizeSnapshot { counts }
+    }
+
+    pub fn reset(&self) {
+        for bucket in self.buckets.iter() {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+fn bucket_of(size: usize) -> usize {
+    let bits = (usize::BITS - size.leading_zeros()) as usize;
+    bits.min(SIZE_BUCKET_COUNT - 1)
+}
+
+///
+/// The counts of a `SizeHistogram` at some point in time.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeSnapshot {
+    pub counts: [u64; SIZE_BUCKET_COUNT],
+}
+
+impl SizeSnapshot {
+    ///
+    /// The number of sizes recorded.
+    ///
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    ///
+    /// The smallest and largest size (inclusive) bucket `index` holds. The largest size of the
+    /// last bucket is `usize::MAX`.
+    ///
+    pub fn bucket_bounds(index: usize) -> (usize, usize) {
+        match index {
+            0 => (0, 0),
+            index if index >= SIZE_BUCKET_COUNT - 1 => (1 << (SIZE_BUCKET_COUNT - 2), usize::MAX),
+            index => (1 << (index - 1), (1 << index) - 1),
+        }
+    }
+
+    ///
+    /// An upper bound of the size which `percentile` (from 0 to 100) percent of the recorded sizes
+    /// do not exceed, which is the largest size of the bucket it falls into. This is the value to
+    /// use when sizing a buffer so that it fits that many frames. Returns `None` if nothing was
+    /// recorded.
+    ///
+    pub fn percentile(&self, percentile: f64) -> Option<usize> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+
+        let target = ((count as f64) * percentile.clamp(0.0, 100.0) / 100.0).ceil() as u64;
+        let mut seen = 0;
+        for (index, bucket_count) in self.counts.iter().enumerate() {
+            seen += bucket_count;
+            if seen >= target.max(1) {
+                return Some(Self::bucket_bounds(index).1);
+            }
+        }
+
+        Some(usize::MAX)
+    }
+}
+
+#[derive(Debug, Default)]
+struct FrameSizeHistograms {
+    read_wire: SizeHistogram,
+    read_body: SizeHistogram,
+    write_wire: SizeHistogram,
+    write_body: SizeHistogram,
+}
+
+///
+/// Histograms of the sizes of the frames a reader reads and a writer writes, both as they are
+/// sent (including the length prefix, after compression), and of the packet (its id and body)
+/// before compression. Comparing the two shows how much compression saves at each size, which
+/// helps choose a compression threshold, and the sizes on the wire help choose buffer sizes (such
+/// as `set_read_ahead`) and spot frames which do not fit into a single segment.
+///
+/// Stats are enabled by setting them on a reader and a writer (see
+/// `CraftReader::set_frame_size_stats` and `CraftWriter::set_frame_size_stats`). They are cheap to
+/// clone, and clones record into the same histograms, so one instance can collect the sizes of
+/// every connection of a server.
+///
+#[derive(Debug, Clone, Default)]
+pub struct FrameSizeStats {
+    histograms: Arc<FrameSizeHistograms>,
+}
+
+impl FrameSizeStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// The sizes of frames read, as they were received.
+    ///
+    pub fn read_wire(&self) -> &SizeHistogram {
+        &self.histograms.read_wire
+    }
+
+    ///
+    /// The sizes of packets read, after decompression.
+    ///
+    pub fn read_body(&self) -> &SizeHistogram {
+        &self.histograms.read_body
+    }
+
+    ///
+    /// The sizes of frames written, as they were sent.
+    ///
+    pub fn write_wire(&self) -> &SizeHistogram {
+        &self.histograms.write_wire
+    }
+
+    ///
+    /// The sizes of packets written, before compression.
+    ///
+    pub fn write_body(&self) -> &SizeHistogram {
+        &self.histograms.write_body
+    }
+
+    pub fn reset(&self) {
+        self.read_wire().reset();
+        self.read_body().reset();
+        self.write_wire().reset();
+        self.write_body().reset();
+    }
+
+    // `frame_len` is the length the frame's prefix declared, which does not include the prefix
+    pub(crate) fn record_read(&self, frame_len: usize, body_len: usize) {
+        self.read_wire().record(frame_len + var_int_len(frame_len));
+        self.read_body().record(body_len);
+    }
+
+    // `wire_len` includes the frame's length prefix
+    pub(crate) fn record_write(&self, wire_len: usize, body_len: usize) {
+        self.write_wire().record(wire_len);
+        self.write_body().record(body_len);
+    }
+}
+
+///
+/// The totals of the packets a writer compressed: their size (id and body) before compression, and
+/// the size of the frames which were sent for them, including the length prefix and the data
+/// length. Packets below the compression threshold are not counted, since they are sent as they
+/// are. See `CraftWriter::compression_stats`.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    pub packets: u64,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+impl CompressionStats {
+    ///
+    /// The number of bytes compression saved, which is negative if compressed frames ended up
+    /// larger than the packets in them (which happens when the threshold is too low).
+    ///
+    pub fn bytes_saved(&self) -> i64 {
+        self.bytes_before as i64 - self.bytes_after as i64
+    }
+
+    ///
+    /// The size of the compressed frames relative to the packets in them (lower is better), or
+    /// `None` if no packet was compressed.
+    ///
+    pub fn ratio(&self) -> Option<f64> {
+        if self.bytes_before == 0 {
+            None
+        } else {
+            Some(self.bytes_after as f64 / self.bytes_before as f64)
+        }
+    }
+
+    pub(crate) fn record(&mut self, before: usize, after: usize) {
+        self.packets += 1;
+        self.bytes_before += before as u64;
+        self.bytes_after += after as u64;
+    }
+}
+
+pub(crate) fn var_int_len(value: usize) -> usize {
+    let bits = (usize::BITS - value.leading_zeros()) as usize;
+    (bits.max(1) + 6) / 7
+}
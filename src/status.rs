@@ -0,0 +1,564 @@
+use crate::connection::CraftConnection;
+use crate::reader::{CraftReader, CraftSyncReader, ReadError};
+use crate::writer::{serialize_string, WriteError};
+use mcproto_rs::protocol::{Id, PacketDirection, State};
+#[cfg(feature = "backtrace")]
+use std::backtrace::Backtrace;
+use std::io;
+use thiserror::Error;
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+use crate::{
+    reader::{AsyncReadExact, CraftAsyncReader},
+    writer::AsyncWriteAll,
+};
+
+#[derive(Debug, Error)]
+pub enum StatusError {
+    #[error("failed to read status packet")]
+    Read {
+        #[from]
+        err: ReadError,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("failed to write status packet")]
+    Write {
+        #[from]
+        err: WriteError,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("unexpected packet {id:?} while responding to status")]
+    UnexpectedPacket {
+        id: Id,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+}
+
+pub type StatusResult = Result<(), StatusError>;
+
+const STATUS_REQUEST_ID: Id = status_id(0x00, PacketDirection::ServerBound);
+const STATUS_PING_ID: Id = status_id(0x01, PacketDirection::ServerBound);
+const STATUS_RESPONSE_ID: Id = status_id(0x00, PacketDirection::ClientBound);
+const STATUS_PONG_ID: Id = status_id(0x01, PacketDirection::ClientBound);
+
+const fn status_id(id: i32, direction: PacketDirection) -> Id {
+    Id {
+        id,
+        state: State::Status,
+        direction,
+    }
+}
+
+// what the connection should do after receiving a packet in the status state
+enum StatusStep {
+    Respond(Vec<u8>),
+    Pong,
+}
+
+struct StatusResponder<F> {
+    status: Option<F>,
+}
+
+impl<F> StatusResponder<F>
+where
+    F: FnOnce() -> String,
+{
+    fn new(status: F) -> Self {
+        Self {
+            status: Some(status),
+        }
+    }
+
+    fn next_step(&mut self, id: Id) -> Result<StatusStep, StatusError> {
+        if id == STATUS_REQUEST_ID {
+            if let Some(status) = self.status.take() {
+                return Ok(StatusStep::Respond(serialize_string(&status())?));
+            }
+        } else if id == STATUS_PING_ID {
+            return Ok(StatusStep::Pong);
+        }
+
+        Err(StatusError::UnexpectedPacket {
+            id,
+            #[cfg(feature = "backtrace")]
+            backtrace: Backtrace::capture(),
+        })
+    }
+}
+
+impl<R, W> CraftConnection<R, W>
+where
+    CraftReader<R>: CraftSyncReader,
+    W: std::io::Write,
+{
+    ///
+    /// Answers a client which is querying the status of this server, then closes the connection.
+    ///
+    /// The connection must be server-bound and already be in the `Status` state (the handshake has
+    /// been read). The `status` function is called to generate the status JSON when the client
+    /// sends its request, and the client's ping is echoed back as a pong. The connection is closed
+    /// after the pong is written, or when the client disconnects.
+    ///
+    pub fn respond_to_status<F>(mut self, status: F) -> StatusResult
+    where
+        F: FnOnce() -> String,
+    {
+        let mut responder = StatusResponder::new(status);
+        loop {
+            let (id, body) = match self.reader.read_raw_untyped_packet()? {
+                Some(packet) => packet,
+                None => {
+                    self.writer.flush()?;
+                    return Ok(());
+                }
+            };
+
+            match responder.next_step(id)? {
+                StatusStep::Respond(response) => {
+                    self.writer.write_raw_untyped_packet(STATUS_RESPONSE_ID, &response)?;
+                }
+                StatusStep::Pong => {
+                    self.writer.write_raw_untyped_packet(STATUS_PONG_ID, body)?;
+                    self.writer.flush()?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+impl<R, W> CraftConnection<R, W>
+where
+    CraftReader<R>: CraftAsyncReader,
+    W: AsyncWriteAll,
+{
+    ///
+    /// Same as `respond_to_status`, but for a connection wrapping async streams.
+    ///
+    pub async fn respond_to_status_async<F>(mut self, status: F) -> StatusResult
+    where
+        F: FnOnce() -> String,
+    {
+        let mut responder = StatusResponder::new(status);
+        loop {
+            let (id, body) = match self.reader.read_raw_untyped_packet_async().await? {
+                Some(packet) => packet,
+                None => {
+                    self.writer.flush_async().await?;
+                    return Ok(());
+                }
+            };
+
+            match responder.next_step(id)? {
+                StatusStep::Respond(response) => {
+                    self.writer
+                        .write_raw_untyped_packet_async(STATUS_RESPONSE_ID, &response)
+                        .await?;
+                }
+                StatusStep::Pong => {
+                    self.writer
+                        .write_raw_untyped_packet_async(STATUS_PONG_ID, body)
+                        .await?;
+                    self.writer.flush_async().await?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+///
+/// The information a server reports in its status, which can be formatted both as the JSON of a
+/// modern status response (see `to_json`, for `respond_to_status`) and as the kick string a
+/// legacy server list ping is answered with (see `respond_to_legacy_ping`).
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerStatus {
+    pub version_name: String,
+    pub protocol_version: i32,
+    pub max_players: i32,
+    pub online_players: i32,
+    ///
+    /// The message of the day, as plain text (legacy clients do not support chat components).
+    ///
+    pub description: String,
+}
+
+// legacy pings start with 0xFE 0x01 (or are a lone 0xFE before 1.4), and are answered with a Kick
+// packet (0xFF)
+const LEGACY_PING: [u8; 2] = [0xFE, 0x01];
+const LEGACY_KICK_ID: u8 = 0xFF;
+
+// 1.6 clients follow the ping with a plugin message (0xFA) on the MC|PingHost channel, whose data is
+// the protocol version, the host name (of at most 255 characters) and the port
+const PLUGIN_MESSAGE_ID: u8 = 0xFA;
+const PING_HOST_CHANNEL: &str = "MC|PingHost";
+const PING_HOST_HEADER_LEN: usize = 3 + 2 + 2 * 11 + 2;
+const MAX_PING_HOST_DATA_LEN: usize = 1 + 2 + 2 * 255 + 4;
+const MAX_LEGACY_PING_LEN: usize = PING_HOST_HEADER_LEN + MAX_PING_HOST_DATA_LEN;
+
+impl ServerStatus {
+    pub fn to_json(&self) -> String {
+        format!(
+            concat!(
+                "{{\"version\":{{\"name\":\"{}\",\"protocol\":{}}},",
+                "\"players\":{{\"max\":{},\"online\":{}}},",
+                "\"description\":{{\"text\":\"{}\"}}}}",
+            ),
+            escape_json(&self.version_name),
+            self.protocol_version,
+            self.max_players,
+            self.online_players,
+            escape_json(&self.description),
+        )
+    }
+
+    ///
+    /// The Kick packet which answers a legacy server list ping, as sent by 1.4 to 1.6 clients:
+    /// `§1`, the protocol version, the version name, the description, and the player counts,
+    /// separated by NUL characters and encoded as UTF-16BE with a length prefix.
+    ///
+    pub fn legacy_response(&self) -> Vec<u8> {
+        legacy_kick(&format!(
+            "\u{a7}1\0{}\0{}\0{}\0{}\0{}",
+            self.protocol_version,
+            self.version_name,
+            self.description,
+            self.online_players,
+            self.max_players,
+        ))
+    }
+
+    ///
+    /// The Kick packet which answers the legacy server list ping of clients before 1.4, which is
+    /// a lone `0xFE`: the description and the player counts, separated by `§`. These clients show
+    /// no version, and the description may not contain a `§`.
+    ///
+    pub fn pre_1_4_legacy_response(&self) -> Vec<u8> {
+        legacy_kick(&format!(
+            "{}\u{a7}{}\u{a7}{}",
+            self.description.replace('\u{a7}', ""),
+            self.online_players,
+            self.max_players,
+        ))
+    }
+}
+
+fn legacy_kick(reason: &str) -> Vec<u8> {
+    let units: Vec<u16> = reason.encode_utf16().collect();
+    let mut out = Vec::with_capacity(3 + units.len() * 2);
+    out.push(LEGACY_KICK_ID);
+    out.extend_from_slice(&(units.len() as u16).to_be_bytes());
+    for unit in units {
+        out.extend_from_slice(&unit.to_be_bytes());
+    }
+    out
+}
+
+fn escape_json(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// what the first read of a legacy ping contained
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum LegacyPing {
+    // a lone 0xFE, sent by clients before 1.4
+    Pre14,
+    // 0xFE 0x01, which the MC|PingHost plugin message of 1.6 clients may still follow
+    Ping,
+    // 0xFE 0x01 followed by the start of the plugin message, which is this long in total (if the
+    // header was read)
+    PingHost(Option<usize>),
+}
+
+fn check_legacy_ping(request: &[u8]) -> io::Result<LegacyPing> {
+    match request {
+        [0xFE] => Ok(LegacyPing::Pre14),
+        [0xFE, 0x01] => Ok(LegacyPing::Ping),
+        [0xFE, 0x01, PLUGIN_MESSAGE_ID, ..] if request.len() < PING_HOST_HEADER_LEN => {
+            Ok(LegacyPing::PingHost(None))
+        }
+        [0xFE, 0x01, PLUGIN_MESSAGE_ID, ..] => {
+            let channel: Vec<u16> = PING_HOST_CHANNEL.encode_utf16().collect();
+            let channel_len = u16::from_be_bytes([request[3], request[4]]) as usize;
+            let channel_matches = channel_len == channel.len()
+                && request[5..PING_HOST_HEADER_LEN - 2]
+                    .chunks(2)
+                    .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+                    .eq(channel.iter().copied());
+            if !channel_matches {
+                return Err(invalid_legacy_ping("legacy ping has an unexpected plugin message"));
+            }
+
+            let data_len = u16::from_be_bytes([
+                request[PING_HOST_HEADER_LEN - 2],
+                request[PING_HOST_HEADER_LEN - 1],
+            ]) as usize;
+            if data_len > MAX_PING_HOST_DATA_LEN {
+                return Err(invalid_legacy_ping("legacy ping host data is too long"));
+            }
+
+            let len = PING_HOST_HEADER_LEN + data_len;
+            if request.len() > len {
+                return Err(invalid_legacy_ping("legacy ping is followed by unexpected data"));
+            }
+
+            Ok(LegacyPing::PingHost(Some(len)))
+        }
+        _ => Err(invalid_legacy_ping("not a legacy server list ping")),
+    }
+}
+
+fn invalid_legacy_ping(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn legacy_response_to(ping: LegacyPing, status: &ServerStatus) -> Vec<u8> {
+    match ping {
+        LegacyPing::Pre14 => status.pre_1_4_legacy_response(),
+        _ => status.legacy_response(),
+    }
+}
+
+// whether reading the rest of a legacy ping after answering it stops at this error
+fn ends_drain(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::UnexpectedEof
+    )
+}
+
+///
+/// Answers a legacy server list ping (which `sniff` classifies as `ProtocolKind::LegacyPing`), so
+/// that old clients and scanners are shown the server's status. The connection should be closed
+/// afterwards.
+///
+/// The ping is read with one read call, whose bytes decide which response is written. Clients
+/// before 1.4 only send `0xFE`, and are answered with `ServerStatus::pre_1_4_legacy_response`
+/// (`sniff` waits for a second byte, so it can't be used to classify these). Clients from 1.4 to
+/// 1.6 send `0xFE 0x01`, and are answered with `ServerStatus::legacy_response`. 1.6 clients follow
+/// it with a `MC|PingHost` plugin message, which is read before the response is written if it
+/// arrived with the first bytes, and is rejected if it is longer than a ping can be.
+///
+/// Otherwise, up to the length of the longest ping is read and discarded after the response is
+/// written, until the client closes the connection or the read times out, so that closing the
+/// connection does not discard the response with a reset while the rest of the ping is unread.
+/// The stream should have a read timeout.
+///
+pub fn respond_to_legacy_ping<R, W>(
+    read: &mut R,
+    write: &mut W,
+    status: &ServerStatus,
+) -> io::Result<()>
+where
+    R: io::Read,
+    W: io::Write,
+{
+    let mut request = [0u8; MAX_LEGACY_PING_LEN];
+    let mut len = read_first(read, &mut request)?;
+    let mut ping = check_legacy_ping(&request[..len])?;
+    while let LegacyPing::PingHost(known_len) = ping {
+        let until = known_len.unwrap_or(PING_HOST_HEADER_LEN);
+        if len == until {
+            break;
+        }
+
+        read.read_exact(&mut request[len..until])?;
+        len = until;
+        ping = check_legacy_ping(&request[..len])?;
+    }
+
+    write.write_all(&legacy_response_to(ping, status))?;
+    write.flush()?;
+
+    if let LegacyPing::PingHost(_) = ping {
+        return Ok(());
+    }
+
+    let mut drained = 0;
+    while drained < MAX_LEGACY_PING_LEN {
+        match read.read(&mut request) {
+            Ok(0) => break,
+            Ok(n) => drained += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) if ends_drain(&err) => break,
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(())
+}
+
+fn read_first<R>(read: &mut R, to: &mut [u8]) -> io::Result<usize>
+where
+    R: io::Read,
+{
+    loop {
+        match read.read(to) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => return Ok(n),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+///
+/// Same as `respond_to_legacy_ping`, but for async streams.
+///
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+pub async fn respond_to_legacy_ping_async<R, W>(
+    read: &mut R,
+    write: &mut W,
+    status: &ServerStatus,
+) -> io::Result<()>
+where
+    R: AsyncReadExact,
+    W: AsyncWriteAll,
+{
+    let mut request = [0u8; MAX_LEGACY_PING_LEN];
+    let mut len = match read.read_some(&mut request).await? {
+        0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+        n => n,
+    };
+    let mut ping = check_legacy_ping(&request[..len])?;
+    while let LegacyPing::PingHost(known_len) = ping {
+        let until = known_len.unwrap_or(PING_HOST_HEADER_LEN);
+        if len == until {
+            break;
+        }
+
+        read.read_exact(&mut request[len..until]).await?;
+        len = until;
+        ping = check_legacy_ping(&request[..len])?;
+    }
+
+    write.write_all(&legacy_response_to(ping, status)).await?;
+    write.flush().await?;
+
+    if let LegacyPing::PingHost(_) = ping {
+        return Ok(());
+    }
+
+    let mut drained = 0;
+    while drained < MAX_LEGACY_PING_LEN {
+        match read.read_some(&mut request).await {
+            Ok(0) => break,
+            Ok(n) => drained += n,
+            Err(err) if ends_drain(&err) => break,
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+
+    fn status() -> ServerStatus {
+        ServerStatus {
+            version_name: "1.6.4".to_owned(),
+            protocol_version: 78,
+            max_players: 20,
+            online_players: 3,
+            description: "A server".to_owned(),
+        }
+    }
+
+    fn utf16(value: &str) -> Vec<u8> {
+        let units: Vec<u16> = value.encode_utf16().collect();
+        let mut out = (units.len() as u16).to_be_bytes().to_vec();
+        for unit in units {
+            out.extend_from_slice(&unit.to_be_bytes());
+        }
+        out
+    }
+
+    // the ping of a 1.6 client, with its MC|PingHost plugin message
+    fn ping_host(host: &str) -> Vec<u8> {
+        let mut data = vec![78];
+        data.extend(utf16(host));
+        data.extend_from_slice(&25565i32.to_be_bytes());
+
+        let mut ping = vec![0xFE, 0x01, 0xFA];
+        ping.extend(utf16(PING_HOST_CHANNEL));
+        ping.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        ping.extend(data);
+        ping
+    }
+
+    fn respond<R: Read>(mut read: R) -> io::Result<(Vec<u8>, R)> {
+        let mut written = Vec::new();
+        respond_to_legacy_ping(&mut read, &mut written, &status())?;
+        Ok((written, read))
+    }
+
+    #[test]
+    fn lone_0xfe_is_answered_as_before_1_4() {
+        let (written, _) = respond(Cursor::new(vec![0xFE])).unwrap();
+        assert_eq!(written, status().pre_1_4_legacy_response());
+    }
+
+    #[test]
+    fn ping_without_plugin_message_is_answered() {
+        let (written, _) = respond(Cursor::new(vec![0xFE, 0x01])).unwrap();
+        assert_eq!(written, status().legacy_response());
+    }
+
+    #[test]
+    fn ping_host_is_read_before_answering() {
+        let ping = ping_host("example.com");
+        let len = ping.len() as u64;
+        let (written, read) = respond(Cursor::new(ping)).unwrap();
+
+        assert_eq!(written, status().legacy_response());
+        assert_eq!(read.position(), len);
+    }
+
+    #[test]
+    fn ping_host_arriving_later_is_drained() {
+        let ping = ping_host("example.com");
+        let (first, rest) = ping.split_at(2);
+        let read = Cursor::new(first.to_vec()).chain(Cursor::new(rest.to_vec()));
+        let (written, read) = respond(read).unwrap();
+
+        assert_eq!(written, status().legacy_response());
+        let (first, rest) = read.into_inner();
+        assert_eq!(first.position(), 2);
+        assert_eq!(rest.position(), rest.get_ref().len() as u64);
+    }
+
+    #[test]
+    fn oversized_ping_host_is_rejected() {
+        let mut ping = ping_host("example.com");
+        let data_len = (MAX_PING_HOST_DATA_LEN as u16 + 1).to_be_bytes();
+        ping[PING_HOST_HEADER_LEN - 2..PING_HOST_HEADER_LEN].copy_from_slice(&data_len);
+        let err = respond(Cursor::new(ping)).unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn other_data_is_rejected() {
+        let err = respond(Cursor::new(vec![0x10, 0x00])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
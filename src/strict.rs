@@ -0,0 +1,224 @@
+use crate::cookie::COOKIE_VERSION;
+use mcproto_rs::protocol::{Id, PacketDirection, State};
+
+// the first protocol version (1.13) with the login plugin request/response packets
+const LOGIN_PLUGIN_VERSION: i32 = 393;
+
+// the first protocol version (1.20.2) with the login acknowledged packet
+const LOGIN_ACKNOWLEDGED_VERSION: i32 = 764;
+
+///
+/// The packet ids which are valid in each state and direction, used by a `CraftReader` in strict
+/// mode (see `CraftReader::set_strict_ids`) to reject packets which are not valid for the current
+/// state with `ReadError::StateViolation`.
+///
+/// Ids are valid from 0 up to a maximum id for each state and direction. The maximum ids of the
+/// `Handshaking`, `Status` and `Login` states are built in. The ids of the `Play` state change with
+/// almost every protocol version, so they are only checked once a maximum id has been set using
+/// `with_max_id`. Ids in a state and direction without a maximum id are not checked.
+///
+#[derive(Debug, Clone)]
+pub struct PacketIdRules {
+    rules: Vec<IdRule>,
+}
+
+#[derive(Debug, Clone)]
+struct IdRule {
+    state: State,
+    direction: PacketDirection,
+    // None means that no packet id is valid
+    max_id: Option<i32>,
+}
+
+impl PacketIdRules {
+    ///
+    /// The built in rules for a protocol version. If the version is not known, the `Login` state
+    /// allows the packets which were added in 1.13, but not the login acknowledged packet of 1.20.2
+    /// or the cookie packets of 1.20.5.
+    ///
+    pub fn for_protocol_version(protocol_version: Option<i32>) -> Self {
+        let has_login_plugin =
+            protocol_version.map_or(true, |version| version >= LOGIN_PLUGIN_VERSION);
+        let has_login_acknowledged =
+            protocol_version.map_or(false, |version| version >= LOGIN_ACKNOWLEDGED_VERSION);
+        let has_cookies = protocol_version.map_or(false, |version| version >= COOKIE_VERSION);
+        let rule = move |state, direction, max_id| IdRule {
+            state,
+            direction,
+            max_id,
+        };
+
+        Self {
+            rules: vec![
+                rule(State::Handshaking, PacketDirection::ServerBound, Some(0x00)),
+                rule(State::Handshaking, PacketDirection::ClientBound, None),
+                rule(State::Status, PacketDirection::ServerBound, Some(0x01)),
+                rule(State::Status, PacketDirection::ClientBound, Some(0x01)),
+                rule(
+                    State::Login,
+                    PacketDirection::ServerBound,
+                    Some(if has_cookies {
+                        0x04
+                    } else if has_login_acknowledged {
+                        0x03
+                    } else if has_login_plugin {
+                        0x02
+                    } else {
+                        0x01
+                    }),
+                ),
+                rule(
+                    State::Login,
+                    PacketDirection::ClientBound,
+                    Some(if has_cookies {
+                        0x05
+                    } else if has_login_plugin {
+                        0x04
+                    } else {
+                        0x03
+                    }),
+                ),
+            ],
+        }
+    }
+
+    ///
+    /// Sets the highest valid packet id in a state and direction, such as the ids of the `Play`
+    /// state for the protocol version in use.
+    ///
+    pub fn with_max_id(mut self, state: State, direction: PacketDirection, max_id: i32) -> Self {
+        self.remove_rule(state, direction);
+        self.rules.push(IdRule {
+            state,
+            direction,
+            max_id: Some(max_id),
+        });
+        self
+    }
+
+    ///
+    /// Stops checking the packet ids of a state and direction.
+    ///
+    pub fn without_check(mut self, state: State, direction: PacketDirection) -> Self {
+        self.remove_rule(state, direction);
+        self
+    }
+
+    pub fn is_valid(&self, id: Id) -> bool {
+        if id.id < 0 {
+            return false;
+        }
+
+        let rule = self
+            .rules
+            .iter()
+            .find(|rule| rule.state == id.state && rule.direction == id.direction);
+        match rule {
+            Some(IdRule { max_id: Some(max_id), .. }) => id.id <= *max_id,
+            Some(IdRule { max_id: None, .. }) => false,
+            None => true,
+        }
+    }
+
+    fn remove_rule(&mut self, state: State, direction: PacketDirection) {
+        self.rules
+            .retain(|rule| !(rule.state == state && rule.direction == direction));
+    }
+}
+
+impl Default for PacketIdRules {
+    fn default() -> Self {
+        Self::for_protocol_version(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::{CraftReader, CraftSyncReader, ReadError};
+    use std::io::Cursor;
+
+    fn login(id: i32) -> Id {
+        Id {
+            id,
+            state: State::Login,
+            direction: PacketDirection::ServerBound,
+        }
+    }
+
+    #[test]
+    fn login_ids_depend_on_the_protocol_version() {
+        let cases = [
+            (Some(340), 0x01, true),
+            (Some(340), 0x02, false),
+            (Some(LOGIN_PLUGIN_VERSION), 0x02, true),
+            (Some(763), 0x03, false),
+            (Some(LOGIN_ACKNOWLEDGED_VERSION), 0x03, true),
+            (Some(765), 0x04, false),
+            (Some(COOKIE_VERSION), 0x04, true),
+            (Some(COOKIE_VERSION), 0x05, false),
+            (None, 0x02, true),
+            (None, 0x03, false),
+        ];
+
+        for (version, id, valid) in cases.iter() {
+            let rules = PacketIdRules::for_protocol_version(*version);
+            assert_eq!(
+                rules.is_valid(login(*id)),
+                *valid,
+                "{:?} {:#x}",
+                version,
+                id
+            );
+        }
+    }
+
+    #[test]
+    fn negative_ids_are_invalid() {
+        assert!(!PacketIdRules::for_protocol_version(None).is_valid(login(-1)));
+    }
+
+    #[test]
+    fn unchecked_states_allow_every_id() {
+        let play = Id {
+            id: 0x7F,
+            state: State::Play,
+            direction: PacketDirection::ServerBound,
+        };
+        let rules = PacketIdRules::for_protocol_version(None);
+        assert!(rules.is_valid(play));
+
+        let rules = rules.with_max_id(State::Play, PacketDirection::ServerBound, 0x30);
+        assert!(!rules.is_valid(play));
+
+        let rules = rules.without_check(State::Play, PacketDirection::ServerBound);
+        assert!(rules.is_valid(play));
+    }
+
+    #[test]
+    fn handshaking_allows_no_clientbound_ids() {
+        let id = Id {
+            id: 0x00,
+            state: State::Handshaking,
+            direction: PacketDirection::ClientBound,
+        };
+        assert!(!PacketIdRules::for_protocol_version(None).is_valid(id));
+    }
+
+    #[test]
+    fn strict_reader_rejects_ids_of_another_state() {
+        // a status frame with the id 0x05, which is only valid in other states
+        let frame = vec![0x01, 0x05];
+        let mut reader = CraftReader::wrap_with_state(
+            Cursor::new(frame),
+            PacketDirection::ServerBound,
+            State::Status,
+        );
+        reader.set_strict_ids(Some(PacketIdRules::for_protocol_version(None)));
+
+        match reader.read_raw_untyped_packet() {
+            Err(ReadError::StateViolation { id, .. }) => assert_eq!(id.id, 0x05),
+            other => panic!("expected StateViolation, got {:?}", other.map(|_| ())),
+        }
+    }
+}
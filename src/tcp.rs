@@ -1,12 +1,31 @@
+use crate::address::{parse_server_address, ServerAddress};
 use crate::connection::CraftConnection;
 use crate::reader::CraftReader;
+use crate::wrapper::SessionOptions;
 use crate::writer::CraftWriter;
 use mcproto_rs::protocol::{PacketDirection, State};
 use std::io::BufReader as StdBufReader;
 use std::net::TcpStream;
+use std::time::Duration;
+#[cfg(feature = "async-dns")]
+use crate::dns::CraftResolver;
 
 #[cfg(any(feature = "futures-io", feature = "tokio-io"))]
 use crate::{CraftAsyncReader, CraftAsyncWriter};
+#[cfg(feature = "local-io")]
+use crate::{CraftLocalReader, CraftLocalWriter};
+
+#[cfg(feature = "glommio-io")]
+use {
+    futures::io::{
+        AsyncReadExt as _, BufReader as FuturesBufReader, ReadHalf as FuturesReadHalf,
+        WriteHalf as FuturesWriteHalf,
+    },
+    glommio::net::TcpStream as GlommioTcpStream,
+};
+
+#[cfg(feature = "monoio-io")]
+use monoio::net::{tcp::TcpStreamPoll as MonoioPollStream, TcpStream as MonoioTcpStream};
 
 #[cfg(feature = "tokio-io")]
 use tokio::{
@@ -33,6 +52,15 @@ impl CraftTcpConnection {
         Self::from_std(TcpStream::connect(to)?, PacketDirection::ClientBound)
     }
 
+    ///
+    /// Connects to a server address in any of the forms accepted by `parse_server_address`, such
+    /// as `example.com`, `example.com:25566` or `[::1]:25565`.
+    ///
+    pub fn connect_server_address_std(address: &str) -> Result<Self, std::io::Error> {
+        let address = parse_address_io(address)?;
+        Self::connect_server_std((address.host.as_str(), address.port_or_default()))
+    }
+
     pub fn wrap_client_stream_std(stream: TcpStream) -> Result<Self, std::io::Error> {
         Self::from_std(stream, PacketDirection::ServerBound)
     }
@@ -49,13 +77,54 @@ impl CraftTcpConnection {
         read_direction: PacketDirection,
         state: State,
     ) -> Result<Self, std::io::Error> {
+        Self::from_std_with_options(s1, read_direction, &SessionOptions::new(state))
+    }
+
+    ///
+    /// Wraps a socket which is already part of an established session (such as one handed over
+    /// from another process), applying `options` before the connection is returned. See
+    /// `SessionOptions`.
+    ///
+    pub fn from_std_with_options(
+        s1: TcpStream,
+        read_direction: PacketDirection,
+        options: &SessionOptions,
+    ) -> Result<Self, std::io::Error> {
+        let peer_addr = s1.peer_addr().ok();
+        let local_addr = s1.local_addr().ok();
         let write = s1.try_clone()?;
         let read = StdBufReader::with_capacity(BUF_SIZE, s1);
 
-        Ok(Self {
-            reader: CraftReader::wrap_with_state(read, read_direction, state),
-            writer: CraftWriter::wrap_with_state(write, read_direction.opposite(), state),
-        })
+        let mut out = Self::wrap_with_options(read, write, read_direction, options);
+        out.peer_addr = peer_addr;
+        out.local_addr = local_addr;
+        Ok(out)
+    }
+}
+
+impl CraftTcpConnection {
+    ///
+    /// Sets the read timeout of the underlying socket. A read which times out fails with
+    /// `ReadError::TimedOut`, and can be retried without losing any data.
+    ///
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), std::io::Error> {
+        self.writer.get_ref().set_read_timeout(timeout)
+    }
+
+    ///
+    /// Sets the write timeout of the underlying socket. A write which times out fails with
+    /// `WriteError::TimedOut`, and the rest of the packet is written before the next packet.
+    ///
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<(), std::io::Error> {
+        self.writer.get_ref().set_write_timeout(timeout)
+    }
+
+    pub fn read_timeout(&self) -> Result<Option<Duration>, std::io::Error> {
+        self.writer.get_ref().read_timeout()
+    }
+
+    pub fn write_timeout(&self) -> Result<Option<Duration>, std::io::Error> {
+        self.writer.get_ref().write_timeout()
     }
 }
 
@@ -72,9 +141,88 @@ impl CraftTokioConnection {
     {
         let conn = TokioTcpStream::connect(to).await?;
         conn.set_nodelay(true)?;
+        let peer_addr = conn.peer_addr().ok();
+        let local_addr = conn.local_addr().ok();
         let (reader, writer) = conn.into_split();
         let reader = TokioBufReader::with_capacity(BUF_SIZE, reader);
-        Ok(Self::from_async((reader, writer), PacketDirection::ClientBound))
+        let mut out = Self::from_async((reader, writer), PacketDirection::ClientBound);
+        out.peer_addr = peer_addr;
+        out.local_addr = local_addr;
+        Ok(out)
+    }
+
+    ///
+    /// Connects to a server by host name, resolving it (including its SRV record, when no port is
+    /// given) using `resolver` instead of the blocking system resolver. Each resolved address is
+    /// tried in order until one accepts the connection.
+    ///
+    #[cfg(feature = "async-dns")]
+    pub async fn connect_server_tokio_resolved(
+        address: &ServerAddress,
+        resolver: &CraftResolver,
+    ) -> Result<Self, TokioIoError> {
+        let addrs = resolver.resolve(address).await?;
+        Self::connect_server_tokio(&addrs[..]).await
+    }
+
+    ///
+    /// Connects to a server address in any of the forms accepted by `parse_server_address`, such
+    /// as `example.com`, `example.com:25566` or `[::1]:25565`.
+    ///
+    pub async fn connect_server_address_tokio(address: &str) -> Result<Self, TokioIoError> {
+        let address = parse_address_io(address)?;
+        Self::connect_server_tokio((address.host.as_str(), address.port_or_default())).await
+    }
+}
+
+#[cfg(feature = "tokio-io")]
+impl CraftTcpConnection {
+    ///
+    /// Moves this connection into a tokio runtime, keeping its state, encryption, compression,
+    /// middleware and observers, and any bytes which were already received. This lets a connection
+    /// be established on a blocking thread (such as a login flow) and then served asynchronously.
+    ///
+    /// This must be called from within a tokio runtime, because the socket is registered with it.
+    ///
+    pub fn into_tokio(self) -> Result<CraftTokioConnection, TokioIoError> {
+        let mut received = Vec::new();
+        let mut out = self.try_map_streams(|read, write| {
+            received.extend_from_slice(read.buffer());
+            // the writer is a clone of the same socket
+            drop(write);
+            let stream = read.into_inner();
+            stream.set_nonblocking(true)?;
+            let (reader, writer) = TokioTcpStream::from_std(stream)?.into_split();
+            Ok::<_, TokioIoError>((TokioBufReader::with_capacity(BUF_SIZE, reader), writer))
+        })?;
+        out.reader.push_received(&received);
+        Ok(out)
+    }
+}
+
+#[cfg(feature = "tokio-io")]
+impl CraftTokioConnection {
+    ///
+    /// Moves this connection out of its tokio runtime into a blocking socket, keeping its state,
+    /// encryption, compression, middleware and observers, and any bytes which were already
+    /// received. The result can be moved to a blocking thread, or into another runtime using
+    /// `into_tokio`.
+    ///
+    pub fn into_std(self) -> Result<CraftTcpConnection, TokioIoError> {
+        let mut received = Vec::new();
+        let mut out = self.try_map_streams(|read, write| {
+            received.extend_from_slice(read.buffer());
+            let stream = read
+                .into_inner()
+                .reunite(write)
+                .map_err(|err| TokioIoError::new(std::io::ErrorKind::Other, err))?
+                .into_std()?;
+            stream.set_nonblocking(false)?;
+            let write = stream.try_clone()?;
+            Ok::<_, TokioIoError>((StdBufReader::with_capacity(BUF_SIZE, stream), write))
+        })?;
+        out.reader.push_received(&received);
+        Ok(out)
     }
 }
 
@@ -91,11 +239,16 @@ impl CraftUnbufferedTokioConnection {
     {
         let conn = TokioTcpStream::connect(to).await?;
         conn.set_nodelay(true)?;
+        let peer_addr = conn.peer_addr().ok();
+        let local_addr = conn.local_addr().ok();
 
-        Ok(Self::from_async(
+        let mut out = Self::from_async(
             conn.into_split(),
             PacketDirection::ClientBound,
-        ))
+        );
+        out.peer_addr = peer_addr;
+        out.local_addr = local_addr;
+        Ok(out)
     }
 }
 
@@ -115,9 +268,210 @@ where
         state: State,
     ) -> Self {
         let (reader, writer) = tuple;
-        Self {
-            reader: CraftReader::wrap_with_state(reader, read_direction, state),
-            writer: CraftWriter::wrap_with_state(writer, read_direction.opposite(), state),
+        Self::from_split(
+            CraftReader::wrap_with_state(reader, read_direction, state),
+            CraftWriter::wrap_with_state(writer, read_direction.opposite(), state),
+        )
+    }
+}
+
+#[cfg(feature = "local-io")]
+impl<R, W> CraftConnection<R, W>
+where
+    CraftReader<R>: CraftLocalReader,
+    CraftWriter<W>: CraftLocalWriter,
+{
+    ///
+    /// Same as `from_async`, but for streams which are not `Send`, such as those of thread-per-core
+    /// runtimes. The connection is read and written using `CraftLocalReader` and
+    /// `CraftLocalWriter`.
+    ///
+    pub fn from_local(tuple: (R, W), read_direction: PacketDirection) -> Self {
+        Self::from_local_with_state(tuple, read_direction, State::Handshaking)
+    }
+
+    pub fn from_local_with_state(
+        tuple: (R, W),
+        read_direction: PacketDirection,
+        state: State,
+    ) -> Self {
+        let (reader, writer) = tuple;
+        Self::from_split(
+            CraftReader::wrap_with_state(reader, read_direction, state),
+            CraftWriter::wrap_with_state(writer, read_direction.opposite(), state),
+        )
+    }
+}
+
+#[cfg(feature = "glommio-io")]
+pub type CraftGlommioConnection = CraftConnection<
+    FuturesBufReader<FuturesReadHalf<GlommioTcpStream>>,
+    FuturesWriteHalf<GlommioTcpStream>,
+>;
+
+#[cfg(feature = "glommio-io")]
+impl CraftGlommioConnection {
+    ///
+    /// Connects to a server from a glommio executor. The connection must stay on the executor's
+    /// thread.
+    ///
+    pub async fn connect_server_glommio<A>(to: A) -> Result<Self, std::io::Error>
+    where
+        A: std::net::ToSocketAddrs,
+    {
+        let conn = GlommioTcpStream::connect(to).await?;
+        conn.set_nodelay(true)?;
+        Ok(Self::wrap_glommio_stream(conn, PacketDirection::ClientBound))
+    }
+
+    pub fn wrap_client_stream_glommio(stream: GlommioTcpStream) -> Self {
+        Self::wrap_glommio_stream(stream, PacketDirection::ServerBound)
+    }
+
+    fn wrap_glommio_stream(stream: GlommioTcpStream, read_direction: PacketDirection) -> Self {
+        let peer_addr = stream.peer_addr().ok();
+        let local_addr = stream.local_addr().ok();
+        let (reader, writer) = stream.split();
+        let reader = FuturesBufReader::with_capacity(BUF_SIZE, reader);
+        let mut out = Self::from_local((reader, writer), read_direction);
+        out.peer_addr = peer_addr;
+        out.local_addr = local_addr;
+        out
+    }
+}
+
+#[cfg(feature = "monoio-io")]
+pub type CraftMonoioConnection = CraftConnection<
+    TokioBufReader<tokio::io::ReadHalf<MonoioPollStream>>,
+    tokio::io::WriteHalf<MonoioPollStream>,
+>;
+
+#[cfg(feature = "monoio-io")]
+impl CraftMonoioConnection {
+    ///
+    /// Connects to a server from a monoio runtime. The connection must stay on the runtime's
+    /// thread.
+    ///
+    pub async fn connect_server_monoio<A>(to: A) -> Result<Self, std::io::Error>
+    where
+        A: std::net::ToSocketAddrs,
+    {
+        let conn = MonoioTcpStream::connect(to).await?;
+        conn.set_nodelay(true)?;
+        Self::wrap_monoio_stream(conn, PacketDirection::ClientBound)
+    }
+
+    pub fn wrap_client_stream_monoio(
+        stream: MonoioTcpStream,
+    ) -> Result<Self, std::io::Error> {
+        Self::wrap_monoio_stream(stream, PacketDirection::ServerBound)
+    }
+
+    // monoio streams take ownership of the buffers they read into, so they are adapted to the
+    // poll based traits the reader and writer are driven by
+    fn wrap_monoio_stream(
+        stream: MonoioTcpStream,
+        read_direction: PacketDirection,
+    ) -> Result<Self, std::io::Error> {
+        let peer_addr = stream.peer_addr().ok();
+        let local_addr = stream.local_addr().ok();
+        let (reader, writer) = tokio::io::split(stream.into_poll_io()?);
+        let reader = TokioBufReader::with_capacity(BUF_SIZE, reader);
+        let mut out = Self::from_local((reader, writer), read_direction);
+        out.peer_addr = peer_addr;
+        out.local_addr = local_addr;
+        Ok(out)
+    }
+}
+
+fn parse_address_io(address: &str) -> Result<ServerAddress, std::io::Error> {
+    parse_server_address(address)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))
+}
+
+// connections (including their ciphers, middleware and observers) must stay Send, so they can be
+// moved between threads and runtimes
+#[allow(dead_code)]
+fn assert_connections_are_send() {
+    fn assert_send<T: Send>() {}
+    assert_send::<CraftTcpConnection>();
+    #[cfg(feature = "tokio-io")]
+    assert_send::<CraftTokioConnection>();
+    #[cfg(feature = "tokio-io")]
+    assert_send::<CraftUnbufferedTokioConnection>();
+    #[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+    assert_send::<crate::boxed::BoxedCraftConnection>();
+}
+
+#[cfg(all(
+    test,
+    feature = "tokio-io",
+    feature = "compression",
+    feature = "encryption"
+))]
+mod tests {
+    use super::*;
+    use crate::reader::CraftSyncReader;
+    use crate::test_util::tcp_pair;
+    use crate::wrapper::CraftIo;
+    use mcproto_rs::protocol::Id;
+
+    const KEY: [u8; 16] = [3; 16];
+
+    fn secured(mut connection: CraftTcpConnection) -> CraftTcpConnection {
+        connection.set_state(State::Play);
+        connection.set_compression_threshold(Some(64));
+        connection.enable_encryption(&KEY, &KEY).unwrap();
+        connection
+    }
+
+    #[test]
+    fn moves_between_std_and_tokio_mid_stream() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        let (client, server) = tcp_pair();
+        let mut client =
+            secured(CraftTcpConnection::from_std(client, PacketDirection::ClientBound).unwrap());
+        let mut server = secured(CraftTcpConnection::wrap_client_stream_std(server).unwrap());
+        let serverbound = Id {
+            id: 0x10,
+            state: State::Play,
+            direction: PacketDirection::ServerBound,
+        };
+        let clientbound = Id {
+            direction: PacketDirection::ClientBound,
+            ..serverbound
+        };
+        let large = vec![7u8; 1000];
+
+        for body in [&b"first"[..], &large, &b"third"[..]].iter() {
+            client.write_raw_untyped_packet(serverbound, body).unwrap();
         }
+        client.flush().unwrap();
+        let (_, body) = server.read_raw_untyped_packet().unwrap().unwrap();
+        assert_eq!(body, b"first");
+
+        let mut server = runtime.block_on(async {
+            let mut server = server.into_tokio().unwrap();
+            let (id, body) = server
+                .read_raw_untyped_packet_async()
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!((id, body), (serverbound, &large[..]));
+            server
+                .write_raw_untyped_packet_async(clientbound, &large)
+                .await
+                .unwrap();
+            server.flush_async().await.unwrap();
+            server.into_std().unwrap()
+        });
+
+        let (id, body) = client.read_raw_untyped_packet().unwrap().unwrap();
+        assert_eq!((id, body), (clientbound, &large[..]));
+        let (id, body) = server.read_raw_untyped_packet().unwrap().unwrap();
+        assert_eq!((id, body), (serverbound, &b"third"[..]));
     }
 }
@@ -0,0 +1,9 @@
+use std::net::{TcpListener, TcpStream};
+
+// a connected pair of sockets over the loopback interface, the first connected to the second
+pub(crate) fn tcp_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let connected = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (accepted, _) = listener.accept().unwrap();
+    (connected, accepted)
+}
@@ -0,0 +1,444 @@
+use crate::connection::CraftConnection;
+use crate::reader::{CraftSyncReader, ReadError};
+use crate::wrapper::{CraftIo, CraftWrapper};
+use crate::writer::WriteError;
+use mcproto_rs::protocol::{Id, State};
+#[cfg(feature = "backtrace")]
+use std::backtrace::Backtrace;
+use std::collections::VecDeque;
+use std::io;
+use std::time::Duration;
+use thiserror::Error;
+
+///
+/// A stream wrapper which simulates a link with a fixed bandwidth and a fixed amount of buffer
+/// space between the two peers.
+///
+/// Time does not pass on its own. Instead, each call to `tick` represents one unit of time passing,
+/// during which at most `bytes_per_tick` queued bytes are delivered to the wrapped stream, and at
+/// most `bytes_per_tick` bytes may be read from the wrapped stream. This makes the behavior of the
+/// simulated link entirely deterministic, so tests which depend on backpressure do not need a real
+/// slow network (or sleeps) to run.
+///
+/// Writes are accepted into the simulated buffer until `buffer_size` bytes are queued. Once the
+/// buffer is full, writes fail with `ErrorKind::WouldBlock` until a `tick` drains some of it. Reads
+/// fail with `ErrorKind::WouldBlock` once the read allowance for the current tick is used up.
+///
+pub struct ThrottledStream<T> {
+    inner: T,
+    bytes_per_tick: usize,
+    buffer_size: usize,
+    queued: VecDeque<u8>,
+    read_allowance: usize,
+}
+
+impl<T> CraftWrapper<T> for ThrottledStream<T> {
+    fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T> ThrottledStream<T> {
+    ///
+    /// Panics if `bytes_per_tick` is zero, since nothing could ever be delivered or read.
+    ///
+    pub fn new(inner: T, bytes_per_tick: usize, buffer_size: usize) -> Self {
+        assert!(
+            bytes_per_tick > 0,
+            "a ThrottledStream must move at least one byte per tick"
+        );
+        Self {
+            inner,
+            bytes_per_tick,
+            buffer_size,
+            queued: VecDeque::with_capacity(buffer_size),
+            read_allowance: bytes_per_tick,
+        }
+    }
+
+    ///
+    /// The number of written bytes which are sitting in the simulated buffer, and have not yet been
+    /// delivered to the wrapped stream.
+    ///
+    pub fn queued_len(&self) -> usize {
+        self.queued.len()
+    }
+
+    ///
+    /// The number of bytes which can be written before the simulated buffer is full.
+    ///
+    pub fn free_space(&self) -> usize {
+        self.buffer_size - self.queued.len()
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    ///
+    /// Advances the simulated clock by one tick for reads only, refilling the read allowance
+    /// without delivering any queued bytes. This is all `tick` does for a stream which is only read
+    /// from, so it also works when the wrapped stream can not be written to.
+    ///
+    pub fn tick_reads(&mut self) {
+        self.read_allowance = self.bytes_per_tick;
+    }
+}
+
+impl<T> ThrottledStream<T>
+where
+    T: io::Write,
+{
+    ///
+    /// Advances the simulated clock by one tick. Delivers up to `bytes_per_tick` queued bytes to the
+    /// wrapped stream and refills the read allowance.
+    ///
+    /// Returns the number of bytes which were delivered during this tick.
+    ///
+    pub fn tick(&mut self) -> io::Result<usize> {
+        self.tick_reads();
+
+        let n = self.bytes_per_tick.min(self.queued.len());
+        if n == 0 {
+            return Ok(0);
+        }
+
+        let (front, back) = self.queued.as_slices();
+        if front.len() >= n {
+            self.inner.write_all(&front[..n])?;
+        } else {
+            self.inner.write_all(front)?;
+            self.inner.write_all(&back[..(n - front.len())])?;
+        }
+        self.queued.drain(..n);
+
+        Ok(n)
+    }
+
+    ///
+    /// Calls `tick` until the simulated buffer is empty, returning the number of ticks it took.
+    ///
+    pub fn drain(&mut self) -> io::Result<usize> {
+        let mut ticks = 0;
+        while !self.queued.is_empty() {
+            self.tick()?;
+            ticks += 1;
+        }
+
+        Ok(ticks)
+    }
+}
+
+impl<T> io::Write for ThrottledStream<T>
+where
+    T: io::Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let n = self.free_space().min(buf.len());
+        if n == 0 {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        self.queued.extend(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T> io::Read for ThrottledStream<T>
+where
+    T: io::Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let n = self.read_allowance.min(buf.len());
+        if n == 0 {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        let read = self.inner.read(&mut buf[..n])?;
+        self.read_allowance -= read;
+        Ok(read)
+    }
+}
+
+///
+/// One step of a `ScriptedPeer`'s script.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptStep {
+    ///
+    /// Reads the next packet, which must have the id, and the body if one is given.
+    ///
+    Expect { id: i32, body: Option<Vec<u8>> },
+    ///
+    /// Writes a packet with the id and body.
+    ///
+    Send { id: i32, body: Vec<u8> },
+    SetState(State),
+    Wait(Duration),
+}
+
+#[derive(Debug, Error)]
+pub enum ScriptError {
+    #[error("step {step}: expected packet {expected:#04x}, but the peer sent {actual:?}")]
+    UnexpectedPacket {
+        step: usize,
+        expected: i32,
+        actual: Id,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error(
+        "step {step}: body of packet {id:#04x} differs from byte {offset} \
+         (expected {expected_len} bytes, got {actual_len})"
+    )]
+    BodyMismatch {
+        step: usize,
+        id: i32,
+        offset: usize,
+        expected_len: usize,
+        actual_len: usize,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("step {step}: connection closed while expecting packet {expected:#04x}")]
+    Closed {
+        step: usize,
+        expected: i32,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("step {step}: failed to read packet")]
+    ReadFailed {
+        step: usize,
+        #[source]
+        err: ReadError,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("step {step}: failed to write packet")]
+    WriteFailed {
+        step: usize,
+        #[source]
+        err: WriteError,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+}
+
+pub type ScriptResult<T> = Result<T, ScriptError>;
+
+///
+/// Plays the other end of a connection according to a script, so that tests of a protocol flow
+/// (such as a login) can be written as the packets which are expected and the replies to them:
+///
+/// ```ignore
+/// let script = ScriptedPeer::new()
+///     .expect(0x00) // handshake
+///     .set_state(State::Login)
+///     .expect(0x00) // login start
+///     .send(0x02, login_success_body);
+/// ```
+///
+/// The script is run on a connection to the code under test (such as one end of a
+/// `UnixStream::pair()`, or a loopback `TcpStream`), while the code under test runs on another
+/// thread. Packets are compared by their id (in the connection's current state) and, if given,
+/// their body, and the first deviation fails the run with an error naming the step and what
+/// differed.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScriptedPeer {
+    steps: Vec<ScriptStep>,
+}
+
+impl ScriptedPeer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn step(mut self, step: ScriptStep) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    pub fn expect(self, id: i32) -> Self {
+        self.step(ScriptStep::Expect { id, body: None })
+    }
+
+    pub fn expect_body(self, id: i32, body: &[u8]) -> Self {
+        self.step(ScriptStep::Expect {
+            id,
+            body: Some(body.to_vec()),
+        })
+    }
+
+    pub fn send(self, id: i32, body: &[u8]) -> Self {
+        self.step(ScriptStep::Send {
+            id,
+            body: body.to_vec(),
+        })
+    }
+
+    pub fn set_state(self, state: State) -> Self {
+        self.step(ScriptStep::SetState(state))
+    }
+
+    pub fn wait(self, duration: Duration) -> Self {
+        self.step(ScriptStep::Wait(duration))
+    }
+
+    pub fn steps(&self) -> &[ScriptStep] {
+        &self.steps
+    }
+
+    ///
+    /// Runs every step of the script in order on `conn`, stopping at the first deviation.
+    ///
+    pub fn run<R, W>(&self, conn: &mut CraftConnection<R, W>) -> ScriptResult<()>
+    where
+        R: io::Read,
+        W: io::Write,
+    {
+        for (step, action) in self.steps.iter().enumerate() {
+            match action {
+                ScriptStep::Expect { id, body } => {
+                    expect_packet(conn, step, *id, body.as_deref())?
+                }
+                ScriptStep::Send { id, body } => {
+                    let id = Id {
+                        id: *id,
+                        state: conn.writer.state(),
+                        direction: conn.writer.direction(),
+                    };
+                    conn.writer
+                        .write_raw_untyped_packet(id, body)
+                        .map_err(|err| ScriptError::WriteFailed {
+                            step,
+                            err,
+                            #[cfg(feature = "backtrace")]
+                            backtrace: Backtrace::capture(),
+                        })?;
+                }
+                ScriptStep::SetState(state) => conn.set_state(*state),
+                ScriptStep::Wait(duration) => std::thread::sleep(*duration),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn expect_packet<R, W>(
+    conn: &mut CraftConnection<R, W>,
+    step: usize,
+    expected: i32,
+    expected_body: Option<&[u8]>,
+) -> ScriptResult<()>
+where
+    R: io::Read,
+    W: io::Write,
+{
+    let (id, body) = match conn.read_raw_untyped_packet() {
+        Ok(Some(packet)) => packet,
+        Ok(None) => {
+            return Err(ScriptError::Closed {
+                step,
+                expected,
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture(),
+            })
+        }
+        Err(err) => {
+            return Err(ScriptError::ReadFailed {
+                step,
+                err,
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture(),
+            })
+        }
+    };
+
+    if id.id != expected {
+        return Err(ScriptError::UnexpectedPacket {
+            step,
+            expected,
+            actual: id,
+            #[cfg(feature = "backtrace")]
+            backtrace: Backtrace::capture(),
+        });
+    }
+
+    let expected_body = match expected_body {
+        Some(expected_body) if expected_body != body => expected_body,
+        _ => return Ok(()),
+    };
+
+    let offset = expected_body
+        .iter()
+        .zip(body.iter())
+        .position(|(a, b)| a != b)
+        .unwrap_or_else(|| expected_body.len().min(body.len()));
+    Err(ScriptError::BodyMismatch {
+        step,
+        id: expected,
+        offset,
+        expected_len: expected_body.len(),
+        actual_len: body.len(),
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace::capture(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    #[should_panic]
+    fn zero_bytes_per_tick_panics() {
+        ThrottledStream::new(Vec::<u8>::new(), 0, 16);
+    }
+
+    #[test]
+    fn reads_are_limited_per_tick() {
+        // a slice can only be read from
+        let mut stream = ThrottledStream::new(&b"abcdef"[..], 4, 0);
+        let mut buf = [0u8; 8];
+        assert_eq!(stream.read(&mut buf).unwrap(), 4);
+        let err = stream.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        stream.tick_reads();
+        assert_eq!(stream.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"ef");
+    }
+
+    #[test]
+    fn writes_are_delivered_per_tick() {
+        let mut stream = ThrottledStream::new(Vec::new(), 2, 3);
+        assert_eq!(stream.write(b"abcd").unwrap(), 3);
+        let err = stream.write(b"d").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        assert_eq!(stream.tick().unwrap(), 2);
+        assert_eq!(stream.get_ref(), b"ab");
+        assert_eq!(stream.drain().unwrap(), 1);
+        assert_eq!(stream.get_ref(), b"abc");
+    }
+}
@@ -0,0 +1,112 @@
+use mcproto_rs::protocol::State;
+use std::time::{Duration, Instant};
+
+///
+/// When a connection entered each protocol state, recorded by `CraftConnection::set_state`. This
+/// gives the duration of each phase of a connection, such as how long a client took from its
+/// handshake to logging in.
+///
+/// The same durations are reported to observers as they happen (see
+/// `ConnectionObserver::on_state_duration`).
+///
+#[derive(Debug, Clone)]
+pub struct StateTimings {
+    created_at: Instant,
+    entered: Vec<(State, Instant)>,
+}
+
+impl StateTimings {
+    pub(crate) fn new(state: State, now: Instant) -> Self {
+        Self {
+            created_at: now,
+            entered: vec![(state, now)],
+        }
+    }
+
+    // records that `next` was entered at `now`, and returns the state which was left, and how long
+    // was spent in it
+    pub(crate) fn enter(&mut self, next: State, now: Instant) -> Option<(State, Duration)> {
+        let left = self
+            .entered
+            .last()
+            .map(|(state, entered_at)| (*state, now.saturating_duration_since(*entered_at)));
+        self.entered.push((next, now));
+        left
+    }
+
+    ///
+    /// When the connection was created.
+    ///
+    pub fn created_at(&self) -> Instant {
+        self.created_at
+    }
+
+    ///
+    /// When the connection first entered `state`, if it has.
+    ///
+    pub fn entered_at(&self, state: State) -> Option<Instant> {
+        self.entered
+            .iter()
+            .find(|(entered, _)| *entered == state)
+            .map(|(_, at)| *at)
+    }
+
+    ///
+    /// The time from the creation of the connection to entering the `Login` state. For a server,
+    /// this is how long the client took to send its handshake.
+    ///
+    pub fn handshake_duration(&self) -> Option<Duration> {
+        self.between(None, State::Login)
+    }
+
+    ///
+    /// The time from entering the `Login` state to entering the `Play` state, which includes
+    /// encryption and authentication.
+    ///
+    pub fn login_duration(&self) -> Option<Duration> {
+        self.between(Some(State::Login), State::Play)
+    }
+
+    fn between(&self, from: Option<State>, to: State) -> Option<Duration> {
+        let from = match from {
+            Some(from) => self.entered_at(from)?,
+            None => self.created_at,
+        };
+
+        Some(self.entered_at(to)?.saturating_duration_since(from))
+    }
+}
+
+///
+/// The slowest rate at which a peer may send the bytes of a frame to a reader, or accept the bytes
+/// of a frame from a writer, before the read or write fails with a `TooSlow` error. This defeats
+/// slow-drip attacks, where a peer sends (or acknowledges) a large frame a few bytes at a time to
+/// keep a buffer and a task busy for as long as it likes.
+///
+/// The rate is only enforced while a frame is in flight, so an idle connection is never too slow,
+/// and only after `grace` has passed since the frame started, so a short stall on a slow link does
+/// not fail a frame.
+///
+/// Throughput is checked whenever the stream makes progress (and, for blocking streams, whenever
+/// their timeout elapses), so a peer which stops sending entirely is only caught if the stream has
+/// a timeout (or, for async streams, if the read or write is wrapped in one).
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MinThroughput {
+    pub bytes_per_sec: u64,
+    pub grace: Duration,
+}
+
+impl MinThroughput {
+    pub fn new(bytes_per_sec: u64, grace: Duration) -> Self {
+        Self {
+            bytes_per_sec,
+            grace,
+        }
+    }
+
+    // whether transferring `bytes` in `elapsed` is slower than this limit allows
+    pub(crate) fn is_violated(&self, bytes: usize, elapsed: Duration) -> bool {
+        elapsed > self.grace && (bytes as f64) < (self.bytes_per_sec as f64) * elapsed.as_secs_f64()
+    }
+}
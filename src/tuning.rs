@@ -0,0 +1,351 @@
+use crate::compression::compress;
+use crate::middleware::{PacketMiddleware, PacketVerdict};
+use mcproto_rs::protocol::Id;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// packets are grouped by size into buckets, where bucket i holds packets of size
+// [2^(i + MIN_BUCKET_SHIFT - 1), 2^(i + MIN_BUCKET_SHIFT)), except the first, which holds
+// everything smaller
+const MIN_BUCKET_SHIFT: u32 = 4;
+const BUCKET_COUNT: usize = 18;
+const MIN_PACKETS_FOR_RECOMMENDATION: u64 = 1000;
+const GOVERNOR_WINDOW: Duration = Duration::from_secs(1);
+
+///
+/// A compression threshold suggested by a `ThresholdAnalyzer`.
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ThresholdRecommendation {
+    ///
+    /// The suggested threshold. Packets at least this large would be compressed.
+    ///
+    pub threshold: i32,
+    ///
+    /// The estimated fraction of the observed bytes which compression would save at this
+    /// threshold.
+    ///
+    pub estimated_savings: f64,
+    ///
+    /// The estimated CPU time spent compressing, per megabyte of observed traffic, at this
+    /// threshold.
+    ///
+    pub estimated_cpu_millis_per_mb: f64,
+}
+
+///
+/// Observes written packets to recommend a compression threshold which suits the traffic the
+/// connection actually carries, instead of a guess which is never revisited.
+///
+/// The analyzer is a `PacketMiddleware`, so it is installed by adding it to the middleware chain of
+/// a `CraftWriter` or `CraftConnection`. It records the size of every written packet, and
+/// compresses a sample of them to measure how well (and how quickly) packets of each size compress.
+/// A `ThresholdAnalyzer` is a handle which can be cloned, and clones share their observations, so
+/// one analyzer can be installed on many connections.
+///
+/// A recommendation only changes anything when it is applied. On links to vanilla clients, the
+/// threshold can only be chosen once per connection (see `CraftConnection::negotiate_compression`),
+/// so it should be applied to new connections. On links where both ends use this crate, both ends
+/// can apply it at any time using `set_compression_threshold`, as long as they do so at the same
+/// point in the stream.
+///
+#[derive(Clone)]
+pub struct ThresholdAnalyzer {
+    shared: Arc<Mutex<AnalyzerState>>,
+}
+
+struct AnalyzerState {
+    sample_every: u64,
+    packets: u64,
+    buckets: [SizeBucket; BUCKET_COUNT],
+    scratch: Option<Vec<u8>>,
+}
+
+#[derive(Default, Copy, Clone)]
+struct SizeBucket {
+    bytes: u64,
+    sampled_bytes: u64,
+    sampled_compressed_bytes: u64,
+    sampled_nanos: u128,
+}
+
+impl SizeBucket {
+    // the fraction of bytes which compressing packets in this bucket saves, which is negative if
+    // compression makes them larger
+    fn savings_ratio(&self) -> f64 {
+        if self.sampled_bytes == 0 {
+            0.0
+        } else {
+            1.0 - (self.sampled_compressed_bytes as f64 / self.sampled_bytes as f64)
+        }
+    }
+
+    fn nanos_per_byte(&self) -> f64 {
+        if self.sampled_bytes == 0 {
+            0.0
+        } else {
+            self.sampled_nanos as f64 / self.sampled_bytes as f64
+        }
+    }
+}
+
+impl ThresholdAnalyzer {
+    ///
+    /// Creates an analyzer which compresses one in every `sample_every` packets to measure them.
+    ///
+    pub fn new(sample_every: u64) -> Self {
+        Self {
+            shared: Arc::new(Mutex::new(AnalyzerState {
+                sample_every: sample_every.max(1),
+                packets: 0,
+                buckets: [SizeBucket::default(); BUCKET_COUNT],
+                scratch: None,
+            })),
+        }
+    }
+
+    pub fn packets_observed(&self) -> u64 {
+        self.lock().packets
+    }
+
+    ///
+    /// Forgets everything observed so far, such as after applying a recommendation.
+    ///
+    pub fn reset(&self) {
+        let mut state = self.lock();
+        state.packets = 0;
+        state.buckets = [SizeBucket::default(); BUCKET_COUNT];
+    }
+
+    ///
+    /// Recommends the threshold which maximizes the bytes saved by compression, minus the CPU time
+    /// spent compressing. `bytes_per_cpu_milli` is how many saved bytes are worth one millisecond of
+    /// CPU time: a larger value favors bandwidth, and a smaller value favors CPU.
+    ///
+    /// Returns `None` until enough packets have been observed to make a recommendation.
+    ///
+    pub fn recommend(&self, bytes_per_cpu_milli: f64) -> Option<ThresholdRecommendation> {
+        let state = self.lock();
+        if state.packets < MIN_PACKETS_FOR_RECOMMENDATION {
+            return None;
+        }
+
+        let total_bytes: u64 = state.buckets.iter().map(|bucket| bucket.bytes).sum();
+        if total_bytes == 0 {
+            return None;
+        }
+
+        // a threshold at the lower bound of bucket i compresses every packet in buckets i..
+        let mut best: Option<(f64, ThresholdRecommendation)> = None;
+        for first in 0..=BUCKET_COUNT {
+            let mut saved_bytes = 0f64;
+            let mut cpu_nanos = 0f64;
+            for bucket in &state.buckets[first..] {
+                saved_bytes += bucket.bytes as f64 * bucket.savings_ratio();
+                cpu_nanos += bucket.bytes as f64 * bucket.nanos_per_byte();
+            }
+
+            let score = saved_bytes - (cpu_nanos / 1_000_000.0) * bytes_per_cpu_milli;
+            let recommendation = ThresholdRecommendation {
+                threshold: bucket_lower_bound(first),
+                estimated_savings: saved_bytes / total_bytes as f64,
+                estimated_cpu_millis_per_mb: (cpu_nanos / 1_000_000.0)
+                    / (total_bytes as f64 / 1_000_000.0),
+            };
+
+            if best.as_ref().map_or(true, |(best_score, _)| score > *best_score) {
+                best = Some((score, recommendation));
+            }
+        }
+
+        best.map(|(_, recommendation)| recommendation)
+    }
+
+    fn observe(&self, body: &[u8]) {
+        let mut state = self.lock();
+        state.packets += 1;
+        let sample = state.packets % state.sample_every == 0;
+        let index = bucket_index(body.len());
+        let bucket = &mut state.buckets[index];
+        bucket.bytes += body.len() as u64;
+        if !sample {
+            return;
+        }
+
+        let started_at = Instant::now();
+        let compressed_len = match compress(body, &mut state.scratch, 0) {
+            Ok(compressed) => compressed.len(),
+            Err(_) => return,
+        };
+        let elapsed = started_at.elapsed();
+
+        let bucket = &mut state.buckets[index];
+        bucket.sampled_bytes += body.len() as u64;
+        bucket.sampled_compressed_bytes += compressed_len as u64;
+        bucket.sampled_nanos += elapsed.as_nanos();
+    }
+
+    // the analyzer's state is always consistent, so a poisoned lock can be ignored
+    fn lock(&self) -> std::sync::MutexGuard<'_, AnalyzerState> {
+        self.shared.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Default for ThresholdAnalyzer {
+    fn default() -> Self {
+        Self::new(16)
+    }
+}
+
+impl PacketMiddleware for ThresholdAnalyzer {
+    fn on_write(&mut self, _id: Id, body: &[u8]) -> PacketVerdict {
+        self.observe(body);
+        PacketVerdict::Pass
+    }
+}
+
+fn bucket_index(size: usize) -> usize {
+    let bits = (usize::BITS - size.leading_zeros()) as usize;
+    bits.saturating_sub(MIN_BUCKET_SHIFT as usize - 1).min(BUCKET_COUNT - 1)
+}
+
+// the smallest packet size in bucket i, or the largest possible threshold past the last bucket
+fn bucket_lower_bound(index: usize) -> i32 {
+    if index == 0 {
+        0
+    } else if index >= BUCKET_COUNT {
+        i32::MAX
+    } else {
+        1 << (index as u32 + MIN_BUCKET_SHIFT - 1)
+    }
+}
+
+///
+/// A load signal which the caller sets when the process is short on CPU, such as when a tick takes
+/// too long or a run queue grows. It is a handle which can be cloned, and every clone sees the same
+/// signal, so one signal can be shared by the governors of every connection.
+///
+#[derive(Debug, Clone, Default)]
+pub struct CpuPressure {
+    flag: Arc<AtomicBool>,
+}
+
+impl CpuPressure {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&self, under_pressure: bool) {
+        self.flag.store(under_pressure, Ordering::Relaxed);
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+    }
+}
+
+///
+/// Temporarily trades bandwidth for CPU time while the process is under CPU pressure, by using a
+/// lower compression level and a higher compression threshold on a `CraftWriter` (see
+/// `CraftWriter::set_compression_governor`). Once the pressure is gone, the writer's own settings
+/// are used again.
+///
+/// Pressure is detected from a `CpuPressure` signal supplied by the caller, or by measuring the time
+/// the writer spends compressing, or both. When measuring, the writer is under pressure once it
+/// spends more than the budget compressing in a second, and stays under pressure until it spends
+/// less than half of the budget.
+///
+/// A raised threshold means packets larger than the negotiated threshold are sent uncompressed.
+/// This crate's reader and vanilla clients up to 1.16.5 accept such packets.
+///
+#[derive(Debug, Clone)]
+pub struct CompressionGovernor {
+    pressure_level: u32,
+    pressure_threshold: i32,
+    signal: Option<CpuPressure>,
+    compress_budget: Option<Duration>,
+    window_started_at: Option<Instant>,
+    window_compress_time: Duration,
+    measured_pressure: bool,
+}
+
+impl CompressionGovernor {
+    ///
+    /// Creates a governor which, under pressure, compresses at `pressure_level` and only compresses
+    /// packets of at least `pressure_threshold` bytes (or the writer's own threshold, if it is
+    /// higher). It detects nothing until a signal or a budget is added.
+    ///
+    pub fn new(pressure_level: u32, pressure_threshold: i32) -> Self {
+        Self {
+            pressure_level,
+            pressure_threshold,
+            signal: None,
+            compress_budget: None,
+            window_started_at: None,
+            window_compress_time: Duration::from_secs(0),
+            measured_pressure: false,
+        }
+    }
+
+    pub fn with_signal(mut self, signal: CpuPressure) -> Self {
+        self.signal = Some(signal);
+        self
+    }
+
+    ///
+    /// Measures the time spent compressing, and detects pressure once it exceeds `budget` per
+    /// second.
+    ///
+    pub fn with_compress_budget(mut self, budget: Duration) -> Self {
+        self.compress_budget = Some(budget);
+        self
+    }
+
+    pub fn under_pressure(&self) -> bool {
+        self.measured_pressure || self.signal.as_ref().map_or(false, CpuPressure::is_set)
+    }
+
+    pub(crate) fn is_measuring(&self) -> bool {
+        self.compress_budget.is_some()
+    }
+
+    // the (level, threshold) to compress a packet with, given the writer's own settings
+    pub(crate) fn settings(&self, level: u32, threshold: i32) -> (u32, i32) {
+        if self.under_pressure() {
+            (
+                level.min(self.pressure_level),
+                threshold.max(self.pressure_threshold),
+            )
+        } else {
+            (level, threshold)
+        }
+    }
+
+    pub(crate) fn record_compress_time(&mut self, elapsed: Duration, now: Instant) {
+        let budget = match self.compress_budget {
+            Some(budget) => budget,
+            None => return,
+        };
+
+        let started_at = *self.window_started_at.get_or_insert(now);
+        self.window_compress_time += elapsed;
+        let window = now.saturating_duration_since(started_at);
+        if window < GOVERNOR_WINDOW {
+            return;
+        }
+
+        // scale the time spent compressing to one second, since windows only end when a packet is
+        // compressed, and so can be much longer than a second
+        let per_second = self.window_compress_time.as_secs_f64() / window.as_secs_f64();
+        let budget = budget.as_secs_f64();
+        self.measured_pressure = if self.measured_pressure {
+            per_second >= budget / 2.0
+        } else {
+            per_second > budget
+        };
+
+        self.window_started_at = Some(now);
+        self.window_compress_time = Duration::from_secs(0);
+    }
+}
@@ -0,0 +1,112 @@
+use crate::connection::CraftConnection;
+use crate::writer::WriteError;
+use std::io;
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+use crate::{reader::AsyncReadExact, writer::AsyncWriteAll};
+
+///
+/// A connection which stopped framing packets, and carries plain bytes in both directions instead,
+/// for protocols which take over a Minecraft connection once both sides agreed to it (usually
+/// using custom payload packets). See `CraftConnection::into_tunnel`.
+///
+/// Encryption stays enabled (bytes are still encrypted and decrypted), but compression,
+/// middleware and strict mode no longer apply, since there are no packets anymore.
+///
+pub struct CraftTunnel<R, W> {
+    connection: CraftConnection<R, W>,
+}
+
+impl<R, W> CraftConnection<R, W> {
+    ///
+    /// Stops framing, so that everything after the last packet read and written is tunneled as
+    /// plain bytes. Bytes the reader already received beyond the last packet (such as with
+    /// read-ahead) are the first bytes read from the tunnel, and a frame which was only partially
+    /// written is finished before the first bytes written to it.
+    ///
+    pub fn into_tunnel(self) -> CraftTunnel<R, W> {
+        CraftTunnel { connection: self }
+    }
+}
+
+impl<R, W> CraftTunnel<R, W> {
+    ///
+    /// The connection this tunnel was made from, such as to look up its addresses.
+    ///
+    pub fn connection(&self) -> &CraftConnection<R, W> {
+        &self.connection
+    }
+}
+
+fn into_io_error(err: WriteError) -> io::Error {
+    match err {
+        WriteError::IoFail { err, .. } => err,
+        WriteError::TimedOut { .. } => io::ErrorKind::TimedOut.into(),
+        other => io::Error::new(io::ErrorKind::Other, other),
+    }
+}
+
+impl<R, W> io::Read for CraftTunnel<R, W>
+where
+    R: io::Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.connection.reader.read_tunneled(buf)
+    }
+}
+
+impl<R, W> io::Write for CraftTunnel<R, W>
+where
+    W: io::Write,
+{
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        // the data is encrypted as a whole before it is written, so it can not be partially written
+        self.connection
+            .writer
+            .write_tunneled(data)
+            .map_err(into_io_error)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.connection.writer.flush().map_err(into_io_error)
+    }
+}
+
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+impl<R, W> CraftTunnel<R, W>
+where
+    R: AsyncReadExact,
+{
+    ///
+    /// Reads at least one byte (unless the stream has ended) and at most `buf.len()` bytes from an
+    /// async stream, returning how many bytes were read.
+    ///
+    pub async fn read_async(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.connection.reader.read_tunneled_async(buf).await
+    }
+}
+
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+impl<R, W> CraftTunnel<R, W>
+where
+    W: AsyncWriteAll,
+{
+    ///
+    /// Writes all of `data` to an async stream.
+    ///
+    pub async fn write_all_async(&mut self, data: &[u8]) -> io::Result<()> {
+        self.connection
+            .writer
+            .write_tunneled_async(data)
+            .await
+            .map_err(into_io_error)
+    }
+
+    pub async fn flush_async(&mut self) -> io::Result<()> {
+        self.connection
+            .writer
+            .flush_async()
+            .await
+            .map_err(into_io_error)
+    }
+}
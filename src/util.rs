@@ -1,26 +1,71 @@
+use crate::wrapper::{BufferAllocator, BufferGrowth};
+use std::io;
+
 pub(crate) const VAR_INT_BUF_SIZE: usize = 5;
 
 pub(crate) fn get_sized_buf(buf: &mut Option<Vec<u8>>, offset: usize, size: usize) -> &mut [u8] {
+    get_sized_buf_with(buf, offset, size, BufferGrowth::Exponential, usize::MAX, None)
+}
+
+pub(crate) fn get_exact_sized_buf<'a>(
+    buf: &'a mut Option<Vec<u8>>,
+    offset: usize,
+    size: usize,
+    allocator: Option<&dyn BufferAllocator>,
+) -> &'a mut [u8] {
+    get_sized_buf_with(buf, offset, size, BufferGrowth::Exact, usize::MAX, allocator)
+}
+
+pub(crate) fn get_sized_buf_with(
+    buf: &mut Option<Vec<u8>>,
+    offset: usize,
+    size: usize,
+    growth: BufferGrowth,
+    cap: usize,
+    allocator: Option<&dyn BufferAllocator>,
+) -> &mut [u8] {
     let end_at = offset + size;
+    let v = buf.get_or_insert_with(Vec::new);
+    ensure_buf_has_size(v, end_at, growth, cap, allocator);
+    &mut v[offset..end_at]
+}
 
-    match buf {
-        Some(v) => {
-            ensure_buf_has_size(v, end_at);
-            &mut v[offset..end_at]
-        }
-        None => {
-            let new_buf = vec![0u8; end_at];
-            *buf = Some(new_buf);
-            
-            unsafe { &mut buf.as_mut().unwrap_unchecked()[offset..end_at] }
+fn ensure_buf_has_size(
+    buf: &mut Vec<u8>,
+    total_size: usize,
+    growth: BufferGrowth,
+    cap: usize,
+    allocator: Option<&dyn BufferAllocator>,
+) {
+    if total_size <= buf.len() {
+        return;
+    }
+
+    if total_size > buf.capacity() {
+        // only the capacity grows according to the policy, the length (which must be zero-filled)
+        // only ever grows to exactly what was requested
+        let grow_to = match growth {
+            BufferGrowth::Exact => total_size,
+            BufferGrowth::Exponential => (buf.capacity() * 2).min(cap).max(total_size),
+        };
+        match allocator {
+            Some(allocator) => {
+                // the data in the buffer is moved into the new buffer, like reallocating does
+                let mut grown = allocator.allocate(grow_to);
+                grown.clear();
+                grown.reserve_exact(grow_to);
+                grown.extend_from_slice(buf);
+                allocator.release(std::mem::replace(buf, grown));
+            }
+            None => buf.reserve_exact(grow_to - buf.len()),
         }
     }
+
+    buf.resize(total_size, 0u8);
 }
 
-fn ensure_buf_has_size(buf: &mut Vec<u8>, total_size: usize) {
-    if total_size > buf.len() {
-        buf.resize(total_size, 0u8);
-    }
+pub(crate) fn buf_capacity(buf: &Option<Vec<u8>>) -> usize {
+    buf.as_ref().map(Vec::capacity).unwrap_or(0)
 }
 
 pub(crate) fn move_data_rightwards(target: &mut [u8], size: usize, shift_amount: usize) {
@@ -34,4 +79,10 @@ pub(crate) fn move_data_rightwards(target: &mut [u8], size: usize, shift_amount:
     }
 
     target.copy_within(0..size, shift_amount);
-}
\ No newline at end of file
+}
+
+// a read or write on a std socket with a timeout fails with WouldBlock on unix, and TimedOut on
+// windows
+pub(crate) fn is_timeout(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
@@ -0,0 +1,482 @@
+use crate::connection::CraftConnection;
+use crate::util::VAR_INT_BUF_SIZE;
+use crate::writer::{serialize_string, PacketSerializeFail, SliceSerializer, WriteResult};
+use hmac::{Hmac, Mac};
+use mcproto_rs::protocol::{Id, PacketDirection, State};
+use mcproto_rs::types::VarInt;
+use mcproto_rs::{Deserialize, DeserializeErr, Deserialized, Serialize};
+use sha2::Sha256;
+#[cfg(feature = "backtrace")]
+use std::backtrace::Backtrace;
+use std::net::{IpAddr, SocketAddr};
+use thiserror::Error;
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+use crate::writer::AsyncWriteAll;
+
+///
+/// The login plugin channel Velocity's modern forwarding uses.
+///
+pub const VELOCITY_FORWARDING_CHANNEL: &str = "velocity:player_info";
+
+// the login plugin packets were added in 1.13, and have had the same ids since
+const LOGIN_PLUGIN_REQUEST_ID: Id = Id {
+    id: 0x04,
+    state: State::Login,
+    direction: PacketDirection::ClientBound,
+};
+
+const LOGIN_PLUGIN_RESPONSE_ID: Id = Id {
+    id: 0x02,
+    state: State::Login,
+    direction: PacketDirection::ServerBound,
+};
+
+// the size of the HMAC-SHA256 signature which comes before the forwarded data
+const SIGNATURE_LEN: usize = 32;
+
+#[derive(Debug, Error)]
+pub enum VelocityError {
+    ///
+    /// The client did not understand the forwarding request, so it did not connect through a
+    /// Velocity proxy with modern forwarding enabled, and must be rejected.
+    ///
+    #[error("connection was not forwarded by velocity")]
+    Unsigned {
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    ///
+    /// The forwarded data was not signed with the configured secret, so it must not be trusted.
+    ///
+    #[error("velocity forwarding signature is not valid")]
+    BadSignature {
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("malformed velocity forwarding data")]
+    Malformed {
+        #[from]
+        err: DeserializeErr,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("velocity forwarded an invalid address '{address}'")]
+    BadAddress {
+        address: String,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("velocity forwarding version {version} is not supported")]
+    UnsupportedVersion {
+        version: i32,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+}
+
+pub type VelocityResult<T> = Result<T, VelocityError>;
+
+///
+/// A property of a forwarded player's game profile, such as their skin (`textures`).
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileProperty {
+    pub name: String,
+    pub value: String,
+    pub signature: Option<String>,
+}
+
+///
+/// The player information a Velocity proxy forwards, after its signature was verified.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForwardedPlayer {
+    ///
+    /// The version of the forwarding data. Data added by versions after 1 (such as the player's
+    /// chat signing key) is not parsed.
+    ///
+    pub version: i32,
+    ///
+    /// The address the player connected to the proxy from.
+    ///
+    pub address: IpAddr,
+    pub uuid: u128,
+    pub username: String,
+    pub properties: Vec<ProfileProperty>,
+}
+
+///
+/// Verifies the player information a Velocity proxy forwards to a backend server using modern
+/// forwarding, with the forwarding secret configured on the proxy.
+///
+/// During login (before Login Success is sent), the server sends a forwarding request (see
+/// `CraftConnection::send_velocity_forwarding_request`), and passes the client's answer to
+/// `CraftConnection::accept_velocity_forwarding`. A connection which was not forwarded by the
+/// proxy, or whose data was not signed with the secret, must be disconnected.
+///
+#[derive(Clone)]
+pub struct VelocityForwarding {
+    secret: Vec<u8>,
+}
+
+impl std::fmt::Debug for VelocityForwarding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // the secret must never end up in logs
+        f.debug_struct("VelocityForwarding").finish()
+    }
+}
+
+impl VelocityForwarding {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            secret: secret.to_vec(),
+        }
+    }
+
+    ///
+    /// Verifies the signature of the data sent in a successful forwarding response (the 32 byte
+    /// HMAC-SHA256 signature followed by the signed data), and parses the signed data. The
+    /// signature is compared in constant time.
+    ///
+    pub fn verify(&self, data: &[u8]) -> VelocityResult<ForwardedPlayer> {
+        if data.len() < SIGNATURE_LEN {
+            return Err(VelocityError::BadSignature {
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture(),
+            });
+        }
+
+        let (signature, signed) = data.split_at(SIGNATURE_LEN);
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret)
+            .expect("hmac accepts keys of any length");
+        mac.update(signed);
+        mac.verify_slice(signature)
+            .map_err(|_| VelocityError::BadSignature {
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture(),
+            })?;
+
+        parse_forwarded_player(signed)
+    }
+}
+
+fn parse_forwarded_player(data: &[u8]) -> VelocityResult<ForwardedPlayer> {
+    let Deserialized { value: version, data: rest } = VarInt::mc_deserialize(data)?;
+    let version = version.0;
+    if version < 1 {
+        return Err(VelocityError::UnsupportedVersion {
+            version,
+            #[cfg(feature = "backtrace")]
+            backtrace: Backtrace::capture(),
+        });
+    }
+
+    let Deserialized { value: address, data: rest } = String::mc_deserialize(rest)?;
+    let address = address.parse().map_err(|_| VelocityError::BadAddress {
+        address,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace::capture(),
+    })?;
+    let Deserialized { value: most_significant, data: rest } = u64::mc_deserialize(rest)?;
+    let Deserialized { value: least_significant, data: rest } = u64::mc_deserialize(rest)?;
+    let Deserialized { value: username, data: rest } = String::mc_deserialize(rest)?;
+    let Deserialized { value: property_count, data: mut rest } = VarInt::mc_deserialize(rest)?;
+
+    let mut properties = Vec::new();
+    for _ in 0..property_count.0.max(0) {
+        let Deserialized { value: name, data: after } = String::mc_deserialize(rest)?;
+        let Deserialized { value, data: after } = String::mc_deserialize(after)?;
+        let Deserialized { value: signed, data: after } = bool::mc_deserialize(after)?;
+        let (signature, after) = if signed {
+            let Deserialized { value, data } = String::mc_deserialize(after)?;
+            (Some(value), data)
+        } else {
+            (None, after)
+        };
+
+        properties.push(ProfileProperty {
+            name,
+            value,
+            signature,
+        });
+        rest = after;
+    }
+
+    Ok(ForwardedPlayer {
+        version,
+        address,
+        uuid: ((most_significant as u128) << 64) | least_significant as u128,
+        username,
+        properties,
+    })
+}
+
+impl<R, W> CraftConnection<R, W> {
+    fn velocity_forwarding_request(&self, message_id: i32) -> WriteResult<Vec<u8>> {
+        let mut id_buf = [0u8; VAR_INT_BUF_SIZE];
+        let mut serializer = SliceSerializer::create(&mut id_buf);
+        VarInt(message_id)
+            .mc_serialize(&mut serializer)
+            .map_err(move |err| PacketSerializeFail::Body(err))?;
+
+        let mut body = serializer.finish().to_vec();
+        body.extend_from_slice(&serialize_string(VELOCITY_FORWARDING_CHANNEL)?);
+        Ok(body)
+    }
+
+    ///
+    /// If the packet is the client's answer to the forwarding request with `message_id`, verifies
+    /// and returns the forwarded player information, and records the player's real address as the
+    /// peer address of this connection (keeping the port of the connection to the proxy). Other
+    /// packets are ignored, and `Ok(None)` is returned.
+    ///
+    /// A client which did not understand the request is rejected with `VelocityError::Unsigned`.
+    ///
+    pub fn accept_velocity_forwarding(
+        &mut self,
+        forwarding: &VelocityForwarding,
+        message_id: i32,
+        id: Id,
+        body: &[u8],
+    ) -> VelocityResult<Option<ForwardedPlayer>> {
+        if id != LOGIN_PLUGIN_RESPONSE_ID {
+            return Ok(None);
+        }
+
+        let Deserialized { value: answered_id, data: rest } = VarInt::mc_deserialize(body)?;
+        if answered_id.0 != message_id {
+            return Ok(None);
+        }
+
+        let Deserialized { value: successful, data: rest } = bool::mc_deserialize(rest)?;
+        if !successful {
+            return Err(VelocityError::Unsigned {
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture(),
+            });
+        }
+
+        let player = forwarding.verify(rest)?;
+        let port = self.peer_addr.map(|addr| addr.port()).unwrap_or(0);
+        self.peer_addr = Some(SocketAddr::new(player.address, port));
+        Ok(Some(player))
+    }
+}
+
+impl<R, W> CraftConnection<R, W>
+where
+    W: std::io::Write,
+{
+    ///
+    /// Asks the proxy for the forwarded player information, by writing a login plugin request on
+    /// the forwarding channel. This requires protocol version 393 (1.13) or later, and must be sent
+    /// in the `Login` state, before Login Success.
+    ///
+    pub fn send_velocity_forwarding_request(&mut self, message_id: i32) -> WriteResult<()> {
+        let body = self.velocity_forwarding_request(message_id)?;
+        self.writer.write_raw_untyped_packet(LOGIN_PLUGIN_REQUEST_ID, &body)
+    }
+}
+
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+impl<R, W> CraftConnection<R, W>
+where
+    W: AsyncWriteAll,
+{
+    ///
+    /// Same as `send_velocity_forwarding_request`, but writes to an async stream.
+    ///
+    pub async fn send_velocity_forwarding_request_async(
+        &mut self,
+        message_id: i32,
+    ) -> WriteResult<()> {
+        let body = self.velocity_forwarding_request(message_id)?;
+        self.writer
+            .write_raw_untyped_packet_async(LOGIN_PLUGIN_REQUEST_ID, &body)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::CraftReader;
+    use crate::writer::CraftWriter;
+    use std::io::Cursor;
+
+    const SECRET: &[u8] = b"forwarding secret";
+    const MESSAGE_ID: i32 = 7;
+    const UUID: u128 = 0x0123_4567_89ab_cdef_fedc_ba98_7654_3210;
+
+    fn var_int(mut value: i32, out: &mut Vec<u8>) {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value = ((value as u32) >> 7) as i32;
+            if value == 0 {
+                out.push(byte);
+                return;
+            }
+            out.push(byte | 0x80);
+        }
+    }
+
+    fn string(value: &str, out: &mut Vec<u8>) {
+        var_int(value.len() as i32, out);
+        out.extend_from_slice(value.as_bytes());
+    }
+
+    fn forwarded_data() -> Vec<u8> {
+        let mut data = Vec::new();
+        var_int(1, &mut data);
+        string("203.0.113.9", &mut data);
+        data.extend_from_slice(&((UUID >> 64) as u64).to_be_bytes());
+        data.extend_from_slice(&(UUID as u64).to_be_bytes());
+        string("Notch", &mut data);
+        var_int(2, &mut data);
+        string("textures", &mut data);
+        string("skin", &mut data);
+        data.push(1);
+        string("signature", &mut data);
+        string("cape", &mut data);
+        string("none", &mut data);
+        data.push(0);
+        data
+    }
+
+    fn signed(secret: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+        mac.update(data);
+        let mut out = mac.finalize().into_bytes().to_vec();
+        out.extend_from_slice(data);
+        out
+    }
+
+    fn response(message_id: i32, successful: bool, data: &[u8]) -> Vec<u8> {
+        let mut body = Vec::new();
+        var_int(message_id, &mut body);
+        body.push(successful as u8);
+        body.extend_from_slice(data);
+        body
+    }
+
+    fn connection() -> CraftConnection<Cursor<Vec<u8>>, Vec<u8>> {
+        CraftConnection::from_split(
+            CraftReader::wrap(Cursor::new(Vec::new()), PacketDirection::ServerBound),
+            CraftWriter::wrap(Vec::new(), PacketDirection::ClientBound),
+        )
+    }
+
+    #[test]
+    fn signed_data_is_parsed() {
+        let forwarding = VelocityForwarding::new(SECRET);
+        let player = forwarding
+            .verify(&signed(SECRET, &forwarded_data()))
+            .unwrap();
+        assert_eq!(
+            player,
+            ForwardedPlayer {
+                version: 1,
+                address: "203.0.113.9".parse().unwrap(),
+                uuid: UUID,
+                username: "Notch".to_owned(),
+                properties: vec![
+                    ProfileProperty {
+                        name: "textures".to_owned(),
+                        value: "skin".to_owned(),
+                        signature: Some("signature".to_owned()),
+                    },
+                    ProfileProperty {
+                        name: "cape".to_owned(),
+                        value: "none".to_owned(),
+                        signature: None,
+                    },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn flipped_signature_is_rejected() {
+        let forwarding = VelocityForwarding::new(SECRET);
+        let mut data = signed(SECRET, &forwarded_data());
+        data[0] ^= 1;
+        assert!(matches!(
+            forwarding.verify(&data),
+            Err(VelocityError::BadSignature { .. })
+        ));
+
+        let other_secret = signed(b"another secret", &forwarded_data());
+        assert!(matches!(
+            forwarding.verify(&other_secret),
+            Err(VelocityError::BadSignature { .. })
+        ));
+    }
+
+    #[test]
+    fn data_shorter_than_a_signature_is_rejected() {
+        let forwarding = VelocityForwarding::new(SECRET);
+        assert!(matches!(
+            forwarding.verify(&[0; SIGNATURE_LEN - 1]),
+            Err(VelocityError::BadSignature { .. })
+        ));
+    }
+
+    #[test]
+    fn unsuccessful_response_is_unsigned() {
+        let forwarding = VelocityForwarding::new(SECRET);
+        let mut connection = connection();
+        let body = response(MESSAGE_ID, false, &[]);
+        assert!(matches!(
+            connection.accept_velocity_forwarding(
+                &forwarding,
+                MESSAGE_ID,
+                LOGIN_PLUGIN_RESPONSE_ID,
+                &body
+            ),
+            Err(VelocityError::Unsigned { .. })
+        ));
+    }
+
+    #[test]
+    fn accepting_records_the_forwarded_address() {
+        let forwarding = VelocityForwarding::new(SECRET);
+        let mut connection = connection();
+        let body = response(MESSAGE_ID, true, &signed(SECRET, &forwarded_data()));
+        let player = connection
+            .accept_velocity_forwarding(&forwarding, MESSAGE_ID, LOGIN_PLUGIN_RESPONSE_ID, &body)
+            .unwrap()
+            .expect("the response answers the request");
+        assert_eq!(player.uuid, UUID);
+        assert_eq!(
+            connection.peer_addr(),
+            Some(SocketAddr::new(player.address, 0))
+        );
+    }
+
+    #[test]
+    fn other_packets_and_message_ids_are_ignored() {
+        let forwarding = VelocityForwarding::new(SECRET);
+        let mut connection = connection();
+        let body = response(MESSAGE_ID, true, &signed(SECRET, &forwarded_data()));
+
+        let login_start = Id {
+            id: 0x00,
+            state: State::Login,
+            direction: PacketDirection::ServerBound,
+        };
+        assert!(connection
+            .accept_velocity_forwarding(&forwarding, MESSAGE_ID, login_start, &body)
+            .unwrap()
+            .is_none());
+        assert!(connection
+            .accept_velocity_forwarding(
+                &forwarding,
+                MESSAGE_ID + 1,
+                LOGIN_PLUGIN_RESPONSE_ID,
+                &body
+            )
+            .unwrap()
+            .is_none());
+        assert_eq!(connection.peer_addr(), None);
+    }
+}
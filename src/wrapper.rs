@@ -1,6 +1,8 @@
 #[cfg(feature = "encryption")]
 use crate::cfb8::CipherError;
-use mcproto_rs::protocol::State;
+use mcproto_rs::protocol::{PacketDirection, State};
+use std::ops::{Add, AddAssign};
+use std::sync::Arc;
 
 ///
 /// Indicates that a type provided by this crate is wrapping some inner value of type `I`, which can
@@ -45,6 +47,10 @@ pub trait CraftIo {
     /// If encryption is already enabled or the arguments are not valid for the cipher, then an
     /// error is returned and nothing in the underlying state is changed.
     ///
+    /// Encryption starts at the frame boundary after the last packet which was read or written.
+    /// Bytes a reader already received past that boundary (such as with read-ahead) are decrypted
+    /// when encryption is enabled.
+    ///
     #[cfg(feature = "encryption")]
     fn enable_encryption(&mut self, key: &[u8], iv: &[u8]) -> Result<(), CipherError>;
 
@@ -61,8 +67,351 @@ pub trait CraftIo {
     ///
     fn set_max_packet_size(&mut self, max_size: usize);
 
+    ///
+    /// Uses a different max packet size in each protocol state, switching to the size of the new
+    /// state on every `set_state` (and to the size of the current state immediately). This keeps
+    /// the limit small before a peer has logged in, when no legitimate packet is large. `None` (the
+    /// default) keeps the max packet size which is currently set in every state.
+    ///
+    /// A size set with `set_max_packet_size` applies until the next state change.
+    ///
+    /// The default implementation ignores the sizes, and keeps the max packet size which is set.
+    ///
+    fn set_state_max_packet_sizes(&mut self, _sizes: Option<StateMaxPacketSizes>) {}
+
     fn ensure_buf_capacity(&mut self, capacity: usize);
 
+    ///
+    /// Changes how the internal packet buffers grow when a packet does not fit in them. See
+    /// `BufferGrowth` for the available policies. The default is `BufferGrowth::Exponential`.
+    ///
+    /// The default implementation ignores the policy.
+    ///
+    fn set_buffer_growth(&mut self, _growth: BufferGrowth) {}
+
+    ///
+    /// Sets the allocator which the internal packet buffers are taken from when they must grow,
+    /// instead of growing them in place. `None` (the default) grows them in place. See
+    /// `BufferAllocator`.
+    ///
+    /// The default implementation ignores the allocator.
+    ///
+    fn set_buffer_allocator(&mut self, _allocator: Option<Arc<dyn BufferAllocator>>) {}
+
+    ///
+    /// Reports the memory currently held by the internal buffers (their capacities, not the size of
+    /// the data inside of them).
+    ///
+    /// The default implementation reports no memory.
+    ///
+    fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage::default()
+    }
+
     #[cfg(feature = "compression")]
     fn ensure_compression_buf_capacity(&mut self, capacity: usize);
 }
+
+///
+/// The max packet size to use in each protocol state. See `CraftIo::set_state_max_packet_sizes`.
+///
+/// The sizes apply to both directions, so they must fit the largest packet either side sends in
+/// that state. The defaults allow handshakes carrying BungeeCord forwarding data, status responses
+/// with a favicon, and login plugin messages, and the default max packet size in `Play`.
+///
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(default))]
+pub struct StateMaxPacketSizes {
+    pub handshaking: usize,
+    pub status: usize,
+    pub login: usize,
+    pub play: usize,
+}
+
+impl Default for StateMaxPacketSizes {
+    fn default() -> Self {
+        Self {
+            handshaking: 8 * 1024,
+            status: 128 * 1024,
+            login: 1024 * 1024,
+            play: crate::DEAFULT_MAX_PACKET_SIZE,
+        }
+    }
+}
+
+impl StateMaxPacketSizes {
+    pub fn for_state(&self, state: State) -> usize {
+        match state {
+            State::Handshaking => self.handshaking,
+            State::Status => self.status,
+            State::Login => self.login,
+            State::Play => self.play,
+        }
+    }
+}
+
+///
+/// The policy used to grow the internal packet buffers of a `CraftReader` or `CraftWriter` when a
+/// packet does not fit in them.
+///
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(rename_all = "lowercase"))]
+pub enum BufferGrowth {
+    ///
+    /// Grow the buffer to exactly the size required by the packet. This uses the least memory, but
+    /// a connection receiving steadily larger packets will reallocate (and copy) its buffer often.
+    ///
+    Exact,
+
+    ///
+    /// At least double the capacity of the buffer whenever it must grow, but never grow it past the
+    /// max packet size (unless a single packet requires it).
+    ///
+    Exponential,
+}
+
+impl Default for BufferGrowth {
+    fn default() -> Self {
+        BufferGrowth::Exponential
+    }
+}
+
+///
+/// Provides the internal packet buffers of a `CraftReader` or `CraftWriter` (see
+/// `CraftIo::set_buffer_allocator`), such as from a pool kept for each shard of a server.
+///
+/// Whenever a buffer must grow, a new buffer is requested using `allocate`, the data is moved into
+/// it, and the old buffer is given back using `release`. Buffers which are still in use when their
+/// reader or writer is dropped are freed normally.
+///
+/// Buffers are `Vec<u8>`, so their memory always belongs to the global allocator. The allocator
+/// decides where and when that memory is allocated: it can hand out buffers which were allocated
+/// up front (on the thread of the shard which uses them, or while huge pages were enabled), and
+/// recycle released buffers for other connections.
+///
+pub trait BufferAllocator: Send + Sync {
+    ///
+    /// Returns a buffer with a capacity of at least `capacity` bytes. Its contents are discarded.
+    ///
+    fn allocate(&self, capacity: usize) -> Vec<u8>;
+
+    ///
+    /// Takes back a buffer which is no longer used. By default it is dropped.
+    ///
+    fn release(&self, buffer: Vec<u8>) {
+        drop(buffer);
+    }
+}
+
+///
+/// The initial sizes of the internal buffers held by a `CraftReader` or `CraftWriter`, used when
+/// constructing one through `wrap_with_capacities`.
+///
+/// A size of 0 means the buffer is allocated lazily, which is the default behavior. Sizes above
+/// the max packet size are capped to the max packet size, like `ensure_buf_capacity`.
+///
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct BufferCapacities {
+    ///
+    /// Initial size of `raw_buf`, which holds packet bytes as they are read or written.
+    ///
+    pub raw: usize,
+
+    ///
+    /// Initial size of the buffer which holds decompressed packets (in a reader) or compressed
+    /// packets (in a writer).
+    ///
+    #[cfg(feature = "compression")]
+    pub compression: usize,
+}
+
+impl BufferCapacities {
+    pub(crate) fn apply<T>(&self, target: &mut T)
+    where
+        T: CraftIo,
+    {
+        if self.raw > 0 {
+            target.ensure_buf_capacity(self.raw);
+        }
+
+        #[cfg(feature = "compression")]
+        if self.compression > 0 {
+            target.ensure_compression_buf_capacity(self.compression);
+        }
+    }
+}
+
+///
+/// The settings of a session which was established elsewhere (such as a socket handed over from
+/// another process, or a connection whose handshake was performed by other code), used when
+/// constructing a `CraftReader`, `CraftWriter` or `CraftConnection` through `wrap_with_options`.
+/// They are applied before the value is returned, so no packet can be read or written before
+/// them.
+///
+/// Create one with `new`, and set the other fields with struct update syntax, which works the
+/// same whichever features are enabled:
+///
+/// ```ignore
+/// let options = SessionOptions {
+///     compression_threshold: Some(256),
+///     encryption: Some((shared_secret, shared_secret)),
+///     ..SessionOptions::new(State::Play)
+/// };
+/// ```
+///
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct SessionOptions {
+    pub state: State,
+    ///
+    /// Has the same meaning as in `set_compression_threshold`.
+    ///
+    #[cfg(feature = "compression")]
+    pub compression_threshold: Option<i32>,
+    ///
+    /// The key and iv which are passed to `enable_encryption`.
+    ///
+    #[cfg(feature = "encryption")]
+    pub encryption: Option<([u8; 16], [u8; 16])>,
+}
+
+impl SessionOptions {
+    pub fn new(state: State) -> Self {
+        Self {
+            state,
+            #[cfg(feature = "compression")]
+            compression_threshold: None,
+            #[cfg(feature = "encryption")]
+            encryption: None,
+        }
+    }
+
+    #[allow(unused_variables)]
+    pub(crate) fn apply<T>(&self, target: &mut T)
+    where
+        T: CraftIo,
+    {
+        #[cfg(feature = "compression")]
+        target.set_compression_threshold(self.compression_threshold);
+        #[cfg(feature = "encryption")]
+        if let Some((key, iv)) = &self.encryption {
+            target
+                .enable_encryption(key, iv)
+                .expect("a new reader or writer accepts a key and iv of 16 bytes");
+        }
+    }
+}
+
+// the key is left out, so it does not end up in logs
+impl std::fmt::Debug for SessionOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut out = f.debug_struct("SessionOptions");
+        out.field("state", &self.state);
+        #[cfg(feature = "compression")]
+        out.field("compression_threshold", &self.compression_threshold);
+        #[cfg(feature = "encryption")]
+        out.field("encrypted", &self.encryption.is_some());
+        out.finish()
+    }
+}
+
+///
+/// A snapshot of the protocol settings of a `CraftReader` or `CraftWriter`, as returned by their
+/// `settings` functions, which gives one consistent view of them (for logging, or for configuring
+/// a matching connection to another peer, such as the other side of a proxy).
+///
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ProtocolSettings {
+    pub state: State,
+    ///
+    /// The direction of the packets which are read (by a reader) or written (by a writer).
+    ///
+    pub direction: PacketDirection,
+    #[cfg(feature = "compression")]
+    pub compression_threshold: Option<i32>,
+    #[cfg(feature = "encryption")]
+    pub encrypted: bool,
+    ///
+    /// The max packet size in the current state.
+    ///
+    pub max_packet_size: usize,
+    pub state_max_packet_sizes: Option<StateMaxPacketSizes>,
+    pub buffer_growth: BufferGrowth,
+    ///
+    /// The current capacities of the internal buffers, which can be passed to
+    /// `wrap_with_capacities` to give another reader or writer the same buffers up front.
+    ///
+    pub buffer_capacities: BufferCapacities,
+}
+
+///
+/// A snapshot of the protocol settings of both halves of a `CraftConnection`, as returned by
+/// `CraftConnection::settings`.
+///
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ConnectionSettings {
+    pub reader: ProtocolSettings,
+    pub writer: ProtocolSettings,
+    pub protocol_version: Option<i32>,
+}
+
+impl From<MemoryUsage> for BufferCapacities {
+    fn from(usage: MemoryUsage) -> Self {
+        Self {
+            raw: usage.raw_buf,
+            #[cfg(feature = "compression")]
+            compression: usage.compression_buf,
+        }
+    }
+}
+
+///
+/// The memory held by the internal buffers of a `CraftReader`, `CraftWriter` or `CraftConnection`,
+/// as reported by `CraftIo::memory_usage`. All values are in bytes.
+///
+/// For a `CraftConnection`, the usage of the reader and writer are added together.
+///
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct MemoryUsage {
+    ///
+    /// Capacity of `raw_buf`, which holds packet bytes as they are read or written. For a writer,
+    /// this includes the buffers which hold frames until they are written.
+    ///
+    pub raw_buf: usize,
+
+    ///
+    /// Capacity of the buffer which holds decompressed packets (in a reader) or compressed packets
+    /// (in a writer).
+    ///
+    #[cfg(feature = "compression")]
+    pub compression_buf: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        #[cfg(feature = "compression")]
+        let total = self.raw_buf + self.compression_buf;
+
+        #[cfg(not(feature = "compression"))]
+        let total = self.raw_buf;
+
+        total
+    }
+}
+
+impl Add for MemoryUsage {
+    type Output = MemoryUsage;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        MemoryUsage {
+            raw_buf: self.raw_buf + rhs.raw_buf,
+            #[cfg(feature = "compression")]
+            compression_buf: self.compression_buf + rhs.compression_buf,
+        }
+    }
+}
+
+impl AddAssign for MemoryUsage {
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
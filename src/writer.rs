@@ -1,19 +1,49 @@
 #[cfg(feature = "encryption")]
 use crate::cfb8::{setup_craft_cipher, CipherError, CraftCipher};
-use crate::util::{get_sized_buf, move_data_rightwards, VAR_INT_BUF_SIZE};
-use crate::wrapper::{CraftIo, CraftWrapper};
+use crate::util::{
+    buf_capacity, get_exact_sized_buf, get_sized_buf, get_sized_buf_with, is_timeout,
+    move_data_rightwards, VAR_INT_BUF_SIZE,
+};
+use crate::cached::CachedFrame;
+use crate::hooks::FrameHooks;
+use crate::middleware::{MiddlewareChain, PacketVerdict};
+use crate::reader::parse_frame_len;
+use crate::stats::{var_int_len, FrameSizeStats};
+#[cfg(feature = "compression")]
+use crate::stats::CompressionStats;
+use crate::timing::MinThroughput;
+use crate::wrapper::{
+    BufferAllocator, BufferCapacities, BufferGrowth, CraftIo, CraftWrapper, MemoryUsage,
+    ProtocolSettings, SessionOptions, StateMaxPacketSizes,
+};
 use crate::DEAFULT_MAX_PACKET_SIZE;
 #[cfg(feature = "compression")]
-use flate2::{CompressError, Compression, FlushCompress, Status};
+use crate::compression::{compress_with_level, DEFAULT_COMPRESSION_LEVEL};
+#[cfg(feature = "compression")]
+use crate::tuning::CompressionGovernor;
+#[cfg(feature = "compression")]
+use flate2::CompressError;
 use mcproto_rs::protocol::{Id, Packet, PacketDirection, RawPacket, State};
 use mcproto_rs::types::VarInt;
 use mcproto_rs::{Serialize, SerializeErr, SerializeResult, Serializer};
 #[cfg(feature = "backtrace")]
 use std::backtrace::Backtrace;
-use std::ops::{Deref, DerefMut};
+#[cfg(feature = "tracing")]
+use tracing::Span;
+use std::ops::{Deref, DerefMut, Range};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 #[cfg(any(feature = "futures-io", feature = "tokio-io"))]
-use async_trait::async_trait;
+use {
+    async_trait::async_trait,
+    futures_core::Stream,
+    std::{
+        future::poll_fn,
+        pin::Pin,
+        task::{Context, Poll},
+    },
+};
 
 #[derive(Debug, Error)]
 pub enum WriteError {
@@ -65,7 +95,78 @@ pub enum WriteError {
         max_size: usize,
         #[cfg(feature = "backtrace")]
         backtrace: Backtrace,
-    }
+    },
+    #[error("failed to enable encryption")]
+    #[cfg(feature = "encryption")]
+    CipherFail {
+        #[from]
+        err: CipherError,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("packet {id:?} was rejected by middleware")]
+    Rejected {
+        id: Id,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("no disconnect packet is known for state {state:?} with protocol version {protocol_version:?}")]
+    NoDisconnectPacket {
+        state: State,
+        protocol_version: Option<i32>,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("no keep alive packet is known for protocol version {protocol_version:?}")]
+    NoKeepAlivePacket {
+        protocol_version: Option<i32>,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    #[error("cached frame was prepared for compression threshold {frame_threshold:?}, but the writer uses {writer_threshold:?}")]
+    CachedFrameMismatch {
+        frame_threshold: Option<i32>,
+        writer_threshold: Option<i32>,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    ///
+    /// The wrapped stream's write timeout elapsed (or a non-blocking stream could not accept more
+    /// data) part way through a packet. The rest of that packet is kept, and is written before the
+    /// next packet, so the stream is never left with a partial frame.
+    ///
+    #[error("write timed out")]
+    TimedOut {
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    ///
+    /// The peer accepted only `sent` bytes of the pending frames in `elapsed`, which is slower
+    /// than the writer's minimum throughput (see `CraftWriter::set_min_throughput`). The rest of
+    /// the frames is dropped, so the connection should be closed.
+    ///
+    #[error("peer accepted {sent} bytes in {elapsed:?}, which is too slow")]
+    TooSlow {
+        sent: usize,
+        elapsed: Duration,
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
+    ///
+    /// An earlier async write was cancelled (its future was dropped) part way through, so the
+    /// peer may have received part of a frame, and will read the next frame from the wrong
+    /// position. This usually means a write was raced against something else (such as in
+    /// `tokio::select!`). `poll_write_packet` can be cancelled safely, since it keeps the rest of
+    /// the frame.
+    ///
+    /// Every later write fails with this error too. It is only detected in debug builds, or with
+    /// the `cancel-guard` feature.
+    ///
+    #[error("an earlier write was cancelled part way through a frame, so the stream is out of sync")]
+    CancelledMidFrame {
+        #[cfg(feature = "backtrace")]
+        backtrace: Backtrace,
+    },
 }
 
 #[derive(Debug, Error)]
@@ -139,6 +240,23 @@ pub trait CraftAsyncWriter {
         P: RawPacket<'a> + Send + Sync;
 }
 
+///
+/// Same as `CraftAsyncWriter`, but the writer, the packets, and the futures it returns need not be
+/// `Send`. This is implemented for writers of streams which can not leave the thread they were
+/// created on, such as the streams of thread-per-core runtimes (glommio or monoio).
+///
+#[cfg(feature = "local-io")]
+#[async_trait(?Send)]
+pub trait CraftLocalWriter {
+    async fn write_packet_local<P>(&mut self, packet: P) -> WriteResult<()>
+    where
+        P: Packet;
+
+    async fn write_raw_packet_local<'a, P>(&mut self, packet: P) -> WriteResult<()>
+    where
+        P: RawPacket<'a>;
+}
+
 ///
 /// This trait is the interface by which you can write packets to some underlying implementor of
 /// `std::io::Write`.
@@ -184,11 +302,41 @@ pub struct CraftWriter<W> {
     compress_buf: Option<Vec<u8>>,
     #[cfg(feature = "compression")]
     compression_threshold: Option<i32>,
+    #[cfg(feature = "compression")]
+    compression_level: u32,
+    #[cfg(feature = "compression")]
+    compression_governor: Option<CompressionGovernor>,
+    #[cfg(feature = "compression")]
+    compression_stats: CompressionStats,
     state: State,
     direction: PacketDirection,
     #[cfg(feature = "encryption")]
     encryption: Option<CraftCipher>,
     max_packet_size: usize,
+    buffer_growth: BufferGrowth,
+    pending_write: Option<PreparedFrame>,
+    staged_write: Option<PreparedFrame>,
+    // set while the frame of the packet passed to poll_write_packet is pending
+    poll_write_started: bool,
+    flush_buf: Option<Vec<u8>>,
+    flush_threshold: Option<usize>,
+    // frames waiting to be written together, see set_flush_threshold
+    coalesce_buf: Option<Vec<u8>>,
+    coalesced: usize,
+    middleware: MiddlewareChain,
+    last_write_at: Option<Instant>,
+    allocator: Option<Arc<dyn BufferAllocator>>,
+    frame_sizes: Option<FrameSizeStats>,
+    state_max_packet_sizes: Option<StateMaxPacketSizes>,
+    min_throughput: Option<MinThroughput>,
+    pending_started_at: Option<Instant>,
+    pending_sent: usize,
+    frame_hooks: Option<Box<dyn FrameHooks>>,
+    // set while an async write is awaited, see WriteError::CancelledMidFrame
+    #[cfg(any(debug_assertions, feature = "cancel-guard"))]
+    write_in_flight: bool,
+    #[cfg(feature = "tracing")]
+    span: Span,
 }
 
 impl<W> CraftWrapper<W> for CraftWriter<W> {
@@ -200,6 +348,10 @@ impl<W> CraftWrapper<W> for CraftWriter<W> {
 impl<W> CraftIo for CraftWriter<W> {
     fn set_state(&mut self, next: State) {
         self.state = next;
+        if let Some(sizes) = self.state_max_packet_sizes {
+            self.max_packet_size = sizes.for_state(next);
+        }
+        self.middleware.on_state_change(self.direction, next);
     }
 
     #[cfg(feature = "compression")]
@@ -217,21 +369,46 @@ impl<W> CraftIo for CraftWriter<W> {
         self.max_packet_size = max_size;
     }
 
+    fn set_state_max_packet_sizes(&mut self, sizes: Option<StateMaxPacketSizes>) {
+        self.state_max_packet_sizes = sizes;
+        if let Some(sizes) = sizes {
+            self.max_packet_size = sizes.for_state(self.state);
+        }
+    }
+
     fn ensure_buf_capacity(&mut self, capacity: usize) {
-        get_sized_buf(&mut self.raw_buf, 0, if capacity > self.max_packet_size {
+        get_exact_sized_buf(&mut self.raw_buf, 0, if capacity > self.max_packet_size {
             self.max_packet_size
         } else {
             capacity
-        });
+        }, self.allocator.as_deref());
+    }
+
+    fn set_buffer_growth(&mut self, growth: BufferGrowth) {
+        self.buffer_growth = growth;
+    }
+
+    fn set_buffer_allocator(&mut self, allocator: Option<Arc<dyn BufferAllocator>>) {
+        self.allocator = allocator;
+    }
+
+    fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            raw_buf: buf_capacity(&self.raw_buf)
+                + buf_capacity(&self.flush_buf)
+                + buf_capacity(&self.coalesce_buf),
+            #[cfg(feature = "compression")]
+            compression_buf: buf_capacity(&self.compress_buf),
+        }
     }
 
     #[cfg(feature = "compression")]
     fn ensure_compression_buf_capacity(&mut self, capacity: usize) {
-        get_sized_buf(&mut self.compress_buf, 0, if capacity > self.max_packet_size {
+        get_exact_sized_buf(&mut self.compress_buf, 0, if capacity > self.max_packet_size {
             self.max_packet_size
         } else {
             capacity
-        });
+        }, self.allocator.as_deref());
     }
 }
 
@@ -243,33 +420,161 @@ where
     where
         P: Packet,
     {
-        let prepared = self.serialize_packet_to_buf(packet)?;
-        write_data_to_target_sync(self.prepare_packet_in_buf(prepared)?)?;
-        Ok(())
+        self.write_pending_sync()?;
+        let prepared = self.serialize_packet_to_buf(&packet)?;
+        self.pending_write = Some(self.prepare_frame(prepared)?);
+        self.write_pending_sync()
     }
 
     fn write_raw_packet<'a, P>(&mut self, packet: P) -> WriteResult<()>
     where
         P: RawPacket<'a>,
     {
+        self.write_pending_sync()?;
         let prepared = self.serialize_raw_packet_to_buf(packet)?;
-        write_data_to_target_sync(self.prepare_packet_in_buf(prepared)?)?;
-        Ok(())
+        self.pending_write = Some(self.prepare_frame(prepared)?);
+        self.write_pending_sync()
     }
 }
 
-fn write_data_to_target_sync<'a, W>(tuple: (&'a [u8], &'a mut W)) -> Result<(), std::io::Error>
+impl<W> CraftWriter<W>
 where
     W: std::io::Write,
 {
-    let (data, target) = tuple;
-    target.write_all(data)
+    ///
+    /// Writes a packet from its id and its serialized body, such as one returned by
+    /// `read_raw_untyped_packet`, without deserializing and serializing it again. The body is
+    /// framed for this writer (length prefix, compression and encryption), so this is the way to
+    /// forward packets between connections which use different compression thresholds or keys.
+    ///
+    /// The body must be decompressed (as readers return it). Whether it is compressed again depends
+    /// only on this writer: if compression is enabled and the packet (id and body) is at least as
+    /// large as the threshold, it is compressed, and otherwise it is sent with a data length of 0
+    /// (or without one, if compression is disabled). So a packet read from a client with threshold
+    /// 256 is written uncompressed to a backend which has compression disabled, and the other way
+    /// around.
+    ///
+    /// The id must belong to the state and direction of this writer, and the body passes through
+    /// the middleware like any other written packet.
+    ///
+    pub fn write_raw_untyped_packet(&mut self, id: Id, body: &[u8]) -> WriteResult<()> {
+        self.write_pending_sync()?;
+        let prepared = self.serialize_untyped_packet_to_buf(id, body)?;
+        self.pending_write = Some(self.prepare_frame(prepared)?);
+        self.write_pending_sync()
+    }
+
+    ///
+    /// Writes the frames which are buffered because of the flush threshold (see
+    /// `set_flush_threshold`), and then flushes the wrapped stream.
+    ///
+    pub fn flush(&mut self) -> WriteResult<()> {
+        self.coalesce_pending(true);
+        self.write_pending_sync()?;
+        self.inner.flush()?;
+        Ok(())
+    }
+
+    // writes bytes without framing (see CraftTunnel), after the rest of any pending frame
+    pub(crate) fn write_tunneled(&mut self, data: &[u8]) -> WriteResult<()> {
+        self.coalesce_pending(true);
+        self.write_pending_sync()?;
+        self.prepare_tunneled(data);
+        let buf = &self.raw_buf.as_ref().expect("data was just copied into raw_buf")[..data.len()];
+        self.inner.write_all(buf)?;
+        Ok(())
+    }
+
+    ///
+    /// Writes a frame which was prepared once by `CachedFrame`, encrypting it for this writer if
+    /// encryption is enabled. The frame must have been prepared for the same compression threshold
+    /// as this writer uses, otherwise `WriteError::CachedFrameMismatch` is returned.
+    ///
+    /// Middleware sees the packet like any other written packet. If it replaces the body, the new
+    /// body is framed for this writer instead of using the cached frame.
+    ///
+    pub fn write_cached_frame(&mut self, frame: &CachedFrame) -> WriteResult<()> {
+        self.write_pending_sync()?;
+        self.pending_write = Some(self.prepare_cached_frame(frame)?);
+        self.write_pending_sync()
+    }
+
+    ///
+    /// Serializes every packet into a single buffer, and writes all of them with one write call,
+    /// which suits servers that collect each player's packets during a tick and send them at once.
+    ///
+    /// Packets the middleware rejects are skipped. If a packet fails to serialize, the packets
+    /// before it are still written (since they were already encrypted), and the error is returned
+    /// without serializing the packets after it.
+    ///
+    pub fn write_packets<I>(&mut self, packets: I) -> WriteResult<()>
+    where
+        I: IntoIterator,
+        I::Item: Packet,
+    {
+        self.write_pending_sync()?;
+        let mut batch_len = 0;
+        let result = packets
+            .into_iter()
+            .try_for_each(|packet| self.batch_packet(&mut batch_len, &packet));
+
+        self.pending_write = Some(PreparedFrame {
+            buffer: FrameBuffer::Flush,
+            range: 0..batch_len,
+        });
+        self.write_pending_sync()?;
+        result
+    }
+
+    // writes the rest of the pending frame (and the staged frame, if any). If the write times out,
+    // the rest of the frame is kept pending, so it can be finished by the next write.
+    fn write_pending_sync(&mut self) -> WriteResult<()> {
+        self.coalesce_pending(false);
+        while let Some(frame) = self.pending_write.clone() {
+            if frame.range.is_empty() {
+                self.finish_pending_write(true);
+                continue;
+            }
+
+            self.check_throughput()?;
+            let (data, target) = self.frame_and_target(frame);
+            match std::io::Write::write(target, data) {
+                Ok(0) => {
+                    self.finish_pending_write(false);
+                    let err = std::io::Error::from(std::io::ErrorKind::WriteZero);
+                    return Err(err.into());
+                }
+                Ok(n) => self.advance_pending_write(n),
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) if is_timeout(&err) => {
+                    self.check_throughput()?;
+                    return Err(WriteError::TimedOut {
+                        #[cfg(feature = "backtrace")]
+                        backtrace: Backtrace::capture(),
+                    })
+                }
+                Err(err) => {
+                    self.finish_pending_write(false);
+                    return Err(err.into());
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[cfg(any(feature = "tokio-io", feature = "futures-io"))]
 #[async_trait]
 pub trait AsyncWriteAll: Unpin + Send + Sync {
     async fn write_all(&mut self, data: &[u8]) -> Result<(), std::io::Error>;
+
+    ///
+    /// Flushes data which the stream buffered. Streams without a buffer need not implement this.
+    ///
+    async fn flush(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
 }
 
 #[cfg(all(feature = "futures-io", not(feature = "tokio-io")))]
@@ -282,6 +587,10 @@ where
         futures::AsyncWriteExt::write_all(self, data).await?;
         Ok(())
     }
+
+    async fn flush(&mut self) -> Result<(), std::io::Error> {
+        futures::AsyncWriteExt::flush(self).await
+    }
 }
 
 #[cfg(feature = "tokio-io")]
@@ -294,6 +603,10 @@ where
         tokio::io::AsyncWriteExt::write_all(self, data).await?;
         Ok(())
     }
+
+    async fn flush(&mut self) -> Result<(), std::io::Error> {
+        tokio::io::AsyncWriteExt::flush(self).await
+    }
 }
 
 #[cfg(any(feature = "futures-io", feature = "tokio-io"))]
@@ -306,200 +619,1425 @@ where
     where
         P: Packet + Send + Sync,
     {
-        let prepared = self.serialize_packet_to_buf(packet)?;
-        write_data_to_target_async(self.prepare_packet_in_buf(prepared)?).await?;
-        Ok(())
+        self.write_pending_async().await?;
+        let prepared = self.serialize_packet_to_buf(&packet)?;
+        let frame = self.prepare_frame(prepared)?;
+        self.write_frame_async(frame).await
     }
 
     async fn write_raw_packet_async<'a, P>(&mut self, packet: P) -> WriteResult<()>
     where
         P: RawPacket<'a> + Send + Sync,
     {
+        self.write_pending_async().await?;
         let prepared = self.serialize_raw_packet_to_buf(packet)?;
-        write_data_to_target_async(self.prepare_packet_in_buf(prepared)?).await?;
-        Ok(())
+        let frame = self.prepare_frame(prepared)?;
+        self.write_frame_async(frame).await
     }
 }
 
 #[cfg(any(feature = "futures-io", feature = "tokio-io"))]
-async fn write_data_to_target_async<'a, W>(
-    tuple: (&'a [u8], &'a mut W),
-) -> Result<(), std::io::Error>
+impl<W> CraftWriter<W>
 where
     W: AsyncWriteAll,
 {
-    let (data, target) = tuple;
-    target.write_all(data).await
-}
+    ///
+    /// Same as `write_raw_untyped_packet`, but writes to an async stream.
+    ///
+    pub async fn write_raw_untyped_packet_async(
+        &mut self,
+        id: Id,
+        body: &[u8],
+    ) -> WriteResult<()> {
+        self.write_pending_async().await?;
+        let prepared = self.serialize_untyped_packet_to_buf(id, body)?;
+        let frame = self.prepare_frame(prepared)?;
+        self.write_frame_async(frame).await
+    }
 
-// this HEADER_OFFSET is basically the number of free 0s at the front of the packet buffer when
-// we setup serialization of a packet. The purpose of doing this is to serialize packet id + body
-// first, then serialize the length in front of it. The length, which is a VarInt, can be up to 5
-// bytes long.
-//
-// Therefore, the general algorithm for serializing a packet is:
-//  * use a Vec<u8> as a buffer
-//  * use GrowVecSerializer to serialize packet id + body into buffer starting at offset HEADER_OFFSET
-//
-//  If we are in compressed mode, then we must write two lengths:
-//    * packet length (literal length, as in number of bytes that follows)
-//    * data length (length of the id + body when uncompressed)
-//
-//  In a compressed mode, we only perform compression when the length >= threshold, and if it's below
-//  the threshold we write these two VarInts at the front of the packet:
-//    * packet length (literal # of bytes to follow)
-//    * 0 - the data length
-//
-//  No matter what mode we are in, we first write packet id + packet body to a buffer called "buf"
-//
-//  If we are not in a compressed mode, then we simply put the packet length at the front of this
-//  buf and return a pointer to the region which contains the length + id + data.
-//
-//  In compressed mode, we lazily allocate a second buffer called "compress_buf" which will only be
-//  used if we actually compress a packet.
-//
-//  "buf" reserves enough space for a length varint and 1 extra byte for a 0 data length
-//
-//  If we are in compressed mode, but not actually performing compression, we use the packet data
-//  already in buf, and simply put the length of the packet (+ 1) into the region starting at 0
-//
-//  If we are in compressed mode, and we perform compression on the packet, we will compress data
-//  in buf from HEADER_OFFSET..packet_id_and_body_len into compress_buf region at COMPRESS_HEADER_OFFSET..
-//  We can then put the packet length and data length in the region 0..COMPRESS_HEADER_OFFSET
-//
-// Once the packet is prepared in a buffer, if encryption is enabled we simply encrypt that entire
-// block of data, and then we write that region of data to the target pipe
-//
-#[cfg(feature = "compression")]
-const HEADER_OFFSET: usize = VAR_INT_BUF_SIZE + 1;
+    pub(crate) async fn write_tunneled_async(&mut self, data: &[u8]) -> WriteResult<()> {
+        self.coalesce_pending(true);
+        self.write_pending_async().await?;
+        self.prepare_tunneled(data);
+        self.set_write_in_flight(true);
+        let buf = &self.raw_buf.as_ref().expect("data was just copied into raw_buf")[..data.len()];
+        let result = self.inner.write_all(buf).await;
+        self.set_write_in_flight(false);
+        Ok(result?)
+    }
 
-#[cfg(not(feature = "compression"))]
-const HEADER_OFFSET: usize = VAR_INT_BUF_SIZE;
+    ///
+    /// Same as `flush`, but writes to an async stream.
+    ///
+    pub async fn flush_async(&mut self) -> WriteResult<()> {
+        self.coalesce_pending(true);
+        self.write_pending_async().await?;
+        self.inner.flush().await?;
+        Ok(())
+    }
 
-#[cfg(feature = "compression")]
-const COMPRESSED_HEADER_OFFSET: usize = VAR_INT_BUF_SIZE * 2;
+    ///
+    /// Same as `write_cached_frame`, but writes to an async stream.
+    ///
+    pub async fn write_cached_frame_async(&mut self, frame: &CachedFrame) -> WriteResult<()> {
+        self.write_pending_async().await?;
+        let frame = self.prepare_cached_frame(frame)?;
+        self.write_frame_async(frame).await
+    }
 
-struct PreparedPacketHandle {
-    id_size: usize,
-    data_size: usize,
-}
+    ///
+    /// Same as `write_packets`, but takes the packets from a `Stream`, and writes to an async
+    /// stream. Nothing is written until the stream of packets ends.
+    ///
+    pub async fn write_packets_async<S>(&mut self, packets: S) -> WriteResult<()>
+    where
+        S: Stream + Unpin,
+        S::Item: Packet,
+    {
+        self.write_pending_async().await?;
+        let mut packets = packets;
+        let mut batch_len = 0;
+        let mut result = Ok(());
+        while let Some(packet) = poll_fn(|cx| Pin::new(&mut packets).poll_next(cx)).await {
+            if let Err(err) = self.batch_packet(&mut batch_len, &packet) {
+                result = Err(err);
+                break;
+            }
+        }
 
-impl<W> CraftWriter<W> {
-    pub fn wrap(inner: W, direction: PacketDirection) -> Self {
-        Self::wrap_with_state(inner, direction, State::Handshaking)
+        if batch_len > 0 {
+            self.write_frame_async(PreparedFrame {
+                buffer: FrameBuffer::Flush,
+                range: 0..batch_len,
+            })
+            .await?;
+        }
+        result
     }
 
-    pub fn wrap_with_state(inner: W, direction: PacketDirection, state: State) -> Self {
-        Self {
-            inner,
-            raw_buf: None,
-            #[cfg(feature = "compression")]
-            compression_threshold: None,
-            #[cfg(feature = "compression")]
-            compress_buf: None,
-            state,
-            direction,
-            #[cfg(feature = "encryption")]
-            encryption: None,
-            max_packet_size: DEAFULT_MAX_PACKET_SIZE,
+    // writes a frame with a single write_all, or buffers it while a flush threshold is set. Nothing
+    // may be pending (see write_pending_async), since the frame takes the place of the pending one
+    async fn write_frame_async(&mut self, frame: PreparedFrame) -> WriteResult<()> {
+        self.check_cancelled()?;
+        if self.flush_threshold.is_some() || self.coalesced > 0 {
+            self.pending_write = Some(frame);
+            self.coalesce_pending(false);
+            return self.write_pending_async().await;
         }
-    }
 
-    fn prepare_packet_in_buf(
-        &mut self,
-        prepared: PreparedPacketHandle,
-    ) -> WriteResult<(&[u8], &mut W)> {
-        // assume id and body are in raw buf from HEADER_OFFSET .. size + HEADER_OFFSET
-        let body_size = prepared.id_size + prepared.data_size;
-        let buf = get_sized_buf(&mut self.raw_buf, 0, HEADER_OFFSET + body_size);
+        self.set_write_in_flight(true);
+        let (data, target) = self.frame_and_target(frame);
+        let result = target.write_all(data).await;
+        self.set_write_in_flight(false);
+        Ok(result?)
+    }
 
-        #[cfg(feature = "compression")]
-        let packet_data = if let Some(threshold) = self.compression_threshold {
-            if threshold >= 0 && (threshold as usize) <= body_size {
-                let body_data = &buf[HEADER_OFFSET..];
-                prepare_packet_compressed(body_data, &mut self.compress_buf)?
-            } else {
-                prepare_packet_compressed_below_threshold(buf, body_size)?
+    // writes the rest of every pending frame: the buffered frames once coalesce_pending made them
+    // the pending frame, the rest of a frame whose sync write timed out, and frames left by the
+    // poll functions. Buffers of pending frames must not be reused before they are written.
+    async fn write_pending_async(&mut self) -> WriteResult<()> {
+        self.check_cancelled()?;
+        while let Some(frame) = self.pending_write.clone() {
+            // the frame is dropped if this future is, like any other frame
+            self.finish_pending_write(true);
+            if frame.range.is_empty() {
+                continue;
             }
-        } else {
-            prepare_packet_normally(buf, body_size)?
-        };
-
-        #[cfg(not(feature = "compression"))]
-        let packet_data = prepare_packet_normally(buf, body_size)?;
 
-        #[cfg(feature = "encryption")]
-        handle_encryption(self.encryption.as_mut(), packet_data);
+            self.set_write_in_flight(true);
+            let (data, target) = self.frame_and_target(frame);
+            let result = target.write_all(data).await;
+            self.set_write_in_flight(false);
+            result?;
+        }
 
-        Ok((packet_data, &mut self.inner))
+        Ok(())
     }
 
-    fn serialize_packet_to_buf<P>(&mut self, packet: P) -> WriteResult<PreparedPacketHandle>
-    where
-        P: Packet,
-    {
-        let id_size = self.serialize_id_to_buf(packet.id())?;
-        let data_size = self.serialize_to_buf(HEADER_OFFSET + id_size, move |serializer| {
-            packet
-                .mc_serialize_body(serializer)
-                .map_err(move |err| PacketSerializeFail::Body(err).into())
-        })?;
-
-        Ok(PreparedPacketHandle { id_size, data_size })
+    #[cfg(any(debug_assertions, feature = "cancel-guard"))]
+    fn set_write_in_flight(&mut self, in_flight: bool) {
+        self.write_in_flight = in_flight;
     }
 
-    fn serialize_raw_packet_to_buf<'a, P>(&mut self, packet: P) -> WriteResult<PreparedPacketHandle>
-    where
-        P: RawPacket<'a>,
-    {
-        let id_size = self.serialize_id_to_buf(packet.id())?;
-        let packet_data = packet.data();
-        let data_size = packet_data.len();
-        if data_size > self.max_packet_size {
-            return Err(WriteError::PacketTooLarge {
-                size: data_size,
-                max_size: self.max_packet_size,
+    #[cfg(not(any(debug_assertions, feature = "cancel-guard")))]
+    fn set_write_in_flight(&mut self, _in_flight: bool) {}
+
+    // fails if an earlier async write was cancelled part way through
+    #[cfg(any(debug_assertions, feature = "cancel-guard"))]
+    fn check_cancelled(&self) -> WriteResult<()> {
+        if self.write_in_flight {
+            Err(WriteError::CancelledMidFrame {
                 #[cfg(feature = "backtrace")]
-                backtrace: Backtrace::capture()
+                backtrace: Backtrace::capture(),
             })
+        } else {
+            Ok(())
         }
-        let buf = get_sized_buf(&mut self.raw_buf, HEADER_OFFSET, id_size + data_size);
-
-        (&mut buf[id_size..]).copy_from_slice(packet_data);
-
-        Ok(PreparedPacketHandle { id_size, data_size })
     }
 
-    fn serialize_id_to_buf(&mut self, id: Id) -> WriteResult<usize> {
-        if id.direction != self.direction {
-            return Err(WriteError::BadDirection {
-                expected: self.direction,
-                attempted: id.direction,
-                #[cfg(feature = "backtrace")]
-                backtrace: Backtrace::capture(),
-            });
-        }
+    #[cfg(not(any(debug_assertions, feature = "cancel-guard")))]
+    fn check_cancelled(&self) -> WriteResult<()> {
+        Ok(())
+    }
+}
 
-        if id.state != self.state {
-            return Err(WriteError::BadState {
-                expected: self.state,
-                attempted: id.state,
+///
+/// This trait is the interface used by `CraftWriter::poll_write_packet` to write to the wrapped
+/// stream without an `async fn`.
+///
+/// It is implemented for implementors of `tokio::io::AsyncWrite` or `futures::AsyncWrite` when the
+/// `tokio-io` or `futures-io` feature is enabled.
+///
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+pub trait AsyncPollWrite: Unpin {
+    ///
+    /// Attempts to write some bytes from `data`, returning how many bytes were written.
+    ///
+    fn poll_write_from(
+        &mut self,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<std::io::Result<usize>>;
+}
+
+#[cfg(all(feature = "futures-io", not(feature = "tokio-io")))]
+impl<W> AsyncPollWrite for W
+where
+    W: futures::AsyncWrite + Unpin,
+{
+    fn poll_write_from(
+        &mut self,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        futures::AsyncWrite::poll_write(Pin::new(self), cx, data)
+    }
+}
+
+#[cfg(feature = "tokio-io")]
+impl<W> AsyncPollWrite for W
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    fn poll_write_from(
+        &mut self,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        tokio::io::AsyncWrite::poll_write(Pin::new(self), cx, data)
+    }
+}
+
+// writers of local streams are driven by the same state machine as poll_write_packet, which only
+// needs the stream to be Unpin
+#[cfg(feature = "local-io")]
+#[async_trait(?Send)]
+impl<W> CraftLocalWriter for CraftWriter<W>
+where
+    W: AsyncPollWrite,
+{
+    async fn write_packet_local<P>(&mut self, packet: P) -> WriteResult<()>
+    where
+        P: Packet,
+    {
+        // the rest of a frame whose write was cancelled is written first, which also forgets the
+        // packet of the cancelled write, so this packet is not mistaken for it
+        poll_fn(|cx| self.poll_flush_packets(cx)).await?;
+        poll_fn(|cx| self.poll_write_packet(cx, &packet)).await
+    }
+
+    async fn write_raw_packet_local<'a, P>(&mut self, packet: P) -> WriteResult<()>
+    where
+        P: RawPacket<'a>,
+    {
+        poll_fn(|cx| self.poll_flush_packets(cx)).await?;
+        let mut packet = Some(packet);
+        poll_fn(|cx| match packet.take() {
+            Some(packet) => self.poll_write_raw_packet(cx, packet),
+            None => self.poll_flush_packets(cx),
+        })
+        .await
+    }
+}
+
+#[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+impl<W> CraftWriter<W>
+where
+    W: AsyncPollWrite,
+{
+    ///
+    /// Attempts to serialize, and then write a packet struct to the wrapped stream, without
+    /// requiring an async runtime to drive a future. This is useful when integrating with a custom
+    /// event loop, or when implementing `Future`/`Sink` by hand.
+    ///
+    /// Frames which are already pending (such as packets staged by `start_send_packet`, or the rest
+    /// of a frame whose sync write timed out) are written first. The packet is then serialized into
+    /// the internal buffer. If the wrapped stream cannot accept all of the bytes, `Poll::Pending` is
+    /// returned and the remainder of the frame is kept in the buffer. Subsequent calls continue
+    /// writing that frame and ignore the `packet` argument, so the caller must keep calling this
+    /// function until it returns `Poll::Ready` before writing any other packet.
+    ///
+    pub fn poll_write_packet<P>(
+        &mut self,
+        cx: &mut Context<'_>,
+        packet: &P,
+    ) -> Poll<WriteResult<()>>
+    where
+        P: Packet,
+    {
+        self.poll_write_with(cx, move |writer| writer.serialize_packet_to_buf(packet))
+    }
+
+    ///
+    /// Same as `poll_write_packet`, but writes a serialized packet. See `write_raw_packet`.
+    ///
+    pub fn poll_write_raw_packet<'a, P>(
+        &mut self,
+        cx: &mut Context<'_>,
+        packet: P,
+    ) -> Poll<WriteResult<()>>
+    where
+        P: RawPacket<'a>,
+    {
+        self.poll_write_with(cx, move |writer| writer.serialize_raw_packet_to_buf(packet))
+    }
+
+    ///
+    /// Prepares for `start_send_packet`, in the same way as `Sink::poll_ready`. This returns
+    /// `Poll::Ready` once there is room to stage another packet, which is immediately unless a
+    /// packet is already staged behind the one being written.
+    ///
+    /// Together with `start_send_packet` and `poll_flush_packets`, this double-buffers the writer:
+    /// the next packet is serialized, compressed and encrypted into a second buffer while the
+    /// previous packet is still being written, which overlaps that work with network I/O on busy
+    /// connections. Packets are always written in the order they were staged.
+    ///
+    pub fn poll_ready_packet(&mut self, cx: &mut Context<'_>) -> Poll<WriteResult<()>> {
+        self.poll_write_while(cx, |writer| writer.staged_write.is_some())
+    }
+
+    ///
+    /// Serializes, compresses and encrypts a packet so it is written after any packet which is
+    /// currently being written. `poll_ready_packet` must have returned `Poll::Ready(Ok(()))` before
+    /// this is called, and `poll_flush_packets` must be called to make sure it is actually written.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a packet is already staged (`poll_ready_packet` was not called).
+    ///
+    pub fn start_send_packet<P>(&mut self, packet: &P) -> WriteResult<()>
+    where
+        P: Packet,
+    {
+        self.stage_frame(move |writer| writer.serialize_packet_to_buf(packet))
+    }
+
+    ///
+    /// Same as `start_send_packet`, but stages a serialized packet. See `write_raw_packet`.
+    ///
+    pub fn start_send_raw_packet<'a, P>(&mut self, packet: P) -> WriteResult<()>
+    where
+        P: RawPacket<'a>,
+    {
+        self.stage_frame(move |writer| writer.serialize_raw_packet_to_buf(packet))
+    }
+
+    ///
+    /// Writes every staged packet (and the rest of the packet being written, if any), in the same
+    /// way as `Sink::poll_flush`. This does not flush the wrapped stream itself.
+    ///
+    pub fn poll_flush_packets(&mut self, cx: &mut Context<'_>) -> Poll<WriteResult<()>> {
+        self.poll_write_pending(cx)
+    }
+
+    fn stage_frame<F>(&mut self, serialize: F) -> WriteResult<()>
+    where
+        F: FnOnce(&mut Self) -> WriteResult<PreparedPacketHandle>,
+    {
+        assert!(
+            self.staged_write.is_none(),
+            "start_send_packet was called before poll_ready_packet returned Poll::Ready"
+        );
+
+        self.detach_pending_write();
+        let frame = serialize(self).and_then(|prepared| self.prepare_frame(prepared))?;
+        if self.pending_write.is_none() {
+            self.pending_write = Some(frame);
+        } else {
+            self.staged_write = Some(frame);
+        }
+
+        Ok(())
+    }
+
+    // writes the frames which are already pending, then serializes the packet of this call and
+    // writes it too. The packet is only serialized once, while poll_write_started is not set.
+    fn poll_write_with<F>(&mut self, cx: &mut Context<'_>, serialize: F) -> Poll<WriteResult<()>>
+    where
+        F: FnOnce(&mut Self) -> WriteResult<PreparedPacketHandle>,
+    {
+        if !self.poll_write_started {
+            match self.poll_write_pending(cx) {
+                Poll::Ready(Ok(())) => {}
+                other => return other,
+            }
+
+            match serialize(self).and_then(|prepared| self.prepare_frame(prepared)) {
+                Ok(frame) => self.pending_write = Some(frame),
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+            self.poll_write_started = true;
+        }
+
+        let result = self.poll_write_pending(cx);
+        if result.is_ready() {
+            self.poll_write_started = false;
+        }
+        result
+    }
+
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<WriteResult<()>> {
+        self.poll_write_while(cx, |writer| writer.pending_write.is_some())
+    }
+
+    // writes pending frames until condition returns false, which it must do once nothing is pending
+    fn poll_write_while<F>(&mut self, cx: &mut Context<'_>, condition: F) -> Poll<WriteResult<()>>
+    where
+        F: Fn(&Self) -> bool,
+    {
+        while condition(self) {
+            let frame = match self.pending_write.clone() {
+                Some(frame) => frame,
+                None => break,
+            };
+
+            if frame.range.is_empty() {
+                self.finish_pending_write(true);
+                continue;
+            }
+
+            self.check_throughput()?;
+            let (data, target) = self.frame_and_target(frame);
+            match target.poll_write_from(cx, data) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(0)) => {
+                    self.finish_pending_write(false);
+                    let err = std::io::Error::from(std::io::ErrorKind::WriteZero);
+                    return Poll::Ready(Err(err.into()));
+                }
+                Poll::Ready(Ok(n)) => self.advance_pending_write(n),
+                Poll::Ready(Err(err)) => {
+                    self.finish_pending_write(false);
+                    return Poll::Ready(Err(err.into()));
+                }
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+// serializes a string packet field (such as a chat component), which is a VarInt length followed by
+// the UTF-8 bytes
+pub(crate) fn serialize_string(value: &str) -> WriteResult<Vec<u8>> {
+    let mut len_buf = [0u8; VAR_INT_BUF_SIZE];
+    let mut len_serializer = SliceSerializer::create(&mut len_buf);
+    VarInt(value.len() as i32)
+        .mc_serialize(&mut len_serializer)
+        .map_err(move |err| PacketSerializeFail::Body(err))?;
+    let len_bytes = len_serializer.finish();
+
+    let mut out = Vec::with_capacity(len_bytes.len() + value.len());
+    out.extend_from_slice(len_bytes);
+    out.extend_from_slice(value.as_bytes());
+    Ok(out)
+}
+
+// this HEADER_OFFSET is basically the number of free 0s at the front of the packet buffer when
+// we setup serialization of a packet. The purpose of doing this is to serialize packet id + body
+// first, then serialize the length in front of it. The length, which is a VarInt, can be up to 5
+// bytes long.
+//
+// Therefore, the general algorithm for serializing a packet is:
+//  * use a Vec<u8> as a buffer
+//  * use GrowVecSerializer to serialize packet id + body into buffer starting at offset HEADER_OFFSET
+//
+//  If we are in compressed mode, then we must write two lengths:
+//    * packet length (literal length, as in number of bytes that follows)
+//    * data length (length of the id + body when uncompressed)
+//
+//  In a compressed mode, we only perform compression when the length >= threshold, and if it's below
+//  the threshold we write these two VarInts at the front of the packet:
+//    * packet length (literal # of bytes to follow)
+//    * 0 - the data length
+//
+//  No matter what mode we are in, we first write packet id + packet body to a buffer called "buf"
+//
+//  If we are not in a compressed mode, then we simply put the packet length at the front of this
+//  buf and return a pointer to the region which contains the length + id + data.
+//
+//  In compressed mode, we lazily allocate a second buffer called "compress_buf" which will only be
+//  used if we actually compress a packet.
+//
+//  "buf" reserves enough space for a length varint and 1 extra byte for a 0 data length
+//
+//  If we are in compressed mode, but not actually performing compression, we use the packet data
+//  already in buf, and simply put the length of the packet (+ 1) into the region starting at 0
+//
+//  If we are in compressed mode, and we perform compression on the packet, we will compress data
+//  in buf from HEADER_OFFSET..packet_id_and_body_len into compress_buf region at COMPRESS_HEADER_OFFSET..
+//  We can then put the packet length and data length in the region 0..COMPRESS_HEADER_OFFSET
+//
+// Once the packet is prepared in a buffer, if encryption is enabled we simply encrypt that entire
+// block of data, and then we write that region of data to the target pipe
+//
+#[cfg(feature = "compression")]
+const HEADER_OFFSET: usize = VAR_INT_BUF_SIZE + 1;
+
+#[cfg(not(feature = "compression"))]
+const HEADER_OFFSET: usize = VAR_INT_BUF_SIZE;
+
+#[cfg(feature = "compression")]
+const COMPRESSED_HEADER_OFFSET: usize = VAR_INT_BUF_SIZE * 2;
+
+struct PreparedPacketHandle {
+    id_size: usize,
+    data_size: usize,
+}
+
+// which buffer holds a prepared frame
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum FrameBuffer {
+    Raw,
+    #[cfg(feature = "compression")]
+    Compressed,
+    // the frame's buffer was swapped into flush_buf, so the next packet can be prepared while this
+    // one is still being written
+    Flush,
+    // the frames buffered while a flush threshold is set, see set_flush_threshold
+    Coalesce,
+}
+
+// the location of a fully prepared (and encrypted, if enabled) frame
+#[derive(Debug, Clone)]
+struct PreparedFrame {
+    buffer: FrameBuffer,
+    range: Range<usize>,
+}
+
+impl PreparedFrame {
+    fn raw(range: Range<usize>) -> Self {
+        Self {
+            buffer: FrameBuffer::Raw,
+            range,
+        }
+    }
+}
+
+impl<W> CraftWriter<W> {
+    pub fn wrap(inner: W, direction: PacketDirection) -> Self {
+        Self::wrap_with_state(inner, direction, State::Handshaking)
+    }
+
+    pub fn wrap_with_state(inner: W, direction: PacketDirection, state: State) -> Self {
+        Self {
+            inner,
+            raw_buf: None,
+            #[cfg(feature = "compression")]
+            compression_threshold: None,
+            #[cfg(feature = "compression")]
+            compress_buf: None,
+            #[cfg(feature = "compression")]
+            compression_level: DEFAULT_COMPRESSION_LEVEL,
+            #[cfg(feature = "compression")]
+            compression_governor: None,
+            #[cfg(feature = "compression")]
+            compression_stats: CompressionStats::default(),
+            state,
+            direction,
+            #[cfg(feature = "encryption")]
+            encryption: None,
+            max_packet_size: DEAFULT_MAX_PACKET_SIZE,
+            buffer_growth: BufferGrowth::default(),
+            pending_write: None,
+            staged_write: None,
+            poll_write_started: false,
+            flush_buf: None,
+            flush_threshold: None,
+            coalesce_buf: None,
+            coalesced: 0,
+            middleware: MiddlewareChain::default(),
+            last_write_at: None,
+            allocator: None,
+            frame_sizes: None,
+            state_max_packet_sizes: None,
+            min_throughput: None,
+            pending_started_at: None,
+            pending_sent: 0,
+            frame_hooks: None,
+            #[cfg(any(debug_assertions, feature = "cancel-guard"))]
+            write_in_flight: false,
+            #[cfg(feature = "tracing")]
+            span: Span::none(),
+        }
+    }
+
+    ///
+    /// Wraps a stream which is already part of an established session, applying its state,
+    /// compression and encryption from `options` before the writer is returned. See
+    /// `SessionOptions`.
+    ///
+    pub fn wrap_with_options(
+        inner: W,
+        direction: PacketDirection,
+        options: &SessionOptions,
+    ) -> Self {
+        let mut out = Self::wrap_with_state(inner, direction, options.state);
+        options.apply(&mut out);
+        out
+    }
+
+    ///
+    /// Same as `wrap_with_state`, but eagerly allocates the internal buffers to the sizes
+    /// specified in `capacities`, so a writer expecting to send large packets early on does not
+    /// grow its buffers several times while writing them.
+    ///
+    pub fn wrap_with_capacities(
+        inner: W,
+        direction: PacketDirection,
+        state: State,
+        capacities: BufferCapacities,
+    ) -> Self {
+        let mut out = Self::wrap_with_state(inner, direction, state);
+        capacities.apply(&mut out);
+        out
+    }
+
+    ///
+    /// Replaces the wrapped stream, keeping all other state of this writer (the packet state,
+    /// compression, encryption and middleware). This is useful to move an established connection
+    /// to a different I/O implementation, such as from a blocking thread into a tokio runtime.
+    ///
+    /// A packet which was partially written by `poll_write_packet` is finished on the new stream.
+    ///
+    pub fn map_inner<W2, F>(self, f: F) -> CraftWriter<W2>
+    where
+        F: FnOnce(W) -> W2,
+    {
+        CraftWriter {
+            inner: f(self.inner),
+            raw_buf: self.raw_buf,
+            #[cfg(feature = "compression")]
+            compress_buf: self.compress_buf,
+            #[cfg(feature = "compression")]
+            compression_threshold: self.compression_threshold,
+            #[cfg(feature = "compression")]
+            compression_level: self.compression_level,
+            #[cfg(feature = "compression")]
+            compression_governor: self.compression_governor,
+            #[cfg(feature = "compression")]
+            compression_stats: self.compression_stats,
+            state: self.state,
+            direction: self.direction,
+            #[cfg(feature = "encryption")]
+            encryption: self.encryption,
+            max_packet_size: self.max_packet_size,
+            buffer_growth: self.buffer_growth,
+            pending_write: self.pending_write,
+            staged_write: self.staged_write,
+            poll_write_started: self.poll_write_started,
+            flush_buf: self.flush_buf,
+            flush_threshold: self.flush_threshold,
+            coalesce_buf: self.coalesce_buf,
+            coalesced: self.coalesced,
+            middleware: self.middleware,
+            last_write_at: self.last_write_at,
+            allocator: self.allocator,
+            frame_sizes: self.frame_sizes,
+            state_max_packet_sizes: self.state_max_packet_sizes,
+            min_throughput: self.min_throughput,
+            pending_started_at: self.pending_started_at,
+            pending_sent: self.pending_sent,
+            frame_hooks: self.frame_hooks,
+            #[cfg(any(debug_assertions, feature = "cancel-guard"))]
+            write_in_flight: self.write_in_flight,
+            #[cfg(feature = "tracing")]
+            span: self.span,
+        }
+    }
+
+    ///
+    /// Sets the zlib compression level used for packets at or above the compression threshold, from
+    /// 1 (fastest, the default) to 9 (best compression).
+    ///
+    #[cfg(feature = "compression")]
+    pub fn set_compression_level(&mut self, level: u32) {
+        self.compression_level = level;
+    }
+
+    #[cfg(feature = "compression")]
+    pub fn compression_level(&self) -> u32 {
+        self.compression_level
+    }
+
+    ///
+    /// The compression threshold packets written by this writer are compressed at, or `None` if
+    /// compression is disabled. A `CompressionGovernor` may raise it while it is active.
+    ///
+    #[cfg(feature = "compression")]
+    pub fn compression_threshold(&self) -> Option<i32> {
+        self.compression_threshold
+    }
+
+    ///
+    /// Installs (or removes) a governor which lowers the compression level and raises the
+    /// compression threshold of this writer while the process is under CPU pressure.
+    ///
+    #[cfg(feature = "compression")]
+    pub fn set_compression_governor(&mut self, governor: Option<CompressionGovernor>) {
+        self.compression_governor = governor;
+    }
+
+    #[cfg(feature = "compression")]
+    pub fn compression_governor(&self) -> Option<&CompressionGovernor> {
+        self.compression_governor.as_ref()
+    }
+
+    ///
+    /// The totals of the packets this writer compressed so far, to tell how much compression saves
+    /// on this connection. Frames written with `write_cached_frame` were compressed ahead of time, and
+    /// are not counted.
+    ///
+    #[cfg(feature = "compression")]
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.compression_stats
+    }
+
+    #[cfg(feature = "compression")]
+    pub fn reset_compression_stats(&mut self) {
+        self.compression_stats = CompressionStats::default();
+    }
+
+    #[cfg(feature = "encryption")]
+    pub(crate) fn set_cipher(&mut self, cipher: Option<CraftCipher>) {
+        self.encryption = cipher;
+    }
+
+    #[cfg(feature = "encryption")]
+    pub(crate) fn has_cipher(&self) -> bool {
+        self.encryption.is_some()
+    }
+
+    ///
+    /// The middleware chain every packet written by this writer passes through. Add middleware to
+    /// it using `MiddlewareChain::push`.
+    ///
+    /// Middleware runs after the packet has been serialized, so it applies to raw packets as well
+    /// as packet structs. This makes it a single place to sanitize outgoing data, no matter which
+    /// code path wrote the packet.
+    ///
+    pub fn middleware(&self) -> &MiddlewareChain {
+        &self.middleware
+    }
+
+    ///
+    /// Replaces the middleware chain of this writer, such as with a chain which is shared with a
+    /// reader.
+    ///
+    pub fn set_middleware(&mut self, chain: MiddlewareChain) {
+        self.middleware = chain;
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn direction(&self) -> PacketDirection {
+        self.direction
+    }
+
+    ///
+    /// A snapshot of the protocol settings of this writer. See `ProtocolSettings`.
+    ///
+    pub fn settings(&self) -> ProtocolSettings {
+        ProtocolSettings {
+            state: self.state,
+            direction: self.direction,
+            #[cfg(feature = "compression")]
+            compression_threshold: self.compression_threshold,
+            #[cfg(feature = "encryption")]
+            encrypted: self.encryption.is_some(),
+            max_packet_size: self.max_packet_size,
+            state_max_packet_sizes: self.state_max_packet_sizes,
+            buffer_growth: self.buffer_growth,
+            // the flush and coalesce buffers have no capacity of their own to restore
+            buffer_capacities: BufferCapacities {
+                raw: buf_capacity(&self.raw_buf),
+                #[cfg(feature = "compression")]
+                compression: buf_capacity(&self.compress_buf),
+            },
+        }
+    }
+
+    ///
+    /// Fails writes with `WriteError::TooSlow` if the peer accepts the bytes of pending frames more
+    /// slowly than `limit` allows, or stops enforcing a minimum throughput if `None` (which is the
+    /// default). See `MinThroughput`.
+    ///
+    /// Only writes which can make partial progress are checked, which are writes to blocking
+    /// streams and the `poll_write_*` functions. The `CraftAsyncWriter` functions write each frame
+    /// with a single `write_all`, so they should be wrapped in a timeout instead.
+    ///
+    pub fn set_min_throughput(&mut self, limit: Option<MinThroughput>) {
+        self.min_throughput = limit;
+        self.pending_started_at = None;
+        self.pending_sent = 0;
+    }
+
+    ///
+    /// Buffers written frames until at least `threshold` bytes of them are waiting, and then
+    /// writes all of them with one write call, or writes every frame as soon as it is prepared if
+    /// `None` (which is the default). `flush` writes the buffered frames regardless of the
+    /// threshold.
+    ///
+    /// This replaces wrapping the stream in a `BufWriter`, which would copy every frame into a
+    /// second buffer of its own size. The buffer grows to hold the threshold plus one frame, and
+    /// is reused afterwards.
+    ///
+    /// Frames buffered when the threshold is removed are written with the next frame, or by
+    /// `flush`. The threshold applies to `CraftSyncWriter`, `CraftAsyncWriter` and the batch
+    /// functions. The `poll_*` functions write frames directly, so `flush` must be called before
+    /// switching to them, and buffered frames are lost if the writer is unwrapped with
+    /// `into_inner` before it is flushed.
+    ///
+    pub fn set_flush_threshold(&mut self, threshold: Option<usize>) {
+        self.flush_threshold = threshold;
+    }
+
+    pub fn flush_threshold(&self) -> Option<usize> {
+        self.flush_threshold
+    }
+
+    ///
+    /// The number of bytes buffered because of the flush threshold, which are not fully written yet.
+    ///
+    pub fn buffered_len(&self) -> usize {
+        self.coalesced
+    }
+
+    ///
+    /// Sets the span the events of this writer are recorded in, and which is entered while
+    /// middleware and frame hooks are called. See `CraftReader::set_span`.
+    ///
+    #[cfg(feature = "tracing")]
+    pub fn set_span(&mut self, span: Span) {
+        self.span = span;
+    }
+
+    #[cfg(feature = "tracing")]
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    ///
+    /// Sets (or removes, with `None`) the callbacks fired at the boundaries of every frame written.
+    /// See `FrameHooks`.
+    ///
+    pub fn set_frame_hooks(&mut self, hooks: Option<Box<dyn FrameHooks>>) {
+        self.frame_hooks = hooks;
+    }
+
+    ///
+    /// Records the size of every frame written into `stats`, or stops recording them if `None`
+    /// (which is the default).
+    ///
+    pub fn set_frame_size_stats(&mut self, stats: Option<FrameSizeStats>) {
+        self.frame_sizes = stats;
+    }
+
+    ///
+    /// When data was last written to the wrapped stream, or `None` if nothing was written yet.
+    ///
+    pub fn last_write_at(&self) -> Option<Instant> {
+        self.last_write_at
+    }
+
+    fn prepare_frame(&mut self, prepared: PreparedPacketHandle) -> WriteResult<PreparedFrame> {
+        // assume id and body are in raw buf from HEADER_OFFSET .. size + HEADER_OFFSET
+        let body_size = prepared.id_size + prepared.data_size;
+        let buf = get_sized_buf_with(
+            &mut self.raw_buf,
+            0,
+            HEADER_OFFSET + body_size,
+            BufferGrowth::Exponential,
+            HEADER_OFFSET + self.max_packet_size,
+            self.allocator.as_deref(),
+        );
+
+        #[cfg(feature = "compression")]
+        let frame = if let Some(threshold) = self.compression_threshold {
+            let (level, threshold) = match &self.compression_governor {
+                Some(governor) => governor.settings(self.compression_level, threshold),
+                None => (self.compression_level, threshold),
+            };
+
+            if threshold >= 0 && (threshold as usize) <= body_size {
+                let body_data = &buf[HEADER_OFFSET..];
+                let started_at = self
+                    .compression_governor
+                    .as_ref()
+                    .filter(|governor| governor.is_measuring())
+                    .map(|_| Instant::now());
+                let range = prepare_packet_compressed(
+                    body_data,
+                    &mut self.compress_buf,
+                    level,
+                    self.max_packet_size,
+                    self.allocator.as_deref(),
+                )?;
+                if let (Some(started_at), Some(governor)) =
+                    (started_at, self.compression_governor.as_mut())
+                {
+                    let now = Instant::now();
+                    governor.record_compress_time(now - started_at, now);
+                }
+                self.compression_stats.record(body_size, range.len());
+
+                PreparedFrame {
+                    buffer: FrameBuffer::Compressed,
+                    range,
+                }
+            } else {
+                PreparedFrame::raw(prepare_packet_compressed_below_threshold(buf, body_size)?)
+            }
+        } else {
+            PreparedFrame::raw(prepare_packet_normally(buf, body_size)?)
+        };
+
+        #[cfg(not(feature = "compression"))]
+        let frame = PreparedFrame::raw(prepare_packet_normally(buf, body_size)?);
+
+        if let Some(stats) = self.frame_sizes.as_ref() {
+            stats.record_write(frame.range.len(), body_size);
+        }
+
+        self.call_write_hook(&frame);
+        #[cfg(feature = "encryption")]
+        self.encrypt_frame(&frame);
+
+        Ok(frame)
+    }
+
+    fn call_write_hook(&mut self, frame: &PreparedFrame) {
+        let hooks = match self.frame_hooks.as_mut() {
+            Some(hooks) => hooks,
+            None => return,
+        };
+        #[cfg(feature = "tracing")]
+        let _span = self.span.enter();
+
+        #[cfg(feature = "compression")]
+        let buf = match frame.buffer {
+            FrameBuffer::Raw => &self.raw_buf,
+            FrameBuffer::Compressed => &self.compress_buf,
+            FrameBuffer::Flush => &self.flush_buf,
+            FrameBuffer::Coalesce => &self.coalesce_buf,
+        };
+        #[cfg(not(feature = "compression"))]
+        let buf = match frame.buffer {
+            FrameBuffer::Raw => &self.raw_buf,
+            FrameBuffer::Flush => &self.flush_buf,
+            FrameBuffer::Coalesce => &self.coalesce_buf,
+        };
+
+        let data = &buf.as_ref().expect("frame was prepared in this buffer")[frame.range.clone()];
+        // the frame starts with its length prefix, which the hooks do not see
+        let header_len = parse_frame_len(data, usize::MAX)
+            .ok()
+            .flatten()
+            .map_or(0, |(_, header_len)| header_len);
+        hooks.before_frame_write(&data[header_len..]);
+    }
+
+    #[cfg(feature = "encryption")]
+    fn encrypt_frame(&mut self, frame: &PreparedFrame) {
+        let buf = match frame.buffer {
+            FrameBuffer::Raw => &mut self.raw_buf,
+            #[cfg(feature = "compression")]
+            FrameBuffer::Compressed => &mut self.compress_buf,
+            FrameBuffer::Flush => &mut self.flush_buf,
+            FrameBuffer::Coalesce => &mut self.coalesce_buf,
+        };
+
+        let buf = buf.as_mut().expect("frame was prepared in this buffer");
+        handle_encryption(self.encryption.as_mut(), &mut buf[frame.range.clone()]);
+    }
+
+    // copies bytes which are written without framing into raw_buf, and encrypts them
+    fn prepare_tunneled(&mut self, data: &[u8]) {
+        let buf = get_sized_buf_with(
+            &mut self.raw_buf,
+            0,
+            data.len(),
+            self.buffer_growth,
+            HEADER_OFFSET + self.max_packet_size,
+            self.allocator.as_deref(),
+        );
+        buf.copy_from_slice(data);
+        #[cfg(feature = "encryption")]
+        handle_encryption(self.encryption.as_mut(), buf);
+        self.last_write_at = Some(Instant::now());
+    }
+
+    fn frame_and_target(&mut self, frame: PreparedFrame) -> (&mut [u8], &mut W) {
+        #[cfg(feature = "compression")]
+        let buf = match frame.buffer {
+            FrameBuffer::Raw => &mut self.raw_buf,
+            FrameBuffer::Compressed => &mut self.compress_buf,
+            FrameBuffer::Flush => &mut self.flush_buf,
+            FrameBuffer::Coalesce => &mut self.coalesce_buf,
+        };
+        #[cfg(not(feature = "compression"))]
+        let buf = match frame.buffer {
+            FrameBuffer::Raw => &mut self.raw_buf,
+            FrameBuffer::Flush => &mut self.flush_buf,
+            FrameBuffer::Coalesce => &mut self.coalesce_buf,
+        };
+
+        let data = &mut buf
+            .as_mut()
+            .expect("frame was prepared in this buffer")[frame.range];
+        self.last_write_at = Some(Instant::now());
+        (data, &mut self.inner)
+    }
+
+    // moves the buffer holding the pending frame into flush_buf (without copying), which frees the
+    // other buffers for preparing the next packet
+    #[cfg(any(feature = "futures-io", feature = "tokio-io"))]
+    fn detach_pending_write(&mut self) {
+        let buffer = match self.pending_write.as_ref() {
+            Some(frame) => frame.buffer,
+            None => return,
+        };
+
+        match buffer {
+            FrameBuffer::Raw => std::mem::swap(&mut self.raw_buf, &mut self.flush_buf),
+            #[cfg(feature = "compression")]
+            FrameBuffer::Compressed => std::mem::swap(&mut self.compress_buf, &mut self.flush_buf),
+            // the buffered frames already have a buffer of their own
+            FrameBuffer::Flush | FrameBuffer::Coalesce => return,
+        }
+
+        if let Some(frame) = self.pending_write.as_mut() {
+            frame.buffer = FrameBuffer::Flush;
+        }
+    }
+
+    // called when the pending frame has been written (or failed), so the staged frame is next
+    fn finish_pending_write(&mut self, success: bool) {
+        // the buffered frames are gone once their frame is done, even if it failed, since the
+        // connection can not be used after a failed write anyway
+        if let Some(FrameBuffer::Coalesce) = self.pending_write.as_ref().map(|frame| frame.buffer) {
+            self.coalesced = 0;
+        }
+
+        self.pending_write = if success {
+            self.staged_write.take()
+        } else {
+            self.staged_write = None;
+            None
+        };
+
+        if self.pending_write.is_none() {
+            self.pending_started_at = None;
+            self.pending_sent = 0;
+            self.poll_write_started = false;
+        }
+    }
+
+    // while a flush threshold is set, moves the pending (and staged) frames into coalesce_buf
+    // instead of writing them. The buffered frames become the pending frame once there are at
+    // least `flush_threshold` bytes of them, or when `force` is set (such as for a flush). Frames
+    // are also appended while any are still buffered after the threshold was removed, so they are
+    // written in order.
+    fn coalesce_pending(&mut self, force: bool) {
+        if self.flush_threshold.is_some() || self.coalesced > 0 {
+            while let Some(frame) = self.pending_write.clone() {
+                if frame.buffer == FrameBuffer::Coalesce {
+                    return;
+                }
+
+                #[cfg(feature = "compression")]
+                let source = match frame.buffer {
+                    FrameBuffer::Raw => &self.raw_buf,
+                    FrameBuffer::Compressed => &self.compress_buf,
+                    FrameBuffer::Flush => &self.flush_buf,
+                    FrameBuffer::Coalesce => unreachable!("buffered frames are never coalesced"),
+                };
+                #[cfg(not(feature = "compression"))]
+                let source = match frame.buffer {
+                    FrameBuffer::Raw => &self.raw_buf,
+                    FrameBuffer::Flush => &self.flush_buf,
+                    FrameBuffer::Coalesce => unreachable!("buffered frames are never coalesced"),
+                };
+
+                let data = &source.as_ref().expect("frame was prepared in this buffer")[frame.range];
+                get_sized_buf_with(
+                    &mut self.coalesce_buf,
+                    self.coalesced,
+                    data.len(),
+                    BufferGrowth::Exponential,
+                    HEADER_OFFSET + self.max_packet_size,
+                    self.allocator.as_deref(),
+                )
+                .copy_from_slice(data);
+                self.coalesced += data.len();
+                self.finish_pending_write(true);
+            }
+        }
+
+        let threshold = if force { 1 } else { self.flush_threshold.unwrap_or(1) };
+        if self.pending_write.is_none() && self.coalesced > 0 && self.coalesced >= threshold {
+            self.pending_write = Some(PreparedFrame {
+                buffer: FrameBuffer::Coalesce,
+                range: 0..self.coalesced,
+            });
+        }
+    }
+
+    fn advance_pending_write(&mut self, n: usize) {
+        if let Some(pending) = self.pending_write.as_mut() {
+            pending.range.start += n;
+        }
+        self.pending_sent += n;
+    }
+
+    // called before each write of a pending frame. Frames are in flight from their first write
+    // until nothing is pending anymore, and the peer must accept their bytes quickly enough.
+    fn check_throughput(&mut self) -> WriteResult<()> {
+        let limit = match self.min_throughput {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(*self.pending_started_at.get_or_insert(now));
+        if limit.is_violated(self.pending_sent, elapsed) {
+            let sent = self.pending_sent;
+            self.finish_pending_write(false);
+            Err(WriteError::TooSlow {
+                sent,
+                elapsed,
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture(),
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    fn serialize_packet_to_buf<P>(&mut self, packet: &P) -> WriteResult<PreparedPacketHandle>
+    where
+        P: Packet,
+    {
+        let id_size = self.serialize_id_to_buf(packet.id())?;
+        let data_size = self.serialize_to_buf(HEADER_OFFSET + id_size, move |serializer| {
+            packet
+                .mc_serialize_body(serializer)
+                .map_err(move |err| PacketSerializeFail::Body(err).into())
+        })?;
+
+        self.apply_middleware(packet.id(), PreparedPacketHandle { id_size, data_size })
+    }
+
+    fn serialize_raw_packet_to_buf<'a, P>(&mut self, packet: P) -> WriteResult<PreparedPacketHandle>
+    where
+        P: RawPacket<'a>,
+    {
+        self.serialize_untyped_packet_to_buf(packet.id(), packet.data())
+    }
+
+    fn serialize_untyped_packet_to_buf(
+        &mut self,
+        id: Id,
+        packet_data: &[u8],
+    ) -> WriteResult<PreparedPacketHandle> {
+        let prepared = self.copy_untyped_packet_to_buf(id, packet_data)?;
+        self.apply_middleware(id, prepared)
+    }
+
+    fn copy_untyped_packet_to_buf(
+        &mut self,
+        id: Id,
+        packet_data: &[u8],
+    ) -> WriteResult<PreparedPacketHandle> {
+        let id_size = self.serialize_id_to_buf(id)?;
+        let data_size = packet_data.len();
+        if id_size + data_size > self.max_packet_size {
+            return Err(WriteError::PacketTooLarge {
+                size: id_size + data_size,
+                max_size: self.max_packet_size,
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture()
+            })
+        }
+        let buf = get_sized_buf_with(
+            &mut self.raw_buf,
+            HEADER_OFFSET,
+            id_size + data_size,
+            self.buffer_growth,
+            self.max_packet_size,
+            self.allocator.as_deref(),
+        );
+
+        (&mut buf[id_size..]).copy_from_slice(packet_data);
+
+        Ok(PreparedPacketHandle { id_size, data_size })
+    }
+
+    ///
+    /// Serializes a packet into a complete frame (with the length prefix, and compressed according
+    /// to this writer's threshold) without writing it. Returns the packet body (without the packet
+    /// id) and the frame, which is not encrypted.
+    ///
+    pub(crate) fn detached_frame<F>(&mut self, serialize: F) -> WriteResult<(Vec<u8>, Vec<u8>)>
+    where
+        F: FnOnce(&mut Self) -> WriteResult<PreparedPacketHandle>,
+    {
+        let prepared = serialize(self)?;
+        let body_offset = HEADER_OFFSET + prepared.id_size;
+        let body = get_sized_buf(&mut self.raw_buf, body_offset, prepared.data_size).to_vec();
+        let frame = self.prepare_frame(prepared)?;
+        let (data, _) = self.frame_and_target(frame);
+        Ok((body, data.to_vec()))
+    }
+
+    // prepares the frame of a packet and appends it to the batch being built in flush_buf, which
+    // is `batch_len` bytes long. Packets rejected by the middleware are left out of the batch.
+    fn batch_packet<P>(&mut self, batch_len: &mut usize, packet: &P) -> WriteResult<()>
+    where
+        P: Packet,
+    {
+        debug_assert!(
+            self.pending_write.is_none(),
+            "packets were batched while a frame was still pending"
+        );
+
+        let prepared = match self.serialize_packet_to_buf(packet) {
+            Ok(prepared) => prepared,
+            Err(WriteError::Rejected { .. }) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let frame = self.prepare_frame(prepared)?;
+
+        #[cfg(feature = "compression")]
+        let source = match frame.buffer {
+            FrameBuffer::Raw => &self.raw_buf,
+            FrameBuffer::Compressed => &self.compress_buf,
+            FrameBuffer::Flush | FrameBuffer::Coalesce => {
+                unreachable!("frames are never prepared in flush_buf or coalesce_buf")
+            }
+        };
+        #[cfg(not(feature = "compression"))]
+        let source = match frame.buffer {
+            FrameBuffer::Raw => &self.raw_buf,
+            FrameBuffer::Flush | FrameBuffer::Coalesce => {
+                unreachable!("frames are never prepared in flush_buf or coalesce_buf")
+            }
+        };
+
+        let data = &source.as_ref().expect("frame was prepared in this buffer")[frame.range];
+        get_sized_buf_with(
+            &mut self.flush_buf,
+            *batch_len,
+            data.len(),
+            BufferGrowth::Exponential,
+            HEADER_OFFSET + self.max_packet_size,
+            self.allocator.as_deref(),
+        )
+        .copy_from_slice(data);
+        *batch_len += data.len();
+        Ok(())
+    }
+
+    pub(crate) fn detached_packet_frame<P>(&mut self, packet: &P) -> WriteResult<(Vec<u8>, Vec<u8>)>
+    where
+        P: Packet,
+    {
+        self.detached_frame(move |writer| writer.serialize_packet_to_buf(packet))
+    }
+
+    pub(crate) fn detached_untyped_frame(
+        &mut self,
+        id: Id,
+        body: &[u8],
+    ) -> WriteResult<(Vec<u8>, Vec<u8>)> {
+        self.detached_frame(move |writer| writer.serialize_untyped_packet_to_buf(id, body))
+    }
+
+    #[cfg(feature = "compression")]
+    fn current_compression_threshold(&self) -> Option<i32> {
+        self.compression_threshold
+    }
+
+    #[cfg(not(feature = "compression"))]
+    fn current_compression_threshold(&self) -> Option<i32> {
+        None
+    }
+
+    // copies a cached frame into raw_buf and encrypts it for this writer
+    fn prepare_cached_frame(&mut self, cached: &CachedFrame) -> WriteResult<PreparedFrame> {
+        let writer_threshold = self.current_compression_threshold();
+        if cached.compression_threshold != writer_threshold {
+            return Err(WriteError::CachedFrameMismatch {
+                frame_threshold: cached.compression_threshold,
+                writer_threshold,
                 #[cfg(feature = "backtrace")]
                 backtrace: Backtrace::capture(),
             });
         }
 
+        self.check_id(cached.id)?;
+        match self.middleware.on_write(cached.id, &cached.body) {
+            PacketVerdict::Pass => {}
+            PacketVerdict::Replace(body) => {
+                let prepared = self.copy_untyped_packet_to_buf(cached.id, &body)?;
+                return self.prepare_frame(prepared);
+            }
+            PacketVerdict::Reject => {
+                return Err(WriteError::Rejected {
+                    id: cached.id,
+                    #[cfg(feature = "backtrace")]
+                    backtrace: Backtrace::capture(),
+                })
+            }
+        }
+
+        let len = cached.frame.len();
+        get_sized_buf_with(
+            &mut self.raw_buf,
+            0,
+            len,
+            self.buffer_growth,
+            HEADER_OFFSET + self.max_packet_size,
+            self.allocator.as_deref(),
+        )
+        .copy_from_slice(&cached.frame);
+        let frame = PreparedFrame::raw(0..len);
+
+        if let Some(stats) = self.frame_sizes.as_ref() {
+            let id_len = var_int_len(cached.id.id as u32 as usize);
+            stats.record_write(len, id_len + cached.body.len());
+        }
+
+        self.call_write_hook(&frame);
+        #[cfg(feature = "encryption")]
+        self.encrypt_frame(&frame);
+
+        Ok(frame)
+    }
+
+    fn apply_middleware(
+        &mut self,
+        id: Id,
+        prepared: PreparedPacketHandle,
+    ) -> WriteResult<PreparedPacketHandle> {
+        #[cfg(feature = "tracing")]
+        let _span = self.span.clone().entered();
+        #[cfg(feature = "tracing")]
+        tracing::trace!(id = id.id, state = ?id.state, size = prepared.data_size, "writing packet");
+
+        let body_offset = HEADER_OFFSET + prepared.id_size;
+        let body = &get_sized_buf(&mut self.raw_buf, body_offset, prepared.data_size)[..];
+        match self.middleware.on_write(id, body) {
+            PacketVerdict::Pass => Ok(prepared),
+            PacketVerdict::Replace(body) => {
+                let data_size = body.len();
+                if prepared.id_size + data_size > self.max_packet_size {
+                    return Err(WriteError::PacketTooLarge {
+                        size: prepared.id_size + data_size,
+                        max_size: self.max_packet_size,
+                        #[cfg(feature = "backtrace")]
+                        backtrace: Backtrace::capture(),
+                    });
+                }
+
+                get_sized_buf_with(
+                    &mut self.raw_buf,
+                    body_offset,
+                    data_size,
+                    self.buffer_growth,
+                    self.max_packet_size,
+                    self.allocator.as_deref(),
+                )
+                .copy_from_slice(&body);
+
+                Ok(PreparedPacketHandle {
+                    id_size: prepared.id_size,
+                    data_size,
+                })
+            }
+            PacketVerdict::Reject => Err(WriteError::Rejected {
+                id,
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture(),
+            }),
+        }
+    }
+
+    fn serialize_id_to_buf(&mut self, id: Id) -> WriteResult<usize> {
+        debug_assert!(
+            self.pending_write
+                .as_ref()
+                .map_or(true, |frame| frame.buffer == FrameBuffer::Flush
+                    || frame.buffer == FrameBuffer::Coalesce),
+            "a packet was written while a poll_write_packet call was still pending"
+        );
+
+        self.check_id(id)?;
         self.serialize_to_buf(HEADER_OFFSET, move |serializer| {
             id.mc_serialize(serializer)
                 .map_err(move |err| PacketSerializeFail::Header(err).into())
         })
     }
 
+    fn check_id(&self, id: Id) -> WriteResult<()> {
+        if id.direction != self.direction {
+            return Err(WriteError::BadDirection {
+                expected: self.direction,
+                attempted: id.direction,
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture(),
+            });
+        }
+
+        if id.state != self.state {
+            return Err(WriteError::BadState {
+                expected: self.state,
+                attempted: id.state,
+                #[cfg(feature = "backtrace")]
+                backtrace: Backtrace::capture(),
+            });
+        }
+
+        Ok(())
+    }
+
     fn serialize_to_buf<'a, F>(&'a mut self, offset: usize, f: F) -> WriteResult<usize>
     where
         F: FnOnce(&mut GrowVecSerializer<'a>) -> Result<(), WriteError>,
     {
-        let mut serializer = GrowVecSerializer::create(&mut self.raw_buf, offset, self.max_packet_size);
+        let mut serializer = GrowVecSerializer::create(
+            &mut self.raw_buf,
+            offset,
+            self.max_packet_size,
+            self.buffer_growth,
+            self.allocator.as_deref(),
+        );
         f(&mut serializer)?;
         let packet_size = serializer.written_data_len();
         if serializer.exceeded_max_size {
@@ -515,7 +2053,7 @@ impl<W> CraftWriter<W> {
     }
 }
 
-fn prepare_packet_normally(buf: &mut [u8], body_size: usize) -> WriteResult<&mut [u8]> {
+fn prepare_packet_normally(buf: &mut [u8], body_size: usize) -> WriteResult<Range<usize>> {
     #[cfg(feature = "compression")]
     const BUF_SKIP_BYTES: usize = 1;
 
@@ -538,15 +2076,29 @@ fn prepare_packet_normally(buf: &mut [u8], body_size: usize) -> WriteResult<&mut
 
     let start_offset = n_shift_packet_len + BUF_SKIP_BYTES;
     let end_at = start_offset + packet_len_bytes + body_size;
-    Ok(&mut buf[start_offset..end_at])
+    Ok(start_offset..end_at)
 }
 
 #[cfg(feature = "compression")]
-fn prepare_packet_compressed<'a>(
-    buf: &'a [u8],
-    compress_buf: &'a mut Option<Vec<u8>>,
-) -> WriteResult<&'a mut [u8]> {
-    let compressed_size = compress(buf, compress_buf, COMPRESSED_HEADER_OFFSET)?.len();
+fn prepare_packet_compressed(
+    buf: &[u8],
+    compress_buf: &mut Option<Vec<u8>>,
+    level: u32,
+    max_packet_size: usize,
+    allocator: Option<&dyn BufferAllocator>,
+) -> WriteResult<Range<usize>> {
+    // compressed data is almost always smaller than its input, so growing the buffer to fit the
+    // input here means compression never has to grow it without the allocator
+    get_sized_buf_with(
+        compress_buf,
+        0,
+        COMPRESSED_HEADER_OFFSET + buf.len(),
+        BufferGrowth::Exponential,
+        COMPRESSED_HEADER_OFFSET + max_packet_size,
+        allocator,
+    );
+    let compressed_size =
+        compress_with_level(buf, compress_buf, COMPRESSED_HEADER_OFFSET, level)?.len();
     let compress_buf = get_sized_buf(compress_buf, 0, compressed_size + COMPRESSED_HEADER_OFFSET);
 
     let data_len_target = &mut compress_buf[VAR_INT_BUF_SIZE..COMPRESSED_HEADER_OFFSET];
@@ -578,14 +2130,14 @@ fn prepare_packet_compressed<'a>(
     let start_offset = n_shift_data_len + n_shift_packet_len;
     let end_at = start_offset + data_len_bytes + packet_len_bytes + compressed_size;
 
-    Ok(&mut compress_buf[start_offset..end_at])
+    Ok(start_offset..end_at)
 }
 
 #[cfg(feature = "compression")]
 fn prepare_packet_compressed_below_threshold(
     buf: &mut [u8],
     body_size: usize,
-) -> WriteResult<&mut [u8]> {
+) -> WriteResult<Range<usize>> {
     let packet_len_target = &mut buf[..HEADER_OFFSET - 1];
     let mut packet_len_serializer = SliceSerializer::create(packet_len_target);
     VarInt((body_size + 1) as i32) // +1 because of data length
@@ -602,7 +2154,7 @@ fn prepare_packet_compressed_below_threshold(
 
     let end_at = n_shift_packet_len + packet_len_bytes + 1 + body_size;
     buf[HEADER_OFFSET - 1] = 0; // data_len = 0
-    Ok(&mut buf[n_shift_packet_len..end_at])
+    Ok(n_shift_packet_len..end_at)
 }
 
 #[cfg(feature = "encryption")]
@@ -618,6 +2170,8 @@ struct GrowVecSerializer<'a> {
     at: usize,
     offset: usize,
     max_size: usize,
+    growth: BufferGrowth,
+    allocator: Option<&'a dyn BufferAllocator>,
     exceeded_max_size: bool,
 }
 
@@ -629,7 +2183,15 @@ impl<'a> Serializer for GrowVecSerializer<'a> {
             if new_len > self.max_size {
                 self.exceeded_max_size = true;
             } else {
-                get_sized_buf(self.target, self.at + self.offset, data.len()).copy_from_slice(data);
+                get_sized_buf_with(
+                    self.target,
+                    self.at + self.offset,
+                    data.len(),
+                    self.growth,
+                    self.max_size,
+                    self.allocator,
+                )
+                .copy_from_slice(data);
             }
         }
 
@@ -640,12 +2202,20 @@ impl<'a> Serializer for GrowVecSerializer<'a> {
 }
 
 impl<'a> GrowVecSerializer<'a> {
-    fn create(target: &'a mut Option<Vec<u8>>, offset: usize, max_size: usize) -> Self {
+    fn create(
+        target: &'a mut Option<Vec<u8>>,
+        offset: usize,
+        max_size: usize,
+        growth: BufferGrowth,
+        allocator: Option<&'a dyn BufferAllocator>,
+    ) -> Self {
         Self {
             target,
             at: 0,
             offset,
             max_size,
+            growth,
+            allocator,
             exceeded_max_size: false,
         }
     }
@@ -655,7 +2225,7 @@ impl<'a> GrowVecSerializer<'a> {
     }
 }
 
-struct SliceSerializer<'a> {
+pub(crate) struct SliceSerializer<'a> {
     target: &'a mut [u8],
     at: usize,
 }
@@ -663,7 +2233,7 @@ struct SliceSerializer<'a> {
 impl<'a> Serializer for SliceSerializer<'a> {
     fn serialize_bytes(&mut self, data: &[u8]) -> SerializeResult {
         let end_at = self.at + data.len();
-        if end_at >= self.target.len() {
+        if end_at > self.target.len() {
             panic!(
                 "cannot fit data in slice ({} exceeds length {} at {})",
                 data.len(),
@@ -679,44 +2249,304 @@ impl<'a> Serializer for SliceSerializer<'a> {
 }
 
 impl<'a> SliceSerializer<'a> {
-    fn create(target: &'a mut [u8]) -> Self {
+    pub(crate) fn create(target: &'a mut [u8]) -> Self {
         Self { target, at: 0 }
     }
 
-    fn finish(self) -> &'a [u8] {
+    pub(crate) fn finish(self) -> &'a [u8] {
         &self.target[..self.at]
     }
 }
 
-#[cfg(feature = "compression")]
-fn compress<'a, 'b>(
-    src: &'b [u8],
-    output: &'a mut Option<Vec<u8>>,
-    offset: usize,
-) -> Result<&'a mut [u8], WriteError> {
-    let target = get_sized_buf(output, offset, src.len());
-    let mut compressor = flate2::Compress::new_with_window_bits(Compression::fast(), true, 15);
-    loop {
-        let input = &src[(compressor.total_in() as usize)..];
-        let eof = input.is_empty();
-        let output = &mut target[(compressor.total_out() as usize)..];
-        let flush = if eof {
-            FlushCompress::Finish
-        } else {
-            FlushCompress::None
-        };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::PacketMiddleware;
+    use crate::reader::{CraftReader, CraftSyncReader};
+    use mcproto_rs::protocol::{HasPacketBody, HasPacketId};
+    use std::io::Cursor;
+
+    const ID: Id = Id {
+        id: 0x00,
+        state: State::Handshaking,
+        direction: PacketDirection::ServerBound,
+    };
+
+    struct TestPacket(&'static [u8]);
+
+    impl HasPacketId for TestPacket {
+        fn id(&self) -> Id {
+            ID
+        }
+    }
 
-        match compressor.compress(input, output, flush)? {
-            Status::Ok => {}
-            Status::BufError => {
-                return Err(WriteError::CompressBufError {
-                    #[cfg(feature = "backtrace")]
-                    backtrace: Backtrace::capture(),
-                })
+    impl HasPacketBody for TestPacket {
+        fn mc_serialize_body<S>(&self, to: &mut S) -> SerializeResult
+        where
+            S: Serializer,
+        {
+            to.serialize_bytes(self.0)
+        }
+    }
+
+    impl Packet for TestPacket {}
+
+    fn writer() -> CraftWriter<Vec<u8>> {
+        CraftWriter::wrap(Vec::new(), PacketDirection::ServerBound)
+    }
+
+    // reads the bodies of every frame which was written
+    fn bodies(written: &[u8]) -> Vec<Vec<u8>> {
+        let mut reader = CraftReader::wrap(Cursor::new(written), PacketDirection::ServerBound);
+        let mut bodies = Vec::new();
+        while let Some((id, body)) = reader.read_raw_untyped_packet().unwrap() {
+            assert_eq!(id, ID);
+            bodies.push(body.to_vec());
+        }
+        bodies
+    }
+
+    #[cfg(feature = "tokio-io")]
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[cfg(feature = "tokio-io")]
+    #[test]
+    fn poll_write_packet_writes_after_a_staged_packet() {
+        let mut writer = writer();
+        block_on(poll_fn(|cx| writer.poll_ready_packet(cx))).unwrap();
+        writer.start_send_packet(&TestPacket(b"staged")).unwrap();
+        block_on(poll_fn(|cx| {
+            writer.poll_write_packet(cx, &TestPacket(b"written"))
+        }))
+        .unwrap();
+
+        assert_eq!(
+            bodies(writer.get_ref()),
+            vec![b"staged".to_vec(), b"written".to_vec()]
+        );
+    }
+
+    #[cfg(feature = "tokio-io")]
+    #[test]
+    fn async_writes_finish_a_pending_frame_first() {
+        use tokio::io::AsyncReadExt;
+
+        let mut expected = writer();
+        expected
+            .write_raw_untyped_packet(ID, b"left pending")
+            .unwrap();
+        expected.write_raw_untyped_packet(ID, b"written").unwrap();
+        let expected = expected.into_inner();
+
+        block_on(async move {
+            // the stream only buffers a few bytes, so the first frame can't be written at once
+            let (stream, mut peer) = tokio::io::duplex(4);
+            let len = expected.len();
+            let reading = tokio::spawn(async move {
+                let mut read = vec![0; len];
+                peer.read_exact(&mut read).await.unwrap();
+                read
+            });
+
+            let mut writer = CraftWriter::wrap(stream, PacketDirection::ServerBound);
+            let polled = poll_fn(|cx| {
+                Poll::Ready(writer.poll_write_packet(cx, &TestPacket(b"left pending")))
+            })
+            .await;
+            assert!(polled.is_pending());
+
+            writer
+                .write_raw_untyped_packet_async(ID, b"written")
+                .await
+                .unwrap();
+            assert_eq!(reading.await.unwrap(), expected);
+        });
+    }
+
+    #[test]
+    fn frames_are_buffered_below_the_flush_threshold() {
+        let mut writer = writer();
+        writer.set_flush_threshold(Some(16));
+        writer.write_raw_untyped_packet(ID, b"one").unwrap();
+        writer.write_raw_untyped_packet(ID, b"two").unwrap();
+
+        assert!(writer.get_ref().is_empty());
+        assert!(writer.buffered_len() > 0);
+    }
+
+    #[test]
+    fn buffered_frames_are_written_once_the_threshold_is_crossed() {
+        let mut writer = writer();
+        writer.set_flush_threshold(Some(16));
+        writer.write_raw_untyped_packet(ID, b"one").unwrap();
+        writer
+            .write_raw_untyped_packet(ID, b"longer than the threshold")
+            .unwrap();
+
+        assert_eq!(writer.buffered_len(), 0);
+        assert_eq!(
+            bodies(writer.get_ref()),
+            vec![b"one".to_vec(), b"longer than the threshold".to_vec()]
+        );
+    }
+
+    #[test]
+    fn flush_writes_buffered_frames() {
+        let mut writer = writer();
+        writer.set_flush_threshold(Some(16));
+        writer.write_raw_untyped_packet(ID, b"one").unwrap();
+        writer.write_raw_untyped_packet(ID, b"two").unwrap();
+        writer.flush().unwrap();
+
+        assert_eq!(writer.buffered_len(), 0);
+        assert_eq!(
+            bodies(writer.get_ref()),
+            vec![b"one".to_vec(), b"two".to_vec()]
+        );
+    }
+
+    #[test]
+    fn settings_report_the_raw_buffer_alone() {
+        let mut writer = writer();
+        writer.set_flush_threshold(Some(64));
+        writer.write_raw_untyped_packet(ID, b"buffered").unwrap();
+
+        assert_eq!(
+            writer.settings().buffer_capacities.raw,
+            buf_capacity(&writer.raw_buf)
+        );
+        assert!(writer.memory_usage().raw_buf > buf_capacity(&writer.raw_buf));
+    }
+
+    #[test]
+    fn untyped_packets_count_the_id_against_the_max_size() {
+        let mut writer = writer();
+        writer.set_max_packet_size(8);
+        writer.write_raw_untyped_packet(ID, b"seven b").unwrap();
+
+        match writer.write_raw_untyped_packet(ID, b"8 bytes!") {
+            Err(WriteError::PacketTooLarge {
+                size: 9,
+                max_size: 8,
+                ..
+            }) => {}
+            other => panic!("expected PacketTooLarge, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    // counts the write calls which reach the stream
+    #[derive(Default)]
+    struct CountingWrites {
+        written: Vec<u8>,
+        writes: usize,
+    }
+
+    impl std::io::Write for CountingWrites {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.writes += 1;
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "tokio-io")]
+    impl tokio::io::AsyncWrite for CountingWrites {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Ready(std::io::Write::write(self.get_mut(), buf))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    struct RejectBody(&'static [u8]);
+
+    impl PacketMiddleware for RejectBody {
+        fn on_write(&mut self, _id: Id, body: &[u8]) -> PacketVerdict {
+            if body == self.0 {
+                PacketVerdict::Reject
+            } else {
+                PacketVerdict::Pass
             }
-            Status::StreamEnd => break,
         }
     }
 
-    Ok(&mut target[..(compressor.total_out() as usize)])
+    fn batch() -> Vec<TestPacket> {
+        vec![
+            TestPacket(b"first"),
+            TestPacket(b"rejected"),
+            TestPacket(b"third"),
+        ]
+    }
+
+    #[test]
+    fn write_packets_writes_the_batch_at_once() {
+        let mut writer = CraftWriter::wrap(CountingWrites::default(), PacketDirection::ServerBound);
+        writer
+            .write_packets(vec![TestPacket(b"first"), TestPacket(b"second")])
+            .unwrap();
+
+        assert_eq!(writer.get_ref().writes, 1);
+        assert_eq!(
+            bodies(&writer.get_ref().written),
+            vec![b"first".to_vec(), b"second".to_vec()]
+        );
+    }
+
+    #[test]
+    fn write_packets_leaves_out_rejected_packets() {
+        let mut writer = CraftWriter::wrap(CountingWrites::default(), PacketDirection::ServerBound);
+        writer.middleware().push(RejectBody(b"rejected"));
+        writer.write_packets(batch()).unwrap();
+
+        assert_eq!(writer.get_ref().writes, 1);
+        assert_eq!(
+            bodies(&writer.get_ref().written),
+            vec![b"first".to_vec(), b"third".to_vec()]
+        );
+    }
+
+    #[cfg(feature = "tokio-io")]
+    struct IterStream<I>(I);
+
+    #[cfg(feature = "tokio-io")]
+    impl<I: Iterator + Unpin> Stream for IterStream<I> {
+        type Item = I::Item;
+
+        fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<I::Item>> {
+            Poll::Ready(self.get_mut().0.next())
+        }
+    }
+
+    #[cfg(feature = "tokio-io")]
+    #[test]
+    fn write_packets_async_writes_the_batch_at_once() {
+        let mut writer = CraftWriter::wrap(CountingWrites::default(), PacketDirection::ServerBound);
+        writer.middleware().push(RejectBody(b"rejected"));
+        block_on(writer.write_packets_async(IterStream(batch().into_iter()))).unwrap();
+
+        assert_eq!(writer.get_ref().writes, 1);
+        assert_eq!(
+            bodies(&writer.get_ref().written),
+            vec![b"first".to_vec(), b"third".to_vec()]
+        );
+    }
 }